@@ -22,11 +22,9 @@ pub fn criterion_benchmark(c: &mut Criterion) {
         b.iter(|| black_box(room.cast_ray(&mut ray, &mut aux)))
     });
 
-    // c.bench_function("unobstructed_room", |b| {
-    //     b.iter(|| {
-    //         room.unobstructed_distance(&ray.geometry, rendering::Float::MAX, &mut aux)
-    //     })
-    // });
+    c.bench_function("unobstructed_room", |b| {
+        b.iter(|| black_box(room.unobstructed_distance(&ray.geometry, 100., &mut aux)))
+    });
 
     // CORNELL
 
@@ -88,11 +86,32 @@ pub fn criterion_benchmark(c: &mut Criterion) {
         b.iter(|| black_box(triangles.cast_ray(&mut ray, &mut aux)))
     });
 
-    // c.bench_function("unobstructed_triangles", |b| {
-    //     b.iter(|| {
-    //         triangles.unobstructed_distance(&ray.geometry, rendering::Float::MAX, &mut aux)
-    //     })
-    // }
+    c.bench_function("unobstructed_triangles", |b| {
+        b.iter(|| black_box(triangles.unobstructed_distance(&ray.geometry, 100., &mut aux)))
+    });
+
+    // Many rays in random directions, so that most of the tree is visited
+    let mut rays: Vec<Ray> = (0..1000)
+        .map(|_| {
+            let (x, y, z): (rendering::Float, rendering::Float, rendering::Float) = rng.gen();
+            Ray {
+                geometry: geometry3d::Ray3D {
+                    direction: geometry3d::Vector3D::new(x - 0.5, y - 0.5, z - 0.5)
+                        .get_normalized(),
+                    origin: geometry3d::Point3D::new(0., 0., 0.),
+                },
+                ..Ray::default()
+            }
+        })
+        .collect();
+
+    c.bench_function("intersect_triangles_1000_rays", |b| {
+        b.iter(|| {
+            for ray in rays.iter_mut() {
+                black_box(triangles.cast_ray(ray, &mut aux));
+            }
+        })
+    });
 
     // SPONZA
 
@@ -24,7 +24,8 @@ struct Inputs {
     pub input2: String,
 
     #[clap(short, long)]
-    /// The output file (it is an HDRE if no colourmap (i.e., -m <Map>);
+    /// The output file. If no colourmap is given (i.e., -m <Map>) its format
+    /// depends on the extension ('.pfm', '.exr' or HDRE otherwise);
     /// otherwise it is falsecolored and stored in JPEG format
     pub output: String,
 
@@ -89,7 +90,10 @@ fn main() {
             }
         }
         None => {
-            diff.save_hdre(out);
+            if let Err(e) = diff.save(out) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
         }
     }
 }
@@ -1,5 +1,6 @@
 use clap::{ArgEnum, Parser};
 use rendering::colourmap::Colourmap;
+use rendering::falsecolour::{FalsecolourMode, FalsecolourOptions};
 
 use rendering::image::ImageBuffer;
 use rendering::Float;
@@ -20,7 +21,7 @@ struct Inputs {
     pub input: String,
 
     #[clap(short, long)]
-    /// The output file (it is a JPEG)
+    /// The output file. It is a PNG if it ends in '.png'; otherwise, it is a JPEG
     pub output: String,
 
     /// The maximum value in the scale
@@ -38,6 +39,28 @@ struct Inputs {
     /// The colour scale to use
     #[clap(arg_enum, short, long, default_value_t=ArgColourMap::Viridis)]
     map: ArgColourMap,
+
+    /// The units written on top of the legend
+    #[clap(short, long, default_value = "cd/m2")]
+    pub units: String,
+
+    /// Do not add a legend to the image
+    #[clap(long = "no_legend")]
+    pub no_legend: bool,
+
+    /// Draw contour lines (over a dimmed version of the image) instead of
+    /// colouring every pixel
+    #[clap(long)]
+    pub lines: bool,
+
+    /// Split the scale into bands of solid colour
+    #[clap(long, conflicts_with = "lines")]
+    pub bands: bool,
+
+    /// The number of divisions in the scale: the number of contours or bands,
+    /// or the number of labels in the legend of a continuous scale
+    #[clap(short = 'n', long, default_value_t = 8)]
+    pub divisions: usize,
 }
 
 fn main() {
@@ -61,19 +84,27 @@ fn main() {
         ArgColourMap::Viridis => Colourmap::Viridis,
     };
 
-    if inputs.log {
-        image.save_log_falsecolour(
-            inputs.min,
-            inputs.max,
-            scale,
-            std::path::Path::new(&inputs.output),
-        )
+    let mode = if inputs.lines {
+        FalsecolourMode::Contours(inputs.divisions)
+    } else if inputs.bands {
+        FalsecolourMode::Bands(inputs.divisions)
     } else {
-        image.save_falsecolour(
-            inputs.min,
-            inputs.max,
-            scale,
-            std::path::Path::new(&inputs.output),
-        )
-    }
+        FalsecolourMode::Continuous
+    };
+
+    let options = FalsecolourOptions {
+        min: inputs.min,
+        max: inputs.max,
+        log: inputs.log,
+        colourmap: scale,
+        mode,
+        legend: if inputs.no_legend {
+            None
+        } else {
+            Some(inputs.units)
+        },
+        n_labels: inputs.divisions,
+    };
+
+    image.save_falsecolour_image(&options, std::path::Path::new(&inputs.output))
 }
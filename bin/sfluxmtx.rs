@@ -20,11 +20,15 @@ SOFTWARE.
 use clap::Parser;
 use rendering::{colour_matrix::save_colour_matrix, Scene};
 use solar::ReinhartSky;
+use std::path::Path;
 // use rendering::from_radiance::from
 use geometry3d::{Point3D, Ray3D, Vector3D};
 use rendering::daylight_coefficients::DCFactory;
+use rendering::environment_map::{EnvironmentMap, EnvironmentProjection};
+use rendering::photon_map::PhotonMapper;
+use rendering::progress::RenderControl;
+use rendering::sampler::SamplerKind;
 use rendering::Float;
-use rendering::Wavelengths;
 
 /// Calculates the Daylight Coefficients
 #[derive(Parser)]
@@ -33,6 +37,12 @@ struct Inputs {
     /// The file to load the model from
     pub input: String,
 
+    /// A file where the scene and its acceleration structure are cached.
+    /// If it exists and was created from the same input file, the scene is
+    /// loaded from it; otherwise, it is created.
+    #[clap(long)]
+    pub cache: Option<String>,
+
     #[clap(short, long)]
     /// The file where the matrix will be stored
     pub output: String,
@@ -62,23 +72,48 @@ struct Inputs {
     /// The number of sensors to receive in the standard input
     #[clap(short = 'n', long, default_value_t = 64)]
     pub n_sensors: usize,
+
+    /// The number of photons emitted for building a photon map. If given,
+    /// rays end at the first Lambertian surface they hit, where the contribution
+    /// of each sky patch is estimated from the photons
+    #[clap(long = "photons")]
+    pub n_photons: Option<usize>,
+
+    /// The seed of the random numbers, for reproducible results. If not
+    /// given, every run is different
+    #[clap(long)]
+    pub seed: Option<u64>,
+
+    /// The sequence the samples are drawn from: 'independent', 'stratified',
+    /// 'sobol' or 'halton'
+    #[clap(long, default_value = "independent")]
+    pub sampler: SamplerKind,
+
+    /// An HDR image of the sky (e.g., a measured sky capture). If given, the daylight
+    /// coefficients are multiplied by its radiance over each patch and the
+    /// output holds the light received by each sensor instead
+    #[clap(long)]
+    pub environment: Option<String>,
+
+    /// How the directions are laid out in the environment image: 'latlong'
+    /// or 'fisheye' (an angular fisheye of the upper hemisphere)
+    #[clap(long = "environment_projection", default_value = "latlong")]
+    pub environment_projection: EnvironmentProjection,
 }
 
 fn main() {
     let inputs = Inputs::parse();
 
     let input_file = inputs.input;
-    let mut scene = if input_file.ends_with(".rad") {
-        Scene::from_radiance(input_file)
-    } else if input_file.ends_with(".spl") {
-        let (model, _header) = simple_model::SimpleModel::from_file(input_file).unwrap();
-        Scene::from_simple_model(&model, Wavelengths::Visible)
-    } else {
-        panic!("Unkwown format in file {}", input_file);
+    let cache = inputs.cache.as_deref().map(Path::new);
+    let scene = match Scene::from_file_with_cache(Path::new(&input_file), cache) {
+        Ok(scene) => scene,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
     };
 
-    scene.build_accelerator();
-
     // Setup sensors
     let mut rays: Vec<Ray3D> = Vec::with_capacity(inputs.n_sensors);
     let mut buffer = String::new();
@@ -167,15 +202,43 @@ fn main() {
         buffer.truncate(0);
     }
 
+    let reinhart = ReinhartSky::new(inputs.mf);
+    let photon_map = inputs.n_photons.map(|n_photons| {
+        let mapper = PhotonMapper {
+            n_global_photons: n_photons,
+            seed: inputs.seed,
+            ..PhotonMapper::default()
+        };
+        std::sync::Arc::new(mapper.build_dc(&scene, &reinhart))
+    });
+
     let factory = DCFactory {
         max_depth: inputs.max_depth,
         n_ambient_samples: inputs.n_ambient_samples,
-        reinhart: ReinhartSky::new(inputs.mf),
+        reinhart,
         limit_weight: inputs.limit_weight,
         count_specular_bounce: inputs.count_specular_bounce,
+        photon_map,
+        seed: inputs.seed,
+        sampler: inputs.sampler,
     };
 
-    let dc_matrix = factory.calc_dc(&rays, &scene);
+    let (dc_matrix, stats) = factory.calc_dc_with_control(&rays, &scene, &RenderControl::stdout());
+    println!("Calculation took {} seconds", stats.elapsed.as_secs());
+    let dc_matrix = match &inputs.environment {
+        Some(file) => {
+            let applied = EnvironmentMap::from_file(Path::new(file), inputs.environment_projection)
+                .and_then(|map| factory.apply_environment(&dc_matrix, &map));
+            match applied {
+                Ok(m) => m,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => dc_matrix,
+    };
     save_colour_matrix(&dc_matrix, std::path::Path::new(&inputs.output)).unwrap()
     // let dc_matrix = rendering::colour_matrix::colour_matrix_to_luminance(&dc_matrix);
     // rendering::colour_matrix::save_matrix(&dc_matrix, &std::path::Path::new(&inputs.output)).unwrap()
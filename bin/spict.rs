@@ -19,7 +19,17 @@ SOFTWARE.
 */
 
 use clap::Parser;
-use rendering::{RayTracer, Scene, Wavelengths};
+use rendering::environment_map::{EnvironmentMap, EnvironmentProjection};
+use rendering::image::ImageBuffer;
+use rendering::irradiance_cache::IrradianceCache;
+use rendering::light_tree::LightSelection;
+use rendering::photon_map::PhotonMapper;
+use rendering::progress::RenderControl;
+use rendering::progressive::ProgressiveOptions;
+use rendering::sampler::SamplerKind;
+use rendering::{RayTracer, Scene};
+use std::path::Path;
+use std::sync::Arc;
 
 use geometry3d::{Point3D, Vector3D};
 use rendering::camera::{Film, Pinhole, View};
@@ -84,8 +94,16 @@ struct Inputs {
     /// The file to load the model from
     pub input: String,
 
+    /// A file where the scene and its acceleration structure are cached.
+    /// If it exists and was created from the same input file, the scene is
+    /// loaded from it; otherwise, it is created.
+    #[clap(long)]
+    pub cache: Option<String>,
+
     #[clap(short, long)]
-    /// The output of the final image (in rgbe format)
+    /// The output of the final image. The format is chosen based on
+    /// the extension: '.pfm' (Portable Float Map), '.exr' (OpenEXR, requires
+    /// the 'exr' feature) or anything else (rgbe format)
     pub output: String,
 
     /* Ray-tracer data */
@@ -114,6 +132,94 @@ struct Inputs {
     #[clap(short = 'c', long = "count_specular", default_value_t = 0.3)]
     pub count_specular_bounce: Float,
 
+    /// Calculate the irradiance (or illuminance, when looking at the luminance
+    /// of the pixels) falling on the surfaces seen, instead of the
+    /// radiance leaving them
+    #[clap(short = 'I', long)]
+    pub irradiance: bool,
+
+    /// Use an irradiance cache with this accuracy (-aa in Radiance lingo). Lower
+    /// values are more accurate but slower. If not given, no cache is used
+    #[clap(short = 'A', long = "ambient_accuracy")]
+    pub ambient_accuracy: Option<Float>,
+
+    /// A file from which to read the irradiance cache (if it exists) and
+    /// where it is written after rendering (-af in Radiance lingo)
+    #[clap(short = 'f', long = "ambient_file", requires = "ambient_accuracy")]
+    pub ambient_file: Option<String>,
+
+    /// The number of photons emitted for building a global photon map. If
+    /// given, secondary rays end at the first Lambertian surface they hit, where
+    /// the light arriving is estimated from the photons
+    #[clap(long = "global_photons")]
+    pub global_photons: Option<usize>,
+
+    /// The number of photons emitted for building a caustic photon map,
+    /// which brings the light transmitted or reflected by specular materials
+    /// (e.g., glass) onto Lambertian surfaces
+    #[clap(long = "caustic_photons")]
+    pub caustic_photons: Option<usize>,
+
+    /// The seed of the random numbers, for reproducible results. If not
+    /// given, every run is different
+    #[clap(long)]
+    pub seed: Option<u64>,
+
+    /// The sequence the samples are drawn from: 'independent', 'stratified',
+    /// 'sobol' or 'halton'
+    #[clap(long, default_value = "independent")]
+    pub sampler: SamplerKind,
+
+    /// How the lights sampled by shadow rays are chosen: 'all' (every
+    /// light gets all the shadow samples), 'power' or 'tree' (the shadow
+    /// samples are shared among the lights chosen at each point)
+    #[clap(long = "light_selection", default_value = "all")]
+    pub light_selection: LightSelection,
+
+    /// An HDR image of the sky (e.g., a measured sky capture) that
+    /// lights the scene, replacing its sky
+    #[clap(long)]
+    pub environment: Option<String>,
+
+    /// How the directions are laid out in the environment image: 'latlong'
+    /// or 'fisheye' (an angular fisheye of the upper hemisphere)
+    #[clap(long = "environment_projection", default_value = "latlong")]
+    pub environment_projection: EnvironmentProjection,
+
+    /* Progressive rendering */
+    /// Render progressively, doing up to this number of passes of one
+    /// sample per pixel (instead of a single pass)
+    #[clap(long = "passes")]
+    pub passes: Option<usize>,
+
+    /// Stop the progressive render when the relative standard error
+    /// of every pixel is below this value
+    #[clap(long = "noise", default_value_t = 0.02, requires = "passes")]
+    pub noise_threshold: Float,
+
+    /// Stop the progressive render after this number of seconds
+    #[clap(long = "time_budget", requires = "passes")]
+    pub time_budget: Option<Float>,
+
+    /// Save the image to this file every `checkpoint_interval` seconds
+    /// while rendering progressively
+    #[clap(long = "checkpoint", requires = "passes")]
+    pub checkpoint: Option<String>,
+
+    /// The number of seconds between checkpoints
+    #[clap(long = "checkpoint_interval", default_value_t = 60.)]
+    pub checkpoint_interval: Float,
+
+    /// Keep sampling every pixel in every pass, instead of only
+    /// those that have not met the noise threshold
+    #[clap(long = "no_adaptive")]
+    pub no_adaptive: bool,
+
+    /// Save the relative error of each pixel to this file after
+    /// rendering progressively
+    #[clap(long = "error_image", requires = "passes")]
+    pub error_image: Option<String>,
+
     /* Film */
     /// The Horizontal resolution of the final image
     #[clap(short = 'x', long, default_value_t = 512)]
@@ -144,21 +250,37 @@ struct Inputs {
 fn main() {
     let inputs = Inputs::parse();
 
-    let input_file = inputs.input;
-    let mut scene = if input_file.ends_with(".rad") {
-        Scene::from_radiance(input_file)
-    } else if input_file.ends_with(".spl") {
-        let (model, _header) = simple_model::SimpleModel::from_file(input_file).unwrap();
-        Scene::from_simple_model(&model, Wavelengths::Visible)
+    // Fail before rendering, rather than after, if the images cannot be saved
+    let outputs = [
+        Some(&inputs.output),
+        inputs.checkpoint.as_ref(),
+        inputs.error_image.as_ref(),
+    ];
+    for file in outputs.into_iter().flatten() {
+        if let Err(e) = ImageBuffer::check_format(Path::new(file)) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
     }
-    // else if input.ends_with(".obj"){
-    //     Scene::from_simple_model(input)
-    // }
-    else {
-        panic!("Unkwown format in file {}", input_file);
-    };
 
-    scene.build_accelerator();
+    let input_file = inputs.input;
+    let cache = inputs.cache.as_deref().map(Path::new);
+    let mut scene = match Scene::from_file_with_cache(Path::new(&input_file), cache) {
+        Ok(scene) => scene,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(file) = &inputs.environment {
+        match EnvironmentMap::from_file(Path::new(file), inputs.environment_projection) {
+            Ok(map) => scene.environment = Some(map),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 
     // Create camera
     let film = Film {
@@ -181,15 +303,77 @@ fn main() {
     // Create camera
     let camera = Pinhole::new(view, film);
 
+    let irradiance_cache = inputs.ambient_accuracy.map(|accuracy| {
+        let cache = IrradianceCache::new(&scene, accuracy);
+        if let Some(file) = &inputs.ambient_file {
+            let file = std::path::Path::new(file);
+            if file.exists() {
+                if let Err(e) = cache.load(file) {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Arc::new(cache)
+    });
+
+    let photon_maps = if inputs.global_photons.is_some() || inputs.caustic_photons.is_some() {
+        let mapper = PhotonMapper {
+            n_global_photons: inputs.global_photons.unwrap_or(0),
+            n_caustic_photons: inputs.caustic_photons.unwrap_or(0),
+            seed: inputs.seed,
+            ..PhotonMapper::default()
+        };
+        Some(Arc::new(mapper.build(&scene)))
+    } else {
+        None
+    };
+
     let integrator = RayTracer {
         n_ambient_samples: inputs.n_ambient_samples,
         n_shadow_samples: inputs.n_shadow_samples,
         max_depth: inputs.max_depth,
         limit_weight: inputs.limit_weight,
         count_specular_bounce: inputs.count_specular_bounce,
+        irradiance: inputs.irradiance,
+        irradiance_cache: irradiance_cache.clone(),
+        photon_maps,
+        seed: inputs.seed,
+        sampler: inputs.sampler,
+        light_selection: inputs.light_selection,
     };
 
-    let buffer = integrator.render(&scene, &camera);
+    let control = RenderControl::stdout();
+    let (buffer, stats) = match inputs.passes {
+        Some(max_passes) => {
+            let options = ProgressiveOptions {
+                max_passes,
+                noise_threshold: inputs.noise_threshold,
+                time_budget: inputs.time_budget,
+                intermediate_file: inputs.checkpoint.map(std::path::PathBuf::from),
+                save_interval: inputs.checkpoint_interval,
+                adaptive: !inputs.no_adaptive,
+                error_file: inputs.error_image.map(std::path::PathBuf::from),
+                ..ProgressiveOptions::default()
+            };
+            integrator.render_progressive(&scene, &camera, &options, &control)
+        }
+        None => integrator.render_with_control(&scene, &camera, &control),
+    };
+    println!(
+        "Scene took {} seconds to render ({} passes)",
+        stats.elapsed.as_secs(),
+        stats.passes
+    );
 
-    buffer.save_hdre(std::path::Path::new(&inputs.output));
+    if let (Some(cache), Some(file)) = (irradiance_cache, &inputs.ambient_file) {
+        if let Err(e) = cache.save(std::path::Path::new(file)) {
+            eprintln!("{}", e);
+        }
+    }
+
+    if let Err(e) = buffer.save(std::path::Path::new(&inputs.output)) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 }
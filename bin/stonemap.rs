@@ -0,0 +1,105 @@
+use clap::{ArgEnum, Parser};
+
+use rendering::image::ImageBuffer;
+use rendering::tonemap::{HumanVisibility, ToneMap};
+use rendering::Float;
+
+#[derive(ArgEnum, Clone)]
+enum ArgToneMap {
+    Linear,
+    Reinhard,
+    ReinhardLocal,
+    Human,
+}
+
+/// Creates a viewable (i.e., Low Dynamic Range) version of an image
+#[derive(Parser)]
+struct Inputs {
+    #[clap(short, long)]
+    /// The image to tone-map
+    pub input: String,
+
+    #[clap(short, long)]
+    /// The output file. It is a PNG if it ends in '.png'; otherwise, it is a JPEG
+    pub output: String,
+
+    /// The tone-mapping operator to use
+    #[clap(arg_enum, short = 't', long, default_value_t=ArgToneMap::Human)]
+    operator: ArgToneMap,
+
+    /// The exposure multiplier for the linear operator. If not given,
+    /// it is calculated automatically
+    #[clap(short, long)]
+    pub exposure: Option<Float>,
+
+    /// The key of the scene, for the Reinhard operators
+    #[clap(short, long, default_value_t = 0.18)]
+    pub key: Float,
+
+    /// The luminance to be mapped to white by the global Reinhard operator.
+    /// If not given, the maximum in the image is used.
+    #[clap(short, long)]
+    pub white: Option<Float>,
+
+    /// The sharpness of the local Reinhard operator
+    #[clap(long, default_value_t = 8.)]
+    pub sharpness: Float,
+
+    /// The horizontal field of view of the image, in degrees (for the human operator)
+    #[clap(short = 'f', long = "field_of_view", default_value_t = 60.)]
+    pub field_of_view: Float,
+
+    /// Do not simulate veiling glare (for the human operator)
+    #[clap(long = "no_glare")]
+    pub no_glare: bool,
+
+    /// Do not simulate the loss of acuity (for the human operator)
+    #[clap(long = "no_acuity")]
+    pub no_acuity: bool,
+
+    /// The maximum luminance of the display, in cd/m2 (for the human operator)
+    #[clap(short = 'u', long = "max_luminance", default_value_t = 100.)]
+    pub max_display_luminance: Float,
+
+    /// The dynamic range of the display (for the human operator)
+    #[clap(short = 'd', long = "dynamic_range", default_value_t = 32.)]
+    pub display_dynamic_range: Float,
+}
+
+fn main() {
+    let inputs = Inputs::parse();
+
+    let image = match ImageBuffer::from_file(std::path::Path::new(&inputs.input)) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let operator = match inputs.operator {
+        ArgToneMap::Linear => ToneMap::Linear(inputs.exposure),
+        ArgToneMap::Reinhard => ToneMap::Reinhard {
+            key: inputs.key,
+            white: inputs.white,
+        },
+        ArgToneMap::ReinhardLocal => ToneMap::ReinhardLocal {
+            key: inputs.key,
+            sharpness: inputs.sharpness,
+        },
+        ArgToneMap::Human => ToneMap::Human(HumanVisibility {
+            field_of_view: inputs.field_of_view,
+            glare: !inputs.no_glare,
+            acuity: !inputs.no_acuity,
+            max_display_luminance: inputs.max_display_luminance,
+            display_dynamic_range: inputs.display_dynamic_range,
+        }),
+    };
+
+    let out = std::path::Path::new(&inputs.output);
+    if inputs.output.to_lowercase().ends_with(".png") {
+        image.save_png(&operator, out)
+    } else {
+        image.save_jpeg(&operator, out)
+    }
+}
@@ -0,0 +1,195 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use clap::Parser;
+use geometry3d::{Point3D, Ray3D, Vector3D};
+use rendering::environment_map::{EnvironmentMap, EnvironmentProjection};
+use rendering::light_tree::LightSelection;
+use rendering::sampler::SamplerKind;
+use rendering::Float;
+use rendering::{RayTracer, Scene};
+
+/// Calculates values for points received through the standard input
+/// (like Radiance's `rtrace`). Each line contains six numbers: the origin
+/// and direction of a ray (e.g., '1. 2. 0.8 0. 0. 1.')
+#[derive(Parser)]
+#[clap(author, version, about, long_about = None)]
+struct Inputs {
+    #[clap(short, long)]
+    /// The file to load the model from
+    pub input: String,
+
+    /// The number of bounces before a ray is terminated (-ab in Radiance lingo)
+    #[clap(short = 'b', long = "max_depth", default_value_t = 2)]
+    pub max_depth: usize,
+
+    /// The number of shadow rays per light source sent from the first
+    /// interaction. From the second interaction and on, only 1 shadow
+    /// ray is sent
+    #[clap(short = 's', long = "shadow_samples", default_value_t = 10)]
+    pub n_shadow_samples: usize,
+
+    /// The number of secondary rays sent from the first interaction.
+    /// From the second interaction and on, this number is reduced
+    #[clap(short = 'a', long = "ambient_samples", default_value_t = 1700)]
+    pub n_ambient_samples: usize,
+
+    /// A lower value makes the Russian roulette less deadly
+    #[clap(short = 'w', long = "limit_weight", default_value_t = 1e-3)]
+    pub limit_weight: Float,
+
+    /// The probability of counting purely specular bounces as an actual bounce.
+    /// (Specular bounces seldom count because that allows achieving caustics better...
+    /// and they are cheap)
+    #[clap(short = 'c', long = "count_specular", default_value_t = 0.3)]
+    pub count_specular_bounce: Float,
+
+    /// Treat each line as a sensor (i.e., a position and a normal) and
+    /// calculate the irradiance over it (like `rtrace -I`), instead of the
+    /// radiance seen along each ray
+    #[clap(short = 'I', long)]
+    pub irradiance: bool,
+
+    /// Write the luminance (or illuminance, with `-I`) instead of the three
+    /// colour channels
+    #[clap(short = 'l', long)]
+    pub luminance: bool,
+
+    /// The seed of the random numbers, for reproducible results. If not
+    /// given, every run is different
+    #[clap(long)]
+    pub seed: Option<u64>,
+
+    /// The sequence the samples are drawn from: 'independent', 'stratified',
+    /// 'sobol' or 'halton'
+    #[clap(long, default_value = "independent")]
+    pub sampler: SamplerKind,
+
+    /// How the lights sampled by shadow rays are chosen: 'all' (every
+    /// light gets all the shadow samples), 'power' or 'tree' (the shadow
+    /// samples are shared among the lights chosen at each point)
+    #[clap(long = "light_selection", default_value = "all")]
+    pub light_selection: LightSelection,
+
+    /// An HDR image of the sky (e.g., a measured sky capture) that
+    /// lights the scene, replacing its sky
+    #[clap(long)]
+    pub environment: Option<String>,
+
+    /// How the directions are laid out in the environment image: 'latlong'
+    /// or 'fisheye' (an angular fisheye of the upper hemisphere)
+    #[clap(long = "environment_projection", default_value = "latlong")]
+    pub environment_projection: EnvironmentProjection,
+}
+
+/// Parses a line with six numbers into a [`Ray3D`]
+fn parse_ray(line: &str) -> Result<Ray3D, String> {
+    let st: Vec<&str> = line.split_ascii_whitespace().collect();
+    if st.len() != 6 {
+        return Err(format!(
+            "Expecting six values—e.g., '1. 2. 3. 4. 5. 6.'—... found '{:?}'",
+            st
+        ));
+    }
+    let mut v = [0.0; 6];
+    for (i, s) in st.iter().enumerate() {
+        v[i] = match s.parse::<Float>() {
+            Ok(v) => v,
+            Err(_) => {
+                return Err(format!(
+                    "Expecting value {} in sensor line to be a number... found '{}'",
+                    i + 1,
+                    s
+                ))
+            }
+        };
+    }
+    Ok(Ray3D {
+        origin: Point3D::new(v[0], v[1], v[2]),
+        direction: Vector3D::new(v[3], v[4], v[5]).get_normalized(),
+    })
+}
+
+fn main() {
+    let inputs = Inputs::parse();
+
+    let input_file = inputs.input;
+    let mut scene = match Scene::from_file_with_cache(std::path::Path::new(&input_file), None) {
+        Ok(scene) => scene,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(file) = &inputs.environment {
+        match EnvironmentMap::from_file(std::path::Path::new(file), inputs.environment_projection) {
+            Ok(map) => scene.environment = Some(map),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Read the rays
+    let mut rays: Vec<Ray3D> = Vec::new();
+    let mut buffer = String::new();
+    let mut ln = 0;
+    while let Ok(n) = std::io::stdin().read_line(&mut buffer) {
+        if n == 0 {
+            break; // Reached EOF
+        }
+        ln += 1;
+        if !buffer.trim().is_empty() {
+            match parse_ray(&buffer) {
+                Ok(r) => rays.push(r),
+                Err(e) => {
+                    eprintln!("Error in line {}: {}", ln, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        buffer.truncate(0);
+    }
+
+    let integrator = RayTracer {
+        n_ambient_samples: inputs.n_ambient_samples,
+        n_shadow_samples: inputs.n_shadow_samples,
+        max_depth: inputs.max_depth,
+        limit_weight: inputs.limit_weight,
+        count_specular_bounce: inputs.count_specular_bounce,
+        irradiance: inputs.irradiance,
+        irradiance_cache: None,
+        photon_maps: None,
+        seed: inputs.seed,
+        sampler: inputs.sampler,
+        light_selection: inputs.light_selection,
+    };
+
+    let values = integrator.calc_points(&scene, &rays);
+
+    for v in values {
+        if inputs.luminance {
+            println!("{}", v.luminance());
+        } else {
+            println!("{}\t{}\t{}", v.0[0], v.0[1], v.0[2]);
+        }
+    }
+}
@@ -18,134 +18,256 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
 use crate::colour::Spectrum;
 use crate::image::ImageBuffer;
 use crate::rand::*;
 use crate::Float;
-use geometry3d::Ray3D;
 
 use crate::backward_metropolis::mutation::{LocalExploration, Mutation, MutationSet, RestartRay};
 use crate::backward_metropolis::path::Path;
 
 use crate::camera::Camera;
-use crate::ray::Ray;
-use crate::ray_tracer::RayTracer;
+use crate::progress::{RenderControl, RenderStats};
 use crate::scene::Scene;
 
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// A Metropolis Light Transport integrator that works in Primary Sample
+/// Space (Kelemen et al. 2002), with paths starting at the camera.
+///
+/// Several Markov Chains are run independently (in parallel, if the
+/// `parallel` feature is enabled). The image is normalized by the
+/// average value of a set of independent paths, which are also used for
+/// choosing where the chains start.
 pub struct BackwardMetropolis {
+    /// The average number of mutations per pixel
     pub mutations_per_pixel: usize,
-    pub min_path_length: usize,
+
+    /// The maximum number of interactions in a path
+    pub max_depth: usize,
+
+    /// The number of shadow rays sent towards each light source
+    /// at each interaction
     pub n_shadow_samples: usize,
+
+    /// The number of independent Markov Chains
+    pub n_chains: usize,
+
+    /// The number of independent paths used for normalizing the image
+    /// and for starting the chains
+    pub n_bootstrap_samples: usize,
+
+    /// The probability of proposing a completely new path instead of
+    /// perturbing the current one
+    pub large_step_probability: Float,
+}
+
+impl std::default::Default for BackwardMetropolis {
+    fn default() -> Self {
+        Self {
+            mutations_per_pixel: 100,
+            max_depth: 4,
+            n_shadow_samples: 1,
+            n_chains: 64,
+            n_bootstrap_samples: 100_000,
+            large_step_probability: 0.3,
+        }
+    }
 }
 
 impl BackwardMetropolis {
-    fn evaluate_anchor(
+    /// Runs a Markov Chain of `n_mutations` starting at `x`, returning
+    /// the (unnormalized) contribution to each pixel
+    fn run_chain(
         &self,
         scene: &Scene,
-        ray: Ray,
-        weight: Float,
-        rng: &mut RandGen,
-    ) -> Spectrum {
-        let integrator = RayTracer {
-            n_shadow_samples: 5,
-            max_depth: 3,
-            limit_weight: 0.001,
-            n_ambient_samples: 129,
-            ..RayTracer::default()
-        };
-
-        let (v, _) = integrator.trace_ray(rng, scene, ray, 0, weight);
-        v
-    }
-
-    pub fn render<'a>(&self, scene: &'a Scene, camera: &dyn Camera) -> ImageBuffer {
+        camera: &dyn Camera,
+        x: Path,
+        n_mutations: usize,
+    ) -> Vec<Spectrum<{ crate::N_CHANNELS }>> {
         let (width, height) = camera.film_resolution();
-        let total_pixels = width * height;
+        let mut pixels = vec![Spectrum::<{ crate::N_CHANNELS }>::BLACK; width * height];
         let mut rng = get_rng();
-
-        // Initialize the direction that will start it all.
-        let camera_sample = camera.gen_random_sample(&mut rng);
-        let (mut primary_ray, weight) = camera.gen_ray(&camera_sample);
-
-        // Calculate an anchor that will be used later for calibrating
-        // the whole image
-        // let anchor_ray = primary_ray;
-        let mut anchor_f = self.evaluate_anchor(scene, primary_ray, weight, &mut rng);
-        let mut anchor_i = camera.pixel_index(camera_sample.p_film);
-        while anchor_f.radiance() < 1e-5 {
-            let camera_sample = camera.gen_random_sample(&mut rng);
-            let (primary_ray2, _weight2) = camera.gen_ray(&camera_sample);
-            anchor_f = self.evaluate_anchor(scene, primary_ray, weight, &mut rng);
-            anchor_i = camera.pixel_index(camera_sample.p_film);
-            primary_ray = primary_ray2;
-        }
+        let mut node_aux = Vec::with_capacity(64);
 
         // Initialize mutations
         let mut mutation = MutationSet::default();
-        mutation.push(0.1, Box::new(RestartRay {}));
-        mutation.push(0.9, Box::new(LocalExploration {}));
-
-        // Run
-        let n_samples = total_pixels * self.mutations_per_pixel;
-
-        // Initialize values... path and its value
-        let mut x1: Path = Path::new_from_random_walk(&primary_ray, scene, &self, &mut rng);
-        let mut fx1 = x1.eval_from_node(0, scene);
-        let mut pixel1 = anchor_i;
-
-        let mut pixels = vec![Spectrum::BLACK; total_pixels];
-
-        let mut lp = 0.0;
-        // Now loop.
-        let mut n = 0;
-        while n < n_samples {
-            let progress = (100. * n as Float / n_samples as Float).round() as Float;
-            if (lp - progress.floor()) < 0.1 && (progress - lp).abs() > 1. {
-                lp = progress;
-                println!("... Done {:.0}%", progress);
+        mutation.push(self.large_step_probability, Box::new(RestartRay {}));
+        mutation.push(
+            1. - self.large_step_probability,
+            Box::new(LocalExploration {}),
+        );
+
+        let mut x1 = x;
+        let (mut pixel1, mut fx1) = x1.eval(
+            scene,
+            camera,
+            self.n_shadow_samples,
+            &mut rng,
+            &mut node_aux,
+        );
+
+        for _ in 0..n_mutations {
+            // Mutate and evaluate
+            let x2 = mutation.mutate(&x1, &mut rng);
+            let (pixel2, fx2) = x2.eval(
+                scene,
+                camera,
+                self.n_shadow_samples,
+                &mut rng,
+                &mut node_aux,
+            );
+
+            // Calculate probability of accepting the mutation
+            let a = mutation.prob_of_accept(fx1, fx2);
+
+            // Add both contributions, weighted by their probability
+            if fx1.radiance() > 0. {
+                pixels[pixel1] += fx1.normalize() * (1. - a);
+            }
+            if fx2.radiance() > 0. {
+                pixels[pixel2] += fx2.normalize() * a;
             }
 
-            // Mutate and evaluate
-            let x2 = mutation.mutate(&x1, scene, camera, &self, &mut rng);
-            let ray2 = Ray3D {
-                origin: x2.start,
-                direction: x2.primary_dir.unwrap(),
-            };
-            let (pixel2, weight) = camera.pixel_from_ray(&ray2);
+            // Accept mutation... maybe
+            let r: Float = rng.gen();
+            if r < a {
+                x1 = x2;
+                fx1 = fx2;
+                pixel1 = pixel2;
+            }
+        }
 
-            // the ray is outside of FOV
-            if weight > 1e-22 {
-                n += 1;
-                // All good.
-                let pixel2 = camera.pixel_index(pixel2);
-                let fx2 = x2.eval_from_node(0, scene);
+        pixels
+    }
 
-                // Calculate probability of accepting the mutation
-                let a = mutation.prob_of_accept(fx1, fx2);
+    /// Renders the scene, without reporting progress
+    pub fn render(&self, scene: &Scene, camera: &dyn Camera) -> ImageBuffer {
+        let (image, _stats) = self.render_with_control(scene, camera, &RenderControl::default());
+        image
+    }
 
-                // Add both contributions
-                pixels[pixel1] += fx1.normalize() * (1. - a);
-                pixels[pixel2] += fx2.normalize() * a;
+    /// Renders the scene, reporting the number of chains done to the
+    /// `control` and checking whether it has been cancelled before
+    /// starting each chain. A cancelled render is normalized by the
+    /// chains that did run.
+    pub fn render_with_control(
+        &self,
+        scene: &Scene,
+        camera: &dyn Camera,
+        control: &RenderControl,
+    ) -> (ImageBuffer, RenderStats) {
+        let (width, height) = camera.film_resolution();
+        let total_pixels = width * height;
+        let mut rng = get_rng();
+        let mut node_aux = Vec::with_capacity(64);
+        let now = Instant::now();
 
-                // Accept mutation... maybe
-                let r: Float = rng.gen();
-                if r < a {
-                    // update values
-                    x1 = x2;
-                    fx1 = fx2;
-                    pixel1 = pixel2;
+        /* BOOTSTRAP */
+        // Evaluate independent paths, for estimating the average value
+        // of the paths (i.e., the normalization constant of the image)
+        let n_bootstrap = self.n_bootstrap_samples.max(1);
+        let mut candidates: Vec<(Path, Float)> = Vec::with_capacity(n_bootstrap);
+        let mut total = 0.0;
+        for _ in 0..n_bootstrap {
+            let path = Path::new_random(self.max_depth, &mut rng);
+            let (_, v) = path.eval(
+                scene,
+                camera,
+                self.n_shadow_samples,
+                &mut rng,
+                &mut node_aux,
+            );
+            let v = v.radiance();
+            total += v;
+            candidates.push((path, v));
+        }
+        let b = total / n_bootstrap as Float;
+        if b <= 0. {
+            // Nothing to see here
+            let image = ImageBuffer::from_pixels(
+                width,
+                height,
+                vec![Spectrum::<{ crate::N_CHANNELS }>::BLACK; total_pixels],
+            );
+            let stats = RenderStats {
+                elapsed: now.elapsed(),
+                passes: 1,
+                cancelled: false,
+            };
+            return (image, stats);
+        }
+
+        // Choose the start of the chains proportionally to their value,
+        // so they start in their stationary distribution
+        let n_chains = self.n_chains.max(1);
+        let starts: Vec<Path> = (0..n_chains)
+            .map(|_| {
+                let mut r = rng.gen::<Float>() * total;
+                for (path, v) in candidates.iter() {
+                    if r < *v {
+                        return path.clone();
+                    }
+                    r -= v;
                 }
+                // Rounding errors
+                candidates
+                    .iter()
+                    .rev()
+                    .find(|(_, v)| *v > 0.)
+                    .map(|(p, _)| p.clone())
+                    .unwrap()
+            })
+            .collect();
+
+        /* RUN THE CHAINS */
+        let mutations_per_chain = (total_pixels * self.mutations_per_pixel) / n_chains + 1;
+        let pixels = Mutex::new(vec![Spectrum::<{ crate::N_CHANNELS }>::BLACK; total_pixels]);
+        let done = AtomicUsize::new(0);
+        let skipped = AtomicBool::new(false);
+
+        #[cfg(not(feature = "parallel"))]
+        let starts = starts.into_iter();
+
+        #[cfg(feature = "parallel")]
+        let starts = starts.into_par_iter();
+
+        starts.for_each(|x| {
+            if control.is_cancelled() {
+                skipped.store(true, Ordering::Relaxed);
+                return;
+            }
+            let chain = self.run_chain(scene, camera, x, mutations_per_chain);
+            let mut pixels = pixels.lock().unwrap();
+            for (p, c) in pixels.iter_mut().zip(chain.iter()) {
+                *p += *c;
             }
+            drop(pixels);
+
+            let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+            control.report(done, n_chains);
+        });
+
+        // Normalize: the pixels add up to the total number of
+        // mutations, and they should add up to b per pixel
+        let n_done = done.into_inner().max(1);
+        let scale = b * total_pixels as Float / (mutations_per_chain * n_done) as Float;
+        let mut pixels = pixels.into_inner().unwrap();
+        for p in pixels.iter_mut() {
+            *p *= scale;
         }
 
-        // return
-        // let found_in_anchor = pixels[anchor_i];
-        // let scale_constant = anchor_f / found_in_anchor;
-        // let scale_constant = 1./ n_samples as Float;
-        // for p in pixels.iter_mut(){
-        //     *p *= scale_constant;
-        // }
-        ImageBuffer::from_pixels(width, height, pixels)
+        let stats = RenderStats {
+            elapsed: now.elapsed(),
+            passes: 1,
+            cancelled: skipped.into_inner(),
+        };
+        (ImageBuffer::from_pixels(width, height, pixels), stats)
     }
 }
 
@@ -153,55 +275,122 @@ impl BackwardMetropolis {
 mod tests {
 
     use super::*;
-    use geometry3d::{Point3D, Vector3D};
+    use geometry3d::{Point3D, Triangle3D, Vector3D};
 
     use crate::camera::{Film, Pinhole, View};
-    use std::time::Instant;
+    use crate::material::{Material, Plastic};
+    use crate::primitive::Primitive;
 
-    #[ignore]
     #[test]
-    fn basic_test() {
-        // cargo test --features parallel --release  -- --ignored --nocapture basic_test
-        let file = "./tests/scenes/exterior_0_diffuse_plastic.rad";
-        // let file = "./tests/scenes/room.rad";
-
-        let mut scene = Scene::from_radiance(file.to_string());
-
+    fn test_normalization() {
+        // A floor under a uniform sky. Every path that sees the floor
+        // carries the same value
+        let mut scene = Scene::new();
+        let rho = 0.5;
+        let grey = scene.push_material(Material::Plastic(Plastic {
+            colour: Spectrum::<{ crate::N_CHANNELS }>::gray(rho),
+            specularity: 0.0,
+            roughness: 0.0,
+        }));
+        const L: Float = 10.;
+        let tri = Triangle3D::new(
+            Point3D::new(-L, -L, 0.),
+            Point3D::new(L, -L, 0.),
+            Point3D::new(L, L, 0.),
+        )
+        .unwrap();
+        scene.push_object(grey, grey, Primitive::Triangle(tri));
+        let tri = Triangle3D::new(
+            Point3D::new(-L, -L, 0.),
+            Point3D::new(L, L, 0.),
+            Point3D::new(-L, L, 0.),
+        )
+        .unwrap();
+        scene.push_object(grey, grey, Primitive::Triangle(tri));
+        scene.sky = Some(Box::new(|_: Vector3D| -> Float { 1. }));
         scene.build_accelerator();
 
-        // Create film
-        let film = Film {
-            resolution: (512, 512),
+        let view = View {
+            view_direction: Vector3D::new(0., 0., -1.),
+            view_up: Vector3D::new(0., 1., 0.),
+            view_point: Point3D::new(0., 0., 1.),
+            ..View::default()
         };
+        let camera = Pinhole::new(view, Film { resolution: (8, 8) });
+
+        let integrator = BackwardMetropolis {
+            mutations_per_pixel: 50,
+            max_depth: 2,
+            n_chains: 4,
+            n_bootstrap_samples: 1000,
+            ..BackwardMetropolis::default()
+        };
+        let image = integrator.render(&scene, &camera);
+
+        let expected = Spectrum::<{ crate::N_CHANNELS }>::gray(rho).radiance();
+        let found: Float =
+            image.pixels.iter().map(|p| p.radiance()).sum::<Float>() / image.pixels.len() as Float;
+        assert!(
+            (found - expected).abs() / expected < 1e-6,
+            "expected {}, found {}",
+            expected,
+            found
+        );
+    }
+
+    #[ignore]
+    #[test]
+    fn test_cornell_vs_ray_tracer() {
+        // cargo test --features parallel --release -- --ignored --nocapture test_cornell_vs_ray_tracer
+        let mut scene = Scene::from_radiance("./tests/scenes/cornell.rad".to_string());
+        scene.build_accelerator();
 
-        // Create view
-        // let view = View {
-        //     view_direction: Vector3D::new(0., 1., 0.).get_normalized(),
-        //     view_point: Point3D::new(2., 1., 1.),
-        //     ..View::default()
-        // };
-        // Create view
         let view = View {
             view_direction: Vector3D::new(0., 1., 0.),
-            view_point: Point3D::new(0., -13., 0.),
+            view_point: Point3D::new(3., -5., 2.25),
+            field_of_view: 50.,
             ..View::default()
         };
-
-        // Create camera
-        let camera = Pinhole::new(view, film);
+        let camera = Pinhole::new(
+            view,
+            Film {
+                resolution: (64, 46),
+            },
+        );
+
+        let reference = crate::RayTracer {
+            n_ambient_samples: 512,
+            n_shadow_samples: 10,
+            max_depth: 3,
+            ..crate::RayTracer::default()
+        }
+        .render(&scene, &camera);
 
         let integrator = BackwardMetropolis {
-            mutations_per_pixel: 1000,
-            min_path_length: 3,
-            n_shadow_samples: 1,
+            mutations_per_pixel: 2000,
+            max_depth: 5,
+            ..BackwardMetropolis::default()
         };
-
-        let now = Instant::now();
-
-        let buffer = integrator.render(&scene, &camera);
-        println!("Room took {} seconds to render", now.elapsed().as_secs());
-        buffer.save_hdre(std::path::Path::new(
-            "./tests/scenes/images/room_METROPOLIS.hdr",
+        let image = integrator.render(&scene, &camera);
+        image.save_hdre(std::path::Path::new(
+            "./tests/scenes/images/cornell_METROPOLIS.hdr",
         ));
+
+        // Compare the average of the image, leaving out the pixels
+        // that show the lamp (directly or through the spheres)
+        let mut expected = 0.0;
+        let mut found = 0.0;
+        for (r, m) in reference.pixels.iter().zip(image.pixels.iter()) {
+            if r.radiance() < 50. {
+                expected += r.radiance();
+                found += m.radiance();
+            }
+        }
+        assert!(
+            (found - expected).abs() / expected < 0.1,
+            "expected {}, found {}",
+            expected,
+            found
+        );
     }
 }
@@ -18,12 +18,14 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
+#[allow(clippy::module_inception)]
 mod backward_metropolis;
 pub use crate::backward_metropolis::backward_metropolis::BackwardMetropolis;
 
 pub mod mutation;
-pub use crate::backward_metropolis::mutation::Mutation;
-pub use crate::backward_metropolis::mutation::*;
+pub use crate::backward_metropolis::mutation::{
+    LocalExploration, Mutation, MutationSet, RestartRay,
+};
 
 pub mod path;
 pub use crate::backward_metropolis::path::Path;
@@ -18,51 +18,52 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
-use crate::camera::Camera;
+use crate::backward_metropolis::path::Path;
 use crate::colour::Spectrum;
 use crate::rand::*;
-use crate::ray::Ray;
-use crate::scene::Scene;
 use crate::Float;
-use geometry3d::Ray3D;
 
-use crate::backward_metropolis::path::{Path, PathNode};
-use crate::backward_metropolis::BackwardMetropolis;
+/// The smallest perturbation applied by [`LocalExploration`]
+const MIN_PERTURBATION: Float = 1. / 1024.;
 
-pub trait Mutation<'a> {
-    fn mutate(
-        &self,
-        _x: &Path<'a>,
-        scene: &'a Scene,
-        camera: &dyn Camera,
-        integrator: &BackwardMetropolis,
-        rng: &mut SmallRng,
-    ) -> Path<'a>;
+/// The largest perturbation applied by [`LocalExploration`]
+const MAX_PERTURBATION: Float = 1. / 64.;
+
+pub trait Mutation {
+    /// Proposes a new [`Path`] based on `x`.
+    ///
+    /// Mutations need to be symmetric (i.e., the probability of proposing `y`
+    /// from `x` must be the same as the probability of proposing `x` from `y`).
+    fn mutate(&self, x: &Path, rng: &mut RandGen) -> Path;
 }
 
 /// A set of mutations and their respective probabilities.
 #[derive(Default)]
-pub struct MutationSet<'a> {
+pub struct MutationSet {
     /// A set of mutations and their respective probabilities
-    mutations: Vec<(Float, Box<dyn Mutation<'a>>)>,
+    mutations: Vec<(Float, Box<dyn Mutation>)>,
 
     // The total probability accumulated so far
     total_prob: Float,
 }
 
-impl<'a> MutationSet<'a> {
+impl MutationSet {
     /// Adds a mutation and its probability.
     ///
     /// Note that the probabilities are relative to each other, so they can add up to
     /// more than 1.
-    pub fn push(&mut self, probability: Float, mutation: Box<dyn Mutation<'a>>) {
+    pub fn push(&mut self, probability: Float, mutation: Box<dyn Mutation>) {
         self.total_prob += probability;
         // We store the accumulated probability
         self.mutations.push((self.total_prob, mutation));
     }
 
     /// Calculates the probability of accepting a mutation
-    pub fn prob_of_accept(&self, fx1: Spectrum, fx2: Spectrum) -> Float {
+    pub fn prob_of_accept(
+        &self,
+        fx1: Spectrum<{ crate::N_CHANNELS }>,
+        fx2: Spectrum<{ crate::N_CHANNELS }>,
+    ) -> Float {
         if fx1.is_black() {
             // Nothing can be worse... mutate
             return 1.;
@@ -74,153 +75,85 @@ impl<'a> MutationSet<'a> {
     }
 }
 
-impl<'a> Mutation<'a> for MutationSet<'a> {
-    fn mutate(
-        &self,
-        x: &Path<'a>,
-        scene: &'a Scene,
-        camera: &dyn Camera,
-        integrator: &BackwardMetropolis,
-        rng: &mut SmallRng,
-    ) -> Path<'a> {
+impl Mutation for MutationSet {
+    fn mutate(&self, x: &Path, rng: &mut RandGen) -> Path {
         let p: Float = rng.gen();
         let p = p * self.total_prob;
         for (acc_prob, mutation) in &self.mutations {
             if p < *acc_prob {
-                return mutation.mutate(x, scene, camera, integrator, rng);
+                return mutation.mutate(x, rng);
             }
         }
         unreachable!();
     }
 }
 
+/// Discards the current path and proposes a completely new one
+/// (i.e., a "large step")
 pub struct RestartRay {}
-impl<'a> Mutation<'a> for RestartRay {
-    fn mutate(
-        &self,
-        _x: &Path<'a>,
-        scene: &'a Scene,
-        camera: &dyn Camera,
-        integrator: &BackwardMetropolis,
-        rng: &mut SmallRng,
-    ) -> Path<'a> {
-        let camera_sample = camera.gen_random_sample(rng);
-        let (primary_ray, _weight) = camera.gen_ray(&camera_sample);
-
-        // Create a random path
-        Path::new_from_random_walk(&primary_ray, scene, integrator, rng)
+impl Mutation for RestartRay {
+    fn mutate(&self, x: &Path, rng: &mut RandGen) -> Path {
+        Path::new_random(x.max_depth(), rng)
     }
 }
 
-/// Chooses one node to modify and mutates it randomly.
+/// Perturbs each primary sample of the path by a small amount, which
+/// explores the neighbourhood of the path (i.e., a "small step", as
+/// proposed by Kelemen et al. 2002)
 pub struct LocalExploration {}
-impl<'a> Mutation<'a> for LocalExploration {
-    fn mutate(
-        &self,
-        x: &Path<'a>,
-        scene: &'a Scene,
-        camera: &dyn Camera,
-        integrator: &BackwardMetropolis,
-        rng: &mut SmallRng,
-    ) -> Path<'a> {
+impl Mutation for LocalExploration {
+    fn mutate(&self, x: &Path, rng: &mut RandGen) -> Path {
         let mut ret = x.clone();
-
-        if ret.nodes.is_empty() {
-            // just restart
-            let m = RestartRay {};
-            return m.mutate(x, scene, camera, integrator, rng);
-        }
-
-        let mut i: usize = rng.gen();
-        i = i % ret.nodes.len();
-
-        let prev_pt = if i == 0 {
-            ret.start
-        } else {
-            ret.nodes[i - 1].point
-        };
-
-        let next_point = ret.nodes[i].point;
-
-        let aim_to = next_point - prev_pt;
-
-        // Calculate new direction... sampling a disk in the next node.
-        let radius: Float = aim_to.length() * 0.01;
-        let target =
-            crate::samplers::uniform_sample_disc(rng, radius, next_point, aim_to.get_normalized());
-
-        let ray = Ray {
-            geometry: Ray3D {
-                origin: prev_pt,
-                direction: (target - prev_pt).get_normalized(),
-            },
-            refraction_index: 1.,
-        };
-
-        if let Some(new_node) = PathNode::new(scene, &ray, integrator.n_shadow_samples, rng) {
-            // update
-            ret.nodes[i] = new_node;
-
-            if i == 0 {
-                // primary_dir has changed
-                ret.primary_dir = Some((ret.nodes[i].point - ret.start).get_normalized());
+        let log_ratio = (MAX_PERTURBATION / MIN_PERTURBATION).ln();
+        for u in ret.samples.iter_mut() {
+            let r: Float = rng.gen();
+            let dv = MAX_PERTURBATION * (-log_ratio * r).exp();
+            // Samples wrap around, so that the mutation is symmetric
+            if rng.gen::<bool>() {
+                *u += dv;
+                if *u >= 1. {
+                    *u -= 1.;
+                }
+            } else {
+                *u -= dv;
+                if *u < 0. {
+                    *u += 1.;
+                }
             }
-
-            ret
-        } else {
-            // did not work... try again
-            self.mutate(x, scene, camera, integrator, rng)
         }
+        ret
     }
 }
 
-// /// Extend path
-// pub struct Extend {}
-// impl <'a>Mutation<'a> for Extend {
-//     fn mutate(&self, x: &Path<'a>,  scene: &'a Scene, camera: &dyn Camera, integrator: &BackwardMetropolis, rng: &mut SmallRng) -> Path<'a> {
-
-//         let mut ret= x.clone();
-
-//         if ret.nodes.is_empty(){
-//             // just restart
-//             let m = RestartRay{};
-//             return m.mutate( x,  scene, camera, integrator, rng)
-//         }
-
-//         let mut i : usize = rng.gen();
-//         i = i % ret.nodes.len();
-
-//         let prev_pt = if i == 0 {
-//             ret.start
-//         } else {
-//             ret.nodes[i-1].point
-//         };
-
-//         let next_point = ret.nodes[i].point;
-
-//         let aim_to = next_point - prev_pt;
-
-//         // Calculate new direction... sampling a disk in the next node.
-//         let radius : Float = aim_to.length() * 0.001;
-//         let target = crate::samplers::uniform_sample_disc(rng, radius, next_point, aim_to.get_normalized());
-
-//         let ray = Ray{
-//             geometry: Ray3D{
-//                 origin: prev_pt,
-//                 direction: (target - prev_pt).get_normalized(),
-//             },
-//             refraction_index: 1.
-//         };
-
-//         if let Some(new_node) = PathNode::new(scene, &ray, integrator.n_shadow_samples, rng){
-
-//             // update
-//             ret.nodes[i] = new_node;
-//             ret
-//         }else{
-//             // did not work... try again
-//             self.mutate(x, scene, camera, integrator, rng)
-//         }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prob_of_accept() {
+        let set = MutationSet::default();
+        let black = Spectrum::<{ crate::N_CHANNELS }>::BLACK;
+        let one = Spectrum::<{ crate::N_CHANNELS }>::gray(1.);
+        let two = Spectrum::<{ crate::N_CHANNELS }>::gray(2.);
+        assert!((set.prob_of_accept(black, one) - 1.).abs() < 1e-9);
+        assert!((set.prob_of_accept(one, two) - 1.).abs() < 1e-9);
+        assert!((set.prob_of_accept(two, one) - 0.5).abs() < 1e-9);
+        assert!(set.prob_of_accept(one, black).abs() < 1e-9);
+    }
 
-//     }
-// }
+    #[test]
+    fn test_local_exploration() {
+        let mut rng = get_rng();
+        let x = Path::new_random(5, &mut rng);
+        let y = LocalExploration {}.mutate(&x, &mut rng);
+        assert_eq!(x.samples.len(), y.samples.len());
+        for (a, b) in x.samples.iter().zip(y.samples.iter()) {
+            assert!((0.0..1.0).contains(b));
+            // The distance, considering that samples wrap around
+            let d = (a - b).abs();
+            let d = d.min(1. - d);
+            assert!(d <= MAX_PERTURBATION + 1e-9, "d = {}", d);
+            assert!(d >= MIN_PERTURBATION - 1e-9, "d = {}", d);
+        }
+    }
+}
@@ -18,539 +18,325 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
-use crate::backward_metropolis::BackwardMetropolis;
+use crate::camera::{Camera, CameraSample};
 use crate::colour::Spectrum;
-use crate::interaction::Interaction;
+use crate::lights::{Light, DELTA_POSITION_LIGHT};
 use crate::material::Material;
 use crate::rand::*;
 use crate::ray::Ray;
-use crate::ray_tracer::sample_light;
-use crate::scene::Object;
+use crate::ray_tracer::intersect_light;
 use crate::scene::Scene;
 use crate::Float;
 use geometry3d::intersection::SurfaceSide;
 use geometry3d::{Point3D, Ray3D, Vector3D};
 
+/// The number of primary samples used for choosing a pixel
+const N_PIXEL_SAMPLES: usize = 2;
+
+/// The number of primary samples consumed by each interaction
+const N_NODE_SAMPLES: usize = 3;
+
+/// A light path described in Primary Sample Space (Kelemen et al. 2002); that
+/// is, by the random numbers (in the range `[0, 1)`) that drive a random walk
+/// starting at the camera. Mutating these numbers mutates the path.
 #[derive(Clone)]
-pub struct Path<'a> {
-    pub nodes: Vec<PathNode<'a>>,
-    pub start: Point3D,
-    pub primary_dir: Option<Vector3D>,
+pub struct Path {
+    /// The primary samples. The first two choose the pixel, and
+    /// each interaction consumes three more.
+    pub samples: Vec<Float>,
 }
 
-impl<'a> Path<'a> {
-    pub fn new(start: Point3D) -> Self {
+impl Path {
+    /// Creates a path with up to `max_depth` interactions, from uniformly
+    /// distributed primary samples
+    pub fn new_random(max_depth: usize, rng: &mut RandGen) -> Self {
+        let n = N_PIXEL_SAMPLES + N_NODE_SAMPLES * max_depth;
         Self {
-            nodes: Vec::new(),
-            start,
-            primary_dir: None,
-        }
-    }
-
-    pub fn with_capacity(start: Point3D, capacity: usize) -> Self {
-        Self {
-            nodes: Vec::with_capacity(capacity),
-            start,
-            primary_dir: None,
-        }
-    }
-
-    pub fn new_from_random_walk(
-        primary_ray: &Ray,
-        scene: &'a Scene,
-        integrator: &BackwardMetropolis,
-        rng: &mut RandGen,
-    ) -> Self {
-        let mut ret =
-            Self::with_capacity(primary_ray.geometry.origin, integrator.min_path_length * 2); // leave some room for future expansions
-        let mut ray = *primary_ray;
-        ret.primary_dir = Some(ray.geometry.direction);
-        for _ in 0..integrator.min_path_length {
-            if ret.extend(scene, &ray, integrator.n_shadow_samples, rng) {
-                // Create new ray.
-                let node = ret.nodes.last().unwrap();
-                let material = node.material;
-                let normal = node.normal;
-                let e1 = node.e1;
-                let e2 = node.e2;
-                let point = node.point;
-
-                let (new_ray, _, _) = material.sample_bsdf(normal, e1, e2, point, ray, rng);
-                ray = new_ray;
-            } else {
-                // Did not hit anything... no point in keeping going.
-                return ret;
-            }
+            samples: (0..n).map(|_| rng.gen()).collect(),
         }
-
-        // return
-        ret
     }
 
-    fn push(&mut self, node: PathNode<'a>) {
-        if self.nodes.is_empty() {
-            // Fill primary_dir
-            let dir = (node.point - self.start).get_normalized();
-            self.primary_dir = Some(dir);
-        }
-        self.nodes.push(node)
+    /// The maximum number of interactions in the path
+    pub fn max_depth(&self) -> usize {
+        (self.samples.len() - N_PIXEL_SAMPLES) / N_NODE_SAMPLES
     }
 
-    /// Adds one level to the Path by sending a `ray` through the `scene`.
+    /// Walks through the `scene`, returning the index of the pixel that the
+    /// path goes through and the radiance it carries towards the camera.
     ///
-    /// If the object that the ray hits has a fully specular material—e.g., Dielectric—
-    /// then another ray will be sent to reflect the quasi-deterministic nature of
-    /// specular reflections.
-    #[must_use]
-    pub fn extend(
-        &mut self,
-        scene: &'a Scene,
-        ray: &Ray,
+    /// Light sources are sampled directly (through `n_shadow_samples` shadow rays)
+    /// at each non-specular interaction, which adds some randomness (taken
+    /// from `rng`) to the result.
+    pub fn eval(
+        &self,
+        scene: &Scene,
+        camera: &dyn Camera,
         n_shadow_samples: usize,
         rng: &mut RandGen,
-    ) -> bool {
-        if let Some(node) = PathNode::new(scene, ray, n_shadow_samples, rng) {
-            self.push(node);
-            true
-        } else {
-            false
-        }
-    }
-
-    /// Walks from start to end, adding the contribution of the different
-    /// nodes.
-    pub fn eval_from_node(&self, i: usize, scene: &Scene) -> Spectrum {
-        if self.nodes.is_empty() {
-            return Spectrum::BLACK;
-        }
-        assert!(
-            i < self.nodes.len(),
-            "Trying to evaluate path of {} starting from node {}",
-            self.nodes.len(),
-            i
-        );
-
-        let prev_pt = if i == 0 {
-            self.start
-        } else {
-            self.nodes[i - 1].point
-        };
-
-        // Add local
-        let node = &self.nodes[i];
-
-        let vin = (node.point - prev_pt).get_normalized();
-        let ret = node.eval(vin);
-
-        // Add next node
-        if let Some(next_node) = self.nodes.get(i + 1) {
-            let vout = next_node.point - node.point;
-            let distance_squared = vout.length_squared();
-            let vout = vout.get_normalized();
-            // Ray frim this node to the next one
-            let shadow_ray = Ray3D {
-                origin: node.point,
-                direction: vout,
+        node_aux: &mut Vec<usize>,
+    ) -> (usize, Spectrum<{ crate::N_CHANNELS }>) {
+        let (width, height) = camera.film_resolution();
+        let x = ((self.samples[0] * width as Float) as usize).min(width - 1);
+        let y = ((self.samples[1] * height as Float) as usize).min(height - 1);
+        let pixel = camera.pixel_index((x, y));
+        let (mut ray, weight) = camera.gen_ray(&CameraSample::new((x, y)));
+
+        let mut radiance = Spectrum::<{ crate::N_CHANNELS }>::BLACK;
+        let mut beta = Spectrum::<{ crate::N_CHANNELS }>::gray(weight);
+
+        // Light sources are sampled directly from non-specular interactions, so
+        // they only count when seen from the camera or through specular reflections
+        let mut count_lights = true;
+
+        for depth in 0..self.max_depth() {
+            let start = N_PIXEL_SAMPLES + depth * N_NODE_SAMPLES;
+            let u = &self.samples[start..start + N_NODE_SAMPLES];
+
+            let triangle_index = match scene.cast_ray(&mut ray, node_aux) {
+                Some(i) => i,
+                None => {
+                    radiance += beta * escaped_radiance(scene, &ray.geometry, count_lights);
+                    break;
+                }
             };
 
-            // if next node is obstructed... then don't bother.
-            if !scene.unobstructed_distance(&shadow_ray, distance_squared) {
-                return ret;
-            }
-
-            // Ray from prev_point to this node
-            let ray = Ray {
-                geometry: Ray3D {
-                    origin: prev_pt,
-                    direction: vin,
-                },
-                refraction_index: 1.,
-            };
-
-            let bsdf = node
-                .material
-                .eval_bsdf(node.normal, node.e1, node.e2, &ray, vout);
-            let cos_theta = (node.normal * vout).abs();
-
-            // return this + the next node.
-            ret + node.material.colour() * bsdf * cos_theta * self.eval_from_node(i + 1, scene)
-        } else {
-            // return
-            ret
-        }
-    }
-}
-
-#[derive(Clone)]
-pub struct PathNode<'a> {
-    pub normal: Vector3D,
-    pub e1: Vector3D,
-    pub e2: Vector3D,
-    pub point: Point3D,
-
-    /// A vector containing the radiance and the direction of direct lighting
-    /// reaching a point
-    local_illuminance: Vec<(Vector3D, Spectrum)>,
-
-    pub material: &'a Material,
-}
-
-impl<'a> PathNode<'a> {
-    pub fn new(
-        scene: &'a Scene,
-        ray: &Ray,
-        n_shadow_samples: usize,
-        rng: &mut RandGen,
-    ) -> Option<Self> {
-        if let Some(Interaction::Surface(data)) = scene.cast_ray(&ray) {
-            let object = &scene.objects[data.prim_index];
-            let material = match data.geometry_shading.side {
-                SurfaceSide::Front => &scene.materials[object.front_material_index],
-                SurfaceSide::Back => &scene.materials[object.back_material_index],
-                SurfaceSide::NonApplicable => {
-                    // Hit parallel to the surface...?
-                    unreachable!();
-                }
+            let material = match ray.interaction.geometry_shading.side {
+                SurfaceSide::Front => &scene.materials[scene.front_material_index(triangle_index)],
+                SurfaceSide::Back => &scene.materials[scene.back_material_index(triangle_index)],
+                SurfaceSide::NonApplicable => break,
             };
 
             if material.emits_light() {
-                return None;
-                // todo!()
+                if count_lights {
+                    radiance += beta * material.colour();
+                }
+                break;
             }
 
+            ray.interaction
+                .interpolate_normal(scene.normals_at(triangle_index));
+            let (intersection_pt, normal, e1, e2) = ray.get_triad();
+
             if material.specular_only() {
-                todo!()
+                let (new_ray, weight) =
+                    match material.choose_path(&normal, &intersection_pt, &ray, u[0]) {
+                        Some(v) => v,
+                        None => break,
+                    };
+                beta *= weight;
+                ray = Ray {
+                    geometry: new_ray.geometry,
+                    refraction_index: new_ray.refraction_index,
+                    ..Ray::default()
+                };
+                count_lights = true;
+                continue;
             }
 
-            // let point = data.point;
-
-            // GET LOCAL ILLUMINATION
-            let normal = data.geometry_shading.normal;
-            let e1 = data.geometry_shading.dpdu.get_normalized();
-            let e2 = normal.cross(e1); //.get_normalized();
-            let point = data.point;
+            /* DIRECT LIGHT */
+            radiance += beta * direct_light(scene, material, &ray, n_shadow_samples, rng, node_aux);
+            count_lights = false;
 
-            let n_lights = scene.count_all_lights();
-            let mut local_illuminance: Vec<(Vector3D, Spectrum)> = Vec::with_capacity(n_lights);
-            for light in &scene.lights {
-                let (dir, colour) =
-                    get_local_illumination(point, normal, n_shadow_samples, light, rng, scene);
-                if !colour.is_black() {
-                    local_illuminance.push((dir, colour))
-                }
-            }
-            for light in &scene.distant_lights {
-                let (dir, colour) =
-                    get_local_illumination(point, normal, n_shadow_samples, light, rng, scene);
-                if !colour.is_black() {
-                    local_illuminance.push((dir, colour))
-                }
-            }
-
-            // Build and push
-            Some(PathNode {
-                local_illuminance,
-                normal,
+            /* NEXT DIRECTION */
+            // Cosine-weighted, so cos(theta)/pdf = PI
+            let r = u[1].sqrt();
+            let phi = 2. * crate::PI * u[2];
+            let (x, y, z) = crate::samplers::local_to_world(
                 e1,
                 e2,
-                point,
-                material,
-            })
-        } else {
-            None
+                normal,
+                Point3D::new(0., 0., 0.),
+                r * phi.cos(),
+                r * phi.sin(),
+                (1. - u[1]).max(0.).sqrt(),
+            );
+            let direction = Vector3D::new(x, y, z).get_normalized();
+            let bsdf = material.eval_bsdf(normal, e1, e2, &ray, direction * -1.);
+            beta *= bsdf * crate::PI;
+            if beta.is_black() {
+                break;
+            }
+            ray = Ray {
+                geometry: Ray3D {
+                    origin: ray.interaction.spawn_ray_origin(direction),
+                    direction,
+                },
+                refraction_index: ray.refraction_index,
+                ..Ray::default()
+            };
         }
-    }
-
-    /// Evaluates the local illumination of a node, as seen
-    /// from a certain point.
-    ///
-    /// `vout` goes from the intersection point to the point of view
-    #[must_use]
-    pub fn eval(&self, vin: Vector3D) -> Spectrum {
-        debug_assert!(
-            (1. - vin.length()).abs() < 1e-5,
-            "length is {}",
-            vin.length()
-        );
-
-        // These variables relate to
-        let normal = self.normal;
 
-        let ray = Ray {
-            geometry: Ray3D {
-                origin: self.point,
-                direction: vin,
-            },
-            refraction_index: 1.,
-        };
-        let mat_colour = self.material.colour();
-
-        let mut ret = Spectrum::BLACK;
-
-        // Denominator of the Balance Heuristic... I am assuming that
-
-        for (direction, radiance) in &self.local_illuminance {
-            let direction = *direction;
-            let radiance = *radiance;
-
-            let cos_theta = (normal * direction).abs();
-            // let vout = shadow_ray.direction * -1.;
+        (pixel, radiance)
+    }
+}
 
-            let mat_bsdf_value =
-                self.material
-                    .eval_bsdf(normal, self.e1, self.e2, ray, direction * -1.);
-            ret += (radiance * cos_theta) * (mat_colour * mat_bsdf_value);
+/// The radiance arriving from the sky and—if `count_lights`—from the distant
+/// light sources, along a ray that did not hit anything
+fn escaped_radiance(
+    scene: &Scene,
+    ray: &Ray3D,
+    count_lights: bool,
+) -> Spectrum<{ crate::N_CHANNELS }> {
+    let mut ret = Spectrum::<{ crate::N_CHANNELS }>::BLACK;
+    if count_lights {
+        for light in scene.delta_lights.iter() {
+            ret += light.le(scene, ray.direction);
         }
-
-        ret
     }
+
+    ret += scene.sky_radiance(ray.direction);
+    ret
 }
 
-fn get_local_illumination(
-    mut point: Point3D,
-    normal: Vector3D,
+/// Estimates the radiance reflected towards the origin of `ray` due to the
+/// light sources in the scene, by sending `n_shadow_samples` towards each of them
+/// (and a single one towards each point or spot light)
+fn direct_light(
+    scene: &Scene,
+    material: &Material,
+    ray: &Ray,
     n_shadow_samples: usize,
-    light: &Object,
     rng: &mut RandGen,
-    scene: &Scene,
-) -> (Vector3D, Spectrum) {
-    let mut ret_light = Spectrum::BLACK;
-    let mut average_direction = Vector3D::new(0., 0., 0.);
-
-    // prevent self-shading... this assumes we are reflecting
-    point += normal * 0.001;
+    node_aux: &mut Vec<usize>,
+) -> Spectrum<{ crate::N_CHANNELS }> {
+    let (intersection_pt, normal, e1, e2) = ray.get_triad();
+    let mut ret = Spectrum::<{ crate::N_CHANNELS }>::BLACK;
+
+    let n = n_shadow_samples.max(1);
+    for light in scene.lights.iter() {
+        for _ in 0..n {
+            let direction = light.primitive.sample_direction(rng, intersection_pt);
+            let shadow_ray = Ray3D {
+                origin: ray.interaction.spawn_ray_origin(direction),
+                direction,
+            };
+            if let Some((light_colour, light_pdf)) =
+                intersect_light(scene, light, &shadow_ray, node_aux)
+            {
+                if light_pdf < 1e-18 {
+                    // obstructed
+                    continue;
+                }
+                let bsdf = material.eval_bsdf(normal, e1, e2, ray, direction * -1.);
+                let cos_theta = (normal * direction).abs();
+                ret += light_colour * bsdf * (cos_theta / (light_pdf * n as Float));
+            }
+        }
+    }
 
-    let mut i = 0;
-    while i < n_shadow_samples {
-        let direction = light.primitive.sample_direction(rng, point);
-        let shadow_ray = Ray3D {
-            origin: point,
-            direction,
+    for light in scene.delta_lights.iter() {
+        // Sampling a point light more than once gives the same result
+        let n = if light.flags() & DELTA_POSITION_LIGHT != 0 {
+            1
+        } else {
+            n
         };
-        if let Some((light_colour, light_pdf)) = sample_light(scene, light, &shadow_ray) {
-            i += 1;
-            if light_pdf < 1e-18 {
-                // The light is obstructed... don't add light, but count it.
+        for _ in 0..n {
+            let sample = match light.sample_li(scene, rng, intersection_pt) {
+                Some(s) => s,
+                None => continue,
+            };
+            if sample.pdf < 1e-18 {
                 continue;
             }
-
-            average_direction += direction;
-            ret_light += light_colour / (light_pdf * n_shadow_samples as Float);
-        } else {
-            // missed += 1;
-            // eprintln!("Missed Light! {} (i = {})", missed, i)
+            let direction = sample.direction;
+            let shadow_ray = Ray3D {
+                origin: ray.interaction.spawn_ray_origin(direction),
+                direction,
+            };
+            let distance_squared = sample.distance * sample.distance;
+            if !scene.unobstructed_distance(&shadow_ray, distance_squared, node_aux) {
+                continue;
+            }
+            let bsdf = material.eval_bsdf(normal, e1, e2, ray, direction * -1.);
+            let cos_theta = (normal * direction).abs();
+            ret += sample.radiance * bsdf * (cos_theta / (sample.pdf * n as Float));
         }
     }
-
-    // return
-    (average_direction, ret_light)
+    ret
 }
 
-// primitive.intersect(ray) --> IntersectionInfo
-
 #[cfg(test)]
 mod tests {
     use super::*;
-
-    use crate::material::PlasticMetal;
+    use crate::camera::{Film, Pinhole, View};
+    use crate::material::{Light, Plastic};
     use crate::primitive::Primitive;
-    use crate::PI;
     use geometry3d::{DistantSource3D, Triangle3D};
 
     #[test]
-    fn test_path_get_local_illumination() {
-        let mut scene = Scene::new();
-        let pt = Point3D::new(0., 0., 0.);
-
-        let brightness = 100.;
-
-        let bright_mat = scene.push_material(Material::Light(Spectrum {
-            red: brightness,
-            green: brightness,
-            blue: brightness,
-        }));
-
-        let source = Primitive::Source(DistantSource3D::new(
-            Vector3D::new(0., 0., 1.),   // direction
-            (0.5 as Float).to_radians(), // angle
-        ));
-        let omega = source.omega(pt);
-        scene.push_object(bright_mat, bright_mat, source);
-
-        scene.build_accelerator();
-
-        let normal = Vector3D::new(0., 0., 1.);
+    fn test_new_random() {
         let mut rng = get_rng();
-        let (found_dir, found) =
-            get_local_illumination(pt, normal, 1, &scene.distant_lights[0], &mut rng, &scene);
-
-        let exp = brightness * omega;
-
-        assert!((exp - found.radiance()).abs() < 1e-5);
-        assert!((1. - normal * found_dir).abs() < 1e-5);
-
-        println!(
-            "found_dir = {} | found = {} | exp = {}",
-            found_dir, found, exp
-        );
+        let path = Path::new_random(4, &mut rng);
+        assert_eq!(path.max_depth(), 4);
+        assert_eq!(path.samples.len(), 14);
+        assert!(path.samples.iter().all(|u| (0.0..1.0).contains(u)));
     }
 
     #[test]
-    fn test_path_build_and_eval_1node() {
-        // setup params
-        let start = Point3D::new(2., 0., 0.);
-        let exp_pt0 = Point3D::new(1., 0., 1.);
-        let exp_pt1 = Point3D::new(0., 0., 0.);
-        let dir_1 = Vector3D::new(-1., 0., 1.).get_normalized();
-        let dir_2 = Vector3D::new(-1., 0., -1.).get_normalized();
-        let floor_reflectance = 0.3;
-        let ceiling_reflectance = 0.73;
-        let light_brightness = 100.;
-
+    fn test_eval_lit_floor() {
+        // A floor lit by a sun right above it
         let mut scene = Scene::new();
-        let mut rng = get_rng();
-
-        // ADD FLOOR
-        let floor_mat = scene.push_material(Material::Plastic(PlasticMetal {
-            color: Spectrum {
-                red: floor_reflectance,
-                green: floor_reflectance,
-                blue: floor_reflectance,
-            },
-            specularity: 0.,
-            roughness: 0.,
-        }));
-        scene.push_object(
-            floor_mat,
-            floor_mat,
-            Primitive::Triangle(
-                Triangle3D::new(
-                    Point3D::new(-0.5, -0.5, 0.),
-                    Point3D::new(0.5, -0.5, 0.),
-                    Point3D::new(0.5, 0.5, 0.),
-                )
-                .unwrap(),
-            ),
-        );
-        scene.push_object(
-            floor_mat,
-            floor_mat,
-            Primitive::Triangle(
-                Triangle3D::new(
-                    Point3D::new(0.5, 0.5, 0.),
-                    Point3D::new(-0.5, 0.5, 0.),
-                    Point3D::new(-0.5, -0.5, 0.),
-                )
-                .unwrap(),
-            ),
-        );
-
-        // ADD CEILING
-        let ceiling_mat = scene.push_material(Material::Plastic(PlasticMetal {
-            color: Spectrum {
-                red: ceiling_reflectance,
-                green: ceiling_reflectance,
-                blue: ceiling_reflectance,
-            },
-            specularity: 0.,
-            roughness: 0.,
+        let rho = 0.5;
+        let grey = scene.push_material(Material::Plastic(Plastic {
+            colour: Spectrum::<{ crate::N_CHANNELS }>::gray(rho),
+            specularity: 0.0,
+            roughness: 0.0,
         }));
+        const L: Float = 10.;
+        let tri = Triangle3D::new(
+            Point3D::new(-L, -L, 0.),
+            Point3D::new(L, -L, 0.),
+            Point3D::new(L, L, 0.),
+        )
+        .unwrap();
+        scene.push_object(grey, grey, Primitive::Triangle(tri));
+        let tri = Triangle3D::new(
+            Point3D::new(-L, -L, 0.),
+            Point3D::new(L, L, 0.),
+            Point3D::new(-L, L, 0.),
+        )
+        .unwrap();
+        scene.push_object(grey, grey, Primitive::Triangle(tri));
+
+        let brightness = 1000.;
+        let angle = (0.5 as Float).to_radians();
+        let sun = scene.push_material(Material::Light(Light(
+            Spectrum::<{ crate::N_CHANNELS }>::gray(brightness),
+        )));
         scene.push_object(
-            ceiling_mat,
-            ceiling_mat,
-            Primitive::Triangle(
-                Triangle3D::new(
-                    Point3D::new(0.5, -0.5, 1.),
-                    Point3D::new(1.5, -0.5, 1.),
-                    Point3D::new(1.5, 0.5, 1.),
-                )
-                .unwrap(),
-            ),
+            sun,
+            sun,
+            Primitive::Source(DistantSource3D::new(Vector3D::new(0., 0., 1.), angle)),
         );
-        scene.push_object(
-            ceiling_mat,
-            ceiling_mat,
-            Primitive::Triangle(
-                Triangle3D::new(
-                    Point3D::new(1.5, 0.5, 1.),
-                    Point3D::new(0.5, 0.5, 1.),
-                    Point3D::new(0.5, -0.5, 1.),
-                )
-                .unwrap(),
-            ),
-        );
-
-        // Add light source
-        let bright_mat = scene.push_material(Material::Light(Spectrum {
-            red: light_brightness,
-            green: light_brightness,
-            blue: light_brightness,
-        }));
-
-        let source = DistantSource3D::new(
-            Vector3D::new(-1., 0., 1.),  // direction
-            (0.5 as Float).to_radians(), // angle
-        );
-        let omega = source.omega;
-        let source = Primitive::Source(source);
-        scene.push_object(bright_mat, bright_mat, source);
-
-        // Scene done... compile
         scene.build_accelerator();
 
-        // Start the test
-        let mut path = Path::new(start);
-        let ray = Ray {
-            refraction_index: 1.,
-            geometry: Ray3D {
-                origin: start,
-                direction: dir_1,
-            },
+        let view = View {
+            view_direction: Vector3D::new(0., 0., -1.),
+            view_up: Vector3D::new(0., 1., 0.),
+            view_point: Point3D::new(0., 0., 1.),
+            ..View::default()
         };
-        // Extend
-        assert!(path.extend(&scene, &ray, 1, &mut rng));
-        // Check node position
-        assert!(
-            (path.nodes[0].point - exp_pt0).length() < 1e-20,
-            "expecting {} ... found {}",
-            exp_pt0,
-            path.nodes[0].point
-        );
-
-        let ray = Ray {
-            refraction_index: 1.,
-            geometry: Ray3D {
-                origin: path.nodes[0].point,
-                direction: dir_2,
-            },
-        };
-        // Extend
-        assert!(path.extend(&scene, &ray, 1, &mut rng));
-        // Check node position
-        assert!(
-            (path.nodes[1].point - exp_pt1).length() < 1e-20,
-            "expecting {} ... found {}",
-            exp_pt1,
-            path.nodes[1].point
-        );
+        let camera = Pinhole::new(view, Film { resolution: (4, 4) });
 
-        let found_v1 = path.eval_from_node(1, &scene);
-        let exp_v1 =
-            (light_brightness * omega) * (45. as Float).to_radians().cos() * floor_reflectance / PI; //
-        assert!(
-            (exp_v1 - found_v1.radiance()).abs() < 1e-5,
-            "found_v1 = {} | exp_v1 = {}",
-            found_v1.radiance(),
-            exp_v1
-        );
+        // The only thing the floor can see is the sun, so every path
+        // carries the same value
+        let omega = 2. * crate::PI * (1. - (angle / 2.).cos());
+        let expected = rho * brightness * omega / crate::PI;
 
-        let found_v0 = path.eval_from_node(0, &scene);
-        let exp_v0 = exp_v1 * (45. as Float).to_radians().cos() * ceiling_reflectance / PI;
-        assert!(
-            (exp_v0 - found_v0.radiance()).abs() < 1e-5,
-            "found_v0 = {} | exp_v0 = {}",
-            found_v0.radiance(),
-            exp_v0
-        );
+        let mut rng = get_rng();
+        let mut node_aux = Vec::new();
+        for _ in 0..10 {
+            let path = Path::new_random(3, &mut rng);
+            let (pixel, found) = path.eval(&scene, &camera, 1, &mut rng, &mut node_aux);
+            assert!(pixel < 16);
+            let found = found.0[0];
+            assert!(
+                (found - expected).abs() / expected < 1e-3,
+                "expected {}, found {}",
+                expected,
+                found
+            );
+        }
     }
 }
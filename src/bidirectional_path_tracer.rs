@@ -0,0 +1,1130 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use std::sync::Mutex;
+
+use crate::camera::{Camera, CameraSample};
+use crate::colour::Spectrum;
+use crate::image::ImageBuffer;
+use crate::interaction::offset_ray_origin;
+use crate::lights::{
+    infinite_pdf_pos, Light, SkyLight, AREA_LIGHT, DELTA_DIRECTION_LIGHT, DELTA_POSITION_LIGHT,
+    INFINITE_LIGHT,
+};
+use crate::material::Material;
+use crate::path_tracer::{escaped_radiance, hit_light};
+use crate::progress::{render_pixels, RenderControl, RenderStats};
+use crate::rand::*;
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::Float;
+use geometry3d::intersection::SurfaceSide;
+use geometry3d::{Point3D, Ray3D, Vector3D};
+
+type Colour = Spectrum<{ crate::N_CHANNELS }>;
+
+/// Does the `light` emit from infinitely far away (i.e., is
+/// it the sky or a distant light)?
+fn is_infinite_light(light: &dyn Light) -> bool {
+    light.flags() & (DELTA_DIRECTION_LIGHT | INFINITE_LIGHT) != 0
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum VertexKind {
+    /// The view point
+    Camera,
+    /// The first vertex of a light subpath
+    Light,
+    /// An interaction with a surface (which might be emitting light)
+    Surface,
+    /// A camera subpath that left the scene
+    Escaped,
+}
+
+/// A vertex of either a camera or a light subpath
+#[derive(Clone, Copy)]
+struct Vertex<'a> {
+    kind: VertexKind,
+    point: Point3D,
+
+    /// The shading normal. It faces the side from which the subpath
+    /// arrived, except for emitters, for which it faces their front.
+    normal: Vector3D,
+    e1: Vector3D,
+    e2: Vector3D,
+
+    /// The normal of the surface itself and a bound on the error of
+    /// `point`, for offsetting the rays that leave the vertex
+    geometric_normal: Vector3D,
+    perror: Vector3D,
+
+    /// The direction in which light travels, for vertices at infinity
+    direction: Vector3D,
+
+    material: Option<&'a Material>,
+    emitter: Option<&'a dyn Light>,
+
+    /// The throughput of the subpath, up to this vertex
+    beta: Colour,
+
+    /// Was the subpath scattered specularly at this vertex?
+    delta: bool,
+
+    /// The probability (per unit area, or per unit solid angle for
+    /// vertices at infinity) of sampling this vertex from the previous one
+    pdf_fwd: Float,
+
+    /// The probability of sampling this vertex from the next one
+    pdf_rev: Float,
+}
+
+impl<'a> Vertex<'a> {
+    fn new(kind: VertexKind, point: Point3D, beta: Colour) -> Self {
+        let zero = Vector3D::new(0., 0., 0.);
+        Self {
+            kind,
+            point,
+            normal: zero,
+            e1: zero,
+            e2: zero,
+            geometric_normal: zero,
+            perror: zero,
+            direction: zero,
+            material: None,
+            emitter: None,
+            beta,
+            delta: false,
+            pdf_fwd: 0.,
+            pdf_rev: 0.,
+        }
+    }
+
+    /// The origin of a ray leaving this vertex in `direction`, offset so
+    /// that it does not hit the surface the vertex is on
+    fn spawn_origin(&self, direction: Vector3D) -> Point3D {
+        offset_ray_origin(self.point, self.perror, self.geometric_normal, direction)
+    }
+
+    fn is_infinite(&self) -> bool {
+        match self.kind {
+            VertexKind::Escaped => true,
+            VertexKind::Light => self.emitter.map_or(false, is_infinite_light),
+            _ => false,
+        }
+    }
+
+    fn on_surface(&self) -> bool {
+        match self.kind {
+            VertexKind::Surface => true,
+            VertexKind::Light => self.emitter.map_or(false, |l| l.flags() & AREA_LIGHT != 0),
+            _ => false,
+        }
+    }
+
+    /// Is this the first vertex of a light subpath that
+    /// starts at a point (e.g., a point or spot light)?
+    fn is_delta_light(&self) -> bool {
+        self.kind == VertexKind::Light
+            && self
+                .emitter
+                .map_or(false, |l| l.flags() & DELTA_POSITION_LIGHT != 0)
+    }
+
+    /// Can this vertex be joined with a vertex from the other subpath?
+    fn is_connectible(&self) -> bool {
+        match self.kind {
+            VertexKind::Camera | VertexKind::Light => true,
+            VertexKind::Escaped => false,
+            VertexKind::Surface => match self.material {
+                Some(m) => !m.specular_only() && !m.emits_light(),
+                None => false,
+            },
+        }
+    }
+
+    /// The direction from this vertex towards `other`
+    fn direction_to(&self, other: &Vertex) -> Vector3D {
+        if other.is_infinite() {
+            other.direction * -1.
+        } else if self.is_infinite() {
+            self.direction
+        } else {
+            (other.point - self.point).get_normalized()
+        }
+    }
+
+    /// Transforms a probability per unit solid angle of sampling `next`
+    /// from this vertex into a probability per unit area
+    fn convert_density(&self, pdf: Float, next: &Vertex) -> Float {
+        if next.is_infinite() {
+            return pdf;
+        }
+        let w = next.point - self.point;
+        let d2 = w.length_squared();
+        if d2 < 1e-18 {
+            return 0.0;
+        }
+        let mut pdf = pdf / d2;
+        if next.on_surface() {
+            pdf *= (next.normal * w).abs() / d2.sqrt();
+        }
+        pdf
+    }
+
+    /// A ray arriving at this vertex from direction `w`
+    fn incoming_ray(&self, w: Vector3D) -> Ray {
+        Ray {
+            geometry: Ray3D {
+                origin: self.point + w,
+                direction: w * -1.,
+            },
+            ..Ray::default()
+        }
+    }
+
+    /// The material at this vertex, if it scatters light non-specularly
+    fn scattering_material(&self) -> Option<&'a Material> {
+        match self.material {
+            Some(m) if self.kind == VertexKind::Surface && self.is_connectible() => Some(m),
+            _ => None,
+        }
+    }
+
+    /// The BSDF at this vertex, for light arriving from `next`
+    /// and leaving towards `prev`
+    fn f(&self, prev: &Vertex, next: &Vertex) -> Colour {
+        let material = match self.scattering_material() {
+            Some(m) => m,
+            None => return Colour::BLACK,
+        };
+        let wp = self.direction_to(prev);
+        let wn = self.direction_to(next);
+        // Non-specular materials only reflect
+        if (self.normal * wp) * (self.normal * wn) <= 0. {
+            return Colour::BLACK;
+        }
+        let ray = self.incoming_ray(wp);
+        material.eval_bsdf(self.normal, self.e1, self.e2, &ray, wn * -1.)
+    }
+
+    /// The probability (per unit area) of sampling `next` from this vertex,
+    /// having arrived from `prev`
+    fn pdf(&self, ctx: &Context, prev: Option<&Vertex>, next: &Vertex) -> Float {
+        match self.kind {
+            VertexKind::Light => self.pdf_light(ctx, next),
+            VertexKind::Camera => {
+                let w = self.direction_to(next);
+                self.convert_density(ctx.camera_pdf(w), next)
+            }
+            VertexKind::Escaped => 0.0,
+            VertexKind::Surface => {
+                let (material, prev) = match (self.scattering_material(), prev) {
+                    (Some(m), Some(p)) => (m, p),
+                    _ => return 0.0,
+                };
+                let wp = self.direction_to(prev);
+                let wn = self.direction_to(next);
+                if (self.normal * wp) * (self.normal * wn) <= 0. {
+                    return 0.0;
+                }
+                let ray = self.incoming_ray(wp);
+                let pdf = material.bsdf_pdf(self.normal, self.e1, self.e2, &ray, wn * -1.);
+                self.convert_density(pdf, next)
+            }
+        }
+    }
+
+    /// The probability (per unit area) of this vertex—an emitter—sending
+    /// light towards `v`
+    fn pdf_light(&self, ctx: &Context, v: &Vertex) -> Float {
+        let w = self.direction_to(v);
+        let mut pdf = if self.is_infinite() {
+            // Every infinite light samples the origin over the same disc
+            infinite_pdf_pos(ctx.scene)
+        } else {
+            let light = match self.emitter {
+                Some(light) => light,
+                None => return 0.0,
+            };
+            let d2 = (v.point - self.point).length_squared();
+            if d2 < 1e-18 {
+                return 0.0;
+            }
+            light.pdf_le(ctx.scene, self.point, self.normal, w).1 / d2
+        };
+        if v.on_surface() {
+            pdf *= (v.normal * w).abs();
+        }
+        pdf
+    }
+
+    /// The probability of starting a light subpath at this vertex
+    fn pdf_light_origin(&self, ctx: &Context) -> Float {
+        if self.is_infinite() {
+            return ctx.infinite_light_density(self.direction);
+        }
+        match self.emitter {
+            Some(light) => {
+                let (pdf_pos, _) = light.pdf_le(ctx.scene, self.point, self.normal, self.direction);
+                ctx.choice_pdf() * pdf_pos
+            }
+            None => 0.0,
+        }
+    }
+}
+
+/// Everything the subpaths need to know about the scene and the camera
+struct Context<'a> {
+    scene: &'a Scene,
+    camera: &'a dyn Camera,
+
+    /// The lights that can start a light subpath. They are chosen
+    /// with equal probability.
+    emitters: Vec<&'a dyn Light>,
+
+    film_area: Float,
+}
+
+impl<'a> Context<'a> {
+    fn new(scene: &'a Scene, camera: &'a dyn Camera) -> Self {
+        let mut emitters: Vec<&'a dyn Light> = Vec::new();
+        emitters.extend(scene.lights.iter().map(|l| l as &dyn Light));
+        emitters.extend(scene.delta_lights.iter().map(|l| l.as_ref()));
+        if scene.has_sky() {
+            emitters.push(&SkyLight);
+        }
+
+        Self {
+            scene,
+            camera,
+            emitters,
+            film_area: camera.film_area(),
+        }
+    }
+
+    /// The probability of choosing each emitter
+    fn choice_pdf(&self) -> Float {
+        1. / self.emitters.len() as Float
+    }
+
+    /// The probability (per unit solid angle) of a light subpath
+    /// leaving the sky or the distant lights in `direction`
+    fn infinite_light_density(&self, direction: Vector3D) -> Float {
+        let origin = Point3D::new(0., 0., 0.);
+        let pdf: Float = self
+            .emitters
+            .iter()
+            .filter(|l| is_infinite_light(**l))
+            .map(|l| l.pdf_le(self.scene, origin, direction, direction).1)
+            .sum();
+        pdf * self.choice_pdf()
+    }
+
+    /// The probability (per unit solid angle) of the camera sending a ray in direction `w`
+    fn camera_pdf(&self, w: Vector3D) -> Float {
+        let cos = w * self.camera.view().view_direction.get_normalized();
+        if cos <= 0. {
+            return 0.0;
+        }
+        1. / (self.film_area * cos * cos * cos)
+    }
+
+    /// The pixel seen in direction `w`, and the importance that the
+    /// camera emits through it
+    fn camera_importance(&self, w: Vector3D) -> Option<(usize, Float)> {
+        let ray = Ray3D {
+            origin: self.camera.view().view_point,
+            direction: w,
+        };
+        let (pixel, weight) = self.camera.pixel_from_ray(&ray);
+        let pdf = self.camera_pdf(w);
+        if weight <= 0. || pdf <= 0. {
+            return None;
+        }
+        let (width, height) = self.camera.film_resolution();
+        Some((
+            self.camera.pixel_index(pixel),
+            pdf * (width * height) as Float,
+        ))
+    }
+
+    /// Finds the element of [`Scene::lights`] hit by `ray` at `point`
+    fn hit_emitter(&self, ray: &Ray3D, point: Point3D) -> Option<&'a dyn Light> {
+        let scene: &'a Scene = self.scene;
+        let (i, _) = hit_light(scene, ray, point)?;
+        Some(&scene.lights[i])
+    }
+
+    /// The geometric term between two vertices, including visibility
+    fn g(&self, a: &Vertex, b: &Vertex, node_aux: &mut Vec<usize>) -> Float {
+        let w = b.point - a.point;
+        let d2 = w.length_squared();
+        if d2 < 1e-18 {
+            return 0.0;
+        }
+        let w = w / d2.sqrt();
+        let mut g = 1. / d2;
+        if a.on_surface() {
+            g *= (a.normal * w).abs();
+        }
+        if b.on_surface() {
+            g *= (b.normal * w).abs();
+        }
+        if g <= 0. {
+            return 0.0;
+        }
+        let ray = Ray3D {
+            origin: a.spawn_origin(w),
+            direction: w,
+        };
+        if self.scene.unobstructed_distance(&ray, d2, node_aux) {
+            g
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Extends a subpath (i.e., `path`, which contains its first vertex) by
+/// following `ray`, until it leaves the scene, is absorbed or
+/// reaches `max_vertices` new vertices.
+#[allow(clippy::too_many_arguments)]
+fn random_walk<'a>(
+    ctx: &Context<'a>,
+    ray: Ray3D,
+    mut beta: Colour,
+    pdf_dir: Float,
+    max_vertices: usize,
+    camera_path: bool,
+    rng: &mut RandGen,
+    node_aux: &mut Vec<usize>,
+    path: &mut Vec<Vertex<'a>>,
+) {
+    let scene = ctx.scene;
+    let mut ray = Ray {
+        geometry: ray,
+        ..Ray::default()
+    };
+    let mut pdf_fwd = pdf_dir;
+    let mut n_vertices = 0;
+
+    while n_vertices < max_vertices && !beta.is_black() {
+        let prev = path.len() - 1;
+        let triangle_index = match scene.cast_ray(&mut ray, node_aux) {
+            Some(i) => i,
+            None => {
+                // Light can only be gathered from the sky by camera subpaths
+                if camera_path {
+                    let mut v = Vertex::new(VertexKind::Escaped, ray.geometry.origin, beta);
+                    v.direction = ray.geometry.direction * -1.;
+                    v.pdf_fwd = pdf_fwd;
+                    path.push(v);
+                }
+                break;
+            }
+        };
+
+        let side = ray.interaction.geometry_shading.side;
+        let material = match side {
+            SurfaceSide::Front => &scene.materials[scene.front_material_index(triangle_index)],
+            SurfaceSide::Back => &scene.materials[scene.back_material_index(triangle_index)],
+            SurfaceSide::NonApplicable => break,
+        };
+
+        // Emitters do not reflect. Camera subpaths keep them, for
+        // gathering their light
+        if material.emits_light() {
+            if camera_path {
+                let point = ray.interaction.point;
+                let mut v = Vertex::new(VertexKind::Surface, point, beta);
+                let n = ray.interaction.geometry_shading.normal;
+                let faces_ray = n * ray.geometry.direction < 0.;
+                v.normal = if faces_ray == (side == SurfaceSide::Front) {
+                    n
+                } else {
+                    n * -1.
+                };
+                v.geometric_normal = ray.interaction.geometric_normal();
+                v.perror = ray.interaction.perror;
+                v.material = Some(material);
+                v.emitter = ctx.hit_emitter(&ray.geometry, point);
+                v.pdf_fwd = path[prev].convert_density(pdf_fwd, &v);
+                path.push(v);
+            }
+            break;
+        }
+
+        ray.interaction
+            .interpolate_normal(scene.normals_at(triangle_index));
+        let (point, normal, e1, e2) = ray.get_triad();
+        let mut v = Vertex::new(VertexKind::Surface, point, beta);
+        v.normal = normal;
+        v.e1 = e1;
+        v.e2 = e2;
+        v.geometric_normal = ray.interaction.geometric_normal();
+        v.perror = ray.interaction.perror;
+        v.material = Some(material);
+        v.pdf_fwd = path[prev].convert_density(pdf_fwd, &v);
+        path.push(v);
+        n_vertices += 1;
+        if n_vertices >= max_vertices {
+            break;
+        }
+        let current = path.len() - 1;
+
+        if material.specular_only() {
+            let (new_ray, weight) = match material.choose_path(&normal, &point, &ray, rng.gen()) {
+                Some(v) => v,
+                None => break,
+            };
+            beta *= weight;
+            path[current].delta = true;
+            path[prev].pdf_rev = 0.0;
+            pdf_fwd = 0.0;
+            ray = Ray {
+                geometry: new_ray.geometry,
+                refraction_index: new_ray.refraction_index,
+                ..Ray::default()
+            };
+            continue;
+        }
+
+        let mut new_ray = ray;
+        material.sample_bsdf(normal, e1, e2, point, &mut new_ray, rng);
+        let w = new_ray.geometry.direction;
+        let pdf = material.bsdf_pdf(normal, e1, e2, &ray, w * -1.);
+        if pdf < 1e-18 {
+            break;
+        }
+        let bsdf = material.eval_bsdf(normal, e1, e2, &ray, w * -1.);
+        beta *= bsdf * ((normal * w).abs() / pdf);
+
+        // The probability of having sampled the previous vertex from this one
+        let mut reverse = ray;
+        reverse.geometry.direction = w * -1.;
+        let pdf_rev = material.bsdf_pdf(normal, e1, e2, &reverse, ray.geometry.direction);
+        let pdf_rev = path[current].convert_density(pdf_rev, &path[prev]);
+        path[prev].pdf_rev = pdf_rev;
+
+        pdf_fwd = pdf;
+        ray = Ray {
+            geometry: new_ray.geometry,
+            refraction_index: ray.refraction_index,
+            ..Ray::default()
+        };
+    }
+}
+
+/// The Multiple Importance Sampling weight of the strategy that joins the first
+/// `s` vertices of the light subpath with the first `t` vertices of the camera
+/// subpath, computed through the balance heuristic.
+fn mis_weight(ctx: &Context, light: &[Vertex], camera: &[Vertex], s: usize, t: usize) -> Float {
+    if s + t == 2 {
+        return 1.;
+    }
+    let remap0 = |f: Float| if f > 0. { f } else { 1. };
+
+    // Update the densities at the connection
+    let mut light: Vec<Vertex> = light[..s].to_vec();
+    let mut camera: Vec<Vertex> = camera[..t].to_vec();
+    let pt = camera[t - 1];
+    let pt_minus = if t > 1 { Some(camera[t - 2]) } else { None };
+    let qs = if s > 0 { Some(light[s - 1]) } else { None };
+    let qs_minus = if s > 1 { Some(light[s - 2]) } else { None };
+
+    camera[t - 1].pdf_rev = match &qs {
+        Some(qs) => qs.pdf(ctx, qs_minus.as_ref(), &pt),
+        None => pt.pdf_light_origin(ctx),
+    };
+    if let Some(pt_minus) = &pt_minus {
+        camera[t - 2].pdf_rev = match &qs {
+            Some(qs) => pt.pdf(ctx, Some(qs), pt_minus),
+            None => pt.pdf_light(ctx, pt_minus),
+        };
+    }
+    if let Some(qs) = &qs {
+        light[s - 1].pdf_rev = pt.pdf(ctx, pt_minus.as_ref(), qs);
+        if let Some(qs_minus) = &qs_minus {
+            light[s - 2].pdf_rev = qs.pdf(ctx, Some(&pt), qs_minus);
+        }
+        light[s - 1].delta = false;
+    }
+    camera[t - 1].delta = false;
+
+    let mut sum_ri = 0.0;
+    let mut ri = 1.0;
+    for i in (1..t).rev() {
+        ri *= remap0(camera[i].pdf_rev) / remap0(camera[i].pdf_fwd);
+        if !camera[i].delta && !camera[i - 1].delta {
+            sum_ri += ri;
+        }
+    }
+    let mut ri = 1.0;
+    for i in (0..s).rev() {
+        ri *= remap0(light[i].pdf_rev) / remap0(light[i].pdf_fwd);
+        let delta_light = if i > 0 {
+            light[i - 1].delta
+        } else {
+            // Nothing but the light subpath can reach a point
+            light[0].is_delta_light()
+        };
+        if !light[i].delta && !delta_light {
+            sum_ri += ri;
+        }
+    }
+    1. / (1. + sum_ri)
+}
+
+/// A bidirectional path tracer. For each pixel sample, it traces a subpath
+/// from the camera and another one from a light source—chosen among the
+/// [`Scene::lights`], the [`Scene::delta_lights`] and the sky—and then
+/// joins every vertex of one with every vertex of the other. The results
+/// are combined by means of Multiple Importance Sampling.
+///
+/// Subpaths that start at the light sources can find their way through
+/// specular materials (e.g., light shelves or glazing), so this captures
+/// caustics that neither [`RayTracer`](crate::RayTracer) nor
+/// [`PathTracer`](crate::PathTracer) can find.
+pub struct BidirectionalPathTracer {
+    /// The number of pairs of subpaths traced per pixel
+    pub n_samples: usize,
+
+    /// The maximum number of bounces
+    pub max_depth: usize,
+
+    /// The kind of sequence the subpaths of each pixel are drawn from
+    pub sampler: SamplerKind,
+
+    /// The seed of the random numbers. If given, each pixel draws its
+    /// random numbers from its own stream, so results are reproducible
+    /// regardless of the number of threads.
+    pub seed: Option<u64>,
+}
+
+impl Default for BidirectionalPathTracer {
+    fn default() -> Self {
+        Self {
+            n_samples: 64,
+            max_depth: 5,
+            sampler: SamplerKind::default(),
+            seed: None,
+        }
+    }
+}
+
+impl BidirectionalPathTracer {
+    /// Fills `path` with a subpath starting at the camera
+    fn camera_subpath<'a>(
+        &self,
+        ctx: &Context<'a>,
+        pixel: (usize, usize),
+        rng: &mut RandGen,
+        node_aux: &mut Vec<usize>,
+        path: &mut Vec<Vertex<'a>>,
+    ) {
+        path.clear();
+        let (ray, weight) = ctx.camera.gen_ray(&CameraSample::new(pixel));
+        let beta = Colour::gray(weight);
+        path.push(Vertex::new(VertexKind::Camera, ray.geometry.origin, beta));
+        let pdf_dir = ctx.camera_pdf(ray.geometry.direction);
+        random_walk(
+            ctx,
+            ray.geometry,
+            beta,
+            pdf_dir,
+            self.max_depth + 1,
+            true,
+            rng,
+            node_aux,
+            path,
+        );
+    }
+
+    /// Fills `path` with a subpath starting at a randomly chosen emitter
+    fn light_subpath<'a>(
+        &self,
+        ctx: &Context<'a>,
+        rng: &mut RandGen,
+        node_aux: &mut Vec<usize>,
+        path: &mut Vec<Vertex<'a>>,
+    ) {
+        path.clear();
+        let n = ctx.emitters.len();
+        if n == 0 {
+            return;
+        }
+        let choice_pdf = ctx.choice_pdf();
+        let light = ctx.emitters[((rng.gen::<Float>() * n as Float) as usize).min(n - 1)];
+        let sample = match light.sample_le(ctx.scene, rng) {
+            Some(sample) => sample,
+            None => return,
+        };
+        if sample.pdf_pos <= 0. || sample.pdf_dir < 1e-18 || sample.radiance.is_black() {
+            return;
+        }
+        let direction = sample.ray.direction;
+
+        let mut v = Vertex::new(VertexKind::Light, sample.ray.origin, Colour::BLACK);
+        v.emitter = Some(light);
+        let infinite = v.is_infinite();
+        if infinite {
+            v.beta = sample.radiance / (choice_pdf * sample.pdf_dir);
+            v.direction = direction;
+            v.pdf_fwd = ctx.infinite_light_density(direction);
+        } else {
+            // The emission is accounted for when connecting
+            v.beta = Colour::gray(1. / (choice_pdf * sample.pdf_pos));
+            if let Some(normal) = sample.normal {
+                v.normal = normal;
+                v.geometric_normal = normal;
+            }
+            v.perror = sample.perror;
+            v.pdf_fwd = choice_pdf * sample.pdf_pos;
+        }
+        let ray = Ray3D {
+            origin: v.spawn_origin(direction),
+            direction,
+        };
+        path.push(v);
+
+        let cos = sample.normal.map_or(1., |n| (n * direction).abs());
+        let beta = sample.radiance * (cos / (choice_pdf * sample.pdf_pos * sample.pdf_dir));
+        random_walk(
+            ctx,
+            ray,
+            beta,
+            sample.pdf_dir,
+            self.max_depth,
+            false,
+            rng,
+            node_aux,
+            path,
+        );
+
+        // The first interaction with light from infinitely far
+        // away is sampled per unit area of the disc it leaves
+        if infinite && path.len() > 1 {
+            let mut pdf = sample.pdf_pos;
+            if path[1].on_surface() {
+                pdf *= (direction * path[1].normal).abs();
+            }
+            path[1].pdf_fwd = pdf;
+        }
+    }
+
+    /// Joins the first `s` vertices of `light` with the first `t` vertices
+    /// of `camera`, returning the weighted contribution. Strategies that
+    /// only use the view point from the camera subpath (i.e., `t == 1`)
+    /// also return the index of the pixel they contribute to.
+    fn connect(
+        &self,
+        ctx: &Context,
+        light: &[Vertex],
+        camera: &[Vertex],
+        s: usize,
+        t: usize,
+        node_aux: &mut Vec<usize>,
+    ) -> (Colour, Option<usize>) {
+        let none = (Colour::BLACK, None);
+        let pt = &camera[t - 1];
+        if s > 0 && pt.kind == VertexKind::Escaped {
+            return none;
+        }
+        let mut pixel = None;
+
+        let value = if s == 0 {
+            // The camera subpath found light by itself
+            match pt.kind {
+                VertexKind::Escaped => {
+                    let ray = Ray3D {
+                        origin: pt.point,
+                        direction: pt.direction * -1.,
+                    };
+                    pt.beta * escaped_radiance(ctx.scene, &ray, None)
+                }
+                VertexKind::Surface => match pt.material {
+                    Some(m) if m.emits_light() => pt.beta * m.colour(),
+                    _ => return none,
+                },
+                _ => return none,
+            }
+        } else if t == 1 {
+            // Join the light subpath with the camera
+            let qs = &light[s - 1];
+            if !qs.is_connectible() || qs.kind != VertexKind::Surface {
+                return none;
+            }
+            let w = (qs.point - pt.point).get_normalized();
+            let (index, importance) = match ctx.camera_importance(w) {
+                Some(v) => v,
+                None => return none,
+            };
+            pixel = Some(index);
+            let f = qs.f(&light[s - 2], pt);
+            if f.is_black() {
+                return none;
+            }
+            qs.beta * f * (importance * ctx.g(pt, qs, node_aux))
+        } else if s == 1 {
+            // Join the camera subpath with the light source
+            let qs = &light[0];
+            if !pt.is_connectible() {
+                return none;
+            }
+            let f = pt.f(&camera[t - 2], qs);
+            if f.is_black() {
+                return none;
+            }
+            if qs.is_infinite() {
+                let w = qs.direction * -1.;
+                let ray = Ray3D {
+                    origin: pt.spawn_origin(w),
+                    direction: w,
+                };
+                if !ctx.scene.unobstructed_distance(&ray, Float::MAX, node_aux) {
+                    return none;
+                }
+                qs.beta * f * pt.beta * (pt.normal * w).abs()
+            } else {
+                let light = match qs.emitter {
+                    Some(light) => light,
+                    None => return none,
+                };
+                let w = (pt.point - qs.point).get_normalized();
+                let emission = light.emitted(ctx.scene, qs.point, qs.normal, w);
+                if emission.is_black() {
+                    return none;
+                }
+                qs.beta * emission * f * pt.beta * ctx.g(qs, pt, node_aux)
+            }
+        } else {
+            let qs = &light[s - 1];
+            if !qs.is_connectible() || !pt.is_connectible() {
+                return none;
+            }
+            let f = qs.f(&light[s - 2], pt) * pt.f(&camera[t - 2], qs);
+            if f.is_black() {
+                return none;
+            }
+            qs.beta * f * pt.beta * ctx.g(qs, pt, node_aux)
+        };
+
+        if value.is_black() {
+            return none;
+        }
+        (value * mis_weight(ctx, light, camera, s, t), pixel)
+    }
+
+    /// Renders the scene, without reporting progress
+    pub fn render(self, scene: &Scene, camera: &dyn Camera) -> ImageBuffer {
+        let (image, _stats) = self.render_with_control(scene, camera, &RenderControl::default());
+        image
+    }
+
+    /// Renders the scene, reporting the number of pixels done to the
+    /// `control` and checking whether it has been cancelled between chunks of
+    /// pixels. The pixels of a cancelled render are left black (but they
+    /// might still receive light from the light subpaths of other pixels).
+    pub fn render_with_control(
+        &self,
+        scene: &Scene,
+        camera: &dyn Camera,
+        control: &RenderControl,
+    ) -> (ImageBuffer, RenderStats) {
+        let (width, height) = camera.film_resolution();
+        let total_pixels = width * height;
+        // What light subpaths deposit directly on the film
+        let splats = Mutex::new(vec![Colour::BLACK; total_pixels]);
+
+        let ctx = Context::new(scene, camera);
+        let seed = self.seed.unwrap_or_else(|| get_rng().gen());
+
+        let (mut pixels, stats) = render_pixels(
+            total_pixels,
+            control,
+            || {
+                let node_aux: Vec<usize> = Vec::with_capacity(64);
+                let camera_path: Vec<Vertex> = Vec::with_capacity(self.max_depth + 2);
+                let light_path: Vec<Vertex> = Vec::with_capacity(self.max_depth + 1);
+                let chunk_splats: Vec<(usize, Colour)> = Vec::new();
+                (node_aux, camera_path, light_path, chunk_splats)
+            },
+            |(node_aux, camera_path, light_path, chunk_splats), pindex| {
+                let y = (pindex as Float / width as Float).floor() as usize;
+                let x = pindex - y * width;
+
+                let mut rng = get_sampler(self.sampler, self.n_samples, seed, pindex as u64);
+                let mut value = Colour::BLACK;
+                for i in 0..self.n_samples {
+                    rng.start_pixel_sample(pindex as u64, i as u64);
+                    self.camera_subpath(&ctx, (x, y), &mut rng, node_aux, camera_path);
+                    self.light_subpath(&ctx, &mut rng, node_aux, light_path);
+                    for t in 1..=camera_path.len() {
+                        for s in 0..=light_path.len() {
+                            if (t == 1 && s < 2) || s + t > self.max_depth + 2 {
+                                continue;
+                            }
+                            let (v, splat) =
+                                self.connect(&ctx, light_path, camera_path, s, t, node_aux);
+                            match splat {
+                                Some(p) => chunk_splats.push((p, v)),
+                                None => value += v,
+                            }
+                        }
+                    }
+                }
+                value / self.n_samples as Float
+            },
+            |(_, _, _, chunk_splats)| {
+                let mut splats = splats.lock().unwrap();
+                for (p, v) in chunk_splats {
+                    splats[p] += v;
+                }
+            },
+        );
+
+        // Each pixel sample traced one light subpath
+        let n_light_paths = (total_pixels * self.n_samples) as Float;
+        let splats = splats.into_inner().unwrap();
+        for (pixel, splat) in pixels.iter_mut().zip(splats.iter()) {
+            *pixel += *splat / n_light_paths;
+        }
+
+        (ImageBuffer::from_pixels(width, height, pixels), stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{Film, Pinhole, View};
+    use crate::lights::PointLight;
+    use crate::material::{Light, Mirror, Plastic};
+    use crate::primitive::Primitive;
+    use crate::samplers::uniform_cone_pdf;
+    use crate::PI;
+    use geometry3d::{DistantSource3D, Triangle3D};
+
+    /// Pushes a horizontal rectangle, made of two triangles
+    fn push_rectangle(
+        scene: &mut Scene,
+        material: usize,
+        min: (Float, Float),
+        max: (Float, Float),
+        z: Float,
+    ) {
+        let (x0, y0) = min;
+        let (x1, y1) = max;
+        let tri = Triangle3D::new(
+            Point3D::new(x0, y0, z),
+            Point3D::new(x1, y0, z),
+            Point3D::new(x1, y1, z),
+        )
+        .unwrap();
+        scene.push_object(material, material, Primitive::Triangle(tri));
+        let tri = Triangle3D::new(
+            Point3D::new(x0, y0, z),
+            Point3D::new(x1, y1, z),
+            Point3D::new(x0, y1, z),
+        )
+        .unwrap();
+        scene.push_object(material, material, Primitive::Triangle(tri));
+    }
+
+    fn push_sun(scene: &mut Scene, direction: Vector3D, angle: Float, brightness: Float) {
+        let sun = scene.push_material(Material::Light(Light(Colour::gray(brightness))));
+        scene.push_object(
+            sun,
+            sun,
+            Primitive::Source(DistantSource3D::new(direction, angle)),
+        );
+    }
+
+    fn average_radiance(image: &ImageBuffer) -> Float {
+        let n = image.pixels.len() as Float;
+        image.pixels.iter().map(|p| p.radiance()).sum::<Float>() / n
+    }
+
+    #[test]
+    fn test_lit_floor() {
+        let mut scene = Scene::new();
+        let reflectance = 0.5;
+        let gray = scene.push_material(Material::Plastic(Plastic {
+            colour: Colour::gray(reflectance),
+            specularity: 0.0,
+            roughness: 0.0,
+        }));
+        push_rectangle(&mut scene, gray, (-10., -10.), (10., 10.), 0.);
+        let angle = (5. as Float).to_radians();
+        let brightness = 1000.;
+        push_sun(&mut scene, Vector3D::new(0., 0., 1.), angle, brightness);
+        scene.build_accelerator();
+
+        let view = View {
+            view_point: Point3D::new(0., 0., 1.),
+            view_direction: Vector3D::new(0., 0., -1.),
+            view_up: Vector3D::new(0., 1., 0.),
+            ..View::default()
+        };
+        let camera = Pinhole::new(view, Film { resolution: (4, 4) });
+        let integrator = BidirectionalPathTracer {
+            n_samples: 32,
+            ..BidirectionalPathTracer::default()
+        };
+        let image = integrator.render(&scene, &camera);
+        let found = average_radiance(&image);
+
+        let omega = 1. / uniform_cone_pdf((angle / 2.).cos());
+        let expected = reflectance / PI * brightness * omega;
+        assert!(
+            (found - expected).abs() / expected < 0.05,
+            "expected {}, found {}",
+            expected,
+            found
+        );
+    }
+
+    #[test]
+    fn test_seed() {
+        let mut scene = Scene::new();
+        let gray = scene.push_material(Material::Plastic(Plastic {
+            colour: Colour::gray(0.5),
+            specularity: 0.0,
+            roughness: 0.0,
+        }));
+        push_rectangle(&mut scene, gray, (-10., -10.), (10., 10.), 0.);
+        scene.sky = Some(Box::new(|_: Vector3D| -> Float { 1. }));
+        scene.build_accelerator();
+
+        let view = View {
+            view_point: Point3D::new(0., 0., 1.),
+            view_direction: Vector3D::new(0., 0., -1.),
+            view_up: Vector3D::new(0., 1., 0.),
+            ..View::default()
+        };
+        let camera = Pinhole::new(view, Film { resolution: (4, 4) });
+        let render = |seed| {
+            let integrator = BidirectionalPathTracer {
+                n_samples: 4,
+                seed,
+                ..BidirectionalPathTracer::default()
+            };
+            integrator.render(&scene, &camera).pixels
+        };
+        // The light subpaths of other pixels are added in any order
+        let same = |a: &[Colour], b: &[Colour]| {
+            a.iter()
+                .zip(b.iter())
+                .all(|(a, b)| (a.radiance() - b.radiance()).abs() <= 1e-4 * a.radiance())
+        };
+        let a = render(Some(1));
+        assert!(same(&a, &render(Some(1))));
+        assert!(!same(&a, &render(Some(2))));
+    }
+
+    #[test]
+    fn test_point_light() {
+        let mut scene = Scene::new();
+        let reflectance = 0.5;
+        let gray = scene.push_material(Material::Plastic(Plastic {
+            colour: Colour::gray(reflectance),
+            specularity: 0.0,
+            roughness: 0.0,
+        }));
+        push_rectangle(&mut scene, gray, (-10., -10.), (10., 10.), 0.);
+        let (height, intensity) = (3., 100.);
+        scene.push_light(Box::new(PointLight {
+            position: Point3D::new(0., 0., height),
+            intensity: Colour::gray(intensity),
+        }));
+        scene.build_accelerator();
+
+        // A narrow view of the floor right below the light
+        let view = View {
+            view_point: Point3D::new(0., 0., 1.),
+            view_direction: Vector3D::new(0., 0., -1.),
+            view_up: Vector3D::new(0., 1., 0.),
+            field_of_view: 2.,
+        };
+        let camera = Pinhole::new(view, Film { resolution: (4, 4) });
+        let integrator = BidirectionalPathTracer {
+            n_samples: 32,
+            ..BidirectionalPathTracer::default()
+        };
+        let image = integrator.render(&scene, &camera);
+        let found = average_radiance(&image);
+
+        let expected = reflectance / PI * intensity / (height * height);
+        assert!(
+            (found - expected).abs() / expected < 0.05,
+            "expected {}, found {}",
+            expected,
+            found
+        );
+    }
+
+    #[test]
+    fn test_caustic() {
+        // The sun is reflected by a mirror on the floor towards the
+        // bottom of a small horizontal patch... the camera looks at it
+        // from below, so only light subpaths can find this light.
+        let mut scene = Scene::new();
+        let reflectance = 0.5;
+        let gray = scene.push_material(Material::Plastic(Plastic {
+            colour: Colour::gray(reflectance),
+            specularity: 0.0,
+            roughness: 0.0,
+        }));
+        let mirror = scene.push_material(Material::Mirror(Mirror(Colour::gray(1.))));
+        push_rectangle(&mut scene, mirror, (-1., -1.), (3., 1.), 0.);
+        push_rectangle(&mut scene, gray, (-0.5, -0.5), (0.5, 0.5), 2.);
+        let angle = (10. as Float).to_radians();
+        let brightness = 100.;
+        push_sun(&mut scene, Vector3D::new(1., 0., 1.), angle, brightness);
+        scene.build_accelerator();
+
+        let view = View {
+            view_point: Point3D::new(0., 0., 1.5),
+            view_direction: Vector3D::new(0., 0., 1.),
+            view_up: Vector3D::new(0., 1., 0.),
+            ..View::default()
+        };
+        let camera = Pinhole::new(view, Film { resolution: (8, 8) });
+        let integrator = BidirectionalPathTracer {
+            n_samples: 256,
+            max_depth: 2,
+            ..BidirectionalPathTracer::default()
+        };
+        let image = integrator.render(&scene, &camera);
+        let found = average_radiance(&image);
+
+        // The mirrored sun arrives 45 degrees away from the normal of the patch
+        let cos_half_alpha = (angle / 2.).cos();
+        let irradiance =
+            brightness / uniform_cone_pdf(cos_half_alpha) * (PI / 4.).cos() * (1. + cos_half_alpha)
+                / 2.;
+        let expected = reflectance / PI * irradiance;
+        assert!(
+            (found - expected).abs() / expected < 0.1,
+            "expected {}, found {}",
+            expected,
+            found
+        );
+    }
+}
@@ -24,19 +24,42 @@ Physically Based Rendering: From Theory To Implementation, © 2004-2021 Matt Pha
 https://pbr-book.org/3ed-2018/Primitives_and_Intersection_Acceleration/Bounding_Volume_Hierarchies
  */
 
+use crate::cache::{CacheReader, CacheWriter};
+use crate::instance::Mesh;
 use crate::ray::Ray;
 use crate::scene::Scene;
 use crate::triangle::*;
 use crate::Float;
-use geometry3d::{BBox3D, BBoxAxis, Point3D, Ray3D, Vector3D};
+use geometry3d::{BBox3D, BBoxAxis, Point3D, Ray3D};
 use std::cmp::Ordering;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Default)]
 struct BucketInfo {
     count: usize,
     bounds: Option<BBox3D>,
 }
 
+impl BucketInfo {
+    /// Registers a primitive with its `centroid` in this bucket
+    fn push(&mut self, centroid: Point3D) {
+        self.count += 1;
+        self.bounds = match self.bounds {
+            Some(b) => Some(BBox3D::from_union_point(&b, centroid)),
+            None => Some(BBox3D::from_point(centroid)),
+        };
+    }
+
+    /// Adds the primitives of another bucket into this one
+    #[cfg(feature = "parallel")]
+    fn merge(&mut self, other: &Self) {
+        self.count += other.count;
+        self.bounds = match (self.bounds, other.bounds) {
+            (Some(a), Some(b)) => Some(BBox3D::from_union(&a, &b)),
+            (a, b) => a.or(b),
+        };
+    }
+}
+
 fn get_bucket_index(
     centroid: Point3D,
     len_axis: Float,
@@ -116,55 +139,34 @@ impl Node {
         })
     }
 
-    #[allow(clippy::too_many_arguments)]
-    fn recursive_build(
-        scene: &Scene,
-        primitives_info: &mut [ObjectInfo],
-        start: usize,
-        end: usize,
-        total_nodes: &mut usize,
-        ordered_triangles: &mut Vec<Triangle>,
-        ordered_front_materials: &mut Vec<usize>,
-        ordered_back_materials: &mut Vec<usize>,
-        ordered_normals: &mut Vec<(Vector3D, Vector3D, Vector3D)>,
-    ) -> Self {
-        let triangles = &scene.triangles;
-        let front_materials = &scene.front_material_indexes;
-        let back_materials = &scene.back_material_indexes;
-        let normals = &scene.normals;
-
-        debug_assert!(start < end);
-        *total_nodes += 1;
+    /// Builds the tree over `primitives_info`, which is sorted so that the
+    /// primitives of each leaf are contiguous. The `offset` is the position
+    /// of the first of the `primitives_info` in the whole array.
+    ///
+    /// With the `parallel` feature, the children of large nodes are built
+    /// in parallel. As each of them sorts its own part of the array, the
+    /// result is the same as when building them one after the other.
+    fn recursive_build(primitives_info: &mut [ObjectInfo], offset: usize) -> Self {
+        debug_assert!(!primitives_info.is_empty());
         // The whole point of most of this function is to idenfity
         // the value this 'mid' variable should have... we are creating
         // a binary tree, you know, so we split things in halves
         let mut mid: Option<usize> = None;
 
         // Get a BBOX containing EVERYTHING within scope
-        let mut bounds = primitives_info[start].bounds;
-        // for i in start+1..end{
-        //     bounds = BBox3D::from_union(&bounds, &primitives_info[i].bounds);
-        // }
-        for info in primitives_info.iter().take(end).skip(start + 1) {
+        let mut bounds = primitives_info[0].bounds;
+        for info in primitives_info.iter().skip(1) {
             bounds = BBox3D::from_union(&bounds, &info.bounds);
         }
-        let n_primitives = end - start;
+        let n_primitives = primitives_info.len();
         if n_primitives == 1 {
             // Create Leaf
-            let first_prim_offset = ordered_triangles.len();
-            for info in primitives_info.iter().take(end).skip(start) {
-                let index = info.index;
-                ordered_triangles.push(triangles[index]);
-                ordered_back_materials.push(back_materials[index]);
-                ordered_front_materials.push(front_materials[index]);
-                ordered_normals.push(normals[index]);
-            }
-            return Node::new_leaf(first_prim_offset, n_primitives, bounds);
+            return Node::new_leaf(offset, n_primitives, bounds);
         }
 
         // Calculate the the BBOX of the centroids
-        let mut centroids_bbox = BBox3D::from_point(primitives_info[start].centroid);
-        for prim_info in primitives_info.iter().take(end).skip(start + 1) {
+        let mut centroids_bbox = BBox3D::from_point(primitives_info[0].centroid);
+        for prim_info in primitives_info.iter().skip(1) {
             centroids_bbox = BBox3D::from_union_point(&centroids_bbox, prim_info.centroid);
         }
 
@@ -200,60 +202,31 @@ impl Node {
         if len_axis < 1e-8 {
             // All primitives seem to be aligned in all directions (i.e., overlapping)
             // Put them al together in a Leaf
-            let first_prim_offset = ordered_triangles.len();
-            // for i in start..end{
-            for prim_info in primitives_info.iter().take(end).skip(start) {
-                let index = prim_info.index;
-                ordered_triangles.push(triangles[index]);
-                ordered_back_materials.push(back_materials[index]);
-                ordered_front_materials.push(front_materials[index]);
-                ordered_normals.push(normals[index]);
-            }
-            return Node::new_leaf(first_prim_offset, n_primitives, bounds);
+            return Node::new_leaf(offset, n_primitives, bounds);
         } else {
             // Line 306 of https://github.com/mmp/pbrt-v3/blob/master/src/accelerators/bvh.cpp
             if n_primitives <= TOO_FEW_TO_BUCKET {
                 // too few... just split in half,
-                let this_mid = (end - start) / 2;
+                let this_mid = n_primitives / 2;
 
                 mid = Some(this_mid); // this operation rounds results
                                       // and sort the relevant range in the array
-                primitives_info[start..end].select_nth_unstable_by(this_mid, cmp_centroids);
+                primitives_info.select_nth_unstable_by(this_mid, cmp_centroids);
             } else {
                 // Use the Surface Area Heuristic...
 
                 // First, put all the elements in a bucket
-                let mut buckets: Vec<BucketInfo> = vec![
-                    BucketInfo {
-                        count: 0,
-                        bounds: None
-                    };
-                    N_BUCKETS
-                ];
-
-                for prim_info in primitives_info.iter().take(end).skip(start) {
+                let bucket_of = |prim_info: &ObjectInfo| -> usize {
                     // Identify which bucket contains this object's centroid
-                    let bucket_index = get_bucket_index(
+                    get_bucket_index(
                         prim_info.centroid,
                         len_axis,
                         split_axis,
                         N_BUCKETS,
                         centroids_bbox.min,
-                    );
-
-                    // Register
-                    buckets[bucket_index].count += 1;
-                    match buckets[bucket_index].bounds {
-                        Some(b) => {
-                            buckets[bucket_index].bounds =
-                                Some(BBox3D::from_union_point(&b, prim_info.centroid))
-                        }
-                        None => {
-                            buckets[bucket_index].bounds =
-                                Some(BBox3D::from_point(prim_info.centroid))
-                        }
-                    }
-                }
+                    )
+                };
+                let buckets = fill_buckets(primitives_info, N_BUCKETS, bucket_of);
 
                 // Compute costs of splitting after each bucket
                 let mut min_cost = Float::MAX;
@@ -296,17 +269,10 @@ impl Node {
                     // We need or want to subdivide... create interior
 
                     // Sort based on centroid position
-                    primitives_info[start..end].sort_unstable_by(cmp_centroids);
+                    primitives_info.sort_unstable_by(cmp_centroids);
                     // Identify the first primitive that is
-                    for (index, i) in (start..end).into_iter().enumerate() {
-                        let bucket_index = get_bucket_index(
-                            primitives_info[i].centroid,
-                            len_axis,
-                            split_axis,
-                            N_BUCKETS,
-                            centroids_bbox.min,
-                        );
-                        if bucket_index <= min_cost_bucket {
+                    for (index, prim_info) in primitives_info.iter().enumerate() {
+                        if bucket_of(prim_info) <= min_cost_bucket {
                             mid = Some(index + 1) // update mid.
                         } else {
                             break; // we are past (these are sorted)... just break and prepare to return.
@@ -314,47 +280,77 @@ impl Node {
                     }
                 } else {
                     // Don't subdivide... create leaf
-                    let first = ordered_triangles.len();
-                    let n_prims = n_primitives;
-                    for prim in primitives_info.iter().take(end).skip(start) {
-                        let prim_num = prim.index;
-                        ordered_triangles.push(triangles[prim_num]);
-                        ordered_back_materials.push(back_materials[prim_num]);
-                        ordered_front_materials.push(front_materials[prim_num]);
-                        ordered_normals.push(normals[prim_num]);
-                    }
-                    return Node::new_leaf(first, n_prims, bounds);
+                    return Node::new_leaf(offset, n_primitives, bounds);
                 }
             }
         }
         // If we have not returned a Leaf yet... split!
-        let mid = mid.unwrap() + start;
-        let child1 = Self::recursive_build(
-            scene,
-            primitives_info,
-            start,
-            mid,
-            total_nodes,
-            ordered_triangles,
-            ordered_front_materials,
-            ordered_back_materials,
-            ordered_normals,
-        );
-        let child2 = Self::recursive_build(
-            scene,
-            primitives_info,
-            mid,
-            end,
-            total_nodes,
-            ordered_triangles,
-            ordered_front_materials,
-            ordered_back_materials,
-            ordered_normals,
+        let mid = mid.unwrap();
+        let (first, second) = primitives_info.split_at_mut(mid);
+
+        #[cfg(feature = "parallel")]
+        let (child1, child2) = if n_primitives >= PARALLEL_BUILD_THRESHOLD {
+            rayon::join(
+                || Self::recursive_build(first, offset),
+                || Self::recursive_build(second, offset + mid),
+            )
+        } else {
+            (
+                Self::recursive_build(first, offset),
+                Self::recursive_build(second, offset + mid),
+            )
+        };
+        #[cfg(not(feature = "parallel"))]
+        let (child1, child2) = (
+            Self::recursive_build(first, offset),
+            Self::recursive_build(second, offset + mid),
         );
+
         Node::new_interior(split_axis, child1, child2)
     }
 }
 
+/// The number of primitives above which the children of a node, and its
+/// buckets, are processed in parallel (with the `parallel` feature)
+#[cfg(feature = "parallel")]
+const PARALLEL_BUILD_THRESHOLD: usize = 4096;
+
+/// Puts the primitives into `n_buckets` buckets, according to `bucket_of`.
+/// With the `parallel` feature, large sets of primitives are split between
+/// threads. The result does not depend on how they are split, as counts
+/// are added and bounds are joined.
+fn fill_buckets<F: Fn(&ObjectInfo) -> usize + Sync>(
+    primitives_info: &[ObjectInfo],
+    n_buckets: usize,
+    bucket_of: F,
+) -> Vec<BucketInfo> {
+    let add = |mut buckets: Vec<BucketInfo>, prim_info: &ObjectInfo| {
+        buckets[bucket_of(prim_info)].push(prim_info.centroid);
+        buckets
+    };
+
+    #[cfg(feature = "parallel")]
+    if primitives_info.len() >= PARALLEL_BUILD_THRESHOLD {
+        use rayon::prelude::*;
+        return primitives_info
+            .par_iter()
+            .fold(|| vec![BucketInfo::default(); n_buckets], add)
+            .reduce(
+                || vec![BucketInfo::default(); n_buckets],
+                |mut a, b| {
+                    for (a, b) in a.iter_mut().zip(b.iter()) {
+                        a.merge(b);
+                    }
+                    a
+                },
+            );
+    }
+
+    primitives_info
+        .iter()
+        .fold(vec![BucketInfo::default(); n_buckets], add)
+}
+
 #[derive(Clone)]
 struct Leaf {
     bounds: BBox3D,
@@ -384,16 +380,166 @@ impl FlatNode {
     }
 }
 
+/// The number of children of each node of the wide tree used for
+/// traversing the [`BoundingVolumeTree`]. The bounds of all the children of
+/// a node are tested at once.
+const WIDTH: usize = 4;
+
+/// Makes the ray-box test conservative, accounting for rounding
+/// errors (i.e., `1 + 2*gamma(3)` in PBR)
+const ROBUST_FACTOR: Float = 1. + 6. * Float::EPSILON;
+
+/// A child of a [`WideNode`]
+#[derive(Clone, Copy)]
+enum WideChild {
+    /// A lane with no child
+    Empty,
+    /// The index of another [`WideNode`]
+    Interior(usize),
+    /// A contiguous range of primitives, which are also stored in
+    /// `n_packs` [`TrianglePack`]s
+    Leaf {
+        first_prim: usize,
+        first_pack: usize,
+        n_packs: usize,
+    },
+}
+
+/// A node of the wide tree. The bounds of its children are stored as
+/// a structure of arrays, with one lane per child.
+#[derive(Clone)]
+struct WideNode {
+    /// The X, Y and Z coordinates of the minimum corner of each child's bounds
+    min: [[Float; WIDTH]; 3],
+    /// The X, Y and Z coordinates of the maximum corner of each child's bounds
+    max: [[Float; WIDTH]; 3],
+    children: [WideChild; WIDTH],
+}
+
+impl WideNode {
+    fn empty() -> Self {
+        Self {
+            min: [[Float::MAX; WIDTH]; 3],
+            max: [[Float::MIN; WIDTH]; 3],
+            children: [WideChild::Empty; WIDTH],
+        }
+    }
+
+    /// Sets the bounds of the child in a `lane`
+    fn set_bounds(&mut self, lane: usize, bounds: &BBox3D) {
+        self.min[0][lane] = bounds.min.x;
+        self.min[1][lane] = bounds.min.y;
+        self.min[2][lane] = bounds.min.z;
+        self.max[0][lane] = bounds.max.x;
+        self.max[1][lane] = bounds.max.y;
+        self.max[2][lane] = bounds.max.z;
+    }
+
+    /// Tests a ray against the bounds of all the children at once (i.e., the
+    /// slab test in each lane), considering only the intersections closer
+    /// than `t_max`. Returns the distance at which the ray enters each child,
+    /// which is infinite for the children that are missed.
+    #[allow(clippy::needless_range_loop)]
+    fn intersect_children(
+        &self,
+        origin: &[Float; 3],
+        inv_dir: &[Float; 3],
+        t_max: Float,
+    ) -> [Float; WIDTH] {
+        let mut t0 = [0.; WIDTH];
+        let mut t1 = [t_max; WIDTH];
+        for axis in 0..3 {
+            for i in 0..WIDTH {
+                let near = (self.min[axis][i] - origin[axis]) * inv_dir[axis];
+                let far = (self.max[axis][i] - origin[axis]) * inv_dir[axis];
+                let (near, far) = if near > far { (far, near) } else { (near, far) };
+                let far = far * ROBUST_FACTOR;
+                // NaNs (i.e., the origin is on a slab's plane and the
+                // ray is parallel to it) are ignored by these comparisons
+                if near > t0[i] {
+                    t0[i] = near;
+                }
+                if far < t1[i] {
+                    t1[i] = far;
+                }
+            }
+        }
+        let mut t_near = [Float::INFINITY; WIDTH];
+        for i in 0..WIDTH {
+            if t0[i] <= t1[i] {
+                t_near[i] = t0[i];
+            }
+        }
+        t_near
+    }
+}
+
+/// Sorts the lanes of a [`WideNode`] by the distance at which
+/// the ray enters them, closest first
+fn sort_lanes(t_near: &[Float; WIDTH]) -> [usize; WIDTH] {
+    let mut lanes: [usize; WIDTH] = std::array::from_fn(|i| i);
+    lanes.sort_unstable_by(|a, b| {
+        t_near[*a]
+            .partial_cmp(&t_near[*b])
+            .unwrap_or(Ordering::Equal)
+    });
+    lanes
+}
+
+/// A Bounding Volume Hierarchy. It is built as a binary tree, which is
+/// then collapsed into a wide tree (i.e., each node has up to four children)
+/// whose leaves hold [`TrianglePack`]s. The wide tree is the one traversed
+/// when tracing rays.
 #[derive(Default, Clone)]
 pub struct BoundingVolumeTree {
     nodes: Vec<FlatNode>,
+
+    /// The wide tree, made by collapsing the `nodes`
+    wide_nodes: Vec<WideNode>,
+
+    /// The primitives in the leaves of the wide tree, in packs
+    packs: Vec<TrianglePack>,
+
+    /// The cost of the tree when it was built, which is
+    /// used for deciding when refitting is no longer worth it
+    built_cost: Float,
 }
 
 impl BoundingVolumeTree {
+    /// Builds the tree over the triangles of the `scene`, which
+    /// are sorted in the process
     pub fn new(scene: &mut Scene) -> Self {
-        let n_objects = scene.triangles.len();
+        let mut mesh = Mesh {
+            triangles: std::mem::take(&mut scene.triangles),
+            normals: std::mem::take(&mut scene.normals),
+            front_material_indexes: std::mem::take(&mut scene.front_material_indexes),
+            back_material_indexes: std::mem::take(&mut scene.back_material_indexes),
+            ..Mesh::default()
+        };
+        let (tree, order) = Self::build(&mut mesh);
+        if !scene.object_ids.is_empty() {
+            scene.object_ids = order.iter().map(|i| scene.object_ids[*i]).collect();
+        }
+        scene.triangles = mesh.triangles;
+        scene.normals = mesh.normals;
+        scene.front_material_indexes = mesh.front_material_indexes;
+        scene.back_material_indexes = mesh.back_material_indexes;
+        tree
+    }
+
+    /// Builds the tree over the triangles of a [`Mesh`], which
+    /// are sorted in the process
+    pub fn new_for_mesh(mesh: &mut Mesh) -> Self {
+        Self::build(mesh).0
+    }
+
+    /// Builds the tree over the triangles of a [`Mesh`], sorting them. Returns
+    /// the tree and the position that each of the sorted triangles had
+    /// before sorting them.
+    fn build(mesh: &mut Mesh) -> (Self, Vec<usize>) {
+        let n_objects = mesh.triangles.len();
         if n_objects == 0 {
-            return Self::default();
+            return (Self::default(), Vec::new());
         }
         /*
         STEP 1:  First, bounding information about each primitive is computed and
@@ -401,7 +547,7 @@ impl BoundingVolumeTree {
         */
         let mut primitives_info: Vec<ObjectInfo> = Vec::with_capacity(n_objects);
 
-        for (i, ob) in scene.triangles.iter().enumerate() {
+        for (i, ob) in mesh.triangles.iter().enumerate() {
             primitives_info.push(ObjectInfo::new(i, ob))
         }
 
@@ -411,39 +557,262 @@ impl BoundingVolumeTree {
         each interior node holds pointers to its children and each
         leaf node holds references to one or more primitives.
         */
-        let mut total_nodes = 0;
-        let mut ordered_triangles: Vec<Triangle> = Vec::with_capacity(n_objects);
-        let mut ordered_front_materials: Vec<usize> = Vec::with_capacity(n_objects);
-        let mut ordered_back_materials: Vec<usize> = Vec::with_capacity(n_objects);
-        let mut ordered_normals: Vec<(Vector3D, Vector3D, Vector3D)> =
-            Vec::with_capacity(n_objects);
-        let root = Node::recursive_build(
-            scene,
-            &mut primitives_info,
-            0,
-            n_objects,
-            &mut total_nodes,
-            &mut ordered_triangles,
-            &mut ordered_front_materials,
-            &mut ordered_back_materials,
-            &mut ordered_normals,
-        );
-
-        scene.triangles = ordered_triangles; // Update the Scene with the ordered primitive.
-        scene.front_material_indexes = ordered_front_materials;
-        scene.back_material_indexes = ordered_back_materials;
-        scene.normals = ordered_normals;
+        let root = Node::recursive_build(&mut primitives_info, 0);
+        let ordered_indexes: Vec<usize> = primitives_info.iter().map(|info| info.index).collect();
+
+        // Update the Mesh with the ordered primitives.
+        mesh.triangles = ordered_indexes.iter().map(|i| mesh.triangles[*i]).collect();
+        mesh.front_material_indexes = ordered_indexes
+            .iter()
+            .map(|i| mesh.front_material_indexes[*i])
+            .collect();
+        mesh.back_material_indexes = ordered_indexes
+            .iter()
+            .map(|i| mesh.back_material_indexes[*i])
+            .collect();
+        mesh.normals = ordered_indexes.iter().map(|i| mesh.normals[*i]).collect();
 
         /*
         STEP 3: Finally, this tree is converted to a more compact
         (and thus more efficient) pointerless representation for
         use during rendering.
         */
-        let mut nodes: Vec<FlatNode> = Vec::with_capacity(total_nodes);
+        // A binary tree with n_objects leaves has, at most, 2*n_objects - 1 nodes
+        let mut nodes: Vec<FlatNode> = Vec::with_capacity(2 * n_objects);
         Self::flatten_node(&root, &mut nodes);
 
-        // return
-        Self { nodes }
+        /*
+        STEP 4: Collapse the binary tree into a wide one, packing
+        the triangles in its leaves.
+        */
+        let (wide_nodes, packs) = Self::wide_tree(&nodes, &mesh.triangles);
+
+        let built_cost = Self::cost(&nodes);
+        let tree = Self {
+            nodes,
+            wide_nodes,
+            packs,
+            built_cost,
+        };
+        (tree, ordered_indexes)
+    }
+
+    /// The cost of tracing rays through the binary tree, according to
+    /// the Surface Area Heuristic: the surface area of each node, weighted
+    /// by the cost of visiting it (i.e., of intersecting its primitives, for
+    /// leaves). It is not normalized by the area of the root, so that
+    /// nodes spanning the space left by moving objects are penalized.
+    fn cost(nodes: &[FlatNode]) -> Float {
+        const RELATIVE_TRANSVERSAL_COST: Float = 0.125;
+        nodes
+            .iter()
+            .map(|node| {
+                let weight = if node.is_leaf() {
+                    node.n_prims as Float
+                } else {
+                    RELATIVE_TRANSVERSAL_COST
+                };
+                weight * node.bounds.surface_area()
+            })
+            .sum()
+    }
+
+    /// Updates the tree after its triangles have moved (e.g., because an
+    /// object was transformed), without changing its structure. The bounds
+    /// of the binary tree are recomputed bottom-up, and the wide tree
+    /// is derived from them again. The `triangles` must be the ones the tree
+    /// was built with, in the same order.
+    ///
+    /// Returns the cost of the refitted tree relative to the cost it had when
+    /// it was built (see [`BoundingVolumeTree::cost`]). Refitting moving objects
+    /// makes the tree increasingly loose, so it should be rebuilt once this
+    /// value grows too large.
+    pub fn refit(&mut self, triangles: &[Triangle]) -> Float {
+        if self.nodes.is_empty() {
+            return 1.;
+        }
+        // Children come after their parents
+        for i in (0..self.nodes.len()).rev() {
+            let node = &self.nodes[i];
+            let next = node.next as usize;
+            let bounds = if node.is_leaf() {
+                let n_prims = node.n_prims as usize;
+                triangles[next..next + n_prims]
+                    .iter()
+                    .map(world_bounds)
+                    .reduce(|a, b| BBox3D::from_union(&a, &b))
+                    .expect("Leaves should have at least one primitive")
+            } else {
+                BBox3D::from_union(&self.nodes[i + 1].bounds, &self.nodes[next].bounds)
+            };
+            self.nodes[i].bounds = bounds;
+        }
+
+        (self.wide_nodes, self.packs) = Self::wide_tree(&self.nodes, triangles);
+
+        if self.built_cost > 0. {
+            Self::cost(&self.nodes) / self.built_cost
+        } else {
+            1.
+        }
+    }
+
+    /// Collapses the binary tree made of `nodes` into a wide one, packing
+    /// the `triangles` in its leaves
+    fn wide_tree(nodes: &[FlatNode], triangles: &[Triangle]) -> (Vec<WideNode>, Vec<TrianglePack>) {
+        let ranges = Self::prim_ranges(nodes);
+        let mut wide_nodes = Vec::with_capacity(nodes.len() / 2 + 1);
+        let mut packs = Vec::with_capacity(triangles.len() / PACK_SIZE + 1);
+        Self::collapse_node(nodes, &ranges, triangles, 0, &mut wide_nodes, &mut packs);
+        (wide_nodes, packs)
+    }
+
+    /// Writes the binary tree into a cache (see [`crate::cache`]). The wide
+    /// tree is not written, as it is quickly derived from it.
+    pub(crate) fn write_cache(&self, w: &mut CacheWriter) {
+        w.usize(self.nodes.len());
+        for node in self.nodes.iter() {
+            w.bbox(&node.bounds);
+            w.u32(node.n_prims as u32);
+            w.axis(node.axis);
+            w.u32(node.next as u32);
+        }
+        w.float(self.built_cost);
+    }
+
+    /// Reads a tree written by [`BoundingVolumeTree::write_cache`], built
+    /// over the `triangles`
+    pub(crate) fn read_cache(r: &mut CacheReader, triangles: &[Triangle]) -> Result<Self, String> {
+        let n_nodes = r.count(1)?;
+        let corrupt = |i: usize| format!("Node {} of the BVH in the cache is corrupt", i);
+        let mut nodes = Vec::with_capacity(n_nodes);
+        for i in 0..n_nodes {
+            let node = FlatNode {
+                bounds: r.bbox()?,
+                n_prims: i16::try_from(r.u32()?).map_err(|_| corrupt(i))?,
+                axis: r.axis()?,
+                next: i32::try_from(r.u32()?).map_err(|_| corrupt(i))?,
+            };
+            // Check the indexes, so that corrupt caches do not lead to
+            // infinite loops or out-of-bounds access. Leaves point to their
+            // primitives, and interior nodes to their second child.
+            let next = node.next as usize;
+            let n_prims = node.n_prims as usize;
+            let is_valid = if node.is_leaf() {
+                next + n_prims <= triangles.len()
+            } else {
+                next > i + 1 && next < n_nodes
+            };
+            if !is_valid {
+                return Err(corrupt(i));
+            }
+            nodes.push(node);
+        }
+        let built_cost = r.float()?;
+        if nodes.is_empty() {
+            return Ok(Self::default());
+        }
+        // The primitives of the children of each node need to be contiguous
+        let ranges = Self::prim_ranges(&nodes);
+        for (i, node) in nodes.iter().enumerate() {
+            if !node.is_leaf() {
+                let (first, n) = ranges[i + 1];
+                if first + n != ranges[node.next as usize].0 {
+                    return Err(corrupt(i));
+                }
+            }
+        }
+        let (wide_nodes, packs) = Self::wide_tree(&nodes, triangles);
+        Ok(Self {
+            nodes,
+            wide_nodes,
+            packs,
+            built_cost,
+        })
+    }
+
+    /// Gets the range of primitives (i.e., the first one and how many)
+    /// contained by each of the flat `nodes`. These are contiguous because the
+    /// primitives were sorted while building the tree.
+    fn prim_ranges(nodes: &[FlatNode]) -> Vec<(usize, usize)> {
+        let mut ranges = vec![(0, 0); nodes.len()];
+        // Children come after their parents
+        for (i, node) in nodes.iter().enumerate().rev() {
+            ranges[i] = if node.is_leaf() {
+                (node.next as usize, node.n_prims as usize)
+            } else {
+                let (first, n1) = ranges[i + 1];
+                let (_, n2) = ranges[node.next as usize];
+                (first, n1 + n2)
+            };
+        }
+        ranges
+    }
+
+    /// Creates a [`WideNode`] out of the flat node `index` and its
+    /// descendants, returning its position in `wide_nodes`. The children
+    /// of the binary tree with the largest surface area are opened until
+    /// there are [`WIDTH`] children; subtrees with few primitives become
+    /// a single leaf.
+    fn collapse_node(
+        nodes: &[FlatNode],
+        ranges: &[(usize, usize)],
+        triangles: &[Triangle],
+        index: usize,
+        wide_nodes: &mut Vec<WideNode>,
+        packs: &mut Vec<TrianglePack>,
+    ) -> usize {
+        let is_interior = |i: usize| -> bool { !nodes[i].is_leaf() && ranges[i].1 > PACK_SIZE };
+        let children_of = |i: usize| -> [usize; 2] { [i + 1, nodes[i].next as usize] };
+
+        let mut children: Vec<usize> = if is_interior(index) {
+            children_of(index).to_vec()
+        } else {
+            vec![index]
+        };
+        while children.len() < WIDTH {
+            let largest = children
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| is_interior(**c))
+                .max_by(|(_, a), (_, b)| {
+                    let a = nodes[**a].bounds.surface_area();
+                    let b = nodes[**b].bounds.surface_area();
+                    a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+                })
+                .map(|(lane, _)| lane);
+            match largest {
+                Some(lane) => {
+                    let c = children.swap_remove(lane);
+                    children.extend_from_slice(&children_of(c));
+                }
+                None => break,
+            }
+        }
+
+        let this_offset = wide_nodes.len();
+        wide_nodes.push(WideNode::empty());
+        for (lane, c) in children.into_iter().enumerate() {
+            let child = if is_interior(c) {
+                let i = Self::collapse_node(nodes, ranges, triangles, c, wide_nodes, packs);
+                WideChild::Interior(i)
+            } else {
+                let (first_prim, n_prims) = ranges[c];
+                let first_pack = packs.len();
+                packs.extend(TrianglePack::pack_all(
+                    &triangles[first_prim..first_prim + n_prims],
+                ));
+                WideChild::Leaf {
+                    first_prim,
+                    first_pack,
+                    n_packs: packs.len() - first_pack,
+                }
+            };
+            let node = &mut wide_nodes[this_offset];
+            node.set_bounds(lane, &nodes[c].bounds);
+            node.children[lane] = child;
+        }
+        this_offset
     }
 
     fn flatten_node(node: &Node, nodes: &mut Vec<FlatNode>) -> usize {
@@ -488,128 +857,70 @@ impl BoundingVolumeTree {
         ray: &mut Ray,
         nodes_to_visit: &mut Vec<usize>,
     ) -> Option<usize> {
-        const MIN_T: Float = 0.0000001;
-
-        if self.nodes.is_empty() {
+        if self.wide_nodes.is_empty() {
             return None;
         }
         // reset
         nodes_to_visit.truncate(0);
 
-        let mut prim_index: Option<usize> = None;
-        let mut t_squared = Float::MAX;
-
-        let inv_x = 1. / ray.geometry.direction.x;
-        let inv_y = 1. / ray.geometry.direction.y;
-        let inv_z = 1. / ray.geometry.direction.z;
-
-        let inv_dir = Vector3D::new(inv_x, inv_y, inv_z);
-        let dir_is_neg = (inv_dir.x < 0., inv_dir.y < 0., inv_dir.z < 0.);
-
+        let direction = ray.geometry.direction;
+        let origin = [
+            ray.geometry.origin.x,
+            ray.geometry.origin.y,
+            ray.geometry.origin.z,
+        ];
+        let inv_dir = [1. / direction.x, 1. / direction.y, 1. / direction.z];
+        let sheared_ray = ShearedRay::new(&ray.geometry);
+
+        // The primitive hit, the distance and the baricentric coordinates
+        let mut closest: Option<(usize, Float, Float, Float)> = None;
+        let mut t_max = Float::INFINITY;
         let mut current_node = 0;
 
         loop {
-            let node = &self.nodes[current_node];
-            if node.bounds.intersect(&ray.geometry, &inv_dir) {
-                if node.is_leaf() {
-                    let offset = node.next;
-
-                    // // Check all the objects in this Node
-                    // const PACK_SIZE: usize = 4;
-                    let ini = offset as usize;
-                    let fin = ini + node.n_prims as usize;
-                    let this_prims: &[Triangle] = &primitives[ini..fin];
-
-                    /* NON_SIMD */
-                    for (i, tri) in this_prims.iter().enumerate() {
-                        if let Some(intersect_info) = triangle_intersect(tri, &ray.geometry) {
-                            // If hit, check the distance.
-                            let this_t_squared =
-                                (intersect_info.p - ray.geometry.origin).length_squared();
-                            // if the distance is less than the prevous one, update the info
-                            if this_t_squared > MIN_T && this_t_squared < t_squared {
-                                // If the distance is less than what we had, update return data
-                                t_squared = this_t_squared;
-                                // let n = offset as usize + PACK_SIZE * n_packs + i as usize;
-                                let n = ini + i;
-                                prim_index = Some(n);
-                                ray.interaction.geometry_shading = intersect_info;
+            let node = &self.wide_nodes[current_node];
+            let t_near = node.intersect_children(&origin, &inv_dir, t_max);
+            let lanes = sort_lanes(&t_near);
+
+            // Check the leaves first, closest first, so that farther
+            // children can be culled
+            for &lane in lanes.iter() {
+                if let WideChild::Leaf {
+                    first_prim,
+                    first_pack,
+                    n_packs,
+                } = node.children[lane]
+                {
+                    if t_near[lane] >= t_max {
+                        continue;
+                    }
+                    for p in 0..n_packs {
+                        let (ts, us, vs) = triangle_pack_baricentric_coorinates(
+                            &self.packs[first_pack + p],
+                            &sheared_ray,
+                        );
+                        for (i, t) in ts.iter().enumerate() {
+                            if *t < t_max {
+                                t_max = *t;
+                                let index = first_prim + p * PACK_SIZE + i;
+                                closest = Some((index, *t, us[i], vs[i]));
                             }
                         }
-                        // i += 1;
                     }
+                }
+            }
 
-                    /* END OF NON-SIMD */
-
-                    /* SIMD INTEGRATION */
-                    // let mut n_packs = 0; // I need to know how many packs went through
-                    // let mut iterator = this_prims.chunks_exact(PACK_SIZE);
-
-                    // for pack in iterator.by_ref() {
-                    //     if let Some((i, intersect_info)) =
-                    //         triangle_intersect_pack(pack, &ray.geometry)
-                    //     {
-                    //         let this_t_squared =
-                    //             (intersect_info.p - ray.geometry.origin).length_squared();
-                    //         // if the distance is less than the prevous one, update the info
-                    //         if this_t_squared > MIN_T && this_t_squared < t_squared {
-                    //             // If the distance is less than what we had, update return data
-                    //             t_squared = this_t_squared;
-                    //             prim_index =
-                    //                 Some(offset as usize + PACK_SIZE * n_packs + i as usize);
-                    //             ray.interaction.geometry_shading = intersect_info;
-                    //         }
-                    //     }
-                    //     n_packs += 1;
-                    // }
-
-                    // // let mut i = 0;
-                    // let mut iterator = iterator.remainder().iter();
-
-                    // // let mut iterator = this_prims.iter();
-                    // // let n_packs = 0;
-                    // for (i, tri) in iterator.by_ref().enumerate() {
-                    //     if let Some(intersect_info) = triangle_intersect(tri, &ray.geometry) {
-                    //         // If hit, check the distance.
-                    //         let this_t_squared =
-                    //             (intersect_info.p - ray.geometry.origin).length_squared();
-                    //         // if the distance is less than the prevous one, update the info
-                    //         if this_t_squared > MIN_T && this_t_squared < t_squared {
-                    //             // If the distance is less than what we had, update return data
-                    //             t_squared = this_t_squared;
-                    //             let n = offset as usize + PACK_SIZE * n_packs + i as usize;
-                    //             prim_index = Some(n);
-                    //             ray.interaction.geometry_shading = intersect_info;
-                    //         }
-                    //     }
-                    //     // i += 1;
-                    // }
-
-                    /* END OF SIMD */
-
-                    // update node we need to visit next, if any... otherwise, finish
-                    if let Some(i) = nodes_to_visit.pop() {
-                        current_node = i;
-                    } else {
-                        break;
-                    }
-                } else {
-                    // is interior... choose first or second child,
-                    // add to the stack
-                    let is_neg = match node.axis {
-                        BBoxAxis::X => dir_is_neg.0,
-                        BBoxAxis::Y => dir_is_neg.1,
-                        BBoxAxis::Z => dir_is_neg.2,
-                    };
-                    if is_neg {
-                        nodes_to_visit.push(current_node + 1);
-                        current_node = node.next as usize;
-                    } else {
-                        nodes_to_visit.push(node.next as usize);
-                        current_node += 1;
+            // Then, add the interior children to the stack, farthest first
+            for &lane in lanes.iter().rev() {
+                if let WideChild::Interior(i) = node.children[lane] {
+                    if t_near[lane] < t_max {
+                        nodes_to_visit.push(i);
                     }
                 }
-            } else if let Some(i) = nodes_to_visit.pop() {
+            }
+
+            // update node we need to visit next, if any... otherwise, finish
+            if let Some(i) = nodes_to_visit.pop() {
                 current_node = i;
             } else {
                 break;
@@ -617,122 +928,74 @@ impl BoundingVolumeTree {
         } // End loop
 
         // return
-        if let Some(_index) = prim_index {
-            let t = t_squared.sqrt();
-
-            ray.interaction.point = ray.geometry.project(t);
-            ray.interaction.wo = ray.geometry.direction * -1.;
-            // ray.interaction.prim_index = index;
-        }
-        prim_index
+        let (index, _, u, v) = closest?;
+        let (point, perror) = triangle_point(&primitives[index], u, v);
+        ray.interaction.geometry_shading = new_info(&primitives[index], point, u, v, direction);
+        ray.interaction.point = point;
+        ray.interaction.perror = perror;
+        ray.interaction.wo = direction * -1.;
+        Some(index)
     }
 
     /// Checks if a ray can travel a certain distance without hitting anything    
     pub fn unobstructed_distance(
         &self,
-        primitives: &[Triangle],
+        _primitives: &[Triangle],
         ray: &Ray3D,
         distance_squared: Float,
         nodes_to_visit: &mut Vec<usize>,
     ) -> bool {
-        if self.nodes.is_empty() {
+        if self.wide_nodes.is_empty() {
             return true;
         }
         // reset
         nodes_to_visit.truncate(0);
-        // let d_squared = distance * distance;
-        const MIN_T: Float = 0.000001;
+        // Hits this close to the end of the ray are the target itself
+        // (e.g., the light source a shadow ray was sent to)
+        const END_TOLERANCE: Float = 0.000001;
 
-        let inv_dir = Vector3D::new(
+        let origin = [ray.origin.x, ray.origin.y, ray.origin.z];
+        let inv_dir = [
             1. / ray.direction.x,
             1. / ray.direction.y,
             1. / ray.direction.z,
-        );
-        let dir_is_neg = (inv_dir.x < 0., inv_dir.y < 0., inv_dir.z < 0.);
+        ];
+        let sheared_ray = ShearedRay::new(ray);
+        let dir_length_squared = ray.direction.length_squared();
+        let t_max = (distance_squared / dir_length_squared).sqrt() * ROBUST_FACTOR;
         let mut current_node = 0;
 
         loop {
-            let node = &self.nodes[current_node];
-            if node.bounds.intersect(ray, &inv_dir) {
-                if node.is_leaf() {
-                    let offset = node.next;
-
-                    // Check all the objects in this Node
-                    let ini = offset as usize;
-                    let fin = ini + node.n_prims as usize;
-                    let this_prims: &[Triangle] = &primitives[ini..fin];
-
-                    /* NON_SIMD */
-                    for tri in this_prims.iter() {
-                        if let Some(p) = simple_triangle_intersect(tri, ray) {
-                            // If hit, check the distance.
-                            let this_t_squared = (p - ray.origin).length_squared();
-
-                            if this_t_squared > MIN_T
-                                && this_t_squared + MIN_T < distance_squared
-                                && (distance_squared - this_t_squared).abs() > 0.0001
-                            {
-                                return false;
+            let node = &self.wide_nodes[current_node];
+            let t_near = node.intersect_children(&origin, &inv_dir, t_max);
+            for (lane, child) in node.children.iter().enumerate() {
+                if t_near[lane].is_infinite() {
+                    continue;
+                }
+                match child {
+                    WideChild::Empty => {}
+                    WideChild::Interior(i) => nodes_to_visit.push(*i),
+                    WideChild::Leaf {
+                        first_pack,
+                        n_packs,
+                        ..
+                    } => {
+                        for pack in &self.packs[*first_pack..*first_pack + *n_packs] {
+                            let (ts, ..) = triangle_pack_baricentric_coorinates(pack, &sheared_ray);
+                            for t in ts.iter().filter(|t| t.is_finite()) {
+                                let this_t_squared = t * t * dir_length_squared;
+                                if this_t_squared + END_TOLERANCE < distance_squared
+                                    && (distance_squared - this_t_squared).abs() > 0.0001
+                                {
+                                    return false;
+                                }
                             }
                         }
-                        // i += 1;
-                    }
-
-                    /* SIMD */
-                    // const PACK_SIZE: usize = 4;
-                    // let mut iterator = this_prims.chunks_exact(PACK_SIZE);
-
-                    // for pack in iterator.by_ref() {
-                    //     if let Some((_i, p)) = simple_triangle_intersect_pack(pack, ray) {
-                    //         let this_t_squared = (p - ray.origin).length_squared();
-
-                    //         // Is it a valid hit and it is earlier than the rest?
-                    //         if this_t_squared > MIN_T
-                    //             && this_t_squared + MIN_T < distance_squared
-                    //             && (distance_squared - this_t_squared).abs() > 0.0001
-                    //         {
-                    //             return false;
-                    //         }
-                    //     }
-                    // }
-
-                    // let iterator = iterator.remainder().iter();
-                    // for tri in iterator {
-                    //     if let Some(p) = simple_triangle_intersect(tri, ray) {
-                    //         // If hit, check the distance.
-                    //         let this_t_squared = (p - ray.origin).length_squared();
-
-                    //         // Is it a valid hit and it is earlier than the rest?
-                    //         if this_t_squared > MIN_T
-                    //             && this_t_squared + MIN_T < distance_squared
-                    //             && (distance_squared - this_t_squared).abs() > 0.0001
-                    //         {
-                    //             return false;
-                    //         }
-                    //     }
-                    // }
-                    /* END OF SIMD */
-
-                    if let Some(i) = nodes_to_visit.pop() {
-                        current_node = i;
-                    } else {
-                        break;
-                    }
-                } else {
-                    let is_neg = match node.axis {
-                        BBoxAxis::X => dir_is_neg.0,
-                        BBoxAxis::Y => dir_is_neg.1,
-                        BBoxAxis::Z => dir_is_neg.2,
-                    };
-                    if is_neg {
-                        nodes_to_visit.push(current_node + 1);
-                        current_node = node.next as usize;
-                    } else {
-                        nodes_to_visit.push(node.next as usize);
-                        current_node += 1;
                     }
                 }
-            } else if let Some(i) = nodes_to_visit.pop() {
+            }
+
+            if let Some(i) = nodes_to_visit.pop() {
                 current_node = i;
             } else {
                 break;
@@ -750,7 +1013,7 @@ mod tests {
     use crate::colour::Spectrum;
     use crate::material::Material;
     use crate::material::Plastic;
-    use geometry3d::{Point3D, Ray3D, Sphere3D};
+    use geometry3d::{Point3D, Ray3D, Sphere3D, Vector3D};
 
     use crate::primitive::Primitive;
 
@@ -765,6 +1028,30 @@ mod tests {
             .is_none());
     }
 
+    #[test]
+    fn test_read_corrupt_cache() {
+        let triangles: Vec<Triangle> = vec![[0., 0., 0., 1., 0., 0., 0., 1., 0.]; 2];
+        // Reads a tree made of a single node
+        let read = |n_prims: u32, next: u32| {
+            let mut w = CacheWriter::default();
+            w.usize(1);
+            w.bbox(&world_bounds(&triangles[0]));
+            w.u32(n_prims);
+            w.axis(BBoxAxis::X);
+            w.u32(next);
+            w.float(1.);
+            BoundingVolumeTree::read_cache(&mut CacheReader::new(&w.data), &triangles)
+        };
+        assert!(read(2, 0).is_ok());
+        // More primitives than fit in a node, or than there are
+        assert!(read(65537, 0).is_err());
+        assert!(read(2, 1).is_err());
+        // An interior node whose children are not in the tree
+        assert!(read(0, 3).is_err());
+        // An offset that does not fit in a node
+        assert!(read(1, u32::MAX).is_err());
+    }
+
     /// A simple scene with two 0.5-r-spheres; one at x = -1 and the other
     /// at x = 1. This should lead to three nodes:
     /// * The main one, being an interior node (e.g., n_prims == 0)
@@ -928,4 +1215,158 @@ mod tests {
 
         assert!((ray.interaction.point - Point3D::new(0., -0.5, 1.)).length() < 1e-9);
     }
+
+    #[test]
+    fn test_refit() {
+        let mut scene = get_horizontal_scene();
+        let mut bvh = BoundingVolumeTree::new(&mut scene);
+
+        // Nothing moved
+        assert!((bvh.refit(&scene.triangles) - 1.).abs() < 1e-9);
+
+        // Move everything up. The tree is just as good.
+        for t in scene.triangles.iter_mut() {
+            for z in t.iter_mut().skip(2).step_by(3) {
+                *z += 2.;
+            }
+        }
+        assert!((bvh.refit(&scene.triangles) - 1.).abs() < 1e-6);
+
+        let mut ray = Ray {
+            geometry: Ray3D {
+                origin: Point3D::new(-1., -10., 2.),
+                direction: Vector3D::new(0., 1., 0.),
+            },
+            ..Ray::default()
+        };
+        let mut aux = Vec::new();
+        assert!(bvh
+            .intersect(&scene.triangles, &mut ray, &mut aux)
+            .is_some());
+        assert!((ray.interaction.point - Point3D::new(-1., -0.5, 2.)).length() < 1e-6);
+
+        // Nothing is left where the spheres were
+        let mut ray = Ray {
+            geometry: Ray3D {
+                origin: Point3D::new(-1., -10., 0.),
+                direction: Vector3D::new(0., 1., 0.),
+            },
+            ..Ray::default()
+        };
+        assert!(bvh
+            .intersect(&scene.triangles, &mut ray, &mut aux)
+            .is_none());
+    }
+
+    /// Casts random rays against a cloud of random triangles, comparing
+    /// the results of the tree with those of testing every triangle
+    #[test]
+    fn test_against_brute_force() {
+        use crate::rand::{get_seeded_rng, Rng};
+        use geometry3d::Triangle3D;
+
+        let mut rng = get_seeded_rng(0, 0);
+        let mut scene = Scene::new();
+        let plastic = scene.push_material(Material::Plastic(Plastic {
+            colour: Spectrum::<{ crate::N_CHANNELS }>::from(0.5),
+            specularity: 0.,
+            roughness: 0.,
+        }));
+        let random_point = |rng: &mut crate::rand::RandGen| {
+            let (x, y, z): (Float, Float, Float) = rng.gen();
+            Point3D::new(10. * x, 10. * y, 10. * z)
+        };
+        while scene.triangles.len() < 1000 {
+            let a = random_point(&mut rng);
+            let b = a + (random_point(&mut rng) - Point3D::new(5., 5., 5.)) * 0.2;
+            let c = a + (random_point(&mut rng) - Point3D::new(5., 5., 5.)) * 0.2;
+            if let Ok(tri) = Triangle3D::new(a, b, c) {
+                scene.push_object(plastic, plastic, Primitive::Triangle(tri));
+            }
+        }
+        let bvh = BoundingVolumeTree::new(&mut scene);
+        let mut aux = Vec::new();
+
+        for _ in 0..2000 {
+            let origin = random_point(&mut rng);
+            let direction = (random_point(&mut rng) - Point3D::new(5., 5., 5.)).get_normalized();
+            let geometry = Ray3D { origin, direction };
+
+            // Brute force
+            let mut expected: Option<(usize, Float)> = None;
+            for (i, tri) in scene.triangles.iter().enumerate() {
+                if let Some(info) = triangle_intersect(tri, &geometry) {
+                    let t2 = (info.p - origin).length_squared();
+                    if t2 > 1e-7 && expected.map_or(true, |(_, best)| t2 < best) {
+                        expected = Some((i, t2));
+                    }
+                }
+            }
+
+            let mut ray = Ray {
+                geometry,
+                ..Ray::default()
+            };
+            let found = bvh.intersect(&scene.triangles, &mut ray, &mut aux);
+            assert_eq!(found, expected.map(|(i, _)| i));
+            if let Some((_, t2)) = expected {
+                let found_t2 = (ray.interaction.point - origin).length_squared();
+                assert!((found_t2 - t2).abs() < 1e-3);
+
+                // Nothing in between... and the triangle hit is there
+                let d2 = 0.9 * t2;
+                assert!(bvh.unobstructed_distance(&scene.triangles, &geometry, d2, &mut aux));
+                let d2 = 1.1 * t2 + 1e-2;
+                assert!(!bvh.unobstructed_distance(&scene.triangles, &geometry, d2, &mut aux));
+            } else {
+                let d2 = 1e4;
+                assert!(bvh.unobstructed_distance(&scene.triangles, &geometry, d2, &mut aux));
+            }
+        }
+    }
+
+    /// Builds the tree of a large cloud of triangles twice—which, with the
+    /// `parallel` feature, is done in parallel—checking that both trees are
+    /// the same and that the leaves cover every triangle, in order.
+    #[test]
+    fn test_deterministic_build() {
+        use crate::instance::Mesh;
+        use crate::rand::{get_seeded_rng, Rng};
+        use geometry3d::Triangle3D;
+
+        let mut rng = get_seeded_rng(1, 0);
+        let mut mesh = Mesh::new();
+        while mesh.triangles.len() < 20_000 {
+            let (x, y, z): (Float, Float, Float) = rng.gen();
+            let a = Point3D::new(100. * x, 100. * y, 100. * z);
+            let b = a + Vector3D::new(0.5, 0., 0.);
+            let c = a + Vector3D::new(0., 0.5, 0.);
+            if let Ok(tri) = Triangle3D::new(a, b, c) {
+                mesh.push_object(0, 0, Primitive::Triangle(tri));
+            }
+        }
+        let key = |n: &FlatNode| {
+            let (min, max) = (n.bounds.min, n.bounds.max);
+            (n.n_prims, n.next, min.x, min.y, min.z, max.x, max.y, max.z)
+        };
+
+        let (first, first_order) = BoundingVolumeTree::build(&mut mesh.clone());
+        let (second, second_order) = BoundingVolumeTree::build(&mut mesh);
+        assert_eq!(first_order, second_order);
+        assert_eq!(
+            first.nodes.iter().map(key).collect::<Vec<_>>(),
+            second.nodes.iter().map(key).collect::<Vec<_>>()
+        );
+
+        let mut sorted_order = first_order.clone();
+        sorted_order.sort_unstable();
+        assert!(sorted_order.iter().enumerate().all(|(i, j)| i == *j));
+
+        let mut next_prim = 0;
+        for node in first.nodes.iter().filter(|n| n.n_prims > 0) {
+            assert_eq!(node.next as usize, next_prim);
+            next_prim += node.n_prims as usize;
+        }
+        assert_eq!(next_prim, mesh.triangles.len());
+    }
 }
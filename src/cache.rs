@@ -0,0 +1,423 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! A binary format for storing compiled scenes (see `Scene::save_cache`), so that
+//! big models do not need to be read, triangulated and put into an acceleration
+//! structure every time they are used (like Radiance's octrees).
+//!
+//! Files start with a header containing the version of the format, the
+//! kind of numbers used, and a hash of the model the scene was created from.
+//! Caches whose header does not match are rejected. All numbers are little endian.
+
+use crate::colour::Spectrum;
+use crate::material::{Dielectric, Glass, Light, Material, Metal, Mirror, Plastic};
+use crate::primitive::Primitive;
+use crate::Float;
+use geometry3d::{BBox3D, BBoxAxis, DistantSource3D, Point3D, Sphere3D, Triangle3D, Vector3D};
+use std::path::Path;
+
+/// The version of the format. It needs to change every time
+/// the content of the files changes.
+pub const CACHE_VERSION: u32 = 1;
+
+/// The first bytes of every cache file
+const MAGIC: &[u8; 8] = b"RNDRSCN\0";
+
+/// Hashes the content of some files (e.g., the model a scene is created
+/// from), so that caches can be checked against them. This uses the
+/// FNV-1a algorithm, which gives the same results in every platform.
+pub fn hash_files(files: &[&Path]) -> Result<u64, String> {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for file in files {
+        let content = std::fs::read(file)
+            .map_err(|e| format!("Could not read file '{}': {}", file.display(), e))?;
+        for byte in content {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    Ok(hash)
+}
+
+/// Writes values into a cache
+#[derive(Default)]
+pub(crate) struct CacheWriter {
+    pub data: Vec<u8>,
+}
+
+impl CacheWriter {
+    /// Writes the header of the file
+    pub fn header(&mut self, source_hash: u64) {
+        self.data.extend_from_slice(MAGIC);
+        self.u32(CACHE_VERSION);
+        self.u8(std::mem::size_of::<Float>() as u8);
+        self.u32(crate::N_CHANNELS as u32);
+        self.u64(source_hash);
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.data.push(v)
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.data.extend_from_slice(&v.to_le_bytes())
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.data.extend_from_slice(&v.to_le_bytes())
+    }
+
+    pub fn usize(&mut self, v: usize) {
+        self.u64(v as u64)
+    }
+
+    pub fn float(&mut self, v: Float) {
+        self.data.extend_from_slice(&v.to_le_bytes())
+    }
+
+    pub fn floats(&mut self, v: &[Float]) {
+        for x in v {
+            self.float(*x)
+        }
+    }
+
+    /// Writes the length of a slice of `usize`, followed by its elements
+    pub fn usizes(&mut self, v: &[usize]) {
+        self.usize(v.len());
+        for x in v {
+            self.usize(*x)
+        }
+    }
+
+    pub fn point(&mut self, p: Point3D) {
+        self.floats(&[p.x, p.y, p.z])
+    }
+
+    pub fn vector(&mut self, v: Vector3D) {
+        self.floats(&[v.x, v.y, v.z])
+    }
+
+    pub fn spectrum(&mut self, s: &Spectrum<{ crate::N_CHANNELS }>) {
+        self.floats(&s.0)
+    }
+
+    pub fn bbox(&mut self, b: &BBox3D) {
+        self.point(b.min);
+        self.point(b.max);
+    }
+
+    pub fn axis(&mut self, axis: BBoxAxis) {
+        self.u8(match axis {
+            BBoxAxis::X => 0,
+            BBoxAxis::Y => 1,
+            BBoxAxis::Z => 2,
+        })
+    }
+
+    pub fn material(&mut self, material: &Material) {
+        match material {
+            Material::Plastic(m) => {
+                self.u8(0);
+                self.spectrum(&m.colour);
+                self.floats(&[m.specularity, m.roughness]);
+            }
+            Material::Metal(m) => {
+                self.u8(1);
+                self.spectrum(&m.colour);
+                self.floats(&[m.specularity, m.roughness]);
+            }
+            Material::Light(m) => {
+                self.u8(2);
+                self.spectrum(&m.0);
+            }
+            Material::Mirror(m) => {
+                self.u8(3);
+                self.spectrum(&m.0);
+            }
+            Material::Dielectric(m) => {
+                self.u8(4);
+                self.spectrum(&m.colour);
+                self.float(m.refraction_index);
+            }
+            Material::Glass(m) => {
+                self.u8(5);
+                self.spectrum(&m.colour);
+                self.float(m.refraction_index);
+            }
+        }
+    }
+
+    pub fn primitive(&mut self, primitive: &Primitive) -> Result<(), String> {
+        match primitive {
+            Primitive::Sphere(s) => {
+                self.u8(0);
+                self.float(s.radius);
+                self.point(s.centre());
+            }
+            Primitive::Triangle(t) => {
+                self.u8(1);
+                self.point(t.a());
+                self.point(t.b());
+                self.point(t.c());
+            }
+            Primitive::Source(s) => {
+                self.u8(2);
+                self.vector(s.direction);
+                self.float(s.angle);
+            }
+            _ => {
+                return Err(format!(
+                    "Primitive '{}' cannot be stored in a cache",
+                    primitive.id()
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the values written by a [`CacheWriter`]
+pub(crate) struct CacheReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> CacheReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    /// Reads the header of the file, checking that it can be
+    /// read and that it was created from the right model
+    pub fn header(&mut self, source_hash: u64) -> Result<(), String> {
+        if self.bytes(MAGIC.len())? != MAGIC {
+            return Err("This is not a scene cache".into());
+        }
+        let version = self.u32()?;
+        if version != CACHE_VERSION {
+            return Err(format!(
+                "The cache was written in version {} of the format, but version {} is needed",
+                version, CACHE_VERSION
+            ));
+        }
+        let float_size = self.u8()? as usize;
+        if float_size != std::mem::size_of::<Float>() {
+            return Err(format!(
+                "The cache was written with {}-byte numbers, but {}-byte numbers are being used",
+                float_size,
+                std::mem::size_of::<Float>()
+            ));
+        }
+        let n_channels = self.u32()? as usize;
+        if n_channels != crate::N_CHANNELS {
+            return Err(format!(
+                "The cache was written with {} colour channels, but {} are being used",
+                n_channels,
+                crate::N_CHANNELS
+            ));
+        }
+        if self.u64()? != source_hash {
+            return Err("The cache was created from a different model".into());
+        }
+        Ok(())
+    }
+
+    /// Checks that the whole file was read
+    pub fn finish(&self) -> Result<(), String> {
+        if self.position == self.data.len() {
+            Ok(())
+        } else {
+            Err("Unexpected data at the end of the cache".into())
+        }
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let end = self.position + n;
+        if end > self.data.len() {
+            return Err("Unexpected end of the cache".into());
+        }
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        let bytes = self.bytes(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, String> {
+        let v = self.u64()?;
+        usize::try_from(v).map_err(|e| e.to_string())
+    }
+
+    /// Reads a length, checking that there are at least `item_size` bytes
+    /// left for each of the items, so that corrupt files do not lead to
+    /// huge allocations
+    pub fn count(&mut self, item_size: usize) -> Result<usize, String> {
+        let n = self.usize()?;
+        if n.saturating_mul(item_size) > self.data.len() - self.position {
+            return Err("Unexpected end of the cache".into());
+        }
+        Ok(n)
+    }
+
+    pub fn float(&mut self) -> Result<Float, String> {
+        let bytes = self.bytes(std::mem::size_of::<Float>())?;
+        Ok(Float::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn usizes(&mut self) -> Result<Vec<usize>, String> {
+        let n = self.count(8)?;
+        (0..n).map(|_| self.usize()).collect()
+    }
+
+    pub fn point(&mut self) -> Result<Point3D, String> {
+        Ok(Point3D::new(self.float()?, self.float()?, self.float()?))
+    }
+
+    pub fn vector(&mut self) -> Result<Vector3D, String> {
+        Ok(Vector3D::new(self.float()?, self.float()?, self.float()?))
+    }
+
+    pub fn spectrum(&mut self) -> Result<Spectrum<{ crate::N_CHANNELS }>, String> {
+        let mut s = Spectrum::<{ crate::N_CHANNELS }>::BLACK;
+        for v in s.0.iter_mut() {
+            *v = self.float()?;
+        }
+        Ok(s)
+    }
+
+    pub fn bbox(&mut self) -> Result<BBox3D, String> {
+        let min = self.point()?;
+        let max = self.point()?;
+        Ok(BBox3D::from_union_point(&BBox3D::from_point(min), max))
+    }
+
+    pub fn axis(&mut self) -> Result<BBoxAxis, String> {
+        match self.u8()? {
+            0 => Ok(BBoxAxis::X),
+            1 => Ok(BBoxAxis::Y),
+            2 => Ok(BBoxAxis::Z),
+            v => Err(format!("Unknown axis {} in cache", v)),
+        }
+    }
+
+    pub fn material(&mut self) -> Result<Material, String> {
+        let material = match self.u8()? {
+            0 => Material::Plastic(Plastic {
+                colour: self.spectrum()?,
+                specularity: self.float()?,
+                roughness: self.float()?,
+            }),
+            1 => Material::Metal(Metal {
+                colour: self.spectrum()?,
+                specularity: self.float()?,
+                roughness: self.float()?,
+            }),
+            2 => Material::Light(Light(self.spectrum()?)),
+            3 => Material::Mirror(Mirror(self.spectrum()?)),
+            4 => Material::Dielectric(Dielectric {
+                colour: self.spectrum()?,
+                refraction_index: self.float()?,
+            }),
+            5 => Material::Glass(Glass {
+                colour: self.spectrum()?,
+                refraction_index: self.float()?,
+            }),
+            v => return Err(format!("Unknown material {} in cache", v)),
+        };
+        Ok(material)
+    }
+
+    pub fn primitive(&mut self) -> Result<Primitive, String> {
+        let primitive = match self.u8()? {
+            0 => {
+                let radius = self.float()?;
+                Primitive::Sphere(Sphere3D::new(radius, self.point()?))
+            }
+            1 => {
+                let (a, b, c) = (self.point()?, self.point()?, self.point()?);
+                Primitive::Triangle(Triangle3D::new(a, b, c)?)
+            }
+            2 => {
+                let direction = self.vector()?;
+                Primitive::Source(DistantSource3D::new(direction, self.float()?))
+            }
+            v => return Err(format!("Unknown primitive {} in cache", v)),
+        };
+        Ok(primitive)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut w = CacheWriter::default();
+        w.header(42);
+        w.usizes(&[1, 2, 3]);
+        w.float(0.25);
+        w.axis(BBoxAxis::Z);
+        w.material(&Material::Mirror(Mirror(
+            Spectrum::<{ crate::N_CHANNELS }>::gray(0.5),
+        )));
+        w.primitive(&Primitive::Sphere(Sphere3D::new(
+            2.,
+            Point3D::new(1., 2., 3.),
+        )))
+        .unwrap();
+
+        let mut r = CacheReader::new(&w.data);
+        r.header(42).unwrap();
+        assert_eq!(r.usizes().unwrap(), vec![1, 2, 3]);
+        assert_eq!(r.float().unwrap(), 0.25);
+        assert!(matches!(r.axis().unwrap(), BBoxAxis::Z));
+        assert!(matches!(r.material().unwrap(), Material::Mirror(_)));
+        match r.primitive().unwrap() {
+            Primitive::Sphere(s) => {
+                assert_eq!(s.radius, 2.);
+                assert!((s.centre() - Point3D::new(1., 2., 3.)).length() < 1e-9);
+            }
+            _ => panic!("Expecting a sphere"),
+        }
+        r.finish().unwrap();
+
+        // Wrong model
+        let mut r = CacheReader::new(&w.data);
+        assert!(r.header(43).is_err());
+
+        // Truncated
+        let mut r = CacheReader::new(&w.data[..30]);
+        r.header(42).unwrap();
+        assert!(r.usizes().is_err());
+    }
+}
@@ -40,6 +40,9 @@ pub trait Camera: Sync {
     /// Borrows the view
     fn view(&self) -> &View;
 
+    /// The area of the film, placed at a unit distance from the view point
+    fn film_area(&self) -> Float;
+
     fn pixel_index(&self, pxl: (usize, usize)) -> usize {
         let (x, y) = pxl;
         let (width, _height) = self.film_resolution();
@@ -51,9 +51,7 @@ impl Camera for Pinhole {
     fn gen_random_sample(&self, rng: &mut RandGen) -> CameraSample {
         let (width, height) = self.film.resolution;
         let (x, y): (usize, usize) = rng.gen();
-        CameraSample {
-            p_film: (x % width, y % height),
-        }
+        CameraSample::jittered((x % width, y % height), rng)
     }
 
     fn pixel_from_ray(&self, ray: &Ray3D) -> ((usize, usize), Float) {
@@ -89,24 +87,25 @@ impl Camera for Pinhole {
         }
         let z = intersection_pt * self.view.view_up;
 
-        // If it is out of the FOV, return None.
-        if z.abs() > 1. || x.abs() > 1. {
-            return ((0, 0), 0.);
-        }
-
         let (width, height) = self.film.resolution;
         let xlim = 2.;
         let aspect_ratio = height as Float / width as Float;
         let ylim = aspect_ratio * xlim;
+
+        // If it is out of the FOV, return None.
+        if z.abs() > aspect_ratio || x.abs() > 1. {
+            return ((0, 0), 0.);
+        }
+
         let dx = xlim / width as Float;
         let dy = ylim / height as Float;
 
         // Else, calculate:
-        let x = (x + 1.) / dx;
-        let y = (1. - z) / dy;
+        let x = ((x + 1.) / dx).floor() as usize;
+        let y = ((aspect_ratio - z) / dy).floor() as usize;
 
         // return
-        ((x.floor() as usize, y.floor() as usize), 1.)
+        ((x.min(width - 1), y.min(height - 1)), 1.)
     }
 
     /// Generates a ray that will go through the View Point and a
@@ -121,8 +120,9 @@ impl Camera for Pinhole {
         let dx = xlim / width as Float;
         let dy = ylim / height as Float;
 
-        let x = dx / 2. + x_pixel as Float * dx - xlim / 2.;
-        let y = dy / 2. + y_pixel as Float * dy - ylim / 2.;
+        let (x_offset, y_offset) = sample.p_pixel;
+        let x = (x_pixel as Float + x_offset) * dx - xlim / 2.;
+        let y = (y_pixel as Float + y_offset) * dy - ylim / 2.;
 
         let direction =
             self.view.view_direction * self.film_distance + self.u * x - self.view.view_up * y;
@@ -146,6 +146,12 @@ impl Camera for Pinhole {
     fn view(&self) -> &View {
         &self.view
     }
+
+    fn film_area(&self) -> Float {
+        let (width, height) = self.film.resolution;
+        let aspect_ratio = height as Float / width as Float;
+        4. * aspect_ratio / (self.film_distance * self.film_distance)
+    }
 }
 
 #[cfg(test)]
@@ -169,10 +175,45 @@ mod tests {
         // Create camera
         let camera = Pinhole::new(view, film);
 
-        let sample = CameraSample { p_film: (10, 20) };
+        let sample = CameraSample::new((10, 20));
         // Let's assume this is right
         let (ray, _weight) = camera.gen_ray(&sample);
         let (found_pixel, _weight) = camera.pixel_from_ray(&ray.geometry);
         assert_eq!(sample.p_film, found_pixel);
     }
+
+    #[test]
+    fn test_ray_pixel_non_square() {
+        let film = Film {
+            resolution: (300, 200),
+        };
+        let view = View {
+            view_direction: Vector3D::new(0., 1., 0.),
+            view_point: Point3D::new(2., 1., 1.),
+            ..View::default()
+        };
+        let camera = Pinhole::new(view, film);
+
+        for p_film in [(0, 0), (299, 199), (250, 150), (10, 190)] {
+            let sample = CameraSample::new(p_film);
+            let (ray, _weight) = camera.gen_ray(&sample);
+            let (found_pixel, weight) = camera.pixel_from_ray(&ray.geometry);
+            assert!(weight > 0.);
+            assert_eq!(sample.p_film, found_pixel);
+        }
+
+        // Out of the film, vertically
+        let ray = Ray3D {
+            origin: Point3D::new(2., 1., 1.),
+            direction: Vector3D::new(0., 1., 0.5).get_normalized(),
+        };
+        let (_, weight) = camera.pixel_from_ray(&ray);
+        assert!(weight < 1e-9);
+
+        // With a 60 degrees field of view, the film is 2/tan(30) wide
+        // and 2/3 of that high, at a unit distance.
+        let w = 2. * (30. as Float).to_radians().tan();
+        let expected = w * w * 2. / 3.;
+        assert!((camera.film_area() - expected).abs() < 1e-9);
+    }
 }
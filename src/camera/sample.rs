@@ -18,12 +18,37 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
+use crate::rand::*;
+use crate::Float;
+
 /// Used for getting a sample ray from the [`Camera`]
 pub struct CameraSample {
     /// The position (x,y) within the [`Film`]
     pub p_film: (usize, usize),
+
+    /// The position within the pixel, from `(0, 0)` to `(1, 1)`.
+    pub p_pixel: (Float, Float),
     // /// The position within the Lens of the camera
     // pub p_lens: (Float, Float),
     // /// Time at which the ray will be emmited
     // pub time: Float,
 }
+
+impl CameraSample {
+    /// A sample at the centre of the pixel `p_film`
+    pub fn new(p_film: (usize, usize)) -> Self {
+        Self {
+            p_film,
+            p_pixel: (0.5, 0.5),
+        }
+    }
+
+    /// A sample at a position within the pixel `p_film` given by the
+    /// next two dimensions of the `rng`
+    pub fn jittered(p_film: (usize, usize), rng: &mut RandGen) -> Self {
+        Self {
+            p_film,
+            p_pixel: rng.gen(),
+        }
+    }
+}
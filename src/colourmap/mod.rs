@@ -28,6 +28,7 @@ pub mod viridis;
 use crate::Float;
 
 /// The options of Colourmap to choose from when falsecolouring an image.
+#[derive(Clone, Copy, Debug)]
 pub enum Colourmap {
     /// Emulates the default Radiance's Colourmap.
     Radiance,
@@ -50,6 +51,19 @@ pub enum Colourmap {
     Viridis,
 }
 
+impl Colourmap {
+    /// Gets the list of colours that make up this `Colourmap`
+    pub fn colours(&self) -> &'static [[Float; 3]] {
+        match self {
+            Self::Inferno => &inferno::INFERNO_COLOURMAP,
+            Self::Magma => &magma::MAGMA_COLOURMAP,
+            Self::Plasma => &plasma::PLASMA_COLOURMAP,
+            Self::Radiance => &radiance::RADIANCE_COLOURMAP,
+            Self::Viridis => &viridis::VIRIDIS_COLOURMAP,
+        }
+    }
+}
+
 /// Maps a linear RGB colour
 pub fn map_linear_colour(
     x: Float,
@@ -20,6 +20,9 @@ SOFTWARE.
 
 use crate::colour::Spectrum;
 use crate::colour_matrix::ColourMatrix;
+use crate::environment_map::EnvironmentMap;
+use crate::photon_map::PhotonMap;
+use crate::progress::{RenderControl, RenderStats};
 use crate::rand::*;
 use crate::ray::Ray;
 use crate::ray_tracer::RayTracerHelper;
@@ -30,6 +33,9 @@ use geometry3d::intersection::SurfaceSide;
 use geometry3d::Vector3D;
 use geometry3d::{Point3D, Ray3D};
 use solar::ReinhartSky;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
 #[cfg(feature = "parallel")]
 use rayon::prelude::*;
@@ -43,6 +49,19 @@ pub struct DCFactory {
     pub limit_weight: Float,
     pub count_specular_bounce: Float,
     // pub limit_reflections: usize,
+    /// A photon map built with [`PhotonMapper::build_dc`](crate::photon_map::PhotonMapper::build_dc)
+    /// and the same `reinhart` sky. If given, rays end at the first Lambertian
+    /// surface they hit, where the contribution of each sky patch is
+    /// estimated from the photons.
+    pub photon_map: Option<Arc<PhotonMap>>,
+
+    /// The seed of the random numbers. If given, each sensor and each of
+    /// its ambient rays draw their random numbers from their own stream, so
+    /// results are reproducible regardless of the number of threads.
+    pub seed: Option<u64>,
+
+    /// The kind of sequence the ambient rays of each sensor are drawn from
+    pub sampler: SamplerKind,
 }
 
 impl Default for DCFactory {
@@ -55,17 +74,67 @@ impl Default for DCFactory {
 
             limit_weight: 1e-4,
             // limit_reflections: 0,
+            photon_map: None,
+            seed: None,
+            sampler: SamplerKind::default(),
         }
     }
 }
 
 impl DCFactory {
+    /// Calculates the daylight coefficients of the sensors represented
+    /// by `rays`, without reporting progress
     pub fn calc_dc(&self, rays: &[Ray3D], scene: &Scene) -> ColourMatrix {
+        let (dc, _stats) = self.calc_dc_with_control(rays, scene, &RenderControl::default());
+        dc
+    }
+
+    /// Multiplies the daylight coefficients of some sensors (i.e., a matrix with
+    /// one row per sensor and one column per patch of the `reinhart` sky) by
+    /// the radiance of each patch in an `environment`, returning the light
+    /// received by each sensor as a column.
+    pub fn apply_environment(
+        &self,
+        dc: &ColourMatrix,
+        environment: &EnvironmentMap,
+    ) -> Result<ColourMatrix, String> {
+        let (n_sensors, n_bins) = dc.size();
+        if n_bins != self.reinhart.n_bins {
+            return Err(format!(
+                "Expecting daylight coefficients for {} sky patches... found {}",
+                self.reinhart.n_bins, n_bins
+            ));
+        }
+        let sky = environment.reinhart_vector(&self.reinhart);
+        let mut ret = ColourMatrix::new(Spectrum::<{ crate::N_CHANNELS }>::BLACK, n_sensors, 1);
+        for sensor in 0..n_sensors {
+            let mut v = Spectrum::<{ crate::N_CHANNELS }>::BLACK;
+            for bin in 0..n_bins {
+                v += dc.get(sensor, bin).unwrap() * sky.get(bin, 0).unwrap();
+            }
+            ret.set(sensor, 0, v).unwrap();
+        }
+        Ok(ret)
+    }
+
+    /// Calculates the daylight coefficients of the sensors represented by
+    /// `rays`, reporting the number of sensors done to the `control` and
+    /// checking whether it has been cancelled before each sensor. The rows of
+    /// the sensors skipped after cancelling are left black.
+    pub fn calc_dc_with_control(
+        &self,
+        rays: &[Ray3D],
+        scene: &Scene,
+        control: &RenderControl,
+    ) -> (ColourMatrix, RenderStats) {
         // Initialize matrix
         let n_bins = self.reinhart.n_bins;
 
-        let counter = std::sync::Arc::new(std::sync::Mutex::new(0));
-        let last_progress = std::sync::Arc::new(std::sync::Mutex::new(0.0));
+        let now = Instant::now();
+        let counter = AtomicUsize::new(0);
+        let skipped = AtomicBool::new(false);
+        // All the sensors share the scrambling of the sequences
+        let seed = self.seed.unwrap_or_else(|| get_rng().gen());
 
         // Process... This can be in parallel, or not.
         #[cfg(not(feature = "parallel"))]
@@ -74,7 +143,12 @@ impl DCFactory {
         let aux_iter = rays.par_iter();
         // Iterate the rays
         let dcs: Vec<ColourMatrix> = aux_iter
-            .map(|primary_ray| -> ColourMatrix {
+            .enumerate()
+            .map(|(sensor_index, primary_ray)| -> ColourMatrix {
+                if control.is_cancelled() {
+                    skipped.store(true, Ordering::Relaxed);
+                    return ColourMatrix::new(Spectrum::<{ crate::N_CHANNELS }>::BLACK, 1, n_bins);
+                }
                 let normal = primary_ray.direction;
                 let origin = primary_ray.origin;
                 let e2 = normal.get_perpendicular().unwrap();
@@ -82,11 +156,22 @@ impl DCFactory {
 
                 // Run each spawned ray in parallel or series, depending on
                 // the compilation options
-                let mut rng = get_rng();
+                // Sensors use the streams `0..rays.len()`, and their ambient
+                // rays use the ones after those. The direction of each
+                // ambient ray takes the first two dimensions of its sample.
+                let mut rng = get_sampler(
+                    self.sampler,
+                    self.n_ambient_samples,
+                    seed,
+                    sensor_index as u64,
+                );
                 #[allow(clippy::needless_collect)]
                 let aux_iter: Vec<Vector3D> = (0..self.n_ambient_samples)
                     .into_iter()
-                    .map(|_| sample_cosine_weighted_horizontal_hemisphere(&mut rng))
+                    .map(|i| {
+                        rng.start_pixel_sample(sensor_index as u64, i as u64);
+                        sample_cosine_weighted_horizontal_hemisphere(&mut rng)
+                    })
                     .collect();
 
                 #[cfg(not(feature = "parallel"))]
@@ -97,7 +182,8 @@ impl DCFactory {
 
                 // Iterate primary rays
                 let ray_contributions: Vec<ColourMatrix> = aux_iter
-                    .map(|local_ray_dir: Vector3D| -> ColourMatrix {
+                    .enumerate()
+                    .map(|(ray_index, local_ray_dir)| -> ColourMatrix {
                         let (x, y, z) = crate::samplers::local_to_world(
                             e1,
                             e2,
@@ -129,7 +215,11 @@ impl DCFactory {
                             ..Ray::default()
                         };
 
-                        let mut rng = get_rng();
+                        let stream = rays.len() + sensor_index * self.n_ambient_samples + ray_index;
+                        let mut rng =
+                            get_sampler(self.sampler, self.n_ambient_samples, seed, stream as u64);
+                        rng.start_pixel_sample(sensor_index as u64, ray_index as u64);
+                        rng.skip_dimensions(2);
                         // let current_weight = cos_theta;
                         self.trace_ray(
                             scene,
@@ -139,16 +229,6 @@ impl DCFactory {
                             &mut aux,
                         );
 
-                        let mut c = counter.lock().unwrap();
-                        *c += 1;
-                        let nrays = rays.len() * self.n_ambient_samples;
-                        let mut lp = last_progress.lock().unwrap();
-                        let progress = (100. * *c as Float / nrays as Float).round() as Float;
-                        if (*lp - progress.floor()) < 0.1 && (progress - *lp).abs() > 1. {
-                            *lp = progress;
-                            println!("... Done {:.0}%", progress);
-                        }
-
                         this_ret
                     })
                     .collect(); // End of iterating primary rays
@@ -158,6 +238,9 @@ impl DCFactory {
                 ray_contributions.iter().for_each(|v| {
                     ret += v;
                 });
+
+                let done = counter.fetch_add(1, Ordering::Relaxed) + 1;
+                control.report(done, rays.len());
                 ret
                 // ray_contributions.iter().sum();
             })
@@ -174,7 +257,12 @@ impl DCFactory {
             }
         }
 
-        ret
+        let stats = RenderStats {
+            elapsed: now.elapsed(),
+            passes: 1,
+            cancelled: skipped.into_inner(),
+        };
+        (ret, stats)
     }
 
     /// Recursively traces a ray until it excedes the `max_depth` of the
@@ -196,10 +284,8 @@ impl DCFactory {
             }
             // NEARLY copied... except from the return statement
             let material = match ray.interaction.geometry_shading.side {
-                SurfaceSide::Front => {
-                    &scene.materials[scene.front_material_indexes[triangle_index]]
-                }
-                SurfaceSide::Back => &scene.materials[scene.back_material_indexes[triangle_index]],
+                SurfaceSide::Front => &scene.materials[scene.front_material_index(triangle_index)],
+                SurfaceSide::Back => &scene.materials[scene.back_material_index(triangle_index)],
                 SurfaceSide::NonApplicable => {
                     // Hit parallel to the surface...
                     return;
@@ -213,7 +299,7 @@ impl DCFactory {
 
             let (intersection_pt, normal, ..) = ray.get_triad();
             ray.interaction
-                .interpolate_normal(scene.normals[triangle_index]);
+                .interpolate_normal(scene.normals_at(triangle_index));
 
             // Handle specular materials... we have 1 or 2 rays... spawn those.
             if material.specular_only() {
@@ -239,6 +325,30 @@ impl DCFactory {
                 return;
             }
 
+            // The photons know how much light arrives from each patch
+            if let (Some(map), Some(reflectance)) =
+                (&self.photon_map, material.lambertian_reflectance())
+            {
+                let (point, normal, ..) = ray.get_triad();
+                let n_bins = self.reinhart.n_bins;
+                let irradiance = map.irradiance_by_patch(point, normal, n_bins);
+                for (bin_n, e) in irradiance.iter().enumerate() {
+                    if e.is_black() {
+                        continue;
+                    }
+                    let li = reflectance * *e / crate::PI;
+                    let old_value = contribution.get(0, bin_n).unwrap();
+                    contribution
+                        .set(
+                            0,
+                            bin_n,
+                            old_value + li * ray.colour / self.n_ambient_samples as Float,
+                        )
+                        .unwrap();
+                }
+                return;
+            }
+
             let n_ambient_samples = ray.get_n_ambient_samples(
                 self.n_ambient_samples,
                 self.max_depth,
@@ -301,4 +411,45 @@ impl DCFactory {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+    use crate::environment_map::EnvironmentProjection;
+    use crate::image::ImageBuffer;
+
+    #[test]
+    fn test_apply_environment() {
+        // A uniform sky of radiance 2 adds up the coefficients of each sensor
+        let mut image = ImageBuffer::new(32, 16);
+        for pixel in image.pixels.iter_mut() {
+            *pixel = Spectrum::<{ crate::N_CHANNELS }>::gray(2.);
+        }
+        let map = EnvironmentMap::new(image, EnvironmentProjection::LatLong).unwrap();
+        let factory = DCFactory::default();
+        let n_bins = factory.reinhart.n_bins;
+        let mut dc = ColourMatrix::new(Spectrum::<{ crate::N_CHANNELS }>::BLACK, 2, n_bins);
+        for bin in 0..n_bins {
+            let v = Spectrum::<{ crate::N_CHANNELS }>::gray(bin as Float);
+            dc.set(0, bin, Spectrum::<{ crate::N_CHANNELS }>::gray(0.1))
+                .unwrap();
+            dc.set(1, bin, v).unwrap();
+        }
+
+        let light = factory.apply_environment(&dc, &map).unwrap();
+        assert_eq!(light.size(), (2, 1));
+        let expected = [0.2 * n_bins as Float, (n_bins * (n_bins - 1)) as Float];
+        for (sensor, exp) in expected.iter().enumerate() {
+            let found = light.get(sensor, 0).unwrap().radiance();
+            assert!(
+                (found - exp).abs() < 1e-2 * exp,
+                "sensor {}: expecting {}, found {}",
+                sensor,
+                exp,
+                found
+            );
+        }
+
+        // The number of patches must match
+        let dc = ColourMatrix::new(Spectrum::<{ crate::N_CHANNELS }>::BLACK, 2, n_bins + 1);
+        assert!(factory.apply_environment(&dc, &map).is_err());
+    }
+}
@@ -0,0 +1,420 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Skies given by HDR images (e.g., measured sky captures), which light
+//! the scene with a different colour from each direction.
+//!
+//! As in the rest of the crate, `Z` points up and `Y` points North.
+
+use crate::colour::Spectrum;
+use crate::colour_matrix::ColourMatrix;
+use crate::image::ImageBuffer;
+use crate::rand::*;
+use crate::{Float, PI};
+use geometry3d::Vector3D;
+use solar::ReinhartSky;
+use std::path::Path;
+
+/// How the directions of the sky are laid out in an [`EnvironmentMap`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EnvironmentProjection {
+    /// Latitude-longitude (i.e., equirectangular) map of the whole sphere. The zenith
+    /// is at the top, the North at the centre and the East at three quarters of
+    /// the width of the image.
+    #[default]
+    LatLong,
+
+    /// Angular fisheye of the upper hemisphere (like Radiance's `-vta`), looking
+    /// up with the North at the top. The zenith is at the centre of the image
+    /// and the horizon at the circle touching its edges. Directions below the
+    /// horizon are black.
+    AngularFisheye,
+}
+
+impl std::str::FromStr for EnvironmentProjection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "latlong" => Ok(Self::LatLong),
+            "fisheye" => Ok(Self::AngularFisheye),
+            _ => Err(format!("Unknown environment projection '{}'", s)),
+        }
+    }
+}
+
+impl EnvironmentProjection {
+    /// The direction at the position `(u,v)` of an image, with both
+    /// coordinates going from `0` to `1` (left to right, top to bottom).
+    /// Returns [`None`] if the position is out of the projection.
+    fn direction(&self, u: Float, v: Float) -> Option<Vector3D> {
+        match self {
+            Self::LatLong => {
+                let theta = PI * v;
+                let phi = 2. * PI * (u - 0.5);
+                let (sin_theta, cos_theta) = theta.sin_cos();
+                Some(Vector3D::new(
+                    sin_theta * phi.sin(),
+                    sin_theta * phi.cos(),
+                    cos_theta,
+                ))
+            }
+            Self::AngularFisheye => {
+                // Looking up, the East is at the left
+                let x = 1. - 2. * u;
+                let y = 1. - 2. * v;
+                let r = (x * x + y * y).sqrt();
+                if r > 1. {
+                    return None;
+                }
+                let theta = r * PI / 2.;
+                let (sin_theta, cos_theta) = theta.sin_cos();
+                if r < 1e-9 {
+                    return Some(Vector3D::new(0., 0., 1.));
+                }
+                Some(Vector3D::new(
+                    sin_theta * x / r,
+                    sin_theta * y / r,
+                    cos_theta,
+                ))
+            }
+        }
+    }
+
+    /// The position `(u,v)` of a (normalized) `direction` in an image,
+    /// or [`None`] if it is out of the projection
+    fn position(&self, direction: Vector3D) -> Option<(Float, Float)> {
+        let cos_theta = direction.z.clamp(-1., 1.);
+        match self {
+            Self::LatLong => {
+                let phi = direction.x.atan2(direction.y);
+                let u = (phi / (2. * PI) + 0.5).clamp(0., 1.);
+                Some((u, cos_theta.acos() / PI))
+            }
+            Self::AngularFisheye => {
+                if cos_theta < 0. {
+                    return None;
+                }
+                let r = cos_theta.acos() * 2. / PI;
+                let horizontal = (direction.x * direction.x + direction.y * direction.y).sqrt();
+                if horizontal < 1e-9 {
+                    return Some((0.5, 0.5));
+                }
+                let x = r * direction.x / horizontal;
+                let y = r * direction.y / horizontal;
+                Some(((1. - x) / 2., (1. - y) / 2.))
+            }
+        }
+    }
+
+    /// The solid angle per unit area of the image around `(u,v)`
+    fn jacobian(&self, u: Float, v: Float) -> Float {
+        match self {
+            Self::LatLong => 2. * PI * PI * (PI * v).sin(),
+            Self::AngularFisheye => {
+                let x = 1. - 2. * u;
+                let y = 1. - 2. * v;
+                let r = (x * x + y * y).sqrt();
+                if r > 1. {
+                    0.0
+                } else if r < 1e-9 {
+                    PI * PI
+                } else {
+                    2. * PI * (r * PI / 2.).sin() / r
+                }
+            }
+        }
+    }
+}
+
+/// A sky given by an HDR image, whose pixels hold the radiance
+/// arriving from each direction.
+///
+/// Directions are importance-sampled in proportion to their
+/// contribution (i.e., brightness times solid angle).
+pub struct EnvironmentMap {
+    image: ImageBuffer,
+    projection: EnvironmentProjection,
+
+    /// The cumulative probability of choosing each pixel
+    cdf: Vec<Float>,
+}
+
+impl EnvironmentMap {
+    /// Creates an `EnvironmentMap` from an `image`
+    pub fn new(image: ImageBuffer, projection: EnvironmentProjection) -> Result<Self, String> {
+        let (width, height) = (image.width, image.height);
+        if width == 0 || height == 0 || image.pixels.len() != width * height {
+            return Err("The environment map has no pixels".into());
+        }
+        let mut cdf = Vec::with_capacity(image.pixels.len());
+        let mut total = 0.0;
+        for (i, pixel) in image.pixels.iter().enumerate() {
+            let u = ((i % width) as Float + 0.5) / width as Float;
+            let v = ((i / width) as Float + 0.5) / height as Float;
+            total += pixel.radiance().max(0.) * projection.jacobian(u, v);
+            cdf.push(total);
+        }
+        if total <= 0. {
+            return Err("The environment map is black".into());
+        }
+        cdf.iter_mut().for_each(|c| *c /= total);
+        Ok(Self {
+            image,
+            projection,
+            cdf,
+        })
+    }
+
+    /// Reads an `EnvironmentMap` from an image file (see [`ImageBuffer::from_file`])
+    pub fn from_file(filename: &Path, projection: EnvironmentProjection) -> Result<Self, String> {
+        let image = ImageBuffer::from_file(filename)?;
+        Self::new(image, projection)
+    }
+
+    /// The index of the pixel at position `(u,v)` of the image
+    fn pixel_index(&self, u: Float, v: Float) -> usize {
+        let x = ((u * self.image.width as Float) as usize).min(self.image.width - 1);
+        let y = ((v * self.image.height as Float) as usize).min(self.image.height - 1);
+        y * self.image.width + x
+    }
+
+    /// The probability of choosing the `i`-th pixel
+    fn pixel_pmf(&self, i: usize) -> Float {
+        if i == 0 {
+            self.cdf[0]
+        } else {
+            self.cdf[i] - self.cdf[i - 1]
+        }
+    }
+
+    /// The radiance arriving from `direction`
+    pub fn radiance(&self, direction: Vector3D) -> Spectrum<{ crate::N_CHANNELS }> {
+        match self.projection.position(direction.get_normalized()) {
+            Some((u, v)) => self.image.pixels[self.pixel_index(u, v)],
+            None => Spectrum::<{ crate::N_CHANNELS }>::BLACK,
+        }
+    }
+
+    /// Samples a direction, returning it together with its probability
+    /// (per unit solid angle) of being sampled
+    pub fn sample(&self, rng: &mut RandGen) -> (Vector3D, Float) {
+        let (r, du, dv): (Float, Float, Float) = rng.gen();
+        let last = self.cdf.len() - 1;
+        let i = self.cdf.partition_point(|c| *c <= r).min(last);
+        let u = ((i % self.image.width) as Float + du) / self.image.width as Float;
+        let v = ((i / self.image.width) as Float + dv) / self.image.height as Float;
+        match self.projection.direction(u, v) {
+            Some(direction) => {
+                let jacobian = self.projection.jacobian(u, v);
+                if jacobian <= 0. {
+                    return (direction, 0.0);
+                }
+                let n_pixels = self.cdf.len() as Float;
+                (direction, self.pixel_pmf(i) * n_pixels / jacobian)
+            }
+            // Corners of a fisheye
+            None => (Vector3D::new(0., 0., 1.), 0.0),
+        }
+    }
+
+    /// The probability (per unit solid angle) of [`EnvironmentMap::sample`]
+    /// returning `direction`
+    pub fn pdf(&self, direction: Vector3D) -> Float {
+        match self.projection.position(direction.get_normalized()) {
+            Some((u, v)) => {
+                let jacobian = self.projection.jacobian(u, v);
+                if jacobian <= 0. {
+                    return 0.0;
+                }
+                let i = self.pixel_index(u, v);
+                self.pixel_pmf(i) * self.cdf.len() as Float / jacobian
+            }
+            None => 0.0,
+        }
+    }
+
+    /// Averages the radiance over each of the patches of a `reinhart` sky,
+    /// returning a column vector that can be multiplied by Daylight Coefficients
+    /// (see [`DCFactory`](crate::DCFactory))
+    pub fn reinhart_vector(&self, reinhart: &ReinhartSky) -> ColourMatrix {
+        // A latitude-longitude grid much finer than the patches
+        const N_PHI: usize = 2048;
+        const N_THETA: usize = N_PHI / 2;
+        let n_bins = reinhart.n_bins;
+        let mut radiance = vec![Spectrum::<{ crate::N_CHANNELS }>::BLACK; n_bins];
+        let mut omega = vec![0.0; n_bins];
+        for row in 0..N_THETA {
+            let theta = PI * (row as Float + 0.5) / N_THETA as Float;
+            let (sin_theta, cos_theta) = theta.sin_cos();
+            for col in 0..N_PHI {
+                let phi = 2. * PI * (col as Float + 0.5) / N_PHI as Float;
+                let direction =
+                    Vector3D::new(sin_theta * phi.sin(), sin_theta * phi.cos(), cos_theta);
+                let bin = reinhart.dir_to_bin(direction);
+                radiance[bin] += self.radiance(direction) * sin_theta;
+                omega[bin] += sin_theta;
+            }
+        }
+
+        let mut ret = ColourMatrix::new(Spectrum::<{ crate::N_CHANNELS }>::BLACK, n_bins, 1);
+        for (bin, (r, o)) in radiance.into_iter().zip(omega).enumerate() {
+            if o > 0. {
+                ret.set(bin, 0, r / o).unwrap();
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_projections() {
+        for projection in [
+            EnvironmentProjection::LatLong,
+            EnvironmentProjection::AngularFisheye,
+        ] {
+            for (u, v) in [(0.3, 0.2), (0.7, 0.45), (0.55, 0.3), (0.1, 0.4)] {
+                let direction = projection.direction(u, v).unwrap();
+                assert!((direction.length() - 1.).abs() < 1e-5);
+                let (u2, v2) = projection.position(direction).unwrap();
+                assert!((u - u2).abs() < 1e-4, "{:?}: {} vs {}", projection, u, u2);
+                assert!((v - v2).abs() < 1e-4, "{:?}: {} vs {}", projection, v, v2);
+            }
+        }
+
+        // North is at the centre of the lat-long map, and East to its right
+        let latlong = EnvironmentProjection::LatLong;
+        let north = latlong.direction(0.5, 0.5).unwrap();
+        assert!((north.y - 1.).abs() < 1e-5);
+        let east = latlong.direction(0.75, 0.5).unwrap();
+        assert!((east.x - 1.).abs() < 1e-5);
+
+        // North is at the top of the fisheye, and East at its left
+        let fisheye = EnvironmentProjection::AngularFisheye;
+        let north = fisheye.direction(0.5, 0.).unwrap();
+        assert!((north.y - 1.).abs() < 1e-5);
+        let east = fisheye.direction(0., 0.5).unwrap();
+        assert!((east.x - 1.).abs() < 1e-5);
+        assert!(fisheye.position(Vector3D::new(0., 0., -1.)).is_none());
+    }
+
+    #[test]
+    fn test_jacobian() {
+        // Integrating the jacobian over the image gives the solid angle
+        let n = 400;
+        for (projection, expected) in [
+            (EnvironmentProjection::LatLong, 4. * PI),
+            (EnvironmentProjection::AngularFisheye, 2. * PI),
+        ] {
+            let mut total = 0.0;
+            for row in 0..n {
+                for col in 0..n {
+                    let u = (col as Float + 0.5) / n as Float;
+                    let v = (row as Float + 0.5) / n as Float;
+                    total += projection.jacobian(u, v);
+                }
+            }
+            total /= (n * n) as Float;
+            assert!(
+                (total - expected).abs() / expected < 1e-2,
+                "{:?}: expected {}, found {}",
+                projection,
+                expected,
+                total
+            );
+        }
+    }
+
+    #[test]
+    fn test_sampling() {
+        // A dark sky with a bright, red patch
+        let (width, height) = (64, 32);
+        let mut image = ImageBuffer::new(width, height);
+        for pixel in image.pixels.iter_mut() {
+            *pixel = Spectrum::<{ crate::N_CHANNELS }>::gray(0.1);
+        }
+        let mut red = Spectrum::<{ crate::N_CHANNELS }>::BLACK;
+        red.0[0] = 100.;
+        image[(10, 5)] = red;
+        let map = EnvironmentMap::new(image, EnvironmentProjection::LatLong).unwrap();
+
+        // Sampled directions have the pdf given by pdf(), and estimate
+        // the irradiance over a horizontal surface
+        let mut rng = get_seeded_rng(1, 0);
+        let n = 20000;
+        let mut irradiance = 0.0;
+        for _ in 0..n {
+            let (direction, pdf) = map.sample(&mut rng);
+            assert!(pdf > 0.);
+            assert!((map.pdf(direction) - pdf).abs() / pdf < 1e-2);
+            let cos_theta = direction.z.max(0.);
+            irradiance += map.radiance(direction).radiance() * cos_theta / pdf;
+        }
+        irradiance /= n as Float;
+
+        // Integrate it exactly, pixel by pixel
+        let mut expected = 0.0;
+        let (n_sub_u, n_sub_v) = (8, 8);
+        for row in 0..height {
+            for col in 0..width {
+                let radiance = map.image[(col, row)].radiance();
+                for i in 0..n_sub_v {
+                    for j in 0..n_sub_u {
+                        let u =
+                            (col as Float + (j as Float + 0.5) / n_sub_u as Float) / width as Float;
+                        let v = (row as Float + (i as Float + 0.5) / n_sub_v as Float)
+                            / height as Float;
+                        let direction = map.projection.direction(u, v).unwrap();
+                        let d_omega = map.projection.jacobian(u, v)
+                            / (width * height * n_sub_u * n_sub_v) as Float;
+                        expected += radiance * direction.z.max(0.) * d_omega;
+                    }
+                }
+            }
+        }
+        assert!(
+            (irradiance - expected).abs() / expected < 0.03,
+            "expected {}, found {}",
+            expected,
+            irradiance
+        );
+    }
+
+    #[test]
+    fn test_reinhart_vector() {
+        // A uniform sky gives the same value to every patch
+        let mut image = ImageBuffer::new(32, 32);
+        for pixel in image.pixels.iter_mut() {
+            *pixel = Spectrum::<{ crate::N_CHANNELS }>::gray(2.);
+        }
+        let map = EnvironmentMap::new(image, EnvironmentProjection::AngularFisheye).unwrap();
+        let reinhart = ReinhartSky::new(1);
+        let vector = map.reinhart_vector(&reinhart);
+        assert_eq!(vector.size(), (reinhart.n_bins, 1));
+        for bin in 1..reinhart.n_bins {
+            let v = vector.get(bin, 0).unwrap().radiance();
+            assert!((v - 2.).abs() < 1e-2, "bin {} is {}", bin, v);
+        }
+    }
+}
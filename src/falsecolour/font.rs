@@ -0,0 +1,189 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! A tiny 5x7 bitmap font, so that legends can be written
+//! without depending on the fonts installed in the system.
+
+/// The width of each glyph, in pixels
+pub const GLYPH_WIDTH: usize = 5;
+
+/// The height of each glyph, in pixels
+pub const GLYPH_HEIGHT: usize = 7;
+
+/// The horizontal space between glyphs, in pixels
+pub const GLYPH_SPACING: usize = 1;
+
+/// Each glyph is described by seven rows, where `#` means
+/// the pixel is painted
+type Glyph = [&'static str; GLYPH_HEIGHT];
+
+#[rustfmt::skip]
+const UNKNOWN: Glyph = [
+    " ### ", "#   #", "    #", "   # ", "  #  ", "     ", "  #  ",
+];
+
+#[rustfmt::skip]
+const GLYPHS: [(char, Glyph); 77] = [
+    (' ', ["     ", "     ", "     ", "     ", "     ", "     ", "     "]),
+    ('0', [" ### ", "#   #", "#  ##", "# # #", "##  #", "#   #", " ### "]),
+    ('1', ["  #  ", " ##  ", "  #  ", "  #  ", "  #  ", "  #  ", " ### "]),
+    ('2', [" ### ", "#   #", "    #", "   # ", "  #  ", " #   ", "#####"]),
+    ('3', ["#####", "   # ", "  #  ", "   # ", "    #", "#   #", " ### "]),
+    ('4', ["   # ", "  ## ", " # # ", "#  # ", "#####", "   # ", "   # "]),
+    ('5', ["#####", "#    ", "#### ", "    #", "    #", "#   #", " ### "]),
+    ('6', ["  ## ", " #   ", "#    ", "#### ", "#   #", "#   #", " ### "]),
+    ('7', ["#####", "    #", "   # ", "  #  ", " #   ", " #   ", " #   "]),
+    ('8', [" ### ", "#   #", "#   #", " ### ", "#   #", "#   #", " ### "]),
+    ('9', [" ### ", "#   #", "#   #", " ####", "    #", "   # ", " ##  "]),
+    ('.', ["     ", "     ", "     ", "     ", "     ", " ##  ", " ##  "]),
+    (',', ["     ", "     ", "     ", "     ", " ##  ", "  #  ", " #   "]),
+    ('-', ["     ", "     ", "     ", "#####", "     ", "     ", "     "]),
+    ('+', ["     ", "  #  ", "  #  ", "#####", "  #  ", "  #  ", "     "]),
+    ('/', ["     ", "    #", "   # ", "  #  ", " #   ", "#    ", "     "]),
+    ('%', ["##   ", "##  #", "   # ", "  #  ", " #   ", "#  ##", "   ##"]),
+    ('(', ["   # ", "  #  ", " #   ", " #   ", " #   ", "  #  ", "   # "]),
+    (')', [" #   ", "  #  ", "   # ", "   # ", "   # ", "  #  ", " #   "]),
+    (':', ["     ", " ##  ", " ##  ", "     ", " ##  ", " ##  ", "     "]),
+    ('=', ["     ", "     ", "#####", "     ", "#####", "     ", "     "]),
+    ('²', [" ##  ", "#  # ", "  #  ", " #   ", "#### ", "     ", "     "]),
+    ('A', [" ### ", "#   #", "#   #", "#####", "#   #", "#   #", "#   #"]),
+    ('B', ["#### ", "#   #", "#   #", "#### ", "#   #", "#   #", "#### "]),
+    ('C', [" ### ", "#   #", "#    ", "#    ", "#    ", "#   #", " ### "]),
+    ('D', ["###  ", "#  # ", "#   #", "#   #", "#   #", "#  # ", "###  "]),
+    ('E', ["#####", "#    ", "#    ", "#### ", "#    ", "#    ", "#####"]),
+    ('F', ["#####", "#    ", "#    ", "#### ", "#    ", "#    ", "#    "]),
+    ('G', [" ### ", "#   #", "#    ", "# ###", "#   #", "#   #", " ####"]),
+    ('H', ["#   #", "#   #", "#   #", "#####", "#   #", "#   #", "#   #"]),
+    ('I', [" ### ", "  #  ", "  #  ", "  #  ", "  #  ", "  #  ", " ### "]),
+    ('J', ["  ###", "   # ", "   # ", "   # ", "   # ", "#  # ", " ##  "]),
+    ('K', ["#   #", "#  # ", "# #  ", "##   ", "# #  ", "#  # ", "#   #"]),
+    ('L', ["#    ", "#    ", "#    ", "#    ", "#    ", "#    ", "#####"]),
+    ('M', ["#   #", "## ##", "# # #", "# # #", "#   #", "#   #", "#   #"]),
+    ('N', ["#   #", "#   #", "##  #", "# # #", "#  ##", "#   #", "#   #"]),
+    ('O', [" ### ", "#   #", "#   #", "#   #", "#   #", "#   #", " ### "]),
+    ('P', ["#### ", "#   #", "#   #", "#### ", "#    ", "#    ", "#    "]),
+    ('Q', [" ### ", "#   #", "#   #", "#   #", "# # #", "#  # ", " ## #"]),
+    ('R', ["#### ", "#   #", "#   #", "#### ", "# #  ", "#  # ", "#   #"]),
+    ('S', [" ####", "#    ", "#    ", " ### ", "    #", "    #", "#### "]),
+    ('T', ["#####", "  #  ", "  #  ", "  #  ", "  #  ", "  #  ", "  #  "]),
+    ('U', ["#   #", "#   #", "#   #", "#   #", "#   #", "#   #", " ### "]),
+    ('V', ["#   #", "#   #", "#   #", "#   #", "#   #", " # # ", "  #  "]),
+    ('W', ["#   #", "#   #", "#   #", "# # #", "# # #", "# # #", " # # "]),
+    ('X', ["#   #", "#   #", " # # ", "  #  ", " # # ", "#   #", "#   #"]),
+    ('Y', ["#   #", "#   #", " # # ", "  #  ", "  #  ", "  #  ", "  #  "]),
+    ('Z', ["#####", "    #", "   # ", "  #  ", " #   ", "#    ", "#####"]),
+    ('a', ["     ", "     ", " ### ", "    #", " ####", "#   #", " ####"]),
+    ('b', ["#    ", "#    ", "# ## ", "##  #", "#   #", "#   #", "#### "]),
+    ('c', ["     ", "     ", " ### ", "#    ", "#    ", "#   #", " ### "]),
+    ('d', ["    #", "    #", " ## #", "#  ##", "#   #", "#   #", " ####"]),
+    ('e', ["     ", "     ", " ### ", "#   #", "#####", "#    ", " ### "]),
+    ('f', ["  ## ", " #  #", " #   ", "###  ", " #   ", " #   ", " #   "]),
+    ('g', ["     ", " ####", "#   #", "#   #", " ####", "    #", " ### "]),
+    ('h', ["#    ", "#    ", "# ## ", "##  #", "#   #", "#   #", "#   #"]),
+    ('i', ["  #  ", "     ", " ##  ", "  #  ", "  #  ", "  #  ", " ### "]),
+    ('j', ["   # ", "     ", "  ## ", "   # ", "   # ", "#  # ", " ##  "]),
+    ('k', ["#    ", "#    ", "#  # ", "# #  ", "##   ", "# #  ", "#  # "]),
+    ('l', [" ##  ", "  #  ", "  #  ", "  #  ", "  #  ", "  #  ", " ### "]),
+    ('m', ["     ", "     ", "## # ", "# # #", "# # #", "#   #", "#   #"]),
+    ('n', ["     ", "     ", "# ## ", "##  #", "#   #", "#   #", "#   #"]),
+    ('o', ["     ", "     ", " ### ", "#   #", "#   #", "#   #", " ### "]),
+    ('p', ["     ", "     ", "#### ", "#   #", "#### ", "#    ", "#    "]),
+    ('q', ["     ", "     ", " ## #", "#  ##", " ####", "    #", "    #"]),
+    ('r', ["     ", "     ", "# ## ", "##  #", "#    ", "#    ", "#    "]),
+    ('s', ["     ", "     ", " ### ", "#    ", " ### ", "    #", "#### "]),
+    ('t', [" #   ", " #   ", "###  ", " #   ", " #   ", " #  #", "  ## "]),
+    ('u', ["     ", "     ", "#   #", "#   #", "#   #", "#  ##", " ## #"]),
+    ('v', ["     ", "     ", "#   #", "#   #", "#   #", " # # ", "  #  "]),
+    ('w', ["     ", "     ", "#   #", "#   #", "# # #", "# # #", " # # "]),
+    ('x', ["     ", "     ", "#   #", " # # ", "  #  ", " # # ", "#   #"]),
+    ('y', ["     ", "     ", "#   #", "#   #", " ####", "    #", " ### "]),
+    ('z', ["     ", "     ", "#####", "   # ", "  #  ", " #   ", "#####"]),
+    ('[', [" ### ", " #   ", " #   ", " #   ", " #   ", " #   ", " ### "]),
+    (']', [" ### ", "   # ", "   # ", "   # ", "   # ", "   # ", " ### "]),
+    ('*', ["     ", "  #  ", "# # #", " ### ", "# # #", "  #  ", "     "]),
+];
+
+/// Returns the glyph of a character. Characters that are not
+/// included in the font are shown as `?`
+fn glyph(c: char) -> &'static Glyph {
+    match GLYPHS.iter().find(|(g, _)| *g == c) {
+        Some((_, glyph)) => glyph,
+        None => &UNKNOWN,
+    }
+}
+
+/// The width of a `text` written with a certain integer `scale`, in pixels
+pub fn text_width(text: &str, scale: usize) -> usize {
+    let n = text.chars().count();
+    if n == 0 {
+        0
+    } else {
+        (n * (GLYPH_WIDTH + GLYPH_SPACING) - GLYPH_SPACING) * scale
+    }
+}
+
+/// Writes `text` into an RGB buffer of width `image_width`. The
+/// top-left corner of the text is placed at `(x, y)`; anything that
+/// falls outside of the buffer is ignored.
+pub fn draw_text(
+    data: &mut [u8],
+    image_width: usize,
+    x: usize,
+    y: usize,
+    text: &str,
+    scale: usize,
+    colour: [u8; 3],
+) {
+    let image_height = data.len() / 3 / image_width;
+    for (n, c) in text.chars().enumerate() {
+        let x0 = x + n * (GLYPH_WIDTH + GLYPH_SPACING) * scale;
+        for (row, line) in glyph(c).iter().enumerate() {
+            for (col, b) in line.chars().enumerate() {
+                if b != '#' {
+                    continue;
+                }
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        let px = x0 + col * scale + dx;
+                        let py = y + row * scale + dy;
+                        if px < image_width && py < image_height {
+                            let i = 3 * (py * image_width + px);
+                            data[i..i + 3].copy_from_slice(&colour);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glyphs_are_well_formed() {
+        for (c, g) in GLYPHS.iter() {
+            for line in g.iter() {
+                assert_eq!(line.chars().count(), GLYPH_WIDTH, "Glyph '{}'", c);
+            }
+        }
+    }
+}
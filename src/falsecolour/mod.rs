@@ -0,0 +1,343 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::colourmap::{map_linear_colour, Colourmap};
+use crate::image::ImageBuffer;
+use crate::Float;
+
+mod font;
+use font::{draw_text, text_width, GLYPH_HEIGHT};
+
+/// The smallest value that can be shown in a log10 scale
+const LOG_MIN: Float = 0.001;
+
+/// The ways in which values can be represented in a falsecolour image
+#[derive(Clone, Copy, Debug)]
+pub enum FalsecolourMode {
+    /// Each value gets its own colour
+    Continuous,
+    /// Values are split into a number of bands of solid
+    /// colour (like Radiance's `falsecolor -cb`)
+    Bands(usize),
+    /// Iso-contour lines are drawn at the boundaries of a number
+    /// of bands (like Radiance's `falsecolor -cl`), on top of a dimmed
+    /// version of the image
+    Contours(usize),
+}
+
+/// The options for creating a falsecolour image
+pub struct FalsecolourOptions {
+    /// The minimum value in the scale. Defaults to `0` (or `0.001` in log scale)
+    pub min: Option<Float>,
+
+    /// The maximum value in the scale. Defaults to the maximum in the image
+    pub max: Option<Float>,
+
+    /// Use a log10 scale
+    pub log: bool,
+
+    /// The colours to use
+    pub colourmap: Colourmap,
+
+    /// How values are represented
+    pub mode: FalsecolourMode,
+
+    /// The units to write on top of the legend (e.g., `cd/m2` or `lux`). If
+    /// `None`, no legend is added to the image
+    pub legend: Option<String>,
+
+    /// The number of labels in the legend when using [`FalsecolourMode::Continuous`].
+    /// Otherwise, each band boundary is labelled.
+    pub n_labels: usize,
+}
+
+impl std::default::Default for FalsecolourOptions {
+    fn default() -> Self {
+        Self {
+            min: None,
+            max: None,
+            log: false,
+            colourmap: Colourmap::Viridis,
+            mode: FalsecolourMode::Continuous,
+            legend: Some("cd/m2".to_string()),
+            n_labels: 8,
+        }
+    }
+}
+
+impl FalsecolourOptions {
+    /// Transforms a value into the space of the scale (i.e., linear or log10)
+    fn to_scale(&self, v: Float) -> Float {
+        if self.log {
+            v.max(LOG_MIN).log10()
+        } else {
+            v
+        }
+    }
+
+    /// Transforms a value from the space of the scale into a physical value
+    fn from_scale(&self, v: Float) -> Float {
+        if self.log {
+            (10. as Float).powf(v)
+        } else {
+            v
+        }
+    }
+
+    /// Calculates the limits of the scale, in scale space
+    fn range(&self, values: &[Float]) -> (Float, Float) {
+        let lower = if self.log {
+            self.min.unwrap_or(LOG_MIN).max(LOG_MIN)
+        } else {
+            self.min.unwrap_or(0.0).max(0.0)
+        };
+        let lower = self.to_scale(lower);
+
+        let upper = match self.max {
+            Some(v) => self.to_scale(v),
+            None => {
+                let m = values.iter().fold(Float::MIN, |a, b| a.max(*b));
+                self.to_scale(m)
+            }
+        };
+        if upper <= lower {
+            (lower, lower + 1.)
+        } else {
+            (lower, upper)
+        }
+    }
+
+    /// Maps a normalized value (i.e., `0` is the minimum of the
+    /// scale and `1` is the maximum) into a colour
+    fn colour(&self, t: Float) -> [u8; 3] {
+        let colours = self.colourmap.colours();
+        let t = t.clamp(0., 1.);
+        let c = match self.mode {
+            FalsecolourMode::Continuous => map_linear_colour(t, 0., 1., colours),
+            FalsecolourMode::Bands(n) | FalsecolourMode::Contours(n) => {
+                let n = n.max(1);
+                let band = ((t * n as Float).floor() as usize).min(n - 1);
+                map_linear_colour((band as Float + 0.5) / n as Float, 0., 1., colours)
+            }
+        };
+        [to_byte(c[0]), to_byte(c[1]), to_byte(c[2])]
+    }
+
+    /// The positions (normalized) at which the legend is labelled
+    fn label_positions(&self) -> Vec<Float> {
+        match self.mode {
+            FalsecolourMode::Continuous => {
+                let n = self.n_labels.max(2);
+                (0..n).map(|i| i as Float / (n - 1) as Float).collect()
+            }
+            FalsecolourMode::Bands(n) | FalsecolourMode::Contours(n) => {
+                let n = n.max(1);
+                (0..=n).map(|i| i as Float / n as Float).collect()
+            }
+        }
+    }
+}
+
+fn to_byte(v: Float) -> u8 {
+    (v.clamp(0., 1.) * 255.).round() as u8
+}
+
+/// Writes a number in a compact way, for labelling legends
+fn format_label(v: Float) -> String {
+    let a = v.abs();
+    if a < 1e-12 {
+        "0".to_string()
+    } else if !(1e-2..1e5).contains(&a) {
+        format!("{:.1e}", v)
+    } else if a >= 100. {
+        format!("{:.0}", v)
+    } else if a >= 10. {
+        format!("{:.1}", v)
+    } else {
+        format!("{:.2}", v)
+    }
+}
+
+/// Creates a falsecolour version of an image, returning its `width`,
+/// `height` and RGB bytes. If a legend is requested, it is added to
+/// the right of the image (so the returned width will be larger than
+/// that of the original image)
+pub fn falsecolour(image: &ImageBuffer, options: &FalsecolourOptions) -> (usize, usize, Vec<u8>) {
+    let (width, height) = (image.width, image.height);
+    let luminance: Vec<Float> = image.pixels.iter().map(|x| x.luminance()).collect();
+    let (lower, upper) = options.range(&luminance);
+
+    let normalized: Vec<Float> = luminance
+        .iter()
+        .map(|v| ((options.to_scale(*v) - lower) / (upper - lower)).clamp(0., 1.))
+        .collect();
+
+    /* THE IMAGE ITSELF */
+    let mut image_data: Vec<u8> = Vec::with_capacity(width * height * 3);
+    match options.mode {
+        FalsecolourMode::Continuous | FalsecolourMode::Bands(_) => {
+            normalized
+                .iter()
+                .for_each(|t| image_data.extend_from_slice(&options.colour(*t)));
+        }
+        FalsecolourMode::Contours(n) => {
+            let n = n.max(1) as Float;
+            let band = |i: usize| -> usize { (normalized[i] * n).floor().min(n - 1.) as usize };
+            for (i, t) in normalized.iter().enumerate() {
+                let (x, y) = (i % width, i / width);
+                let is_edge = (x + 1 < width && band(i) != band(i + 1))
+                    || (y + 1 < height && band(i) != band(i + width));
+                if is_edge {
+                    image_data.extend_from_slice(&options.colour(*t));
+                } else {
+                    let gray = to_byte(0.4 * t);
+                    image_data.extend_from_slice(&[gray, gray, gray]);
+                }
+            }
+        }
+    }
+
+    let units = match &options.legend {
+        Some(u) => u,
+        None => return (width, height, image_data),
+    };
+
+    /* THE LEGEND */
+    const WHITE: [u8; 3] = [255, 255, 255];
+    let scale = (height / 300).max(1);
+    let margin = 4 * scale;
+    let char_height = GLYPH_HEIGHT * scale;
+    let bar_width = 16 * scale;
+    let tick_length = 3 * scale;
+
+    let positions = options.label_positions();
+    let labels: Vec<String> = positions
+        .iter()
+        .map(|t| format_label(options.from_scale(lower + t * (upper - lower))))
+        .collect();
+    let labels_width = labels
+        .iter()
+        .map(|l| text_width(l, scale))
+        .max()
+        .unwrap_or(0);
+    let legend_width = (margin + bar_width + tick_length + margin + labels_width + margin)
+        .max(margin + text_width(units, scale) + margin);
+
+    let bar_top = margin + char_height + margin + char_height / 2;
+    let bar_bottom = height.saturating_sub(margin + char_height / 2 + 1);
+    if bar_bottom <= bar_top {
+        // Not enough room for a legend
+        return (width, height, image_data);
+    }
+
+    let mut legend = vec![0; legend_width * height * 3];
+    let bar_length = (bar_bottom - bar_top) as Float;
+    for y in bar_top..=bar_bottom {
+        let t = (bar_bottom - y) as Float / bar_length;
+        let colour = options.colour(t);
+        for x in margin..margin + bar_width {
+            let i = 3 * (y * legend_width + x);
+            legend[i..i + 3].copy_from_slice(&colour);
+        }
+    }
+
+    for (t, label) in positions.iter().zip(labels.iter()) {
+        let y = bar_bottom - (t * bar_length).round() as usize;
+        // tick
+        for x in margin + bar_width..margin + bar_width + tick_length {
+            for dy in 0..scale {
+                let i = 3 * ((y + dy).min(height - 1) * legend_width + x);
+                legend[i..i + 3].copy_from_slice(&WHITE);
+            }
+        }
+        // label
+        draw_text(
+            &mut legend,
+            legend_width,
+            margin + bar_width + tick_length + margin,
+            y.saturating_sub(char_height / 2),
+            label,
+            scale,
+            WHITE,
+        );
+    }
+    draw_text(
+        &mut legend,
+        legend_width,
+        margin,
+        margin,
+        units,
+        scale,
+        WHITE,
+    );
+
+    /* PUT THEM TOGETHER */
+    let total_width = width + legend_width;
+    let mut data = Vec::with_capacity(total_width * height * 3);
+    for (image_row, legend_row) in image_data
+        .chunks_exact(width * 3)
+        .zip(legend.chunks_exact(legend_width * 3))
+    {
+        data.extend_from_slice(image_row);
+        data.extend_from_slice(legend_row);
+    }
+
+    (total_width, height, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colour::Spectrum;
+
+    #[test]
+    fn test_format_label() {
+        assert_eq!(format_label(0.0), "0");
+        assert_eq!(format_label(150.), "150");
+        assert_eq!(format_label(12.34), "12.3");
+        assert_eq!(format_label(1.234), "1.23");
+        assert_eq!(format_label(1e6), "1.0e6");
+    }
+
+    #[test]
+    fn test_falsecolour_size() {
+        let image = ImageBuffer::from_pixels(
+            40,
+            100,
+            vec![Spectrum::<{ crate::N_CHANNELS }>::gray(1.); 4000],
+        );
+        let options = FalsecolourOptions {
+            legend: None,
+            ..FalsecolourOptions::default()
+        };
+        let (width, height, data) = falsecolour(&image, &options);
+        assert_eq!((width, height), (40, 100));
+        assert_eq!(data.len(), 40 * 100 * 3);
+
+        let options = FalsecolourOptions {
+            mode: FalsecolourMode::Bands(5),
+            ..FalsecolourOptions::default()
+        };
+        let (width, height, data) = falsecolour(&image, &options);
+        assert!(width > 40);
+        assert_eq!(height, 100);
+        assert_eq!(data.len(), width * height * 3);
+    }
+}
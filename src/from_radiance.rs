@@ -740,10 +740,10 @@ mod tests {
         assert_eq!(scene.materials.len(), 1);
         assert_eq!(scanner.modifiers.len(), 1);
         assert!(scene.triangles.is_empty());
-        assert_eq!(1, scene.distant_lights.len());
+        assert_eq!(1, scene.delta_lights.len());
         assert_eq!(scene.normals.len(), scene.triangles.len());
 
-        if let Primitive::Source(p) = &scene.distant_lights[0].primitive {
+        if let Some(Primitive::Source(p)) = scene.delta_lights[0].object().map(|l| &l.primitive) {
             let l = Vector3D::new(1., 2., 3.).get_normalized();
             assert_close!(p.direction.x, l.x);
             assert_close!(p.direction.y, l.y);
@@ -777,7 +777,7 @@ mod tests {
         assert_eq!(scene.materials.len(), 1);
         assert_eq!(scanner.modifiers.len(), 1);
         assert_eq!(scene.triangles.len(), 1);
-        assert!(scene.distant_lights.is_empty());
+        assert!(scene.delta_lights.is_empty());
         assert_eq!(scene.normals.len(), scene.triangles.len());
 
         let exp = [21., 12., 53., -4., 125., 66., 75., 8.1, 9.2];
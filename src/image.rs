@@ -30,6 +30,8 @@ SOFTWARE.
 */
 use crate::colour::Spectrum;
 use crate::colourmap::Colourmap;
+use crate::falsecolour::{falsecolour, FalsecolourMode, FalsecolourOptions};
+use crate::tonemap::{display_to_byte, tone_map, ToneMap};
 use crate::Float;
 use jpeg_encoder::{ColorType, Encoder};
 use std::io::Write;
@@ -92,6 +94,17 @@ fn rgbe_to_colour(r: u8, g: u8, b: u8, e: u8) -> Spectrum<{ crate::N_CHANNELS }>
     Spectrum::<{ crate::N_CHANNELS }>([red, green, blue])
 }
 
+/// The precision used for storing the colour channels
+/// of an OpenEXR image
+#[cfg(feature = "exr")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExrPrecision {
+    /// 16-bit floating point numbers
+    Half,
+    /// 32-bit floating point numbers
+    Float,
+}
+
 /// A buffer with all the physical values in the image
 /// (i.e., Radiance, Irradiance or whatever being calculated)
 ///
@@ -172,26 +185,371 @@ impl ImageBuffer {
 
     /// Saves the image in HDRE format
     pub fn save_hdre(&self, filename: &Path) {
+        self.write_hdre(filename).unwrap()
+    }
+
+    /// Writes the image in HDRE format, returning an error if the
+    /// file cannot be written
+    fn write_hdre(&self, filename: &Path) -> Result<(), String> {
+        let err = |e: std::io::Error| {
+            format!("Could not write image file '{}': {}", filename.display(), e)
+        };
         // Create the file
-        let mut file = std::fs::File::create(filename).unwrap();
+        let mut file = std::fs::File::create(filename).map_err(err)?;
         // Write header
         // let gamma = 1.0;
         // let exposure = 1.0;
-        file.write_all(b"#?RGBE\n").unwrap();
+        file.write_all(b"#?RGBE\n").map_err(err)?;
         // file.write_all(format!("GAMMA={}\n", gamma).as_bytes()).unwrap();
         // file.write_all(format!("EXPOSURE={}\n", exposure).as_bytes()).unwrap();
-        file.write_all(b"FORMAT=32-bit_rle_rgbe\n\n").unwrap();
+        file.write_all(b"FORMAT=32-bit_rle_rgbe\n\n").map_err(err)?;
         file.write_all(format!("-Y {} +X {}\n", self.height, self.width).as_bytes())
-            .unwrap();
+            .map_err(err)?;
 
         for pixel in self.pixels.iter() {
             file.write_all(&colour_to_rgbe(pixel.0[0], pixel.0[1], pixel.0[2]))
-                .unwrap();
+                .map_err(err)?;
+        }
+        Ok(())
+    }
+
+    /// Saves the image in Portable Float Map (PFM) format. Unlike HDRE,
+    /// this keeps the full precision of each channel, including negative values.
+    pub fn save_pfm(&self, filename: &Path) -> Result<(), String> {
+        if self.width == 0 || self.height == 0 || self.pixels.len() != self.width * self.height {
+            return Err(format!(
+                "Cannot save a {}x{} image with {} pixels as PFM",
+                self.width,
+                self.height,
+                self.pixels.len()
+            ));
+        }
+
+        // Write header... a negative scale means little-endian
+        let header = format!("PF\n{} {}\n-1.0\n", self.width, self.height);
+        let mut data: Vec<u8> = Vec::with_capacity(header.len() + self.pixels.len() * 3 * 4);
+        data.extend_from_slice(header.as_bytes());
+
+        // PFM stores rows from bottom to top
+        for row in self.pixels.chunks_exact(self.width).rev() {
+            for pixel in row.iter() {
+                for v in pixel.0.iter().take(3) {
+                    data.extend_from_slice(&(*v as f32).to_le_bytes());
+                }
+            }
+        }
+        std::fs::write(filename, data)
+            .map_err(|e| format!("Could not write image file '{}': {}", filename.display(), e))
+    }
+
+    /// Creates a new [`ImageBuffer`] from a Portable Float Map (PFM) file.
+    ///
+    /// Both colour (i.e., `PF`) and grayscale (i.e., `Pf`) files are supported.
+    pub fn from_pfm_file(filename: &Path) -> Result<Self, String> {
+        let content = match std::fs::read(filename) {
+            Ok(v) => v,
+            Err(_) => {
+                return Err(format!(
+                    "Could not read image file '{}'",
+                    filename.to_str().unwrap()
+                ))
+            }
+        };
+        let filename = filename.to_str().unwrap();
+
+        // READ HEADER... three whitespace-separated lines
+        let mut content = content.as_slice();
+        let mut header: Vec<&[u8]> = Vec::with_capacity(4);
+        while header.len() < 4 {
+            // skip whitespace
+            let start = match content.iter().position(|u| !u.is_ascii_whitespace()) {
+                Some(i) => i,
+                None => {
+                    return Err(format!(
+                        "When reading file '{}' : Incomplete PFM header",
+                        filename
+                    ))
+                }
+            };
+            content = &content[start..];
+            let end = match content.iter().position(|u| u.is_ascii_whitespace()) {
+                Some(i) => i,
+                None => {
+                    return Err(format!(
+                        "When reading file '{}' : Incomplete PFM header",
+                        filename
+                    ))
+                }
+            };
+            header.push(&content[..end]);
+            // Only a single whitespace character separates the header from the data
+            content = &content[end + 1..];
+        }
+
+        let n_channels = match header[0] {
+            b"PF" => 3,
+            b"Pf" => 1,
+            _ => {
+                let found = std::str::from_utf8(header[0]).unwrap_or("?");
+                return Err(format!(
+                    "When reading file '{}' : Expecting PFM identifier to be 'PF' or 'Pf'... found '{}'",
+                    filename, found
+                ));
+            }
+        };
+
+        let parse = |bytes: &[u8]| -> Result<String, String> {
+            match std::str::from_utf8(bytes) {
+                Ok(v) => Ok(v.to_string()),
+                Err(_) => Err(format!(
+                    "When reading file '{}' : Found non-UTF8 characters in PFM header",
+                    filename
+                )),
+            }
+        };
+        let width = parse(header[1])?;
+        let width = match width.parse::<usize>() {
+            Ok(v) => v,
+            Err(_) => {
+                return Err(format!(
+                    "When reading file '{}' : Expecting width to be an integer... found '{}'",
+                    filename, width
+                ))
+            }
+        };
+        let height = parse(header[2])?;
+        let height = match height.parse::<usize>() {
+            Ok(v) => v,
+            Err(_) => {
+                return Err(format!(
+                    "When reading file '{}' : Expecting height to be an integer... found '{}'",
+                    filename, height
+                ))
+            }
+        };
+        let scale = parse(header[3])?;
+        let scale = match scale.parse::<f32>() {
+            Ok(v) => v,
+            Err(_) => {
+                return Err(format!(
+                    "When reading file '{}' : Expecting scale to be a number... found '{}'",
+                    filename, scale
+                ))
+            }
+        };
+        if scale == 0. || !scale.is_finite() {
+            return Err(format!(
+                "When reading file '{}' : Expecting a non-zero scale... found '{}'",
+                filename, scale
+            ));
+        }
+        // The sign gives the byte order, and the magnitude multiplies the pixels
+        let little_endian = scale < 0.;
+        let scale = scale.abs();
+
+        // Read body
+        if width == 0 || height == 0 {
+            return Err(format!(
+                "When reading file '{}' : Expecting a non-empty image... found {}x{}",
+                filename, width, height
+            ));
+        }
+        let n_bytes = match width
+            .checked_mul(height)
+            .and_then(|n| n.checked_mul(n_channels * 4))
+        {
+            Some(v) => v,
+            None => {
+                return Err(format!(
+                    "When reading file '{}' : Image of {}x{} pixels is too large",
+                    filename, width, height
+                ))
+            }
+        };
+        if content.len() != n_bytes {
+            return Err(format!(
+                "When reading file '{}' : Expecting {} bytes of data... found {}",
+                filename,
+                n_bytes,
+                content.len()
+            ));
+        }
+        let values: Vec<Float> = content
+            .chunks_exact(4)
+            .map(|x| {
+                let bytes = [x[0], x[1], x[2], x[3]];
+                let v = if little_endian {
+                    f32::from_le_bytes(bytes)
+                } else {
+                    f32::from_be_bytes(bytes)
+                };
+                (v * scale) as Float
+            })
+            .collect();
+
+        // PFM stores rows from bottom to top
+        let mut pixels = Vec::with_capacity(width * height);
+        for row in values.chunks_exact(width * n_channels).rev() {
+            for v in row.chunks_exact(n_channels) {
+                let pixel = if n_channels == 1 {
+                    Spectrum::<{ crate::N_CHANNELS }>::gray(v[0])
+                } else {
+                    Spectrum::<{ crate::N_CHANNELS }>([v[0], v[1], v[2]])
+                };
+                pixels.push(pixel);
+            }
+        }
+
+        Ok(Self {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    /// Saves the image in OpenEXR format, with `R`, `G` and `B` channels
+    /// stored with the given `precision`.
+    ///
+    /// Additional named channels (e.g., a variance or a depth channel) can be
+    /// added through `extra_channels`. Each of these must contain one value per pixel.
+    #[cfg(feature = "exr")]
+    pub fn save_exr(
+        &self,
+        filename: &Path,
+        precision: ExrPrecision,
+        extra_channels: &[(&str, &[Float])],
+    ) -> Result<(), String> {
+        use exr::prelude::*;
+
+        let n_pixels = self.width * self.height;
+        for (name, values) in extra_channels.iter() {
+            if values.len() != n_pixels {
+                return Err(format!(
+                    "Channel '{}' has {} values... expecting one per pixel (i.e., {})",
+                    name,
+                    values.len(),
+                    n_pixels
+                ));
+            }
+        }
+
+        let samples = |values: Vec<Float>| -> FlatSamples {
+            match precision {
+                ExrPrecision::Half => {
+                    FlatSamples::F16(values.iter().map(|v| f16::from_f32(*v as f32)).collect())
+                }
+                ExrPrecision::Float => FlatSamples::F32(values.iter().map(|v| *v as f32).collect()),
+            }
+        };
+
+        let mut channels = Vec::with_capacity(3 + extra_channels.len());
+        for (i, name) in ["R", "G", "B"].iter().enumerate() {
+            let values = self.pixels.iter().map(|p| p.0[i]).collect();
+            channels.push(AnyChannel::new(*name, samples(values)));
+        }
+        for (name, values) in extra_channels.iter() {
+            channels.push(AnyChannel::new(*name, samples(values.to_vec())));
+        }
+
+        let image = Image::from_encoded_channels(
+            (self.width, self.height),
+            Encoding::FAST_LOSSLESS,
+            AnyChannels::sort(channels.into()),
+        );
+
+        match image.write().to_file(filename) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!(
+                "Could not write image file '{}': {}",
+                filename.to_str().unwrap(),
+                e
+            )),
         }
     }
 
-    /// Creates a new empty [`ImageBuffer`] from a File
+    /// Creates a new [`ImageBuffer`] from the first RGB(A) layer of an OpenEXR file.
+    #[cfg(feature = "exr")]
+    pub fn from_exr_file(filename: &Path) -> Result<Self, String> {
+        use exr::prelude::*;
+
+        let image = read_first_rgba_layer_from_file(
+            filename,
+            |resolution, _| -> (usize, Vec<Spectrum<{ crate::N_CHANNELS }>>) {
+                let width = resolution.width();
+                let height = resolution.height();
+                (
+                    width,
+                    vec![Spectrum::<{ crate::N_CHANNELS }>::BLACK; width * height],
+                )
+            },
+            |(width, pixels), position, (r, g, b, _a): (f32, f32, f32, f32)| {
+                pixels[position.y() * *width + position.x()] =
+                    Spectrum::<{ crate::N_CHANNELS }>([r as Float, g as Float, b as Float]);
+            },
+        );
+
+        let image = match image {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(format!(
+                    "Could not read image file '{}': {}",
+                    filename.to_str().unwrap(),
+                    e
+                ))
+            }
+        };
+        let (width, pixels) = image.layer_data.channel_data.pixels;
+        let height = pixels.len() / width.max(1);
+
+        Ok(Self {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    /// Checks whether [`ImageBuffer::save`] can write an image called
+    /// `filename`, so that this can be known before spending time
+    /// producing it
+    pub fn check_format(filename: &Path) -> Result<(), String> {
+        match filename.extension().and_then(|e| e.to_str()) {
+            #[cfg(not(feature = "exr"))]
+            Some("exr") | Some("EXR") => {
+                Err("Saving OpenEXR images requires enabling the 'exr' feature".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Saves the image, choosing the format based on the extension
+    /// of `filename`: `.pfm` produces a Portable Float Map, `.exr` produces
+    /// an OpenEXR (requires the `exr` feature) and anything else produces
+    /// an HDRE.
+    pub fn save(&self, filename: &Path) -> Result<(), String> {
+        Self::check_format(filename)?;
+        match filename.extension().and_then(|e| e.to_str()) {
+            Some("pfm") | Some("PFM") => self.save_pfm(filename),
+            #[cfg(feature = "exr")]
+            Some("exr") | Some("EXR") => self.save_exr(filename, ExrPrecision::Float, &[]),
+            _ => self.write_hdre(filename),
+        }
+    }
+
+    /// Creates a new empty [`ImageBuffer`] from a File. The format is chosen
+    /// based on the extension: `.pfm` is read as a Portable Float Map, `.exr` as
+    /// OpenEXR (requires the `exr` feature), and anything else as HDRE.
     pub fn from_file(filename: &Path) -> Result<Self, String> {
+        match filename.extension().and_then(|e| e.to_str()) {
+            Some("pfm") | Some("PFM") => return Self::from_pfm_file(filename),
+            #[cfg(feature = "exr")]
+            Some("exr") | Some("EXR") => return Self::from_exr_file(filename),
+            #[cfg(not(feature = "exr"))]
+            Some("exr") | Some("EXR") => {
+                return Err("Reading OpenEXR images requires enabling the 'exr' feature".to_string())
+            }
+            _ => {}
+        }
+
         let content = match std::fs::read(filename) {
             Ok(v) => v,
             Err(_) => {
@@ -290,7 +648,62 @@ impl ImageBuffer {
         })
     } // end of from_file()
 
-    /// Creates a new version of an image, but in (log10) falsecolour
+    /// Tone-maps the image and returns its pixels as gamma-encoded RGB bytes
+    fn ldr_data(&self, operator: &ToneMap) -> Vec<u8> {
+        let mut data: Vec<u8> = Vec::with_capacity(self.width * self.height * 3);
+        tone_map(self, operator).iter().for_each(|p| {
+            data.push(display_to_byte(p.0[0]));
+            data.push(display_to_byte(p.0[1]));
+            data.push(display_to_byte(p.0[2]));
+        });
+        data
+    }
+
+    /// Writes RGB bytes into a PNG file
+    fn write_png(data: &[u8], width: usize, height: usize, outfile: &Path) {
+        let file = std::fs::File::create(outfile).unwrap();
+        let mut encoder =
+            png::Encoder::new(std::io::BufWriter::new(file), width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(data).unwrap();
+    }
+
+    /// Writes RGB bytes into a JPEG file
+    fn write_jpeg(data: &[u8], width: usize, height: usize, outfile: &Path) {
+        let encoder = Encoder::new_file(outfile, 100).unwrap();
+        encoder
+            .encode(data, width as u16, height as u16, ColorType::Rgb)
+            .unwrap();
+    }
+
+    /// Saves a tone-mapped version of the image in PNG format
+    pub fn save_png(&self, operator: &ToneMap, outfile: &Path) {
+        let data = self.ldr_data(operator);
+        Self::write_png(&data, self.width, self.height, outfile)
+    }
+
+    /// Saves a tone-mapped version of the image in JPEG format
+    pub fn save_jpeg(&self, operator: &ToneMap, outfile: &Path) {
+        let data = self.ldr_data(operator);
+        Self::write_jpeg(&data, self.width, self.height, outfile)
+    }
+
+    /// Saves a falsecolour version of the image, possibly with a legend,
+    /// contour lines or bands. It is a PNG if the file name ends
+    /// in `.png`; otherwise, it is a JPEG
+    pub fn save_falsecolour_image(&self, options: &FalsecolourOptions, outfile: &Path) {
+        let (width, height, data) = falsecolour(self, options);
+        match outfile.extension().and_then(|e| e.to_str()) {
+            Some("png") | Some("PNG") => Self::write_png(&data, width, height, outfile),
+            _ => Self::write_jpeg(&data, width, height, outfile),
+        }
+    }
+
+    /// Creates a new version of an image, but in (log10) falsecolour. The
+    /// result is always a JPEG; use [`ImageBuffer::save_falsecolour_image`]
+    /// for PNG output, legends, contours or bands.
     pub fn save_log_falsecolour(
         &self,
         min: Option<Float>,
@@ -298,55 +711,23 @@ impl ImageBuffer {
         scale: Colourmap,
         outfile: &Path,
     ) {
-        let log_luminance: Vec<Float> = self.pixels.iter().map(|x| x.luminance().log10()).collect();
-        const MIN_MIN: Float = 0.001;
-        // Get min and max
-        let log_min = match min {
-            Some(mut v) => {
-                v = v.max(MIN_MIN);
-                v.log10()
-            }
-            None => MIN_MIN.log10(),
-        };
-        let log_max = match max {
-            Some(v) => v.log10(),
-            None => {
-                let mut m = MIN_MIN;
-                log_luminance.iter().for_each(|v| {
-                    if *v > m {
-                        m = *v
-                    }
-                });
-                if m <= MIN_MIN {
-                    m = 2. * MIN_MIN;
-                }
-                m
-            }
-        };
-
-        let scale = match scale {
-            Colourmap::Inferno => crate::colourmap::inferno::INFERNO_COLOURMAP.as_slice(),
-            Colourmap::Magma => crate::colourmap::magma::MAGMA_COLOURMAP.as_slice(),
-            Colourmap::Plasma => crate::colourmap::plasma::PLASMA_COLOURMAP.as_slice(),
-            Colourmap::Radiance => crate::colourmap::radiance::RADIANCE_COLOURMAP.as_slice(),
-            Colourmap::Viridis => crate::colourmap::viridis::VIRIDIS_COLOURMAP.as_slice(),
+        let options = FalsecolourOptions {
+            min,
+            max,
+            log: true,
+            colourmap: scale,
+            mode: FalsecolourMode::Continuous,
+            legend: None,
+            ..FalsecolourOptions::default()
         };
-
-        let mut data: Vec<u8> = Vec::with_capacity(self.width * self.height * 3);
-        log_luminance.iter().for_each(|x| {
-            let s = crate::colourmap::map_linear_colour(*x, log_min, log_max, scale);
-            data.push((s[0] * 256.).round() as u8);
-            data.push((s[1] * 256.).round() as u8);
-            data.push((s[2] * 256.).round() as u8);
-        });
-
-        let encoder = Encoder::new_file(outfile, 100).unwrap();
-        encoder
-            .encode(&data, self.width as u16, self.height as u16, ColorType::Rgb)
-            .unwrap();
+        // Always a JPEG, whatever the extension of `outfile`
+        let (width, height, data) = falsecolour(self, &options);
+        Self::write_jpeg(&data, width, height, outfile)
     }
 
-    /// Creates a new version of an image, but in (linear) falsecolour
+    /// Creates a new version of an image, but in (linear) falsecolour. The
+    /// result is always a JPEG; use [`ImageBuffer::save_falsecolour_image`]
+    /// for PNG output, legends, contours or bands.
     pub fn save_falsecolour(
         &self,
         min: Option<Float>,
@@ -354,64 +735,18 @@ impl ImageBuffer {
         scale: Colourmap,
         outfile: &Path,
     ) {
-        let luminance: Vec<Float> = self.pixels.iter().map(|x| x.luminance()).collect();
-
-        const MIN_MIN: Float = 0.000;
-        // Get min and max
-        let min = match min {
-            Some(mut v) => {
-                v = v.max(MIN_MIN);
-                v
-            }
-            None => MIN_MIN,
-        };
-        let max = match max {
-            Some(v) => v,
-            None => {
-                /* // From RADIANCE's Falsecolor.pl
-                 # Find a good scale for auto mode.
-                if ($scale =~ m/[aA].* /) {
-                    my @histo = split(/\s/, `phisto $picture`);
-                    # e.g. $ phisto tests/richmond.hdr| tail -2
-                    # 3.91267	14
-                    # 3.94282	6
-                    my $LogLmax = $histo[-4];
-                    $scale = $mult / 179 * 10**$LogLmax;
-                }
-                */
-                let mut m = MIN_MIN;
-                luminance.iter().for_each(|v| {
-                    if *v > m {
-                        m = *v
-                    }
-                });
-                if m <= MIN_MIN {
-                    m = 2. * MIN_MIN;
-                }
-                m
-            }
+        let options = FalsecolourOptions {
+            min,
+            max,
+            log: false,
+            colourmap: scale,
+            mode: FalsecolourMode::Continuous,
+            legend: None,
+            ..FalsecolourOptions::default()
         };
-
-        let scale = match scale {
-            Colourmap::Inferno => crate::colourmap::inferno::INFERNO_COLOURMAP.as_slice(),
-            Colourmap::Magma => crate::colourmap::magma::MAGMA_COLOURMAP.as_slice(),
-            Colourmap::Plasma => crate::colourmap::plasma::PLASMA_COLOURMAP.as_slice(),
-            Colourmap::Radiance => crate::colourmap::radiance::RADIANCE_COLOURMAP.as_slice(),
-            Colourmap::Viridis => crate::colourmap::viridis::VIRIDIS_COLOURMAP.as_slice(),
-        };
-
-        let mut data: Vec<u8> = Vec::with_capacity(self.width * self.height * 3);
-        luminance.iter().for_each(|x| {
-            let s = crate::colourmap::map_linear_colour(*x, min, max, scale);
-            data.push((s[0] * 256.).round() as u8);
-            data.push((s[1] * 256.).round() as u8);
-            data.push((s[2] * 256.).round() as u8);
-        });
-
-        let encoder = Encoder::new_file(outfile, 100).unwrap();
-        encoder
-            .encode(&data, self.width as u16, self.height as u16, ColorType::Rgb)
-            .unwrap();
+        // Always a JPEG, whatever the extension of `outfile`
+        let (width, height, data) = falsecolour(self, &options);
+        Self::write_jpeg(&data, width, height, outfile)
     }
 }
 
@@ -591,6 +926,135 @@ mod tests {
         .unwrap();
     }
 
+    #[test]
+    fn test_pfm_roundtrip() {
+        let width = 3;
+        let height = 2;
+        let pixels: Vec<Spectrum<{ crate::N_CHANNELS }>> = (0..width * height)
+            .map(|i| {
+                let i = i as Float;
+                Spectrum::<{ crate::N_CHANNELS }>([i, -2. * i, 0.5 + i])
+            })
+            .collect();
+        let buffer = ImageBuffer::from_pixels(width, height, pixels);
+
+        let filename = std::env::temp_dir().join("rendering_test_pfm_roundtrip.pfm");
+        buffer.save(&filename).unwrap();
+        let found = ImageBuffer::from_file(&filename).unwrap();
+
+        assert_eq!(found.width, width);
+        assert_eq!(found.height, height);
+        for (a, b) in buffer.pixels.iter().zip(found.pixels.iter()) {
+            // PFM stores f32... so this should be exact
+            assert_eq!(a, b);
+        }
+    }
+
+    #[test]
+    fn test_pfm_errors() {
+        let filename = std::env::temp_dir().join("rendering_test_pfm_errors.pfm");
+
+        // Images without pixels, or whose pixels do not match their size
+        let empty = ImageBuffer {
+            width: 0,
+            height: 2,
+            pixels: Vec::new(),
+        };
+        assert!(empty.save_pfm(&filename).is_err());
+        let wrong = ImageBuffer {
+            width: 2,
+            height: 2,
+            pixels: vec![Spectrum::<{ crate::N_CHANNELS }>::BLACK; 3],
+        };
+        assert!(wrong.save_pfm(&filename).is_err());
+
+        // Files whose header does not match their data
+        let mut data = b"PF\n0 2\n-1.0\n".to_vec();
+        std::fs::write(&filename, &data).unwrap();
+        assert!(ImageBuffer::from_pfm_file(&filename).is_err());
+
+        data = b"Pf\n2 2\n-1.0\n".to_vec();
+        data.extend_from_slice(&[0; 3 * 4]);
+        std::fs::write(&filename, &data).unwrap();
+        assert!(ImageBuffer::from_pfm_file(&filename).is_err());
+        data.extend_from_slice(&[0; 4]);
+        std::fs::write(&filename, &data).unwrap();
+        assert!(ImageBuffer::from_pfm_file(&filename).is_ok());
+        data.extend_from_slice(&[0; 4]);
+        std::fs::write(&filename, &data).unwrap();
+        assert!(ImageBuffer::from_pfm_file(&filename).is_err());
+
+        let _ = std::fs::remove_file(&filename);
+    }
+
+    #[test]
+    fn test_pfm_scale() {
+        let filename = std::env::temp_dir().join("rendering_test_pfm_scale.pfm");
+
+        // A 1x2 little-endian grayscale image, scaled by 2
+        let mut data = b"Pf\n1 2\n-2.0\n".to_vec();
+        data.extend_from_slice(&1f32.to_le_bytes());
+        data.extend_from_slice(&3f32.to_le_bytes());
+        std::fs::write(&filename, &data).unwrap();
+        let found = ImageBuffer::from_pfm_file(&filename).unwrap();
+        // Rows are stored from bottom to top
+        assert_eq!(found.pixels[0], Spectrum::<{ crate::N_CHANNELS }>::gray(6.));
+        assert_eq!(found.pixels[1], Spectrum::<{ crate::N_CHANNELS }>::gray(2.));
+
+        // ... which survives being written back and read again
+        found.save_pfm(&filename).unwrap();
+        let again = ImageBuffer::from_pfm_file(&filename).unwrap();
+        assert_eq!(found.pixels, again.pixels);
+
+        // A zero scale is not valid
+        data = b"Pf\n1 1\n0.0\n".to_vec();
+        data.extend_from_slice(&1f32.to_be_bytes());
+        std::fs::write(&filename, &data).unwrap();
+        assert!(ImageBuffer::from_pfm_file(&filename).is_err());
+
+        let _ = std::fs::remove_file(&filename);
+    }
+
+    #[test]
+    #[cfg(feature = "exr")]
+    fn test_exr_roundtrip() {
+        let width = 3;
+        let height = 2;
+        let pixels: Vec<Spectrum<{ crate::N_CHANNELS }>> = (0..width * height)
+            .map(|i| {
+                let i = i as Float;
+                Spectrum::<{ crate::N_CHANNELS }>([i, -2. * i, 0.5 + i])
+            })
+            .collect();
+        let buffer = ImageBuffer::from_pixels(width, height, pixels);
+
+        let filename = std::env::temp_dir().join("rendering_test_exr_roundtrip.exr");
+        buffer.save(&filename).unwrap();
+        let found = ImageBuffer::from_file(&filename).unwrap();
+        assert_eq!(found.width, width);
+        assert_eq!(found.height, height);
+        for (a, b) in buffer.pixels.iter().zip(found.pixels.iter()) {
+            // These values fit in an f32... so this should be exact
+            assert_eq!(a, b);
+        }
+
+        // Half precision only keeps about three significant digits
+        buffer.save_exr(&filename, ExrPrecision::Half, &[]).unwrap();
+        let found = ImageBuffer::from_exr_file(&filename).unwrap();
+        for (a, b) in buffer.pixels.iter().zip(found.pixels.iter()) {
+            for (x, y) in a.0.iter().zip(b.0.iter()) {
+                assert!((x - y).abs() < 1e-2, "expected {}, found {}", x, y);
+            }
+        }
+
+        // Extra channels need one value per pixel
+        let depth: &[Float] = &[1., 2.];
+        assert!(buffer
+            .save_exr(&filename, ExrPrecision::Float, &[("Z", depth)])
+            .is_err());
+        let _ = std::fs::remove_file(&filename);
+    }
+
     // #[test]
     // #[ignore]
     // fn test_from_file() {
@@ -0,0 +1,516 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Instancing: the same [`Mesh`] placed in the scene several times (e.g.,
+//! repeated furniture, facade panels or trees), each with its own
+//! [`Transform`], without copying its triangles.
+
+use crate::bvh::BoundingVolumeTree;
+use crate::primitive::Primitive;
+use crate::ray::Ray;
+use crate::triangle::{world_bounds, Triangle};
+use crate::Float;
+use geometry3d::{BBox3D, BBoxAxis, Point3D, Ray3D, Transform, Vector3D};
+use std::cmp::Ordering;
+
+/// The maximum depth of the [`InstanceTree`] that can be traversed
+const MAX_DEPTH: usize = 64;
+
+/// A set of triangles, in object space, that can be placed in the
+/// `Scene` several times through [`Instance`]s.
+#[derive(Default, Clone)]
+pub struct Mesh {
+    /// The triangles, in object space
+    pub triangles: Vec<Triangle>,
+
+    /// The normal of each vertex of each triangle, in object space
+    pub normals: Vec<(Vector3D, Vector3D, Vector3D)>,
+
+    /// The index of the front material of each triangle, in the
+    /// `materials` of the `Scene`
+    pub front_material_indexes: Vec<usize>,
+
+    /// The index of the back material of each triangle, in the
+    /// `materials` of the `Scene`
+    pub back_material_indexes: Vec<usize>,
+
+    /// The acceleration structure of this mesh, which is shared by
+    /// all of its instances. It is built with that of the `Scene`.
+    pub accelerator: Option<BoundingVolumeTree>,
+}
+
+impl Mesh {
+    /// Creates an empty mesh
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pushes a [`Primitive`] into the [`Mesh`]. Only spheres and triangles
+    /// are supported.
+    pub fn push_object(
+        &mut self,
+        front_material_index: usize,
+        back_material_index: usize,
+        primitive: Primitive,
+    ) {
+        let (triangles, normals) = match &primitive {
+            Primitive::Triangle(tr) => crate::triangle::mesh_triangle(tr),
+            Primitive::Sphere(s) => crate::triangle::mesh_sphere(s),
+            _ => panic!("Unsupported Primitive '{}' in Mesh", primitive.id()),
+        };
+        self.triangles.extend_from_slice(&triangles);
+        self.normals.extend_from_slice(&normals);
+        let n = self.triangles.len();
+        self.front_material_indexes.resize(n, front_material_index);
+        self.back_material_indexes.resize(n, back_material_index);
+    }
+
+    /// Builds the acceleration structure of the mesh. This
+    /// sorts the triangles.
+    pub fn build_accelerator(&mut self) {
+        let accelerator = BoundingVolumeTree::new_for_mesh(self);
+        self.accelerator = Some(accelerator);
+    }
+
+    /// The bounds of the mesh, in object space
+    fn bounds(&self) -> Option<BBox3D> {
+        self.triangles
+            .iter()
+            .map(world_bounds)
+            .reduce(|a, b| BBox3D::from_union(&a, &b))
+    }
+}
+
+/// A [`Mesh`] placed in the scene
+#[derive(Clone)]
+pub struct Instance {
+    /// The index of the [`Mesh`] in the `meshes` of the `Scene`
+    pub mesh: usize,
+
+    /// Goes from object space to world space
+    transform: Transform,
+
+    /// Goes from world space to object space
+    inverse: Transform,
+}
+
+impl Instance {
+    /// Places the `mesh` in the scene, through a `transform`
+    /// from object space to world space
+    pub fn new(mesh: usize, transform: Transform) -> Self {
+        let inverse = transform.inverse();
+        Self {
+            mesh,
+            transform,
+            inverse,
+        }
+    }
+
+    /// The transform from object space to world space
+    pub fn transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    /// Transforms a normal from object space to world space
+    pub fn normal_to_world(&self, normal: Vector3D) -> Vector3D {
+        self.transform.transform_normal(normal).get_normalized()
+    }
+
+    /// The bounds of the instance, in world space. These contain
+    /// the transformed corners of the bounds of the `mesh`.
+    pub fn bounds(&self, mesh: &Mesh) -> Option<BBox3D> {
+        let b = mesh.bounds()?;
+        let corners = [
+            Point3D::new(b.min.x, b.min.y, b.min.z),
+            Point3D::new(b.max.x, b.min.y, b.min.z),
+            Point3D::new(b.min.x, b.max.y, b.min.z),
+            Point3D::new(b.max.x, b.max.y, b.min.z),
+            Point3D::new(b.min.x, b.min.y, b.max.z),
+            Point3D::new(b.max.x, b.min.y, b.max.z),
+            Point3D::new(b.min.x, b.max.y, b.max.z),
+            Point3D::new(b.max.x, b.max.y, b.max.z),
+        ];
+        let first = BBox3D::from_point(self.transform.transform_pt(corners[0]));
+        let bounds = corners.iter().skip(1).fold(first, |bounds, c| {
+            BBox3D::from_union_point(&bounds, self.transform.transform_pt(*c))
+        });
+        Some(bounds)
+    }
+}
+
+/// A node of the [`InstanceTree`]. As in the [`BoundingVolumeTree`], the first
+/// child of an interior node is the one that follows it.
+#[derive(Clone)]
+struct InstanceNode {
+    bounds: BBox3D,
+    /// The number of instances in the node. Interior nodes have zero.
+    n_instances: usize,
+    /// The second child, for interior nodes; the position of the
+    /// first instance in `order`, for leaves
+    next: usize,
+}
+
+/// The top-level acceleration structure of the `Scene`, built over
+/// the bounds of its [`Instance`]s. Rays are taken into the object space
+/// of each instance they reach, and traced through its [`Mesh`]'s
+/// [`BoundingVolumeTree`].
+///
+/// Triangles hit within an instance are identified by indexes that come
+/// after those of the triangles in the `Scene`. The `Scene` translates them back
+/// (see [`InstanceTree::locate`]).
+#[derive(Default, Clone)]
+pub struct InstanceTree {
+    nodes: Vec<InstanceNode>,
+
+    /// The indexes of the instances, sorted so that those
+    /// in each leaf are contiguous
+    order: Vec<usize>,
+
+    /// The index of the first triangle of each instance, counting
+    /// the triangles of the previous instances
+    first_triangle: Vec<usize>,
+}
+
+impl InstanceTree {
+    /// Builds the tree. The accelerators of the `meshes` must have been built.
+    pub fn new(meshes: &[Mesh], instances: &[Instance]) -> Self {
+        let mut first_triangle = Vec::with_capacity(instances.len());
+        let mut n_triangles = 0;
+        for instance in instances {
+            first_triangle.push(n_triangles);
+            n_triangles += meshes[instance.mesh].triangles.len();
+        }
+
+        // Empty meshes are left out of the tree
+        let mut info: Vec<(usize, BBox3D, Point3D)> = instances
+            .iter()
+            .enumerate()
+            .filter_map(|(i, instance)| {
+                let bounds = instance.bounds(&meshes[instance.mesh])?;
+                Some((i, bounds, (bounds.max + bounds.min) * 0.5))
+            })
+            .collect();
+
+        let mut nodes = Vec::with_capacity(2 * info.len());
+        if !info.is_empty() {
+            Self::recursive_build(&mut info, 0, &mut nodes);
+        }
+        Self {
+            nodes,
+            order: info.into_iter().map(|(i, ..)| i).collect(),
+            first_triangle,
+        }
+    }
+
+    /// Builds the nodes over `info`, splitting the instances in halves
+    /// along the axis in which their centroids spread the most. Returns the
+    /// position of the node created.
+    fn recursive_build(
+        info: &mut [(usize, BBox3D, Point3D)],
+        offset: usize,
+        nodes: &mut Vec<InstanceNode>,
+    ) -> usize {
+        const MAX_IN_LEAF: usize = 2;
+
+        let bounds = info
+            .iter()
+            .skip(1)
+            .fold(info[0].1, |b, (_, ib, _)| BBox3D::from_union(&b, ib));
+        let this_offset = nodes.len();
+        nodes.push(InstanceNode {
+            bounds,
+            n_instances: info.len(),
+            next: offset,
+        });
+        if info.len() <= MAX_IN_LEAF {
+            return this_offset;
+        }
+
+        let centroids = info
+            .iter()
+            .skip(1)
+            .fold(BBox3D::from_point(info[0].2), |b, (.., c)| {
+                BBox3D::from_union_point(&b, *c)
+            });
+        let split_axis = centroids.max_extent();
+        let coordinate = |p: &Point3D| -> Float {
+            match split_axis {
+                BBoxAxis::X => p.x,
+                BBoxAxis::Y => p.y,
+                BBoxAxis::Z => p.z,
+            }
+        };
+        let mid = info.len() / 2;
+        info.select_nth_unstable_by(mid, |a, b| {
+            coordinate(&a.2)
+                .partial_cmp(&coordinate(&b.2))
+                .unwrap_or(Ordering::Equal)
+        });
+
+        let (left, right) = info.split_at_mut(mid);
+        nodes[this_offset].n_instances = 0;
+        Self::recursive_build(left, offset, nodes);
+        nodes[this_offset].next = Self::recursive_build(right, offset + mid, nodes);
+        this_offset
+    }
+
+    /// Gets the instance and the index of the triangle within its
+    /// [`Mesh`] that correspond to an `index` counted from the first
+    /// triangle of the first instance
+    pub fn locate(&self, index: usize) -> (usize, usize) {
+        let instance = self.first_triangle.partition_point(|first| *first <= index) - 1;
+        (instance, index - self.first_triangle[instance])
+    }
+
+    /// Intersects the instances that are closer to the origin of the
+    /// ray than `sqrt(max_distance_squared)`. If one is hit, the `interaction`
+    /// of the ray is set in world space and the index of the triangle hit,
+    /// counted from the first triangle of the first instance, is returned.
+    pub fn intersect(
+        &self,
+        meshes: &[Mesh],
+        instances: &[Instance],
+        ray: &mut Ray,
+        max_distance_squared: Float,
+        nodes_to_visit: &mut Vec<usize>,
+    ) -> Option<usize> {
+        let mut closest = None;
+        let mut best_distance_squared = max_distance_squared;
+        let geometry = ray.geometry;
+        self.visit_instances(&geometry, |i| {
+            let instance = &instances[i];
+            let mesh = &meshes[instance.mesh];
+            let accelerator = mesh
+                .accelerator
+                .as_ref()
+                .expect("Trying to intersect a Mesh without an acceleration structure");
+
+            let mut local_ray = *ray;
+            local_ray.transform(&instance.inverse);
+            if let Some(index) =
+                accelerator.intersect(&mesh.triangles, &mut local_ray, nodes_to_visit)
+            {
+                let interaction = local_ray.interaction.transform(&instance.transform);
+                let distance_squared = (interaction.point - geometry.origin).length_squared();
+                if distance_squared < best_distance_squared {
+                    best_distance_squared = distance_squared;
+                    ray.interaction = interaction;
+                    closest = Some(self.first_triangle[i] + index);
+                }
+            }
+            true
+        });
+        closest
+    }
+
+    /// Checks whether a ray can travel a certain distance without
+    /// hitting any instance
+    pub fn unobstructed_distance(
+        &self,
+        meshes: &[Mesh],
+        instances: &[Instance],
+        ray: &Ray3D,
+        distance_squared: Float,
+        nodes_to_visit: &mut Vec<usize>,
+    ) -> bool {
+        let dir_length_squared = ray.direction.length_squared();
+        self.visit_instances(ray, |i| {
+            let instance = &instances[i];
+            let mesh = &meshes[instance.mesh];
+            let accelerator = mesh
+                .accelerator
+                .as_ref()
+                .expect("Trying to intersect a Mesh without an acceleration structure");
+
+            let (local_ray, ..) = instance.inverse.transform_ray(ray);
+            // The parametric distance along the ray is the same in both
+            // spaces, but the length of the direction is not
+            let local_distance_squared =
+                distance_squared * local_ray.direction.length_squared() / dir_length_squared;
+            accelerator.unobstructed_distance(
+                &mesh.triangles,
+                &local_ray,
+                local_distance_squared,
+                nodes_to_visit,
+            )
+        })
+    }
+
+    /// Calls `f` with the index of each instance whose bounds are hit by
+    /// the `ray`, until it returns `false`. Returns whether all the
+    /// instances were visited.
+    fn visit_instances<F: FnMut(usize) -> bool>(&self, ray: &Ray3D, mut f: F) -> bool {
+        if self.nodes.is_empty() {
+            return true;
+        }
+        let inv_dir = Vector3D::new(
+            1. / ray.direction.x,
+            1. / ray.direction.y,
+            1. / ray.direction.z,
+        );
+        // The stack of nodes to visit is kept here, as that of
+        // the meshes is used when visiting the instances
+        let mut to_visit = [0; MAX_DEPTH];
+        let mut n_to_visit = 0;
+        let mut current_node = 0;
+        loop {
+            let node = &self.nodes[current_node];
+            if node.bounds.intersect(ray, &inv_dir) {
+                if node.n_instances > 0 {
+                    for i in &self.order[node.next..node.next + node.n_instances] {
+                        if !f(*i) {
+                            return false;
+                        }
+                    }
+                } else {
+                    to_visit[n_to_visit] = node.next;
+                    n_to_visit += 1;
+                    current_node += 1;
+                    continue;
+                }
+            }
+            if n_to_visit == 0 {
+                break;
+            }
+            n_to_visit -= 1;
+            current_node = to_visit[n_to_visit];
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colour::Spectrum;
+    use crate::material::{Material, Plastic};
+    use crate::Scene;
+    use geometry3d::Sphere3D;
+
+    fn plastic() -> Material {
+        Material::Plastic(Plastic {
+            colour: Spectrum::<{ crate::N_CHANNELS }>::from(0.5),
+            specularity: 0.,
+            roughness: 0.,
+        })
+    }
+
+    /// The same spheres, pushed as objects and as instances of
+    /// a single sphere at the origin
+    fn get_scenes() -> (Scene, Scene) {
+        let centres = [
+            Vector3D::new(-2., 0., 0.),
+            Vector3D::new(2., 0., 0.),
+            Vector3D::new(0., 3., 1.),
+        ];
+
+        let mut flat = Scene::new();
+        let mat = flat.push_material(plastic());
+        for c in centres.iter() {
+            let sphere = Sphere3D::new(0.5, Point3D::new(c.x, c.y, c.z));
+            flat.push_object(mat, mat, Primitive::Sphere(sphere));
+        }
+        flat.build_accelerator();
+
+        let mut instanced = Scene::new();
+        let mat = instanced.push_material(plastic());
+        let mut mesh = Mesh::new();
+        let sphere = Sphere3D::new(0.5, Point3D::new(0., 0., 0.));
+        mesh.push_object(mat, mat, Primitive::Sphere(sphere));
+        let mesh = instanced.push_mesh(mesh);
+        for c in centres.iter() {
+            instanced.push_instance(mesh, Transform::translate(*c));
+        }
+        instanced.build_accelerator();
+
+        (flat, instanced)
+    }
+
+    #[test]
+    fn test_locate() {
+        let (_, scene) = get_scenes();
+        let tree = scene.instance_accelerator.as_ref().unwrap();
+        let n = scene.meshes[0].triangles.len();
+        assert_eq!(tree.locate(0), (0, 0));
+        assert_eq!(tree.locate(n - 1), (0, n - 1));
+        assert_eq!(tree.locate(n), (1, 0));
+        assert_eq!(tree.locate(2 * n + 3), (2, 3));
+    }
+
+    #[test]
+    fn test_instances_match_objects() {
+        let (flat, instanced) = get_scenes();
+        let mut aux = Vec::new();
+        let origins = [
+            Point3D::new(-2., -5., 0.),
+            Point3D::new(2., -5., 0.1),
+            Point3D::new(0., 3., 6.),
+            Point3D::new(0., 0., 0.),
+        ];
+        let directions = [
+            Vector3D::new(0., 1., 0.),
+            Vector3D::new(0., 1., 0.),
+            Vector3D::new(0., 0., -1.),
+            Vector3D::new(1., 0., 0.),
+        ];
+        for (origin, direction) in origins.iter().zip(directions.iter()) {
+            let geometry = Ray3D {
+                origin: *origin,
+                direction: *direction,
+            };
+            let mut flat_ray = Ray {
+                geometry,
+                ..Ray::default()
+            };
+            let mut instanced_ray = flat_ray;
+
+            let flat_hit = flat.cast_ray(&mut flat_ray, &mut aux);
+            let instanced_hit = instanced.cast_ray(&mut instanced_ray, &mut aux);
+            assert!(flat_hit.is_some());
+            let index = instanced_hit.unwrap();
+            assert!(index >= instanced.triangles.len());
+
+            let flat_pt = flat_ray.interaction.point;
+            let instanced_pt = instanced_ray.interaction.point;
+            assert!((flat_pt - instanced_pt).length() < 1e-5);
+            let flat_normal = flat_ray.interaction.geometry_shading.normal;
+            let instanced_normal = instanced_ray.interaction.geometry_shading.normal;
+            assert!((flat_normal - instanced_normal).length() < 1e-5);
+
+            assert_eq!(instanced.front_material_index(index), 0);
+            let (n0, ..) = instanced.normals_at(index);
+            assert!((n0.length() - 1.).abs() < 1e-5);
+
+            let d2 = (flat_pt - *origin).length_squared();
+            assert!(!instanced.unobstructed_distance(&geometry, d2 * 1.1, &mut aux));
+            assert!(instanced.unobstructed_distance(&geometry, d2 * 0.9, &mut aux));
+        }
+
+        // A ray that misses everything
+        let mut ray = Ray {
+            geometry: Ray3D {
+                origin: Point3D::new(0., -5., 0.),
+                direction: Vector3D::new(0., 0., 1.),
+            },
+            ..Ray::default()
+        };
+        assert!(instanced.cast_ray(&mut ray, &mut aux).is_none());
+    }
+}
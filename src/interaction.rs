@@ -18,10 +18,65 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
+use crate::Float;
 use geometry3d::intersection::IntersectionInfo;
 use geometry3d::intersection::SurfaceSide;
 use geometry3d::{Point3D, Transform, Vector3D};
 
+/// Gets the smallest floating point number larger than `v`
+fn next_float_up(v: Float) -> Float {
+    if v.is_infinite() && v > 0. {
+        return v;
+    }
+    // Skip the negative zero
+    let v = if v == -0. { 0. } else { v };
+    if v >= 0. {
+        Float::from_bits(v.to_bits() + 1)
+    } else {
+        Float::from_bits(v.to_bits() - 1)
+    }
+}
+
+/// Gets the largest floating point number smaller than `v`
+fn next_float_down(v: Float) -> Float {
+    -next_float_up(-v)
+}
+
+/// Moves the `point` of a surface—which has an absolute error of up to
+/// `error` in each axis—along its geometric `normal`, so that rays leaving it in
+/// `direction` do not hit the same surface again. The offset grows with the
+/// error of the point instead of being fixed, so it works both close to and
+/// far away from the origin (see Section 3.9.5 of Pharr et al., Physically
+/// Based Rendering).
+pub fn offset_ray_origin(
+    point: Point3D,
+    error: Vector3D,
+    normal: Vector3D,
+    direction: Vector3D,
+) -> Point3D {
+    let d = normal.x.abs() * error.x + normal.y.abs() * error.y + normal.z.abs() * error.z;
+    let mut offset = normal * d;
+    if direction * normal < 0. {
+        offset *= -1.;
+    }
+    let p = point + offset;
+    // Round away from the point, so that rounding does not undo the offset
+    let round = |p: Float, offset: Float| {
+        if offset > 0. {
+            next_float_up(p)
+        } else if offset < 0. {
+            next_float_down(p)
+        } else {
+            p
+        }
+    };
+    Point3D::new(
+        round(p.x, offset.x),
+        round(p.y, offset.y),
+        round(p.z, offset.z),
+    )
+}
+
 /// The data for a SurfaceInteraction]
 #[derive(Default, Clone, Copy)]
 pub struct Interaction {
@@ -29,6 +84,9 @@ pub struct Interaction {
     /// The [`Point3D`] of the interaction
     pub point: Point3D,
 
+    /// A conservative bound on the absolute error of `point`, in each axis
+    pub perror: Vector3D,
+
     /// The outgoing direction at the interaction.
     /// This is the negative ray direction
     pub wo: Vector3D,
@@ -44,8 +102,7 @@ pub struct Interaction {
 
 impl Interaction {
     pub fn transform(&self, t: &Transform) -> Self {
-        // let (point, perror) = t.transform_pt_propagate_error(self.point, self.perror);
-        let point = t.transform_pt(self.point);
+        let (point, perror) = t.transform_pt_propagate_error(self.point, self.perror);
         let wo = t.transform_vec(self.wo);
 
         // shading
@@ -53,11 +110,31 @@ impl Interaction {
 
         Self {
             point,
+            perror,
             wo,
             geometry_shading,
         }
     }
 
+    /// The normal of the surface itself, facing the side that was hit. Unlike
+    /// `geometry_shading.normal`, this is not changed by [`Self::interpolate_normal`].
+    pub fn geometric_normal(&self) -> Vector3D {
+        let info = &self.geometry_shading;
+        let normal = info.dpdu.cross(info.dpdv);
+        if normal.length_squared() > 0. {
+            normal.get_normalized()
+        } else {
+            info.normal
+        }
+    }
+
+    /// Gets the origin of a ray leaving the interaction in `direction`, which
+    /// is offset from `point` so that the ray does not hit the same surface
+    /// again (see [`offset_ray_origin`])
+    pub fn spawn_ray_origin(&self, direction: Vector3D) -> Point3D {
+        offset_ray_origin(self.point, self.perror, self.geometric_normal(), direction)
+    }
+
     pub fn interpolate_normal(&mut self, normals: (Vector3D, Vector3D, Vector3D)) {
         let n0 = normals.0;
         let n1 = normals.1;
@@ -0,0 +1,527 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! An irradiance cache (i.e., Radiance's "ambient cache"), based on
+//! "Irradiance Gradients" (1992), by Gregory Ward and Paul Heckbert, and
+//! on "Practical Global Illumination with Irradiance Caching" (2009),
+//! by Jaroslav Křivánek and Pascal Gautron.
+
+use crate::colour::Spectrum;
+use crate::rand::*;
+use crate::scene::Scene;
+use crate::{Float, PI};
+use geometry3d::{Point3D, Vector3D};
+use std::io::Write;
+use std::path::Path;
+use std::sync::RwLock;
+
+/// The maximum depth of the octree holding the records
+const MAX_OCTREE_DEPTH: usize = 16;
+
+/// A direction sampled from the hemisphere over a point, and
+/// what was found in that direction
+pub struct HemisphereSample {
+    /// The direction, in local coordinates (i.e., `z` is the normal)
+    pub direction: Vector3D,
+
+    /// The radiance arriving from `direction`
+    pub radiance: Spectrum<{ crate::N_CHANNELS }>,
+
+    /// The distance to the surface found in `direction`
+    /// (`Float::INFINITY` if nothing was hit)
+    pub distance: Float,
+}
+
+/// The irradiance at a point, and how it changes around it
+#[derive(Clone, Copy)]
+pub struct IrradianceRecord {
+    /// The position of the record
+    pub point: Point3D,
+
+    /// The normal of the surface at `point`
+    pub normal: Vector3D,
+
+    /// The (clamped) harmonic mean distance to the surrounding surfaces
+    pub radius: Float,
+
+    /// The indirect irradiance
+    pub irradiance: Spectrum<{ crate::N_CHANNELS }>,
+
+    /// How the irradiance changes when the normal rotates, per channel
+    pub rotational_gradient: [Vector3D; crate::N_CHANNELS],
+
+    /// How the irradiance changes when the point moves, per channel
+    pub translational_gradient: [Vector3D; crate::N_CHANNELS],
+}
+
+/// A node of the octree that accelerates lookups
+#[derive(Default)]
+struct OctreeNode {
+    /// The indexes of the records stored in this node
+    records: Vec<usize>,
+    children: Option<Box<[OctreeNode; 8]>>,
+}
+
+/// Returns the centre of the `i`th child of a node
+fn child_centre(centre: Point3D, half_size: Float, i: usize) -> Point3D {
+    let q = half_size / 2.;
+    let dx = if i & 1 == 0 { -q } else { q };
+    let dy = if i & 2 == 0 { -q } else { q };
+    let dz = if i & 4 == 0 { -q } else { q };
+    centre + Vector3D::new(dx, dy, dz)
+}
+
+impl OctreeNode {
+    /// Stores a record in the nodes that overlap its area of influence
+    fn insert(
+        &mut self,
+        index: usize,
+        centre: Point3D,
+        half_size: Float,
+        point: Point3D,
+        radius: Float,
+        depth: usize,
+    ) {
+        if depth == MAX_OCTREE_DEPTH || half_size < radius {
+            self.records.push(index);
+            return;
+        }
+        let children = self.children.get_or_insert_with(Default::default);
+        let q = half_size / 2.;
+        for (i, child) in children.iter_mut().enumerate() {
+            let c = child_centre(centre, half_size, i);
+            if (point.x - c.x).abs() <= q + radius
+                && (point.y - c.y).abs() <= q + radius
+                && (point.z - c.z).abs() <= q + radius
+            {
+                child.insert(index, c, q, point, radius, depth + 1);
+            }
+        }
+    }
+
+    /// Visits the records that might be valid at `point`
+    fn lookup<F: FnMut(usize)>(
+        &self,
+        centre: Point3D,
+        half_size: Float,
+        point: Point3D,
+        f: &mut F,
+    ) {
+        self.records.iter().for_each(|i| f(*i));
+        if let Some(children) = &self.children {
+            let i = (point.x > centre.x) as usize
+                | ((point.y > centre.y) as usize) << 1
+                | ((point.z > centre.z) as usize) << 2;
+            children[i].lookup(child_centre(centre, half_size, i), half_size / 2., point, f)
+        }
+    }
+}
+
+struct CacheData {
+    records: Vec<IrradianceRecord>,
+    root: OctreeNode,
+}
+
+/// A cache of [`IrradianceRecord`] that can be shared across threads.
+pub struct IrradianceCache {
+    /// The maximum error allowed when interpolating (like Radiance's `-aa`).
+    /// Lower values produce more records.
+    pub accuracy: Float,
+
+    /// The minimum radius of a record (like Radiance's `-ar`)
+    pub min_spacing: Float,
+
+    /// The maximum radius of a record
+    pub max_spacing: Float,
+
+    centre: Point3D,
+    half_size: Float,
+    data: RwLock<CacheData>,
+}
+
+impl IrradianceCache {
+    /// Creates an empty cache covering the objects in a [`Scene`]
+    pub fn new(scene: &Scene, accuracy: Float) -> Self {
+        let mut min = Point3D::new(Float::MAX, Float::MAX, Float::MAX);
+        let mut max = Point3D::new(Float::MIN, Float::MIN, Float::MIN);
+        for t in scene.triangles.iter() {
+            for v in t.chunks_exact(3) {
+                min = Point3D::new(min.x.min(v[0]), min.y.min(v[1]), min.z.min(v[2]));
+                max = Point3D::new(max.x.max(v[0]), max.y.max(v[1]), max.z.max(v[2]));
+            }
+        }
+        if scene.triangles.is_empty() {
+            min = Point3D::new(-1., -1., -1.);
+            max = Point3D::new(1., 1., 1.);
+        }
+        let size = (max.x - min.x)
+            .max(max.y - min.y)
+            .max(max.z - min.z)
+            .max(1e-3);
+        let centre = Point3D::new(
+            (max.x + min.x) / 2.,
+            (max.y + min.y) / 2.,
+            (max.z + min.z) / 2.,
+        );
+
+        // Same defaults as Radiance (i.e., -ar 128)
+        let min_spacing = size / 128.;
+        Self {
+            accuracy,
+            min_spacing,
+            max_spacing: 64. * min_spacing,
+            centre,
+            half_size: 0.51 * size,
+            data: RwLock::new(CacheData {
+                records: Vec::new(),
+                root: OctreeNode::default(),
+            }),
+        }
+    }
+
+    /// The number of records in the cache
+    pub fn len(&self) -> usize {
+        self.data.read().unwrap().records.len()
+    }
+
+    /// Checks whether the cache has no records
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds a record to the cache
+    pub fn push(&self, record: IrradianceRecord) {
+        let mut data = self.data.write().unwrap();
+        let index = data.records.len();
+        data.records.push(record);
+        data.root.insert(
+            index,
+            self.centre,
+            self.half_size,
+            record.point,
+            self.accuracy * record.radius,
+            0,
+        );
+    }
+
+    /// Interpolates the irradiance at a point from the records in
+    /// the cache. Returns `None` if no record is valid at `point`.
+    pub fn interpolate(
+        &self,
+        point: Point3D,
+        normal: Vector3D,
+    ) -> Option<Spectrum<{ crate::N_CHANNELS }>> {
+        let data = self.data.read().unwrap();
+        let mut sum = Spectrum::<{ crate::N_CHANNELS }>::BLACK;
+        let mut sum_weights = 0.0;
+        data.root
+            .lookup(self.centre, self.half_size, point, &mut |index| {
+                let record = &data.records[index];
+                let d = point - record.point;
+
+                // Records in front of the point are not valid
+                if d * (normal + record.normal) * 0.5 < -0.01 * record.radius {
+                    return;
+                }
+                let error =
+                    d.length() / record.radius + (1. - (normal * record.normal).min(1.)).sqrt();
+                if error >= self.accuracy {
+                    return;
+                }
+                let w = 1. / error.max(1e-9);
+
+                let rotation = record.normal.cross(normal);
+                let mut value = record.irradiance;
+                for c in 0..crate::N_CHANNELS {
+                    value.0[c] += rotation * record.rotational_gradient[c]
+                        + d * record.translational_gradient[c];
+                    value.0[c] = value.0[c].max(0.0);
+                }
+                sum += value * w;
+                sum_weights += w;
+            });
+
+        if sum_weights > 0. {
+            Some(sum / sum_weights)
+        } else {
+            None
+        }
+    }
+
+    /// Creates a new [`IrradianceRecord`] from stratified samples of the hemisphere
+    /// (see [`stratified_directions`]). `samples` are sorted by `theta` index first.
+    #[allow(clippy::too_many_arguments)]
+    pub fn record(
+        &self,
+        point: Point3D,
+        normal: Vector3D,
+        e1: Vector3D,
+        e2: Vector3D,
+        n_theta: usize,
+        n_phi: usize,
+        samples: &[HemisphereSample],
+    ) -> IrradianceRecord {
+        debug_assert_eq!(samples.len(), n_theta * n_phi);
+        let (m, n) = (n_theta as Float, n_phi as Float);
+        let sample = |j: usize, k: usize| &samples[j * n_phi + k];
+
+        // Irradiance and harmonic mean distance
+        let mut irradiance = Spectrum::<{ crate::N_CHANNELS }>::BLACK;
+        let mut inv_distances = 0.0;
+        for s in samples.iter() {
+            irradiance += s.radiance;
+            inv_distances += 1. / s.distance;
+        }
+        irradiance *= PI / (m * n);
+
+        // Rotational gradient
+        let mut rot = [Vector3D::new(0., 0., 0.); crate::N_CHANNELS];
+        for s in samples.iter() {
+            let cos_theta = s.direction.z;
+            if cos_theta < 1e-6 {
+                continue;
+            }
+            let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+            let phi = s.direction.y.atan2(s.direction.x);
+            let v = Vector3D::new(-phi.sin(), phi.cos(), 0.);
+            let tan_theta = sin_theta / cos_theta;
+            for (c, g) in rot.iter_mut().enumerate() {
+                *g = *g + v * (-tan_theta * s.radiance.0[c] * PI / (m * n));
+            }
+        }
+
+        // Translational gradient
+        let mut trans = [Vector3D::new(0., 0., 0.); crate::N_CHANNELS];
+        for k in 0..n_phi {
+            let phi = 2. * PI * k as Float / n;
+            let u_k = Vector3D::new(phi.cos(), phi.sin(), 0.);
+            let v_k = Vector3D::new(-phi.sin(), phi.cos(), 0.);
+            let prev_k = (k + n_phi - 1) % n_phi;
+            for j in 0..n_theta {
+                let sin2_minus = j as Float / m;
+                let cos_minus = (1. - sin2_minus).sqrt();
+                let cos_plus = (1. - (j + 1) as Float / m).max(0.).sqrt();
+                let sin_centre = ((j as Float + 0.5) / m).sqrt();
+
+                let this = sample(j, k);
+                if j > 0 {
+                    let below = sample(j - 1, k);
+                    let r = this.distance.min(below.distance);
+                    let factor = 2. * PI / n * sin2_minus.sqrt() * (1. - sin2_minus) / r;
+                    for (c, g) in trans.iter_mut().enumerate() {
+                        *g = *g + u_k * (factor * (this.radiance.0[c] - below.radiance.0[c]));
+                    }
+                }
+                let side = sample(j, prev_k);
+                let r = this.distance.min(side.distance);
+                let factor = (cos_minus - cos_plus) / (sin_centre * r);
+                for (c, g) in trans.iter_mut().enumerate() {
+                    *g = *g + v_k * (factor * (this.radiance.0[c] - side.radiance.0[c]));
+                }
+            }
+        }
+
+        // Clamp the radius... it should not be so large that the
+        // gradient predicts negative values
+        let mut radius = if inv_distances > 0. {
+            m * n / inv_distances
+        } else {
+            Float::INFINITY
+        };
+        for (c, g) in trans.iter().enumerate() {
+            let g = g.length();
+            if g > 1e-9 && irradiance.0[c] > 0. {
+                radius = radius.min(irradiance.0[c] / g);
+            }
+        }
+        let radius = radius.clamp(self.min_spacing, self.max_spacing);
+
+        // Go to world coordinates
+        let to_world = |v: Vector3D| e1 * v.x + e2 * v.y + normal * v.z;
+        IrradianceRecord {
+            point,
+            normal,
+            radius,
+            irradiance,
+            rotational_gradient: rot.map(to_world),
+            translational_gradient: trans.map(to_world),
+        }
+    }
+
+    /// Saves the records into a file, so they can be reused (like
+    /// Radiance's `-af` option)
+    pub fn save(&self, filename: &Path) -> Result<(), String> {
+        let data = self.data.read().unwrap();
+        let file = std::fs::File::create(filename).map_err(|e| e.to_string())?;
+        let mut file = std::io::BufWriter::new(file);
+        for r in data.records.iter() {
+            let mut line = format!(
+                "{} {} {} {} {} {} {}",
+                r.point.x, r.point.y, r.point.z, r.normal.x, r.normal.y, r.normal.z, r.radius
+            );
+            for c in 0..crate::N_CHANNELS {
+                let (g, t) = (r.rotational_gradient[c], r.translational_gradient[c]);
+                line += &format!(
+                    " {} {} {} {} {} {} {}",
+                    r.irradiance.0[c], g.x, g.y, g.z, t.x, t.y, t.z
+                );
+            }
+            writeln!(file, "{}", line).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Adds the records stored in a file (see [`IrradianceCache::save`])
+    pub fn load(&self, filename: &Path) -> Result<(), String> {
+        let content = std::fs::read_to_string(filename).map_err(|e| e.to_string())?;
+        let expected = 7 + 7 * crate::N_CHANNELS;
+        for (ln, line) in content.lines().enumerate() {
+            let v = line
+                .split_ascii_whitespace()
+                .map(|x| x.parse::<Float>())
+                .collect::<Result<Vec<Float>, _>>()
+                .map_err(|e| format!("Line {} of file {}: {}", ln + 1, filename.display(), e))?;
+            if v.len() != expected {
+                return Err(format!(
+                    "Line {} of file {}: expecting {} values, found {}",
+                    ln + 1,
+                    filename.display(),
+                    expected,
+                    v.len()
+                ));
+            }
+            let mut record = IrradianceRecord {
+                point: Point3D::new(v[0], v[1], v[2]),
+                normal: Vector3D::new(v[3], v[4], v[5]),
+                radius: v[6],
+                irradiance: Spectrum::<{ crate::N_CHANNELS }>::BLACK,
+                rotational_gradient: [Vector3D::new(0., 0., 0.); crate::N_CHANNELS],
+                translational_gradient: [Vector3D::new(0., 0., 0.); crate::N_CHANNELS],
+            };
+            for c in 0..crate::N_CHANNELS {
+                let i = 7 + 7 * c;
+                record.irradiance.0[c] = v[i];
+                record.rotational_gradient[c] = Vector3D::new(v[i + 1], v[i + 2], v[i + 3]);
+                record.translational_gradient[c] = Vector3D::new(v[i + 4], v[i + 5], v[i + 6]);
+            }
+            self.push(record);
+        }
+        Ok(())
+    }
+}
+
+/// Splits `n_samples` into `n_theta` by `n_phi` cells of the hemisphere
+/// with the same projected area, and returns a cosine-weighted direction (in local
+/// coordinates) within each of them, sorted by `theta` index first.
+pub fn stratified_directions(n_samples: usize, rng: &mut RandGen) -> (usize, usize, Vec<Vector3D>) {
+    let n_theta = ((n_samples as Float / PI).sqrt().round() as usize).max(1);
+    let n_phi = ((n_samples as Float / n_theta as Float).round() as usize).max(1);
+
+    let mut directions = Vec::with_capacity(n_theta * n_phi);
+    for j in 0..n_theta {
+        for k in 0..n_phi {
+            let (u1, u2): (Float, Float) = rng.gen();
+            let sin2_theta = (j as Float + u1) / n_theta as Float;
+            let phi = 2. * PI * (k as Float + u2) / n_phi as Float;
+            let sin_theta = sin2_theta.sqrt();
+            let cos_theta = (1. - sin2_theta).max(0.).sqrt();
+            directions.push(Vector3D::new(
+                phi.cos() * sin_theta,
+                phi.sin() * sin_theta,
+                cos_theta,
+            ));
+        }
+    }
+    (n_theta, n_phi, directions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uniform_record(cache: &IrradianceCache, point: Point3D, value: Float) -> IrradianceRecord {
+        let mut rng = get_rng();
+        let (m, n, directions) = stratified_directions(100, &mut rng);
+        let samples: Vec<HemisphereSample> = directions
+            .into_iter()
+            .map(|direction| HemisphereSample {
+                direction,
+                radiance: Spectrum::<{ crate::N_CHANNELS }>::gray(value),
+                distance: 1.,
+            })
+            .collect();
+        cache.record(
+            point,
+            Vector3D::new(0., 0., 1.),
+            Vector3D::new(1., 0., 0.),
+            Vector3D::new(0., 1., 0.),
+            m,
+            n,
+            &samples,
+        )
+    }
+
+    #[test]
+    fn test_uniform_record() {
+        let scene = Scene::new();
+        let cache = IrradianceCache::new(&scene, 0.2);
+        let record = uniform_record(&cache, Point3D::new(0., 0., 0.), 1.);
+
+        // A uniform environment of radiance 1 produces an irradiance of PI,
+        // and no gradients
+        assert!((record.irradiance.radiance() - PI).abs() < 1e-5);
+        for c in 0..crate::N_CHANNELS {
+            assert!(record.translational_gradient[c].length() < 1e-5);
+        }
+        assert!((record.radius - 1.).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let scene = Scene::new();
+        let mut cache = IrradianceCache::new(&scene, 0.5);
+        cache.min_spacing = 0.01;
+        cache.max_spacing = 10.;
+        let normal = Vector3D::new(0., 0., 1.);
+
+        assert!(cache
+            .interpolate(Point3D::new(0., 0., 0.), normal)
+            .is_none());
+
+        cache.push(uniform_record(&cache, Point3D::new(0., 0., 0.), 1.));
+        assert_eq!(cache.len(), 1);
+
+        // Close by
+        let v = cache
+            .interpolate(Point3D::new(0.1, 0., 0.), normal)
+            .unwrap();
+        assert!((v.radiance() - PI).abs() < 1e-5);
+
+        // Too far
+        assert!(cache
+            .interpolate(Point3D::new(0.9, 0., 0.), normal)
+            .is_none());
+
+        // Facing the other way
+        assert!(cache
+            .interpolate(Point3D::new(0.1, 0., 0.), normal * -1.)
+            .is_none());
+    }
+}
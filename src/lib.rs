@@ -40,12 +40,18 @@ pub const N_CHANNELS: usize = 3;
 
 // Core
 pub mod bvh;
+pub mod cache;
 pub mod camera;
 mod colour;
 pub use colour::Spectrum;
 pub mod colourmap;
+pub mod environment_map;
+pub mod falsecolour;
 pub mod image;
+pub mod instance;
 pub mod interaction;
+pub mod light_tree;
+pub mod lights;
 pub mod material;
 
 pub mod primitive;
@@ -53,10 +59,12 @@ pub mod primitive;
 pub mod primitive_samplers;
 pub mod rand;
 mod ray;
+pub mod sampler;
 pub use ray::Ray;
 pub mod samplers;
 mod scene;
 pub use scene::{Scene, Wavelengths};
+pub mod tonemap;
 pub mod triangle;
 
 // Climate Based Daylight Model
@@ -73,8 +81,17 @@ pub mod from_simple_model;
 // Ray-tracer
 mod ray_tracer;
 pub use ray_tracer::{RayTracer, RayTracerHelper};
+pub mod irradiance_cache;
+pub mod photon_map;
+pub mod progress;
+pub mod progressive;
+mod path_tracer;
+pub use path_tracer::PathTracer;
 
-// mod backward_metropolis;
-// pub use crate::backward_metropolis::{
-//     mutation::RestartRay, BackwardMetropolis, Mutation, MutationSet,
-// };
+mod bidirectional_path_tracer;
+pub use bidirectional_path_tracer::BidirectionalPathTracer;
+
+mod backward_metropolis;
+pub use crate::backward_metropolis::{
+    BackwardMetropolis, LocalExploration, Mutation, MutationSet, Path, RestartRay,
+};
@@ -0,0 +1,590 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Choosing which light sources to sample from each point, so that scenes
+//! with many luminaires (e.g., an office with hundreds of troffers) do not
+//! need shadow rays towards every one of them.
+//!
+//! The [`LightTree`] is a bounding volume hierarchy over the lights, in
+//! which every node bounds the position, orientation and power of the
+//! lights below it (Estevez and Kulla, 2018, "Importance Sampling of Many
+//! Lights with Adaptive Tree Splitting"). Lights are chosen by
+//! descending the tree, picking each child in proportion to an estimate of
+//! its contribution to the point being shaded.
+
+use crate::material::Material;
+use crate::primitive::Primitive;
+use crate::scene::Object;
+use crate::{Float, PI};
+use geometry3d::{BBox3D, Point3D, Vector3D};
+
+/// How the lights sampled by shadow rays are chosen
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LightSelection {
+    /// Every light gets all the shadow samples
+    #[default]
+    All,
+
+    /// Each shadow sample goes to a single light, chosen in
+    /// proportion to its power
+    Power,
+
+    /// Each shadow sample goes to a single light, chosen through the
+    /// [`LightTree`] in proportion to an estimate of its contribution
+    Tree,
+}
+
+impl std::str::FromStr for LightSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "all" => Ok(Self::All),
+            "power" => Ok(Self::Power),
+            "tree" => Ok(Self::Tree),
+            _ => Err(format!("Unknown light selection '{}'", s)),
+        }
+    }
+}
+
+/// A cone of directions around `w`
+#[derive(Clone, Copy)]
+struct DirectionCone {
+    w: Vector3D,
+    cos_theta: Float,
+}
+
+impl DirectionCone {
+    /// A cone containing every direction
+    fn entire() -> Self {
+        Self {
+            w: Vector3D::new(0., 0., 1.),
+            cos_theta: -1.,
+        }
+    }
+
+    /// The smallest cone containing two others
+    fn union(a: &Self, b: &Self) -> Self {
+        let theta_a = a.cos_theta.clamp(-1., 1.).acos();
+        let theta_b = b.cos_theta.clamp(-1., 1.).acos();
+        let theta_d = (a.w * b.w).clamp(-1., 1.).acos();
+        if (theta_d + theta_b).min(PI) <= theta_a {
+            return *a;
+        }
+        if (theta_d + theta_a).min(PI) <= theta_b {
+            return *b;
+        }
+
+        let theta_o = (theta_a + theta_d + theta_b) / 2.;
+        if theta_o >= PI {
+            return Self::entire();
+        }
+        // Rotate `a.w` towards `b.w`, around their perpendicular
+        let axis = a.w.cross(b.w);
+        if axis.length_squared() < 1e-12 {
+            return Self::entire();
+        }
+        let axis = axis.get_normalized();
+        let (sin_r, cos_r) = (theta_o - theta_a).sin_cos();
+        let w = a.w * cos_r + axis.cross(a.w) * sin_r + axis * ((axis * a.w) * (1. - cos_r));
+        Self {
+            w: w.get_normalized(),
+            cos_theta: theta_o.cos(),
+        }
+    }
+}
+
+/// Bounds the light emitted by one or more light sources
+#[derive(Clone, Copy)]
+struct LightBounds {
+    /// Bounds the position of the lights
+    bounds: BBox3D,
+
+    /// The total power of the lights
+    phi: Float,
+
+    /// Bounds the normals of the emitting surfaces
+    normals: DirectionCone,
+
+    /// The cosine of the maximum angle between the normal of
+    /// an emitting surface and the directions it emits light towards
+    cos_theta_e: Float,
+
+    /// Do surfaces emit light on both sides?
+    two_sided: bool,
+}
+
+impl LightBounds {
+    /// Bounds the light emitted by a light source in the scene
+    fn new(light: &Object, materials: &[Material]) -> Self {
+        let radiance = |i: usize| -> Float {
+            let material = &materials[i];
+            if material.emits_light() {
+                material.colour().radiance()
+            } else {
+                0.0
+            }
+        };
+        let front = radiance(light.front_material_index);
+        let back = radiance(light.back_material_index);
+        let bounds = light.primitive.world_bounds();
+        match &light.primitive {
+            Primitive::Triangle(t) => {
+                let normal = t.normal();
+                Self {
+                    bounds,
+                    phi: PI * t.area() * (front + back),
+                    normals: DirectionCone {
+                        w: if front > 0. { normal } else { normal * -1. },
+                        cos_theta: 1.,
+                    },
+                    cos_theta_e: 0.,
+                    two_sided: front > 0. && back > 0.,
+                }
+            }
+            Primitive::Sphere(s) => Self {
+                bounds,
+                phi: PI * 4. * PI * s.radius * s.radius * front,
+                normals: DirectionCone::entire(),
+                cos_theta_e: 0.,
+                two_sided: false,
+            },
+            _ => {
+                // Approximate it through the surface of its bounds
+                let d = bounds.max - bounds.min;
+                let area = 2. * (d.x * d.y + d.y * d.z + d.z * d.x);
+                Self {
+                    bounds,
+                    phi: PI * area * front.max(back),
+                    normals: DirectionCone::entire(),
+                    cos_theta_e: 0.,
+                    two_sided: false,
+                }
+            }
+        }
+    }
+
+    /// Bounds the light emitted by the lights bounded by `a` and `b`
+    fn union(a: &Self, b: &Self) -> Self {
+        if a.phi <= 0. {
+            return *b;
+        }
+        if b.phi <= 0. {
+            return *a;
+        }
+        Self {
+            bounds: BBox3D::from_union(&a.bounds, &b.bounds),
+            phi: a.phi + b.phi,
+            normals: DirectionCone::union(&a.normals, &b.normals),
+            cos_theta_e: a.cos_theta_e.min(b.cos_theta_e),
+            two_sided: a.two_sided || b.two_sided,
+        }
+    }
+
+    /// The centre of the `bounds`
+    fn centre(&self) -> Point3D {
+        let (min, max) = (self.bounds.min, self.bounds.max);
+        Point3D::new(
+            (min.x + max.x) / 2.,
+            (min.y + max.y) / 2.,
+            (min.z + max.z) / 2.,
+        )
+    }
+
+    /// A conservative estimate of the light arriving at `point` from
+    /// the bounded lights. It is zero only if none of them can reach it.
+    fn importance(&self, point: Point3D) -> Float {
+        if self.phi <= 0. {
+            return 0.0;
+        }
+        let cos_sub_clamped = |sin_a: Float, cos_a: Float, sin_b: Float, cos_b: Float| {
+            if cos_a > cos_b {
+                1.
+            } else {
+                cos_a * cos_b + sin_a * sin_b
+            }
+        };
+        let sin_sub_clamped = |sin_a: Float, cos_a: Float, sin_b: Float, cos_b: Float| {
+            if cos_a > cos_b {
+                0.
+            } else {
+                sin_a * cos_b - cos_a * sin_b
+            }
+        };
+        let sin_of = |cos: Float| (1. - cos * cos).max(0.).sqrt();
+
+        let centre = self.centre();
+        let radius = (self.bounds.max - self.bounds.min).length() / 2.;
+        let d = point - centre;
+        let d2 = d.length_squared().max(radius);
+
+        // The angle between the normals and the direction towards the point
+        let mut cos_theta_w = if d.length_squared() > 0. {
+            self.normals.w * d.get_normalized()
+        } else {
+            1.
+        };
+        if self.two_sided {
+            cos_theta_w = cos_theta_w.abs();
+        }
+        let sin_theta_w = sin_of(cos_theta_w);
+
+        // The angle subtended by the bounds, as seen from the point
+        let cos_theta_b = if d.length_squared() < radius * radius {
+            -1.
+        } else {
+            (1. - radius * radius / d.length_squared()).max(0.).sqrt()
+        };
+        let sin_theta_b = sin_of(cos_theta_b);
+
+        // The minimum angle between an emitting normal and the point
+        let cos_theta_o = self.normals.cos_theta;
+        let sin_theta_o = sin_of(cos_theta_o);
+        let cos_theta_x = cos_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, cos_theta_o);
+        let sin_theta_x = sin_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, cos_theta_o);
+        let cos_theta_p = cos_sub_clamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+        if cos_theta_p <= self.cos_theta_e {
+            return 0.0;
+        }
+        self.phi * cos_theta_p / d2
+    }
+}
+
+/// A node in the [`LightTree`]
+#[derive(Clone, Copy)]
+struct LightNode {
+    bounds: LightBounds,
+
+    /// The index of the light, in leaves. Otherwise, the index of the
+    /// second child (the first one comes right after this node).
+    index: usize,
+
+    is_leaf: bool,
+}
+
+/// Chooses the lights sampled from each point (see [`LightSelection`])
+#[derive(Default)]
+pub struct LightTree {
+    nodes: Vec<LightNode>,
+
+    /// The path from the root to each light: the `i`-th bit indicates
+    /// whether the second child is chosen at depth `i`
+    trails: Vec<u64>,
+
+    /// The cumulative distribution of the power of the lights
+    power_cdf: Vec<Float>,
+}
+
+impl LightTree {
+    /// Builds the tree over the `lights` of a scene, made of `materials`
+    pub fn new(lights: &[Object], materials: &[Material]) -> Self {
+        let bounds: Vec<LightBounds> = lights
+            .iter()
+            .map(|light| LightBounds::new(light, materials))
+            .collect();
+
+        let mut power_cdf = Vec::with_capacity(bounds.len());
+        let mut total = 0.0;
+        for b in bounds.iter() {
+            total += b.phi;
+            power_cdf.push(total);
+        }
+        if total > 0. {
+            power_cdf.iter_mut().for_each(|v| *v /= total);
+        }
+
+        let mut ret = Self {
+            nodes: Vec::with_capacity(2 * bounds.len()),
+            trails: vec![0; bounds.len()],
+            power_cdf,
+        };
+        let mut indexes: Vec<usize> = (0..bounds.len()).filter(|i| bounds[*i].phi > 0.).collect();
+        if !indexes.is_empty() {
+            ret.build(&bounds, &mut indexes, 0, 0);
+        }
+        ret
+    }
+
+    /// Builds the node bounding the `lights`, which is reached through
+    /// `trail` at a certain `depth`
+    fn build(&mut self, bounds: &[LightBounds], lights: &mut [usize], trail: u64, depth: u32) {
+        if lights.len() == 1 {
+            let index = lights[0];
+            self.trails[index] = trail;
+            self.nodes.push(LightNode {
+                bounds: bounds[index],
+                index,
+                is_leaf: true,
+            });
+            return;
+        }
+
+        // Split in halves along the largest extent of the centres,
+        // which keeps the depth below 64 levels
+        let centres: Vec<Point3D> = lights.iter().map(|i| bounds[*i].centre()).collect();
+        let (mut min, mut max) = (centres[0], centres[0]);
+        for c in centres.iter() {
+            min = Point3D::new(min.x.min(c.x), min.y.min(c.y), min.z.min(c.z));
+            max = Point3D::new(max.x.max(c.x), max.y.max(c.y), max.z.max(c.z));
+        }
+        let extent = max - min;
+        let axis = |p: Point3D| -> Float {
+            if extent.x >= extent.y && extent.x >= extent.z {
+                p.x
+            } else if extent.y >= extent.z {
+                p.y
+            } else {
+                p.z
+            }
+        };
+        let mid = lights.len() / 2;
+        lights.select_nth_unstable_by(mid, |a, b| {
+            axis(bounds[*a].centre())
+                .partial_cmp(&axis(bounds[*b].centre()))
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(a.cmp(b))
+        });
+
+        let node = self.nodes.len();
+        self.nodes.push(LightNode {
+            bounds: bounds[lights[0]],
+            index: 0,
+            is_leaf: false,
+        });
+        let (first, second) = lights.split_at_mut(mid);
+        self.build(bounds, first, trail, depth + 1);
+        self.nodes[node].index = self.nodes.len();
+        self.build(bounds, second, trail | (1 << depth), depth + 1);
+
+        let b = LightBounds::union(
+            &self.nodes[node + 1].bounds,
+            &self.nodes[self.nodes[node].index].bounds,
+        );
+        self.nodes[node].bounds = b;
+    }
+
+    /// The probabilities of choosing each of the children of the interior
+    /// node at position `node`, from a `point`. Nodes are stored in depth-first
+    /// order, so the first child comes right after its parent.
+    fn child_probabilities(&self, node: usize, point: Point3D) -> Option<(Float, Float)> {
+        let first = self.nodes[node + 1].bounds.importance(point);
+        let second = self.nodes[self.nodes[node].index].bounds.importance(point);
+        let total = first + second;
+        if total <= 0. {
+            return None;
+        }
+        Some((first / total, second / total))
+    }
+
+    /// Chooses a light (i.e., its index in the [`Scene`](crate::Scene)'s
+    /// `lights`) to be sampled from a `point`, given a number `u` in `[0, 1)`.
+    /// Returns it along with the probability of choosing it, or [`None`] if
+    /// no light can reach the point.
+    ///
+    /// Lights cannot be chosen through [`LightSelection::All`].
+    pub fn sample(
+        &self,
+        selection: LightSelection,
+        point: Point3D,
+        mut u: Float,
+    ) -> Option<(usize, Float)> {
+        match selection {
+            LightSelection::All => None,
+            LightSelection::Power => {
+                let last = self.power_cdf.len().checked_sub(1)?;
+                let index = self.power_cdf.partition_point(|v| *v <= u).min(last);
+                let pmf = self.pmf(selection, point, index);
+                if pmf > 0. {
+                    Some((index, pmf))
+                } else {
+                    None
+                }
+            }
+            LightSelection::Tree => {
+                if self.nodes.is_empty() {
+                    return None;
+                }
+                let mut node = 0;
+                let mut pmf = 1.;
+                while !self.nodes[node].is_leaf {
+                    let (first, second) = self.child_probabilities(node, point)?;
+                    if u < first {
+                        u = (u / first).min(1. - Float::EPSILON);
+                        pmf *= first;
+                        node += 1;
+                    } else {
+                        u = ((u - first) / second).min(1. - Float::EPSILON);
+                        pmf *= second;
+                        node = self.nodes[node].index;
+                    }
+                }
+                let leaf = &self.nodes[node];
+                if leaf.bounds.importance(point) > 0. {
+                    Some((leaf.index, pmf))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// The probability of choosing the `light`-th light from a `point`
+    /// through [`LightTree::sample`]. It is one for [`LightSelection::All`],
+    /// as every light is sampled.
+    pub fn pmf(&self, selection: LightSelection, point: Point3D, light: usize) -> Float {
+        match selection {
+            LightSelection::All => 1.,
+            LightSelection::Power => {
+                let previous = if light == 0 {
+                    0.
+                } else {
+                    self.power_cdf[light - 1]
+                };
+                self.power_cdf.get(light).map_or(0., |v| v - previous)
+            }
+            LightSelection::Tree => {
+                if self.nodes.is_empty() || light >= self.trails.len() {
+                    return 0.;
+                }
+                let mut node = 0;
+                let mut trail = self.trails[light];
+                let mut pmf = 1.;
+                while !self.nodes[node].is_leaf {
+                    let (first, second) = match self.child_probabilities(node, point) {
+                        Some(p) => p,
+                        None => return 0.,
+                    };
+                    if trail & 1 == 0 {
+                        pmf *= first;
+                        node += 1;
+                    } else {
+                        pmf *= second;
+                        node = self.nodes[node].index;
+                    }
+                    trail >>= 1;
+                }
+                let leaf = &self.nodes[node];
+                if leaf.index != light || leaf.bounds.importance(point) <= 0. {
+                    return 0.;
+                }
+                pmf
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colour::Spectrum;
+    use crate::material::Light;
+    use crate::rand::*;
+    use crate::Scene;
+    use geometry3d::{Sphere3D, Triangle3D};
+
+    /// A ceiling with a grid of `n` by `n` small triangles emitting light
+    /// downwards, at `z=3`
+    fn ceiling(n: usize) -> Scene {
+        let mut scene = Scene::new();
+        let light = scene.push_material(Material::Light(Light(
+            Spectrum::<{ crate::N_CHANNELS }>::gray(100.),
+        )));
+        let dark = scene.push_material(Material::Plastic(crate::material::Plastic {
+            colour: Spectrum::<{ crate::N_CHANNELS }>::BLACK,
+            specularity: 0.,
+            roughness: 0.,
+        }));
+        for i in 0..n {
+            for j in 0..n {
+                let (x, y) = (i as Float, j as Float);
+                // Pointing down
+                let tri = Triangle3D::new(
+                    Point3D::new(x, y, 3.),
+                    Point3D::new(x, y + 0.2, 3.),
+                    Point3D::new(x + 0.2, y, 3.),
+                )
+                .unwrap();
+                scene.push_object(light, dark, Primitive::Triangle(tri));
+            }
+        }
+        scene
+    }
+
+    #[test]
+    fn test_pmfs_add_up() {
+        let scene = ceiling(12);
+        let tree = LightTree::new(&scene.lights, &scene.materials);
+        let mut rng = get_seeded_rng(0, 0);
+        for point in [Point3D::new(3.1, 4.2, 0.), Point3D::new(-10., 20., 1.)] {
+            for selection in [LightSelection::Power, LightSelection::Tree] {
+                let total: Float = (0..scene.lights.len())
+                    .map(|i| tree.pmf(selection, point, i))
+                    .sum();
+                assert!((1. - total).abs() < 1e-4, "total is {}", total);
+
+                for _ in 0..100 {
+                    let (index, pmf) = tree.sample(selection, point, rng.gen()).unwrap();
+                    assert!((pmf - tree.pmf(selection, point, index)).abs() < 1e-6);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_importance() {
+        let scene = ceiling(12);
+        let tree = LightTree::new(&scene.lights, &scene.materials);
+
+        // Lights right above are more likely than those far away
+        let point = Point3D::new(0.05, 0.05, 2.);
+        let close = tree.pmf(LightSelection::Tree, point, 0);
+        let far = tree.pmf(LightSelection::Tree, point, scene.lights.len() - 1);
+        assert!(close > 10. * far, "close = {}, far = {}", close, far);
+
+        // Lights pointing down do not reach points above
+        let point = Point3D::new(5., 5., 4.);
+        let mut rng = get_seeded_rng(1, 0);
+        assert!(tree
+            .sample(LightSelection::Tree, point, rng.gen())
+            .is_none());
+        assert_eq!(tree.pmf(LightSelection::Tree, point, 0), 0.);
+    }
+
+    #[test]
+    fn test_power() {
+        let mut scene = Scene::new();
+        for (brightness, radius) in [(1., 1.), (2., 1.), (1., 2.)] {
+            let light = scene.push_material(Material::Light(Light(Spectrum::<
+                { crate::N_CHANNELS },
+            >::gray(brightness))));
+            let sphere = Sphere3D::new(radius, Point3D::new(0., 0., 10.));
+            scene.push_object(light, light, Primitive::Sphere(sphere));
+        }
+        let tree = LightTree::new(&scene.lights, &scene.materials);
+        let point = Point3D::new(0., 0., 0.);
+        let pmfs: Vec<Float> = (0..3)
+            .map(|i| tree.pmf(LightSelection::Power, point, i))
+            .collect();
+        assert!((pmfs[0] - 1. / 7.).abs() < 1e-5);
+        assert!((pmfs[1] - 2. / 7.).abs() < 1e-5);
+        assert!((pmfs[2] - 4. / 7.).abs() < 1e-5);
+        assert_eq!(tree.pmf(LightSelection::All, point, 1), 1.);
+    }
+}
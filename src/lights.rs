@@ -18,45 +18,882 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
-use crate::Float;
-use crate::sampleable_trait::Sampleable;
+//! The sources of direct light, which the [`RayTracer`](crate::RayTracer)
+//! samples through shadow rays.
+//!
+//! Area lights (i.e., objects made of a light-emitting [`Material`](crate::material::Material))
+//! and distant lights (e.g., the sun) are objects pushed through
+//! [`Scene::push_object`]. Point and spot lights have no surface, so
+//! they are pushed through [`Scene::push_light`]. The distant, point and
+//! spot lights are all kept in [`Scene::delta_lights`]. The [`SkyLight`] surrounds
+//! the scene, and is found by the rays that hit nothing.
+//!
+//! Integrators that trace light from the sources themselves (e.g., the
+//! [`BidirectionalPathTracer`](crate::BidirectionalPathTracer)) sample it
+//! through [`Light::sample_le`].
+
 use crate::colour::Spectrum;
+use crate::interaction::offset_ray_origin;
+use crate::primitive::Primitive;
+use crate::primitive_samplers::{sample_sphere_surface, sample_triangle_surface};
+use crate::rand::*;
+use crate::samplers::{
+    local_to_world, sample_cone, sample_cosine_weighted_horizontal_hemisphere, uniform_cone_pdf,
+    uniform_sample_disc, uniform_sample_sphere,
+};
+use crate::scene::{Object, Scene};
+use crate::triangle::gamma;
+use crate::{Float, PI};
+use geometry3d::intersection::SurfaceSide;
+use geometry3d::{Point3D, Ray3D, Vector3D};
 
 // Flags
-const DELTA_POSITION_LIGHT: u8 = 1;
-const DELTA_DIRECTION_LIGHT : u8 = 2;
-const AREA_LIGHT : u8 = 4;
-const INFINITE_LIGHT : u8 = 8;
-
-trait Light : Sampleable {    
-    
-    /// Returns the light flags associated with the 
-    /// light source
-    fn flags(&self)->u8;
 
+/// The light is emitted from a single point
+pub const DELTA_POSITION_LIGHT: u8 = 1;
+
+/// The light arrives from a single (or a very narrow range of) direction,
+/// so it is not found by rays sampled from BSDFs
+pub const DELTA_DIRECTION_LIGHT: u8 = 2;
+
+/// The light is emitted by a surface
+pub const AREA_LIGHT: u8 = 4;
+
+/// The light surrounds the scene
+pub const INFINITE_LIGHT: u8 = 8;
+
+/// The light arriving at a point from a direction sampled
+/// through [`Light::sample_li`]
+pub struct LightSample {
+    /// The radiance arriving at the point
+    pub radiance: Spectrum<{ crate::N_CHANNELS }>,
+
+    /// The (normalized) direction from the point towards the light
+    pub direction: Vector3D,
+
+    /// The distance between the point and the light (infinite for
+    /// the sky), which shadow rays must travel unobstructed
+    pub distance: Float,
+
+    /// The probability (per unit solid angle) of having sampled the
+    /// `direction`. It is one for lights with a delta position.
+    pub pdf: Float,
+}
+
+/// Light leaving a light source, sampled through [`Light::sample_le`]
+/// (e.g., for starting a light subpath or shooting a photon)
+pub struct EmissionSample {
+    /// The ray along which light leaves. For area lights, its origin is on
+    /// their surface (see [`EmissionSample::spawn_ray`]).
+    pub ray: Ray3D,
+
+    /// The normal of the front of the surface the light leaves, for
+    /// area lights
+    pub normal: Option<Vector3D>,
+
+    /// A bound on the error of the origin of the `ray`
+    pub perror: Vector3D,
+
+    /// The radiance carried by the `ray` or, for lights with
+    /// a delta position, their intensity in its direction
+    pub radiance: Spectrum<{ crate::N_CHANNELS }>,
+
+    /// The probability (per unit area) of having sampled the origin of the
+    /// `ray`. Infinite lights sample it over a disc that covers the [`Scene`],
+    /// and it is one for lights with a delta position.
+    pub pdf_pos: Float,
+
+    /// The probability (per unit solid angle) of having sampled
+    /// the direction of the `ray`
+    pub pdf_dir: Float,
+}
+
+impl EmissionSample {
+    /// The `ray`, with its origin offset so that it does
+    /// not hit the surface of the light
+    pub fn spawn_ray(&self) -> Ray3D {
+        match self.normal {
+            Some(normal) => Ray3D {
+                origin: offset_ray_origin(self.ray.origin, self.perror, normal, self.ray.direction),
+                direction: self.ray.direction,
+            },
+            None => self.ray,
+        }
+    }
+}
 
-    /// Returns the number of samples that should be used for this
-    /// specific light source
-    fn n_samples(&self)->usize;
+/// A source of direct light
+pub trait Light: Send + Sync {
+    /// Returns the light flags associated with the
+    /// light source
+    fn flags(&self) -> u8;
 
     /// Checks whether a light source has a Dirac's delta position
     /// or direction
-    fn is_delta_light(&self)->bool{        
-        self.flags() & DELTA_POSITION_LIGHT == 1 ||
-        self.flags() & DELTA_DIRECTION_LIGHT == 1        
+    fn is_delta_light(&self) -> bool {
+        self.flags() & (DELTA_POSITION_LIGHT | DELTA_DIRECTION_LIGHT) != 0
+    }
+
+    /// Samples a direction from `point` towards the light, returning the
+    /// radiance arriving from it. A sample with a zero `pdf` carries no
+    /// light. Returns [`None`] if the sampled direction missed the light
+    /// (e.g., due to numerical errors), in which case another one should be sampled.
+    fn sample_li(&self, scene: &Scene, rng: &mut RandGen, point: Point3D) -> Option<LightSample>;
+
+    /// The probability (per unit solid angle) of [`Light::sample_li`] sampling
+    /// `direction` from `point`. It is zero for delta lights.
+    fn pdf_li(&self, scene: &Scene, point: Point3D, direction: Vector3D) -> Float;
+
+    /// The radiance arriving from an infinite light through
+    /// a ray travelling in `direction` that hits nothing
+    fn le(&self, _scene: &Scene, _direction: Vector3D) -> Spectrum<{ crate::N_CHANNELS }> {
+        Spectrum::<{ crate::N_CHANNELS }>::BLACK
+    }
+
+    /// Samples a ray leaving the light. Returns [`None`] if the light
+    /// cannot emit (e.g., area lights with no surface to sample).
+    fn sample_le(&self, scene: &Scene, rng: &mut RandGen) -> Option<EmissionSample>;
+
+    /// The probabilities of [`Light::sample_le`] sampling a ray that leaves
+    /// `point` (whose front `normal` is ignored except for area lights)
+    /// in `direction`: per unit area of its origin, and per unit solid angle
+    /// of its direction. The former is zero for lights with a delta position.
+    fn pdf_le(
+        &self,
+        scene: &Scene,
+        point: Point3D,
+        normal: Vector3D,
+        direction: Vector3D,
+    ) -> (Float, Float);
+
+    /// The radiance (or, for lights with a delta position, the intensity)
+    /// leaving `point`—whose front `normal` is ignored except for area
+    /// lights—in `direction`. It is zero for infinite lights, which have
+    /// [`Light::le`] instead.
+    fn emitted(
+        &self,
+        _scene: &Scene,
+        _point: Point3D,
+        _normal: Vector3D,
+        _direction: Vector3D,
+    ) -> Spectrum<{ crate::N_CHANNELS }> {
+        Spectrum::<{ crate::N_CHANNELS }>::BLACK
+    }
+
+    /// The [`Object`] this light is, if any (e.g., so that it
+    /// can be saved through [`Scene::save_cache`])
+    fn object(&self) -> Option<&Object> {
+        None
+    }
+}
+
+impl<L: Light + ?Sized> Light for Box<L> {
+    fn flags(&self) -> u8 {
+        (**self).flags()
+    }
+
+    fn sample_li(&self, scene: &Scene, rng: &mut RandGen, point: Point3D) -> Option<LightSample> {
+        (**self).sample_li(scene, rng, point)
     }
 
-    // fn power(&self)->Float;
-    
-    
-    fn sample_li(&self)->Spectrum;
-    fn pdf_li(&self)->Float;
+    fn pdf_li(&self, scene: &Scene, point: Point3D, direction: Vector3D) -> Float {
+        (**self).pdf_li(scene, point, direction)
+    }
+
+    fn le(&self, scene: &Scene, direction: Vector3D) -> Spectrum<{ crate::N_CHANNELS }> {
+        (**self).le(scene, direction)
+    }
+
+    fn sample_le(&self, scene: &Scene, rng: &mut RandGen) -> Option<EmissionSample> {
+        (**self).sample_le(scene, rng)
+    }
+
+    fn pdf_le(
+        &self,
+        scene: &Scene,
+        point: Point3D,
+        normal: Vector3D,
+        direction: Vector3D,
+    ) -> (Float, Float) {
+        (**self).pdf_le(scene, point, normal, direction)
+    }
+
+    fn emitted(
+        &self,
+        scene: &Scene,
+        point: Point3D,
+        normal: Vector3D,
+        direction: Vector3D,
+    ) -> Spectrum<{ crate::N_CHANNELS }> {
+        (**self).emitted(scene, point, normal, direction)
+    }
+
+    fn object(&self) -> Option<&Object> {
+        (**self).object()
+    }
+}
+
+/// Objects in [`Scene::lights`] are area lights, and those in
+/// [`Scene::delta_lights`] are distant lights
+impl Light for Object {
+    fn flags(&self) -> u8 {
+        match self.primitive {
+            Primitive::Source(_) => DELTA_DIRECTION_LIGHT,
+            _ => AREA_LIGHT,
+        }
+    }
+
+    fn sample_li(&self, scene: &Scene, rng: &mut RandGen, point: Point3D) -> Option<LightSample> {
+        let direction = self.primitive.sample_direction(rng, point);
+        let ray = Ray3D {
+            origin: point,
+            direction,
+        };
+        let info = self.primitive.intersect(&ray)?;
+        let material = match info.side {
+            SurfaceSide::Front => &scene.materials[self.front_material_index],
+            SurfaceSide::Back => &scene.materials[self.back_material_index],
+            SurfaceSide::NonApplicable => {
+                // Hit parallel to the surface
+                return Some(LightSample {
+                    radiance: Spectrum::<{ crate::N_CHANNELS }>::BLACK,
+                    direction,
+                    distance: 0.0,
+                    pdf: 0.0,
+                });
+            }
+        };
+        let radiance = if material.emits_light() {
+            material.colour()
+        } else {
+            Spectrum::<{ crate::N_CHANNELS }>::BLACK
+        };
+        Some(LightSample {
+            radiance,
+            direction,
+            distance: (info.p - point).length(),
+            pdf: self.primitive.solid_angle_pdf(&info, &ray),
+        })
+    }
+
+    fn pdf_li(&self, _scene: &Scene, point: Point3D, direction: Vector3D) -> Float {
+        let ray = Ray3D {
+            origin: point,
+            direction,
+        };
+        match self.primitive.intersect(&ray) {
+            Some(info) => self.primitive.solid_angle_pdf(&info, &ray),
+            None => 0.0,
+        }
+    }
 
-    fn le(&self)->Spectrum;
-    fn sample_le(&self)->Spectrum;
-    fn pdf_le(&self)->Float;
+    fn le(&self, scene: &Scene, direction: Vector3D) -> Spectrum<{ crate::N_CHANNELS }> {
+        // Only distant lights are reached by rays that hit nothing
+        if !matches!(self.primitive, Primitive::Source(_)) {
+            return Spectrum::<{ crate::N_CHANNELS }>::BLACK;
+        }
+        let ray = Ray3D {
+            origin: Point3D::new(0., 0., 0.),
+            direction,
+        };
+        match self.primitive.intersect(&ray) {
+            Some(info) if info.side == SurfaceSide::Back => {
+                scene.materials[self.back_material_index].colour()
+            }
+            Some(_) => scene.materials[self.front_material_index].colour(),
+            None => Spectrum::<{ crate::N_CHANNELS }>::BLACK,
+        }
+    }
+
+    fn sample_le(&self, scene: &Scene, rng: &mut RandGen) -> Option<EmissionSample> {
+        let (point, normal) = match &self.primitive {
+            Primitive::Source(source) => {
+                let w = sample_cone(
+                    rng,
+                    source.direction.get_normalized(),
+                    source.cos_half_alpha,
+                );
+                let pdf_dir = uniform_cone_pdf(source.cos_half_alpha);
+                return Some(sample_infinite(scene, rng, w, self.le(scene, w), pdf_dir));
+            }
+            Primitive::Sphere(s) => {
+                let p = sample_sphere_surface(s, rng);
+                (p, (p - s.centre()).get_normalized())
+            }
+            Primitive::Triangle(t) => {
+                let normal = (t.b() - t.a()).cross(t.c() - t.a()).get_normalized();
+                (sample_triangle_surface(t, rng), normal)
+            }
+            Primitive::Cylinder(_) => return None,
+        };
+        let (front, back) = self.emitting_sides(scene);
+        let side = match (front, back) {
+            (true, true) if rng.gen::<Float>() < 0.5 => normal * -1.,
+            (true, _) => normal,
+            (false, true) => normal * -1.,
+            (false, false) => return None,
+        };
+        let local = sample_cosine_weighted_horizontal_hemisphere(rng);
+        let e2 = side.get_perpendicular().unwrap();
+        let e1 = e2.cross(side);
+        let (x, y, z) = local_to_world(
+            e1,
+            e2,
+            side,
+            Point3D::new(0., 0., 0.),
+            local.x,
+            local.y,
+            local.z,
+        );
+        let direction = Vector3D::new(x, y, z).get_normalized();
+        let (pdf_pos, pdf_dir) = self.pdf_le(scene, point, normal, direction);
+        Some(EmissionSample {
+            ray: Ray3D {
+                origin: point,
+                direction,
+            },
+            normal: Some(normal),
+            // The error of a point sampled over a triangle
+            perror: Vector3D::new(point.x.abs(), point.y.abs(), point.z.abs()) * gamma(6.),
+            radiance: self.emitted(scene, point, normal, direction),
+            pdf_pos,
+            pdf_dir,
+        })
+    }
+
+    fn pdf_le(
+        &self,
+        scene: &Scene,
+        _point: Point3D,
+        normal: Vector3D,
+        direction: Vector3D,
+    ) -> (Float, Float) {
+        let area = match &self.primitive {
+            Primitive::Source(source) => {
+                let inside =
+                    direction * -1. * source.direction.get_normalized() >= source.cos_half_alpha;
+                let pdf_dir = if inside {
+                    uniform_cone_pdf(source.cos_half_alpha)
+                } else {
+                    0.0
+                };
+                return (infinite_pdf_pos(scene), pdf_dir);
+            }
+            Primitive::Sphere(s) => 4. * PI * s.radius * s.radius,
+            Primitive::Triangle(t) => t.area(),
+            Primitive::Cylinder(_) => return (0.0, 0.0),
+        };
+        // Emitting sides are chosen with equal probability, and then
+        // directions are distributed according to their cosine
+        let (front, back) = self.emitting_sides(scene);
+        let cos = normal * direction;
+        if (cos > 0. && !front) || (cos <= 0. && !back) {
+            return (1. / area, 0.0);
+        }
+        let side_pdf = if front && back { 0.5 } else { 1. };
+        (1. / area, side_pdf * cos.abs() / PI)
+    }
+
+    fn emitted(
+        &self,
+        scene: &Scene,
+        _point: Point3D,
+        normal: Vector3D,
+        direction: Vector3D,
+    ) -> Spectrum<{ crate::N_CHANNELS }> {
+        if matches!(self.primitive, Primitive::Source(_)) {
+            return Spectrum::<{ crate::N_CHANNELS }>::BLACK;
+        }
+        let material = if normal * direction > 0. {
+            &scene.materials[self.front_material_index]
+        } else {
+            &scene.materials[self.back_material_index]
+        };
+        if material.emits_light() {
+            material.colour()
+        } else {
+            Spectrum::<{ crate::N_CHANNELS }>::BLACK
+        }
+    }
+
+    fn object(&self) -> Option<&Object> {
+        Some(self)
+    }
 }
 
+impl Object {
+    /// Whether the front and the back of this object emit light
+    fn emitting_sides(&self, scene: &Scene) -> (bool, bool) {
+        (
+            scene.materials[self.front_material_index].emits_light(),
+            scene.materials[self.back_material_index].emits_light(),
+        )
+    }
+}
 
+/// A light emitted equally in every direction from a point
+pub struct PointLight {
+    /// The position of the light
+    pub position: Point3D,
 
+    /// The intensity (i.e., the flux per unit solid angle) of the light
+    pub intensity: Spectrum<{ crate::N_CHANNELS }>,
+}
+
+impl Light for PointLight {
+    fn flags(&self) -> u8 {
+        DELTA_POSITION_LIGHT
+    }
+
+    fn sample_li(&self, _scene: &Scene, _rng: &mut RandGen, point: Point3D) -> Option<LightSample> {
+        Some(sample_point(self.position, self.intensity, point))
+    }
+
+    fn pdf_li(&self, _scene: &Scene, _point: Point3D, _direction: Vector3D) -> Float {
+        0.0
+    }
 
+    fn sample_le(&self, _scene: &Scene, rng: &mut RandGen) -> Option<EmissionSample> {
+        let p = uniform_sample_sphere(rng);
+        let direction = Vector3D::new(p.x, p.y, p.z).get_normalized();
+        Some(EmissionSample {
+            ray: Ray3D {
+                origin: self.position,
+                direction,
+            },
+            normal: None,
+            perror: Vector3D::new(0., 0., 0.),
+            radiance: self.intensity,
+            pdf_pos: 1.,
+            pdf_dir: 1. / (4. * PI),
+        })
+    }
+
+    fn pdf_le(
+        &self,
+        _scene: &Scene,
+        _point: Point3D,
+        _normal: Vector3D,
+        _direction: Vector3D,
+    ) -> (Float, Float) {
+        (0.0, 1. / (4. * PI))
+    }
+
+    fn emitted(
+        &self,
+        _scene: &Scene,
+        _point: Point3D,
+        _normal: Vector3D,
+        _direction: Vector3D,
+    ) -> Spectrum<{ crate::N_CHANNELS }> {
+        self.intensity
+    }
+}
+
+/// A light emitted from a point within a cone, like a spotlight
+/// in a theatre
+pub struct SpotLight {
+    /// The position of the light
+    pub position: Point3D,
+
+    /// The (normalized) axis of the cone
+    pub direction: Vector3D,
+
+    /// The intensity (i.e., the flux per unit solid angle) along the axis
+    pub intensity: Spectrum<{ crate::N_CHANNELS }>,
+
+    /// The cosine of the angle between the axis and the edge of the cone
+    pub cos_total_width: Float,
+
+    /// The cosine of the angle between the axis and the directions
+    /// where the intensity starts falling
+    pub cos_falloff_start: Float,
+}
+
+impl SpotLight {
+    /// Creates a new `SpotLight` pointing in `direction`. The intensity
+    /// starts falling at `falloff_start` radians from the axis, and reaches
+    /// zero at `total_width` radians from it.
+    pub fn new(
+        position: Point3D,
+        direction: Vector3D,
+        intensity: Spectrum<{ crate::N_CHANNELS }>,
+        total_width: Float,
+        falloff_start: Float,
+    ) -> Self {
+        Self {
+            position,
+            direction: direction.get_normalized(),
+            intensity,
+            cos_total_width: total_width.cos(),
+            cos_falloff_start: falloff_start.min(total_width).cos(),
+        }
+    }
+
+    /// The fraction of the `intensity` emitted towards `w`
+    fn falloff(&self, w: Vector3D) -> Float {
+        let cos_theta = self.direction * w;
+        if cos_theta < self.cos_total_width {
+            return 0.0;
+        }
+        if cos_theta >= self.cos_falloff_start {
+            return 1.0;
+        }
+        let delta =
+            (cos_theta - self.cos_total_width) / (self.cos_falloff_start - self.cos_total_width);
+        delta * delta * delta * delta
+    }
+}
+
+impl Light for SpotLight {
+    fn flags(&self) -> u8 {
+        DELTA_POSITION_LIGHT
+    }
+
+    fn sample_li(&self, _scene: &Scene, _rng: &mut RandGen, point: Point3D) -> Option<LightSample> {
+        let mut sample = sample_point(self.position, self.intensity, point);
+        sample.radiance *= self.falloff(sample.direction * -1.);
+        Some(sample)
+    }
+
+    fn pdf_li(&self, _scene: &Scene, _point: Point3D, _direction: Vector3D) -> Float {
+        0.0
+    }
+
+    fn sample_le(&self, _scene: &Scene, rng: &mut RandGen) -> Option<EmissionSample> {
+        let direction = sample_cone(rng, self.direction, self.cos_total_width);
+        Some(EmissionSample {
+            ray: Ray3D {
+                origin: self.position,
+                direction,
+            },
+            normal: None,
+            perror: Vector3D::new(0., 0., 0.),
+            radiance: self.intensity * self.falloff(direction),
+            pdf_pos: 1.,
+            pdf_dir: uniform_cone_pdf(self.cos_total_width),
+        })
+    }
+
+    fn pdf_le(
+        &self,
+        _scene: &Scene,
+        _point: Point3D,
+        _normal: Vector3D,
+        direction: Vector3D,
+    ) -> (Float, Float) {
+        if self.direction * direction >= self.cos_total_width {
+            (0.0, uniform_cone_pdf(self.cos_total_width))
+        } else {
+            (0.0, 0.0)
+        }
+    }
+
+    fn emitted(
+        &self,
+        _scene: &Scene,
+        _point: Point3D,
+        _normal: Vector3D,
+        direction: Vector3D,
+    ) -> Spectrum<{ crate::N_CHANNELS }> {
+        self.intensity * self.falloff(direction)
+    }
+}
+
+/// The light arriving at `point` from a source of a certain
+/// `intensity` at `position`
+fn sample_point(
+    position: Point3D,
+    intensity: Spectrum<{ crate::N_CHANNELS }>,
+    point: Point3D,
+) -> LightSample {
+    let d = position - point;
+    let distance_squared = d.length_squared();
+    if distance_squared <= 0. {
+        return LightSample {
+            radiance: Spectrum::<{ crate::N_CHANNELS }>::BLACK,
+            direction: Vector3D::new(0., 0., 1.),
+            distance: 0.0,
+            pdf: 0.0,
+        };
+    }
+    let distance = distance_squared.sqrt();
+    LightSample {
+        radiance: intensity / distance_squared,
+        direction: d / distance,
+        distance,
+        pdf: 1.0,
+    }
+}
+
+/// The probability (per unit area) of infinite lights sampling the
+/// origin of a ray in [`sample_infinite`]
+pub(crate) fn infinite_pdf_pos(scene: &Scene) -> Float {
+    let (_, radius) = scene.bounding_sphere();
+    1. / (PI * radius * radius)
+}
+
+/// Light of a certain `radiance` arriving from an infinite light in
+/// direction `w` (i.e., travelling in `-w`), sampled with a probability
+/// `pdf_dir`. It leaves a disc that covers the `scene`, facing `w`.
+fn sample_infinite(
+    scene: &Scene,
+    rng: &mut RandGen,
+    w: Vector3D,
+    radiance: Spectrum<{ crate::N_CHANNELS }>,
+    pdf_dir: Float,
+) -> EmissionSample {
+    let (centre, radius) = scene.bounding_sphere();
+    EmissionSample {
+        ray: Ray3D {
+            origin: uniform_sample_disc(rng, radius, centre + w * radius, w),
+            direction: w * -1.,
+        },
+        normal: None,
+        perror: Vector3D::new(0., 0., 0.),
+        radiance,
+        pdf_pos: infinite_pdf_pos(scene),
+        pdf_dir,
+    }
+}
+
+/// The sky, as given by the [`Scene`]'s `environment` or, if there is
+/// none, by its `sky` function and `sky_colour`. Directions are sampled in
+/// proportion to the brightness of the `environment`, or uniformly
+/// over the sphere.
+pub struct SkyLight;
+
+impl Light for SkyLight {
+    fn flags(&self) -> u8 {
+        INFINITE_LIGHT
+    }
+
+    fn sample_li(&self, scene: &Scene, rng: &mut RandGen, _point: Point3D) -> Option<LightSample> {
+        let (direction, pdf) = match &scene.environment {
+            Some(environment) => environment.sample(rng),
+            None => {
+                let p = uniform_sample_sphere(rng);
+                let direction = Vector3D::new(p.x, p.y, p.z);
+                let pdf = self.pdf_li(scene, Point3D::new(0., 0., 0.), direction);
+                (direction, pdf)
+            }
+        };
+        Some(LightSample {
+            radiance: self.le(scene, direction),
+            direction,
+            distance: Float::INFINITY,
+            pdf,
+        })
+    }
+
+    fn pdf_li(&self, scene: &Scene, _point: Point3D, direction: Vector3D) -> Float {
+        if let Some(environment) = &scene.environment {
+            environment.pdf(direction)
+        } else if scene.sky.is_some() {
+            1. / (4. * PI)
+        } else {
+            0.0
+        }
+    }
+
+    fn le(&self, scene: &Scene, direction: Vector3D) -> Spectrum<{ crate::N_CHANNELS }> {
+        scene.sky_radiance(direction)
+    }
+
+    fn sample_le(&self, scene: &Scene, rng: &mut RandGen) -> Option<EmissionSample> {
+        let sample = self.sample_li(scene, rng, Point3D::new(0., 0., 0.))?;
+        let w = sample.direction.get_normalized();
+        Some(sample_infinite(scene, rng, w, sample.radiance, sample.pdf))
+    }
+
+    fn pdf_le(
+        &self,
+        scene: &Scene,
+        point: Point3D,
+        _normal: Vector3D,
+        direction: Vector3D,
+    ) -> (Float, Float) {
+        (
+            infinite_pdf_pos(scene),
+            self.pdf_li(scene, point, direction * -1.),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{Light as LightMaterial, Material, Plastic};
+    use crate::rand::get_seeded_rng;
+    use geometry3d::{DistantSource3D, Sphere3D};
+
+    #[test]
+    fn test_flags() {
+        let scene = Scene::new();
+        let point = PointLight {
+            position: Point3D::new(0., 0., 0.),
+            intensity: Spectrum::<{ crate::N_CHANNELS }>::gray(1.),
+        };
+        assert!(point.is_delta_light());
+        assert!(!SkyLight.is_delta_light());
+        assert_eq!(
+            SkyLight.pdf_li(&scene, point.position, Vector3D::new(0., 0., 1.)),
+            0.
+        );
+
+        let distant = Object {
+            primitive: Primitive::Source(DistantSource3D::new(Vector3D::new(0., 0., 1.), 0.01)),
+            front_material_index: 0,
+            back_material_index: 0,
+        };
+        assert!(distant.is_delta_light());
+        let sphere = Object {
+            primitive: Primitive::Sphere(Sphere3D::new(1., Point3D::new(0., 0., 0.))),
+            ..distant
+        };
+        assert_eq!(sphere.flags(), AREA_LIGHT);
+    }
+
+    #[test]
+    fn test_point_and_spot() {
+        let scene = Scene::new();
+        let mut rng = get_seeded_rng(0, 0);
+        let intensity = Spectrum::<{ crate::N_CHANNELS }>::gray(100.);
+        let position = Point3D::new(0., 0., 2.);
+
+        let point = PointLight {
+            position,
+            intensity,
+        };
+        let sample = point
+            .sample_li(&scene, &mut rng, Point3D::new(0., 0., 0.))
+            .unwrap();
+        assert!((sample.radiance.radiance() - intensity.radiance() / 4.).abs() < 1e-5);
+        assert!((sample.distance - 2.).abs() < 1e-6);
+        assert!((sample.direction.z - 1.).abs() < 1e-6);
+
+        let spot = SpotLight::new(
+            position,
+            Vector3D::new(0., 0., -1.),
+            intensity,
+            (30. as Float).to_radians(),
+            (20. as Float).to_radians(),
+        );
+        let radiance = |x: Float| -> Float {
+            spot.sample_li(&scene, &mut get_seeded_rng(0, 0), Point3D::new(x, 0., 0.))
+                .unwrap()
+                .radiance
+                .radiance()
+        };
+        // Right below, within the falloff and out of the cone
+        assert!((radiance(0.) - intensity.radiance() / 4.).abs() < 1e-5);
+        let x = 2. * (25. as Float).to_radians().tan();
+        let inside = radiance(x);
+        assert!(inside > 0. && inside < intensity.radiance() / (4. + x * x));
+        assert_eq!(radiance(2.), 0.0);
+    }
+
+    #[test]
+    fn test_area_light() {
+        let mut scene = Scene::new();
+        let light = scene.push_material(Material::Light(LightMaterial(Spectrum::<
+            { crate::N_CHANNELS },
+        >::gray(10.))));
+        let (radius, height) = (0.5, 3.);
+        let centre = Point3D::new(0., 0., height);
+        scene.push_object(
+            light,
+            light,
+            Primitive::Sphere(Sphere3D::new(radius, centre)),
+        );
+
+        let mut rng = get_seeded_rng(0, 0);
+        let point = Point3D::new(0., 0., 0.);
+        let sin2 = radius * radius / (height * height);
+        let cone_pdf = 1. / (2. * PI * (1. - (1. - sin2).sqrt()));
+        for _ in 0..10 {
+            let sample = scene.lights[0].sample_li(&scene, &mut rng, point).unwrap();
+            assert!((sample.radiance.radiance() - 10.).abs() < 1e-4);
+            assert!((sample.pdf - cone_pdf).abs() / cone_pdf < 1e-4);
+            assert!(sample.distance < height && sample.distance > height - 2. * radius);
+            let pdf = scene.lights[0].pdf_li(&scene, point, sample.direction);
+            assert!((pdf - sample.pdf).abs() / cone_pdf < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_emission() {
+        let mut scene = Scene::new();
+        let light = scene.push_material(Material::Light(LightMaterial(Spectrum::<
+            { crate::N_CHANNELS },
+        >::gray(10.))));
+        let black = scene.push_material(Material::Plastic(Plastic {
+            colour: Spectrum::<{ crate::N_CHANNELS }>::gray(0.),
+            specularity: 0.,
+            roughness: 0.,
+        }));
+        scene.push_object(
+            light,
+            black,
+            Primitive::Sphere(Sphere3D::new(0.5, Point3D::new(0., 0., 3.))),
+        );
+        scene.push_object(
+            light,
+            light,
+            Primitive::Source(DistantSource3D::new(Vector3D::new(0., 0., 1.), 0.1)),
+        );
+        scene.push_light(Box::new(SpotLight::new(
+            Point3D::new(0., 0., 2.),
+            Vector3D::new(0., 0., -1.),
+            Spectrum::<{ crate::N_CHANNELS }>::gray(100.),
+            (30. as Float).to_radians(),
+            (20. as Float).to_radians(),
+        )));
+
+        let mut rng = get_seeded_rng(0, 0);
+        let lights = [
+            &scene.lights[0] as &dyn Light,
+            scene.delta_lights[0].as_ref(),
+            scene.delta_lights[1].as_ref(),
+        ];
+        for light in lights {
+            for _ in 0..10 {
+                let sample = light.sample_le(&scene, &mut rng).unwrap();
+                let normal = sample.normal.unwrap_or(Vector3D::new(0., 0., 1.));
+                let (pdf_pos, pdf_dir) =
+                    light.pdf_le(&scene, sample.ray.origin, normal, sample.ray.direction);
+                assert!((pdf_pos - sample.pdf_pos).abs() <= 1e-4 * sample.pdf_pos);
+                assert!(sample.pdf_dir > 0.);
+                assert!((pdf_dir - sample.pdf_dir).abs() < 1e-4 * sample.pdf_dir);
+                if light.flags() != DELTA_DIRECTION_LIGHT {
+                    let emitted =
+                        light.emitted(&scene, sample.ray.origin, normal, sample.ray.direction);
+                    assert!((emitted.radiance() - sample.radiance.radiance()).abs() < 1e-4);
+                }
+            }
+        }
+
+        // Only the front of the sphere emits, and the distant light
+        // sends light downwards
+        let sample = scene.lights[0].sample_le(&scene, &mut rng).unwrap();
+        assert!(sample.normal.unwrap() * sample.ray.direction > 0.);
+        let sample = scene.delta_lights[0].sample_le(&scene, &mut rng).unwrap();
+        assert!(sample.ray.direction.z < -0.99);
+        assert!((sample.radiance.radiance() - 10.).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_sky() {
+        let mut scene = Scene::new();
+        scene.sky = Some(Box::new(|d: Vector3D| -> Float { d.z.max(0.) }));
+        let mut rng = get_seeded_rng(0, 0);
+        let point = Point3D::new(0., 0., 0.);
+        for _ in 0..10 {
+            let sample = SkyLight.sample_li(&scene, &mut rng, point).unwrap();
+            assert!((sample.direction.length() - 1.).abs() < 1e-4);
+            assert!((sample.radiance.radiance() - sample.direction.z.max(0.)).abs() < 1e-4);
+            assert!((sample.pdf - 1. / (4. * PI)).abs() < 1e-6);
+            assert!(sample.distance.is_infinite());
+        }
+    }
+}
@@ -129,7 +129,7 @@ impl Dielectric {
         // Process reflection...
         let mut ray1 = *ray;
         ray1.geometry.direction = mirror_dir;
-        ray1.geometry.origin = intersection_pt + normal * 0.00001;
+        ray1.geometry.origin = spawn_origin(intersection_pt, ray, mirror_dir);
         let pair1 = Some((ray1, refl * cos1));
 
         let mut ray = *ray;
@@ -137,10 +137,10 @@ impl Dielectric {
         // let pair2 = if trans.radiance() > 0.0 && ray_dir * normal < 0.0 {
         let pair2 = match cos2 {
             Some(cos2) => {
-                ray.geometry.origin = intersection_pt - normal * 0.00001;
                 ray.refraction_index = n2;
                 let trans_dir = fresnel_transmission_dir(ray_dir, normal, n1, cos1, n2, cos2);
                 ray.geometry.direction = trans_dir;
+                ray.geometry.origin = spawn_origin(intersection_pt, &ray, trans_dir);
                 ray.colour *= self.colour;
                 Some((ray, trans * cos2))
             }
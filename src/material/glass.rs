@@ -141,13 +141,13 @@ impl Glass {
         // Process reflection...
         let mut ray1 = *ray;
         ray1.geometry.direction = mirror_dir;
-        ray1.geometry.origin = intersection_pt + normal * 0.00001;
+        ray1.geometry.origin = spawn_origin(intersection_pt, ray, mirror_dir);
         let pair1 = Some((ray1, refl));
 
         // process transmission
         let mut ray = *ray;
         let pair2 = if trans.radiance() > 0.0 {
-            ray.geometry.origin = intersection_pt - normal * 0.00001;
+            ray.geometry.origin = spawn_origin(intersection_pt, &ray, ray.geometry.direction);
 
             // ray.colour *= self.colour() * trans;
             Some((ray, trans))
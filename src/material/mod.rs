@@ -100,6 +100,17 @@ impl Material {
         matches!(self, Self::Mirror(_) | Self::Glass(_) | Self::Dielectric(_))
     }
 
+    /// Returns the reflectance of materials that scatter light
+    /// like a perfect Lambertian (i.e., [`Plastic`] and [`Metal`] without
+    /// specularity). Returns `None` for any other material.
+    pub fn lambertian_reflectance(&self) -> Option<Spectrum<{ crate::N_CHANNELS }>> {
+        match self {
+            Self::Plastic(m) if m.specularity < 1e-5 => Some(m.colour),
+            Self::Metal(m) if m.specularity < 1e-5 => Some(m.colour),
+            _ => None,
+        }
+    }
+
     pub fn get_possible_paths(
         &self,
         normal: &Vector3D,
@@ -114,6 +125,38 @@ impl Material {
         }
     }
 
+    /// Chooses one of the [`Material::get_possible_paths`] proportionally to its
+    /// value, through a uniform random number `u`. Returns the new ray and its
+    /// weight (i.e., its value divided by the probability of choosing it).
+    pub fn choose_path(
+        &self,
+        normal: &Vector3D,
+        intersection_pt: &Point3D,
+        ray: &Ray,
+        u: Float,
+    ) -> Option<(Ray, Spectrum<{ crate::N_CHANNELS }>)> {
+        let paths = self.get_possible_paths(normal, intersection_pt, ray);
+        let total: Float = paths.iter().flatten().map(|(_, v)| v.radiance()).sum();
+        if total <= 0. {
+            return None;
+        }
+        let mut r = u * total;
+        let mut chosen = None;
+        for (new_ray, value) in paths.iter().flatten() {
+            let p = value.radiance();
+            if p <= 0. {
+                continue;
+            }
+            chosen = Some((*new_ray, *value));
+            if r < p {
+                break;
+            }
+            r -= p;
+        }
+        let (new_ray, value) = chosen?;
+        Some((new_ray, value * (total / value.radiance())))
+    }
+
     /// Samples the bsdf (returned by modifying the given `Ray`).
     /// Returns the value of the BSDF in that direction (as a Spectrum) and the probability
     pub fn sample_bsdf(
@@ -153,6 +196,25 @@ impl Material {
             Self::Glass(m) => m.eval_bsdf(normal, e1, e2, ray, vout),
         }
     }
+
+    /// The probability (per unit solid angle) of [`Material::sample_bsdf`]
+    /// choosing a certain direction. The arguments are the same as those of [`Material::eval_bsdf`].
+    ///
+    /// It is zero for specular materials, which cannot be sampled
+    pub fn bsdf_pdf(
+        &self,
+        normal: Vector3D,
+        e1: Vector3D,
+        e2: Vector3D,
+        ray: &Ray,
+        vout: Vector3D,
+    ) -> Float {
+        match self {
+            Self::Plastic(m) => m.bsdf_pdf(normal, e1, e2, ray, vout),
+            Self::Metal(m) => m.bsdf_pdf(normal, e1, e2, ray, vout),
+            Self::Light(_) | Self::Mirror(_) | Self::Dielectric(_) | Self::Glass(_) => 0.0,
+        }
+    }
 }
 
 #[cfg(test)]
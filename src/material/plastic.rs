@@ -88,6 +88,26 @@ impl Plastic {
 
         Spectrum::<{ crate::N_CHANNELS }>::gray(direct) + self.colour * diffuse
     }
+
+    pub fn bsdf_pdf(
+        &self,
+        normal: Vector3D,
+        e1: Vector3D,
+        e2: Vector3D,
+        ray: &Ray,
+        vout: Vector3D,
+    ) -> Float {
+        crate::material::ward::ward_pdf(
+            normal,
+            e1,
+            e2,
+            self.specularity,
+            self.roughness,
+            self.roughness,
+            ray,
+            vout * -1.,
+        )
+    }
 }
 
 #[cfg(test)]
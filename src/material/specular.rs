@@ -18,6 +18,7 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
+use crate::interaction::offset_ray_origin;
 use crate::ray::Ray;
 use crate::Float;
 use geometry3d::{Point3D, Vector3D};
@@ -153,6 +154,18 @@ pub fn fresnel_transmission_dir(
     ret
 }
 
+/// Gets the origin of a ray leaving `intersection_pt`—the point last hit
+/// by `ray`—in `direction`, so that it does not hit the same surface again
+/// (see [`Interaction::spawn_ray_origin`](crate::interaction::Interaction::spawn_ray_origin))
+pub fn spawn_origin(intersection_pt: Point3D, ray: &Ray, direction: Vector3D) -> Point3D {
+    offset_ray_origin(
+        intersection_pt,
+        ray.interaction.perror,
+        ray.interaction.geometric_normal(),
+        direction,
+    )
+}
+
 /// Calculates the purely specular reflection direction.
 pub fn mirror_direction(vin: Vector3D, normal: Vector3D) -> Vector3D {
     debug_assert!((vin.length() - 1.).abs() < 1e-6);
@@ -166,11 +179,11 @@ pub fn mirror_direction(vin: Vector3D, normal: Vector3D) -> Vector3D {
 
 /// Calculates the Mirror BSDF and modifies the given ray so that it now points in that direction
 pub fn mirror_bsdf(intersection_pt: Point3D, ray: &mut Ray, normal: Vector3D) -> Float {
-    // avoid self shading
-    ray.geometry.origin = intersection_pt + normal * 0.00001;
     let ray_dir = ray.geometry.direction;
     let cos = (ray_dir * normal).abs();
     ray.geometry.direction = mirror_direction(ray_dir, normal);
+    // avoid self shading
+    ray.geometry.origin = spawn_origin(intersection_pt, ray, ray.geometry.direction);
     debug_assert!(
         (ray.geometry.direction.length() - 1.).abs() < 1e-5,
         "dir len is {}",
@@ -23,6 +23,7 @@ use crate::ray::Ray;
 use crate::{Float, PI};
 use geometry3d::{Point3D, Vector3D};
 
+use crate::material::specular::spawn_origin;
 use crate::samplers::{local_to_world, sample_cosine_weighted_horizontal_hemisphere};
 
 const LOW_ROUGHNESS: Float = 1e-3;
@@ -50,8 +51,6 @@ pub fn sample_ward_anisotropic(
     if beta < LOW_ROUGHNESS {
         beta = LOW_ROUGHNESS
     }
-    ray.geometry.origin = intersection_pt + normal * 0.00001;
-
     let prob_spec: Float = rng.gen();
 
     if prob_spec < specularity {
@@ -99,6 +98,7 @@ pub fn sample_ward_anisotropic(
                     panic!("incorrect (i.e., NaN) bsdf when calculating Ward aniso.");
                 }
                 ray.geometry.direction = v; // update ray
+                ray.geometry.origin = spawn_origin(intersection_pt, ray, v);
                 let weight = 2. / (1. + v_n / l_n); // Eq. 15
                 return (spec, diffuse, weight);
             }
@@ -125,6 +125,7 @@ pub fn sample_ward_anisotropic(
         let pdf = normal * new_dir / PI;
         // let pdf = 1./(2.*PI);
         ray.geometry.direction = new_dir;
+        ray.geometry.origin = spawn_origin(intersection_pt, ray, new_dir);
         (0.0, diffuse, pdf)
     }
 }
@@ -170,3 +171,85 @@ pub fn evaluate_ward_anisotropic(
 
     (spec, (1. - specularity) / PI)
 }
+
+/// Calculates the probability (per unit solid angle) of [`sample_ward_anisotropic`]
+/// choosing the outgoing direction `l` when the incident direction is that of `ray`.
+///
+/// The probability of the specular lobe is based on "Notes on the Ward BRDF" (2005),
+/// by Bruce Walter
+#[allow(clippy::too_many_arguments)]
+pub fn ward_pdf(
+    normal: Vector3D,
+    e1: Vector3D,
+    e2: Vector3D,
+    specularity: Float,
+    mut alpha: Float,
+    mut beta: Float,
+    ray: &Ray,
+    l: Vector3D,
+) -> Float {
+    let l_n = l * normal;
+    let diffuse = if l_n > 0. { l_n / PI } else { 0.0 };
+    if specularity <= 0. {
+        return diffuse;
+    }
+    if alpha < LOW_ROUGHNESS {
+        alpha = LOW_ROUGHNESS;
+    }
+    if beta < LOW_ROUGHNESS {
+        beta = LOW_ROUGHNESS;
+    }
+
+    let h = (l - ray.geometry.direction).get_normalized();
+    let h_n = h * normal;
+    let l_h = l * h;
+    let spec = if h_n > 0. && l_h > 0. {
+        let x = h * e1 / (alpha * h_n);
+        let y = h * e2 / (beta * h_n);
+        let p_h = (-(x * x + y * y)).exp() / (PI * alpha * beta * h_n.powi(3));
+        p_h / (4. * l_h)
+    } else {
+        0.0
+    };
+
+    specularity * spec + (1. - specularity) * diffuse
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geometry3d::Ray3D;
+
+    #[test]
+    fn test_ward_pdf_integrates_to_one() {
+        let normal = Vector3D::new(0., 0., 1.);
+        let e1 = Vector3D::new(1., 0., 0.);
+        let e2 = Vector3D::new(0., 1., 0.);
+        let ray = Ray {
+            geometry: Ray3D {
+                origin: Point3D::new(0., 0., 1.),
+                direction: Vector3D::new(0.3, 0., -1.).get_normalized(),
+            },
+            ..Ray::default()
+        };
+
+        let mut rng = get_rng();
+        let n = 200_000;
+        for (specularity, roughness) in [(0.0, 0.0), (0.5, 0.2), (0.9, 0.15)] {
+            let mut sum = 0.0;
+            for _ in 0..n {
+                let l = crate::samplers::uniform_sample_hemisphere(&mut rng, e1, e2, normal);
+                sum += ward_pdf(normal, e1, e2, specularity, roughness, roughness, &ray, l);
+            }
+            // Uniform hemisphere... pdf = 1/(2PI)
+            let integral = 2. * PI * sum / n as Float;
+            assert!(
+                (integral - 1.).abs() < 0.05,
+                "specularity = {}, roughness = {} ... integral = {}",
+                specularity,
+                roughness,
+                integral
+            );
+        }
+    }
+}
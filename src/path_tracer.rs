@@ -0,0 +1,645 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use crate::camera::{Camera, CameraSample};
+use crate::colour::Spectrum;
+use crate::image::ImageBuffer;
+use crate::light_tree::LightSelection;
+use crate::lights::{Light, SkyLight, DELTA_POSITION_LIGHT};
+use crate::material::Material;
+use crate::progress::{render_pixels, RenderControl, RenderStats};
+use crate::rand::*;
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::Float;
+use geometry3d::intersection::SurfaceSide;
+use geometry3d::{Point3D, Ray3D};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// The power heuristic (with an exponent of 2) for combining two sampling strategies
+pub fn power_heuristic(pdf: Float, other_pdf: Float) -> Float {
+    let a = pdf * pdf;
+    let b = other_pdf * other_pdf;
+    if a + b > 0. {
+        a / (a + b)
+    } else {
+        0.0
+    }
+}
+
+/// Finds the element of [`Scene::lights`] hit by `ray` at `point`. Returns its
+/// index and the probability of sampling it (through [`Primitive::sample_direction`](crate::primitive::Primitive::sample_direction))
+/// from the origin of the `ray`.
+pub fn hit_light(scene: &Scene, ray: &Ray3D, point: Point3D) -> Option<(usize, Float)> {
+    let mut best: Option<(Float, Float, usize)> = None; // (distance squared to point, pdf, light)
+    for (i, light) in scene.lights.iter().enumerate() {
+        if let Some(info) = light.primitive.intersect(ray) {
+            let d = (info.p - point).length_squared();
+            match best {
+                Some((bd, ..)) if bd <= d => {}
+                _ => best = Some((d, light.primitive.solid_angle_pdf(&info, ray), i)),
+            }
+        }
+    }
+    best.map(|(_, pdf, i)| (i, pdf))
+}
+
+/// The probability of sampling the light hit by `ray` at `point` (see
+/// [`hit_light`]), including that of choosing it from the origin of
+/// the `ray` through a [`LightSelection`]
+pub fn hit_light_pdf(
+    scene: &Scene,
+    ray: &Ray3D,
+    point: Point3D,
+    selection: LightSelection,
+) -> Float {
+    hit_light(scene, ray, point).map_or(0.0, |(i, pdf)| {
+        pdf * scene
+            .light_tree
+            .as_ref()
+            .map_or(1., |tree| tree.pmf(selection, ray.origin, i))
+    })
+}
+
+/// The radiance arriving from the sky and the distant light sources
+/// along a ray that did not hit anything. `bsdf_pdf` is the probability
+/// of the BSDF sampling `ray`, or `None` if it was not sampled from a BSDF
+/// (in which case the light is not weighted against the shadow rays).
+pub fn escaped_radiance(
+    scene: &Scene,
+    ray: &Ray3D,
+    bsdf_pdf: Option<Float>,
+) -> Spectrum<{ crate::N_CHANNELS }> {
+    let mut ret = Spectrum::<{ crate::N_CHANNELS }>::BLACK;
+
+    // These are also sampled by shadow rays
+    let sky: &'static dyn Light = &SkyLight;
+    let lights = scene.delta_lights.iter().map(|l| l.as_ref());
+    for light in lights.chain(std::iter::once(sky)) {
+        let colour = light.le(scene, ray.direction);
+        if colour.is_black() {
+            continue;
+        }
+        let weight = match bsdf_pdf {
+            Some(pdf) => power_heuristic(pdf, light.pdf_li(scene, ray.origin, ray.direction)),
+            None => 1.,
+        };
+        ret += colour * weight;
+    }
+    ret
+}
+
+/// An unbiased, unidirectional, path tracer. At each interaction, light
+/// sources are sampled directly (i.e., next-event estimation) and the
+/// results are combined with those of sampling the BSDF by means of
+/// Multiple Importance Sampling.
+///
+/// It is much slower than [`RayTracer`](crate::RayTracer), but it converges
+/// to the right answer, so it is useful for producing reference solutions.
+pub struct PathTracer {
+    /// The number of paths traced per pixel (or per point)
+    pub n_samples: usize,
+
+    /// The maximum number of bounces
+    pub max_depth: usize,
+
+    /// The number of bounces after which paths can be terminated
+    /// by Russian roulette
+    pub russian_roulette_depth: usize,
+
+    /// The kind of sequence the paths of each pixel are drawn from
+    pub sampler: SamplerKind,
+
+    /// The seed of the random numbers. If given, each pixel (or point)
+    /// draws its random numbers from its own stream, so results are
+    /// reproducible regardless of the number of threads.
+    pub seed: Option<u64>,
+}
+
+impl Default for PathTracer {
+    fn default() -> Self {
+        Self {
+            n_samples: 64,
+            max_depth: 12,
+            russian_roulette_depth: 3,
+            sampler: SamplerKind::default(),
+            seed: None,
+        }
+    }
+}
+
+impl PathTracer {
+    /// Samples every light source in the scene (including the sky),
+    /// returning the radiance reflected towards the origin of `ray`
+    fn sample_lights(
+        &self,
+        rng: &mut RandGen,
+        scene: &Scene,
+        material: &Material,
+        ray: &Ray,
+        node_aux: &mut Vec<usize>,
+    ) -> Spectrum<{ crate::N_CHANNELS }> {
+        let mut ret = self.sample_light_array(rng, scene, material, ray, &scene.lights, node_aux);
+        ret += self.sample_light_array(rng, scene, material, ray, &scene.delta_lights, node_aux);
+        if scene.has_sky() {
+            let sky = std::slice::from_ref(&SkyLight);
+            ret += self.sample_light_array(rng, scene, material, ray, sky, node_aux);
+        }
+        ret
+    }
+
+    /// Sends one shadow ray towards each of the `lights`, weighting the
+    /// results against the paths that might find them by sampling the BSDF
+    fn sample_light_array<L: Light>(
+        &self,
+        rng: &mut RandGen,
+        scene: &Scene,
+        material: &Material,
+        ray: &Ray,
+        lights: &[L],
+        node_aux: &mut Vec<usize>,
+    ) -> Spectrum<{ crate::N_CHANNELS }> {
+        let (intersection_pt, normal, e1, e2) = ray.get_triad();
+        let mut ret = Spectrum::<{ crate::N_CHANNELS }>::BLACK;
+
+        for light in lights.iter() {
+            let sample = match light.sample_li(scene, rng, intersection_pt) {
+                Some(s) => s,
+                None => continue,
+            };
+            // The materials that get here only reflect light
+            let direction = sample.direction;
+            if sample.pdf < 1e-18 || normal * direction <= 0. {
+                continue;
+            }
+            let shadow_ray = Ray3D {
+                origin: ray.interaction.spawn_ray_origin(direction),
+                direction,
+            };
+            let distance_squared = sample.distance * sample.distance;
+            if !scene.unobstructed_distance(&shadow_ray, distance_squared, node_aux) {
+                continue;
+            }
+            let vout = direction * -1.;
+            let bsdf = material.eval_bsdf(normal, e1, e2, ray, vout);
+            let cos_theta = normal * direction;
+            // Paths never hit lights that have no surface
+            let weight = if light.flags() & DELTA_POSITION_LIGHT != 0 {
+                1.
+            } else {
+                let bsdf_pdf = material.bsdf_pdf(normal, e1, e2, ray, vout);
+                power_heuristic(sample.pdf, bsdf_pdf)
+            };
+            ret += sample.radiance * bsdf * (cos_theta * weight / sample.pdf);
+        }
+        ret
+    }
+
+    /// Traces a single path, returning the radiance travelling
+    /// against `ray`
+    pub fn trace_path(
+        &self,
+        rng: &mut RandGen,
+        scene: &Scene,
+        ray: Ray3D,
+        node_aux: &mut Vec<usize>,
+    ) -> Spectrum<{ crate::N_CHANNELS }> {
+        let mut ray = Ray {
+            geometry: ray,
+            ..Ray::default()
+        };
+        let mut radiance = Spectrum::<{ crate::N_CHANNELS }>::BLACK;
+        let mut beta = Spectrum::<{ crate::N_CHANNELS }>::ONE;
+
+        // The probability of the BSDF having chosen the current ray... `None` if
+        // the ray comes from the camera or from a specular reflection
+        let mut bsdf_pdf: Option<Float> = None;
+        let mut depth = 0;
+
+        loop {
+            let triangle_index = match scene.cast_ray(&mut ray, node_aux) {
+                Some(i) => i,
+                None => {
+                    radiance += beta * escaped_radiance(scene, &ray.geometry, bsdf_pdf);
+                    break;
+                }
+            };
+
+            let material = match ray.interaction.geometry_shading.side {
+                SurfaceSide::Front => &scene.materials[scene.front_material_index(triangle_index)],
+                SurfaceSide::Back => &scene.materials[scene.back_material_index(triangle_index)],
+                SurfaceSide::NonApplicable => break,
+            };
+
+            // Emitting materials don't reflect
+            if material.emits_light() {
+                let weight = match bsdf_pdf {
+                    Some(pdf) => {
+                        let light_pdf = hit_light_pdf(
+                            scene,
+                            &ray.geometry,
+                            ray.interaction.point,
+                            LightSelection::All,
+                        );
+                        power_heuristic(pdf, light_pdf)
+                    }
+                    None => 1.,
+                };
+                radiance += beta * material.colour() * weight;
+                break;
+            }
+
+            if depth >= self.max_depth {
+                break;
+            }
+            depth += 1;
+
+            ray.interaction
+                .interpolate_normal(scene.normals_at(triangle_index));
+            let (intersection_pt, normal, e1, e2) = ray.get_triad();
+
+            if material.specular_only() {
+                let u = rng.gen::<Float>();
+                let (new_ray, weight) =
+                    match material.choose_path(&normal, &intersection_pt, &ray, u) {
+                        Some(v) => v,
+                        None => break,
+                    };
+                beta *= weight;
+                ray = Ray {
+                    geometry: new_ray.geometry,
+                    refraction_index: new_ray.refraction_index,
+                    ..Ray::default()
+                };
+                bsdf_pdf = None;
+                continue;
+            }
+
+            /* DIRECT LIGHT */
+            radiance += beta * self.sample_lights(rng, scene, material, &ray, node_aux);
+
+            /* SAMPLE THE BSDF */
+            let mut new_ray = ray;
+            material.sample_bsdf(normal, e1, e2, intersection_pt, &mut new_ray, rng);
+            let direction = new_ray.geometry.direction;
+            let vout = direction * -1.;
+            let pdf = material.bsdf_pdf(normal, e1, e2, &ray, vout);
+            if pdf < 1e-18 {
+                break;
+            }
+            let bsdf = material.eval_bsdf(normal, e1, e2, &ray, vout);
+            beta *= bsdf * ((normal * direction).abs() / pdf);
+            bsdf_pdf = Some(pdf);
+            ray = Ray {
+                geometry: new_ray.geometry,
+                refraction_index: ray.refraction_index,
+                ..Ray::default()
+            };
+
+            /* RUSSIAN ROULETTE */
+            let max_beta = beta.0.iter().cloned().fold(0.0, Float::max);
+            if max_beta <= 0. {
+                break;
+            }
+            if depth > self.russian_roulette_depth && max_beta < 1. {
+                let q = (1. - max_beta).max(0.05);
+                if rng.gen::<Float>() < q {
+                    break;
+                }
+                beta *= 1. / (1. - q);
+            }
+        }
+
+        radiance
+    }
+
+    /// Averages `n_samples` paths along a ray, which are the
+    /// samples of the `pixel`-th pixel (or point)
+    fn average_paths(
+        &self,
+        rng: &mut RandGen,
+        pixel: u64,
+        scene: &Scene,
+        ray: Ray3D,
+        node_aux: &mut Vec<usize>,
+    ) -> Spectrum<{ crate::N_CHANNELS }> {
+        let mut ret = Spectrum::<{ crate::N_CHANNELS }>::BLACK;
+        for i in 0..self.n_samples {
+            rng.start_pixel_sample(pixel, i as u64);
+            ret += self.trace_path(rng, scene, ray, node_aux);
+        }
+        ret / self.n_samples.max(1) as Float
+    }
+
+    /// Calculates the radiance travelling against each of the `rays`
+    pub fn calc_points(
+        &self,
+        scene: &Scene,
+        rays: &[Ray3D],
+    ) -> Vec<Spectrum<{ crate::N_CHANNELS }>> {
+        #[cfg(not(feature = "parallel"))]
+        let aux_iter = rays.iter();
+        #[cfg(feature = "parallel")]
+        let aux_iter = rays.par_iter();
+
+        let seed = self.seed.unwrap_or_else(|| get_rng().gen());
+        aux_iter
+            .enumerate()
+            .map(|(i, ray)| {
+                let mut node_aux = Vec::with_capacity(64);
+                let mut rng = get_sampler(self.sampler, self.n_samples, seed, i as u64);
+                let ray = Ray3D {
+                    origin: ray.origin,
+                    direction: ray.direction.get_normalized(),
+                };
+                self.average_paths(&mut rng, i as u64, scene, ray, &mut node_aux)
+            })
+            .collect()
+    }
+
+    /// Renders the scene, without reporting progress
+    pub fn render(self, scene: &Scene, camera: &dyn Camera) -> ImageBuffer {
+        let (image, _stats) = self.render_with_control(scene, camera, &RenderControl::default());
+        image
+    }
+
+    /// Renders the scene, reporting the number of pixels done to the
+    /// `control` and checking whether it has been cancelled between chunks of
+    /// pixels. The pixels of a cancelled render are left black.
+    pub fn render_with_control(
+        &self,
+        scene: &Scene,
+        camera: &dyn Camera,
+        control: &RenderControl,
+    ) -> (ImageBuffer, RenderStats) {
+        let (width, height) = camera.film_resolution();
+        let seed = self.seed.unwrap_or_else(|| get_rng().gen());
+
+        let (pixels, stats) = render_pixels(
+            width * height,
+            control,
+            || Vec::with_capacity(64),
+            |node_aux, pindex| {
+                let y = (pindex as Float / width as Float).floor() as usize;
+                let x = pindex - y * width;
+                let (ray, _weight) = camera.gen_ray(&CameraSample::new((x, y)));
+
+                let mut rng = get_sampler(self.sampler, self.n_samples, seed, pindex as u64);
+                self.average_paths(&mut rng, pindex as u64, scene, ray.geometry, node_aux)
+            },
+            |_| {},
+        );
+        (ImageBuffer::from_pixels(width, height, pixels), stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{Light, Plastic};
+    use crate::primitive::Primitive;
+    use geometry3d::{DistantSource3D, Sphere3D, Triangle3D, Vector3D};
+
+    #[test]
+    fn test_power_heuristic() {
+        assert!((power_heuristic(1., 1.) - 0.5).abs() < 1e-9);
+        assert!((power_heuristic(1., 0.) - 1.).abs() < 1e-9);
+        assert!(power_heuristic(0., 1.).abs() < 1e-9);
+        assert!(power_heuristic(0., 0.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_lit_floor() {
+        let mut scene = Scene::new();
+        let reflectance = 0.5;
+        let gray = scene.push_material(Material::Plastic(Plastic {
+            colour: Spectrum::<{ crate::N_CHANNELS }>::gray(reflectance),
+            specularity: 0.0,
+            roughness: 0.0,
+        }));
+        const L: Float = 10.;
+        let tri = Triangle3D::new(
+            Point3D::new(-L, -L, 0.),
+            Point3D::new(L, -L, 0.),
+            Point3D::new(L, L, 0.),
+        )
+        .unwrap();
+        scene.push_object(gray, gray, Primitive::Triangle(tri));
+        let tri = Triangle3D::new(
+            Point3D::new(-L, -L, 0.),
+            Point3D::new(L, L, 0.),
+            Point3D::new(-L, L, 0.),
+        )
+        .unwrap();
+        scene.push_object(gray, gray, Primitive::Triangle(tri));
+
+        let angle = (5. as Float).to_radians();
+        let brightness = 1000.;
+        let sun = scene.push_material(Material::Light(Light(
+            Spectrum::<{ crate::N_CHANNELS }>::gray(brightness),
+        )));
+        scene.push_object(
+            sun,
+            sun,
+            Primitive::Source(DistantSource3D::new(Vector3D::new(0., 0., 1.), angle)),
+        );
+        scene.build_accelerator();
+
+        let integrator = PathTracer {
+            n_samples: 300,
+            ..PathTracer::default()
+        };
+        let ray = Ray3D {
+            origin: Point3D::new(0., 0., 1.),
+            direction: Vector3D::new(0., 0., -1.),
+        };
+        let found = integrator.calc_points(&scene, &[ray])[0].radiance();
+
+        let omega = 2. * crate::PI * (1. - (angle / 2.).cos());
+        let expected = reflectance / crate::PI * brightness * omega;
+        assert!(
+            (found - expected).abs() / expected < 0.05,
+            "expected {}, found {}",
+            expected,
+            found
+        );
+    }
+
+    #[test]
+    fn test_seed() {
+        let mut scene = Scene::new();
+        let gray = scene.push_material(Material::Plastic(Plastic {
+            colour: Spectrum::<{ crate::N_CHANNELS }>::gray(0.5),
+            specularity: 0.0,
+            roughness: 0.0,
+        }));
+        let tri = Triangle3D::new(
+            Point3D::new(-10., -10., 0.),
+            Point3D::new(10., -10., 0.),
+            Point3D::new(0., 10., 0.),
+        )
+        .unwrap();
+        scene.push_object(gray, gray, Primitive::Triangle(tri));
+        scene.sky = Some(Box::new(|_: Vector3D| -> Float { 1. }));
+        scene.build_accelerator();
+
+        let rays: Vec<Ray3D> = (0..20)
+            .map(|i| Ray3D {
+                origin: Point3D::new(i as Float / 10., 0., 1.),
+                direction: Vector3D::new(0., 0., -1.),
+            })
+            .collect();
+        let integrator = |seed| PathTracer {
+            n_samples: 4,
+            seed,
+            ..PathTracer::default()
+        };
+        let a = integrator(Some(1)).calc_points(&scene, &rays);
+        let b = integrator(Some(1)).calc_points(&scene, &rays);
+        let c = integrator(Some(2)).calc_points(&scene, &rays);
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn test_punctual_lights_and_sky() {
+        // A grey floor lit by a point light right above it, and
+        // by a sky of unit radiance
+        let mut scene = Scene::new();
+        let rho = 0.5;
+        let gray = scene.push_material(Material::Plastic(Plastic {
+            colour: Spectrum::<{ crate::N_CHANNELS }>::gray(rho),
+            specularity: 0.0,
+            roughness: 0.0,
+        }));
+        const L: Float = 10.;
+        let tri = Triangle3D::new(
+            Point3D::new(-L, -L, 0.),
+            Point3D::new(L, -L, 0.),
+            Point3D::new(L, L, 0.),
+        )
+        .unwrap();
+        scene.push_object(gray, gray, Primitive::Triangle(tri));
+        let tri = Triangle3D::new(
+            Point3D::new(-L, -L, 0.),
+            Point3D::new(L, L, 0.),
+            Point3D::new(-L, L, 0.),
+        )
+        .unwrap();
+        scene.push_object(gray, gray, Primitive::Triangle(tri));
+
+        let (intensity, height) = (100., 2.);
+        scene.push_light(Box::new(crate::lights::PointLight {
+            position: Point3D::new(0., 0., height),
+            intensity: Spectrum::<{ crate::N_CHANNELS }>::gray(intensity),
+        }));
+        scene.sky = Some(Box::new(|d: Vector3D| -> Float {
+            if d.z > 0. {
+                1.
+            } else {
+                0.
+            }
+        }));
+        scene.build_accelerator();
+
+        let integrator = PathTracer {
+            n_samples: 500,
+            max_depth: 1,
+            ..PathTracer::default()
+        };
+        let ray = Ray3D {
+            origin: Point3D::new(0., 0., 1.),
+            direction: Vector3D::new(0., 0., -1.),
+        };
+        let found = integrator.calc_points(&scene, &[ray])[0].radiance();
+
+        // A Lambertian reflects rho/PI of the irradiance, which is
+        // I/h^2 from the light and PI from the sky
+        let expected = rho / crate::PI * (intensity / (height * height) + crate::PI);
+        assert!(
+            (found - expected).abs() / expected < 0.05,
+            "expected {}, found {}",
+            expected,
+            found
+        );
+    }
+
+    #[test]
+    fn test_sphere_light() {
+        // A grey floor under a spherical light that subtends a wide cone, so
+        // both light and BSDF samples contribute through MIS
+        let mut scene = Scene::new();
+        let rho = 0.5;
+        let gray = scene.push_material(Material::Plastic(Plastic {
+            colour: Spectrum::<{ crate::N_CHANNELS }>::gray(rho),
+            specularity: 0.0,
+            roughness: 0.0,
+        }));
+        const L: Float = 10.;
+        let tri = Triangle3D::new(
+            Point3D::new(-L, -L, 0.),
+            Point3D::new(L, -L, 0.),
+            Point3D::new(L, L, 0.),
+        )
+        .unwrap();
+        scene.push_object(gray, gray, Primitive::Triangle(tri));
+        let tri = Triangle3D::new(
+            Point3D::new(-L, -L, 0.),
+            Point3D::new(L, L, 0.),
+            Point3D::new(-L, L, 0.),
+        )
+        .unwrap();
+        scene.push_object(gray, gray, Primitive::Triangle(tri));
+
+        let (radiance, radius, height) = (10., 1., 2.);
+        let light = scene.push_material(Material::Light(Light(
+            Spectrum::<{ crate::N_CHANNELS }>::gray(radiance),
+        )));
+        let centre = Point3D::new(0., 0., height);
+        scene.push_object(
+            light,
+            light,
+            Primitive::Sphere(Sphere3D::new(radius, centre)),
+        );
+        scene.build_accelerator();
+
+        let integrator = PathTracer {
+            n_samples: 300,
+            seed: Some(1),
+            ..PathTracer::default()
+        };
+        let ray = Ray3D {
+            origin: Point3D::new(0., 0., 0.5),
+            direction: Vector3D::new(0., 0., -1.),
+        };
+        let found = integrator.calc_points(&scene, &[ray])[0].radiance();
+
+        // The irradiance under a sphere of radiance Le is PI*Le*sin^2(alpha),
+        // where sin(alpha) = r/h
+        let expected = rho * radiance * (radius / height).powi(2);
+        assert!(
+            (found - expected).abs() / expected < 0.05,
+            "expected {}, found {}",
+            expected,
+            found
+        );
+    }
+}
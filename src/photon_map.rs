@@ -0,0 +1,755 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Photon maps (i.e., the equivalent to Radiance's `mkpmap`), based on
+//! "Realistic Image Synthesis Using Photon Mapping" (2001), by Henrik Wann Jensen.
+//!
+//! Photons are emitted from every light source (see [`Light::sample_le`]), they
+//! travel through the specular materials (e.g., `Glass`, `Dielectric` and `Mirror`) and
+//! they are stored when they hit other surfaces. The _global_ map holds every
+//! stored photon, while the _caustic_ map only holds those that reached a
+//! surface after being transmitted or reflected specularly.
+
+use crate::colour::Spectrum;
+use crate::lights::{EmissionSample, Light, SkyLight};
+use crate::rand::*;
+use crate::ray::Ray;
+use crate::samplers::{uniform_sample_disc, uniform_sample_sphere};
+use crate::scene::Scene;
+use crate::{Float, PI};
+use geometry3d::intersection::SurfaceSide;
+use geometry3d::{Point3D, Ray3D, Vector3D};
+use solar::ReinhartSky;
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// A packet of light stored on a surface
+#[derive(Clone, Copy)]
+pub struct Photon {
+    /// Where the photon hit a surface
+    pub position: Point3D,
+
+    /// The direction in which the photon was travelling
+    pub direction: Vector3D,
+
+    /// The flux carried by the photon
+    pub power: Spectrum<{ crate::N_CHANNELS }>,
+
+    /// The sky patch—in a [`ReinhartSky`]—from which the photon was emitted. This is
+    /// only used for calculating Daylight Coefficients.
+    pub patch: Option<usize>,
+}
+
+impl Photon {
+    fn coordinate(&self, axis: u8) -> Float {
+        match axis {
+            0 => self.position.x,
+            1 => self.position.y,
+            _ => self.position.z,
+        }
+    }
+}
+
+/// A set of [`Photon`] organized in a kd-tree, so that the
+/// nearest ones to a point can be found quickly
+pub struct PhotonMap {
+    /// The photons, sorted so that the one in the middle of every
+    /// range splits that range in two
+    photons: Vec<Photon>,
+
+    /// The axis along which each photon splits its range
+    axes: Vec<u8>,
+
+    /// The number of photons used for each density estimate
+    pub n_lookup: usize,
+
+    /// The maximum distance between a point and the photons used
+    /// for estimating the irradiance over it
+    pub max_radius: Float,
+}
+
+impl PhotonMap {
+    /// Builds the kd-tree
+    pub fn new(mut photons: Vec<Photon>, n_lookup: usize, max_radius: Float) -> Self {
+        let mut axes = vec![0; photons.len()];
+        Self::balance(&mut photons, &mut axes);
+        Self {
+            photons,
+            axes,
+            n_lookup,
+            max_radius,
+        }
+    }
+
+    /// Sorts `photons` so that the one in the middle splits the rest along
+    /// the axis in which they spread the most, and then does the same
+    /// with each half
+    fn balance(photons: &mut [Photon], axes: &mut [u8]) {
+        if photons.len() < 2 {
+            return;
+        }
+        let mut min = Point3D::new(Float::MAX, Float::MAX, Float::MAX);
+        let mut max = Point3D::new(Float::MIN, Float::MIN, Float::MIN);
+        for p in photons.iter() {
+            let v = p.position;
+            min = Point3D::new(min.x.min(v.x), min.y.min(v.y), min.z.min(v.z));
+            max = Point3D::new(max.x.max(v.x), max.y.max(v.y), max.z.max(v.z));
+        }
+        let d = max - min;
+        let axis = if d.x >= d.y && d.x >= d.z {
+            0
+        } else if d.y >= d.z {
+            1
+        } else {
+            2
+        };
+
+        let mid = photons.len() / 2;
+        photons.select_nth_unstable_by(mid, |a, b| {
+            a.coordinate(axis)
+                .partial_cmp(&b.coordinate(axis))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        axes[mid] = axis;
+        let (left_photons, right_photons) = photons.split_at_mut(mid);
+        let (left_axes, right_axes) = axes.split_at_mut(mid);
+        Self::balance(left_photons, left_axes);
+        Self::balance(&mut right_photons[1..], &mut right_axes[1..]);
+    }
+
+    /// The number of photons in the map
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    /// Checks whether the map has no photons
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    /// Borrows the photons
+    pub fn photons(&self) -> &[Photon] {
+        &self.photons
+    }
+
+    /// Collects (into `found`, as `(squared distance, index)` pairs) the
+    /// `n_lookup` photons closest to `point` that arrived from the side
+    /// `normal` points to
+    fn nearest(
+        &self,
+        lo: usize,
+        hi: usize,
+        point: Point3D,
+        normal: Vector3D,
+        found: &mut Vec<(Float, usize)>,
+    ) {
+        if lo >= hi {
+            return;
+        }
+        let mid = (lo + hi) / 2;
+        let photon = &self.photons[mid];
+        let axis = self.axes[mid];
+        let delta = match axis {
+            0 => point.x,
+            1 => point.y,
+            _ => point.z,
+        } - photon.coordinate(axis);
+
+        let (near, far) = if delta < 0. {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+        self.nearest(near.0, near.1, point, normal, found);
+
+        let d2 = (photon.position - point).length_squared();
+        if d2 < self.search_radius_squared(found) && photon.direction * normal < 0. {
+            if found.len() < self.n_lookup {
+                found.push((d2, mid));
+            } else if let Some(furthest) = found
+                .iter_mut()
+                .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal))
+            {
+                *furthest = (d2, mid);
+            }
+        }
+
+        if delta * delta < self.search_radius_squared(found) {
+            self.nearest(far.0, far.1, point, normal, found);
+        }
+    }
+
+    /// The squared distance within which closer photons are still useful
+    fn search_radius_squared(&self, found: &[(Float, usize)]) -> Float {
+        if found.len() < self.n_lookup {
+            self.max_radius * self.max_radius
+        } else {
+            found.iter().map(|(d2, _)| *d2).fold(0.0, Float::max)
+        }
+    }
+
+    /// Finds the photons used for estimating the irradiance at `point`, and
+    /// the area of the disc that contains them
+    fn lookup(&self, point: Point3D, normal: Vector3D) -> (Vec<(Float, usize)>, Float) {
+        let mut found = Vec::with_capacity(self.n_lookup);
+        self.nearest(0, self.photons.len(), point, normal, &mut found);
+        let r2 = self.search_radius_squared(&found);
+        (found, PI * r2)
+    }
+
+    /// Estimates the irradiance over a surface at `point`, with
+    /// a `normal` pointing towards the side that receives light
+    pub fn irradiance(&self, point: Point3D, normal: Vector3D) -> Spectrum<{ crate::N_CHANNELS }> {
+        let mut ret = Spectrum::<{ crate::N_CHANNELS }>::BLACK;
+        let (found, area) = self.lookup(point, normal);
+        if area <= 0. {
+            return ret;
+        }
+        for (_, i) in found {
+            ret += self.photons[i].power;
+        }
+        ret / area
+    }
+
+    /// Like [`Self::irradiance`], but separating the contribution of each of
+    /// the `n_patches` sky patches
+    pub fn irradiance_by_patch(
+        &self,
+        point: Point3D,
+        normal: Vector3D,
+        n_patches: usize,
+    ) -> Vec<Spectrum<{ crate::N_CHANNELS }>> {
+        let mut ret = vec![Spectrum::<{ crate::N_CHANNELS }>::BLACK; n_patches];
+        let (found, area) = self.lookup(point, normal);
+        if area <= 0. {
+            return ret;
+        }
+        for (_, i) in found {
+            let photon = &self.photons[i];
+            if let Some(patch) = photon.patch {
+                if patch < n_patches {
+                    ret[patch] += photon.power / area;
+                }
+            }
+        }
+        ret
+    }
+}
+
+/// The global and the caustic photon maps of a scene
+pub struct PhotonMaps {
+    /// Every photon that hit a non-specular surface
+    pub global: PhotonMap,
+
+    /// The photons that hit a non-specular surface after being
+    /// reflected or transmitted only by specular ones
+    pub caustic: PhotonMap,
+}
+
+/// Emits photons into a [`Scene`] and builds [`PhotonMaps`]
+pub struct PhotonMapper {
+    /// The number of photons emitted for building the global map
+    pub n_global_photons: usize,
+
+    /// The number of photons emitted for building the caustic map
+    pub n_caustic_photons: usize,
+
+    /// The maximum number of times a photon bounces
+    pub max_depth: usize,
+
+    /// The number of photons used for each density estimate
+    pub n_lookup: usize,
+
+    /// The maximum distance between a point and the photons used
+    /// for estimating the irradiance over it
+    pub max_radius: Float,
+
+    /// The seed of the random numbers. If given, each chunk of photons
+    /// draws its random numbers from its own stream, so the maps are
+    /// reproducible regardless of the number of threads.
+    pub seed: Option<u64>,
+}
+
+impl Default for PhotonMapper {
+    fn default() -> Self {
+        Self {
+            n_global_photons: 100_000,
+            n_caustic_photons: 100_000,
+            max_depth: 8,
+            n_lookup: 50,
+            max_radius: 0.5,
+            seed: None,
+        }
+    }
+}
+
+impl PhotonMapper {
+    /// The number of photons emitted by each parallel task
+    const CHUNK_LEN: usize = 1024;
+
+    /// Gets the random number generator of the `stream`-th task
+    fn rng(&self, stream: usize) -> RandGen {
+        match self.seed {
+            Some(seed) => get_seeded_rng(seed, stream as u64),
+            None => get_rng(),
+        }
+    }
+
+    /// The lights of a [`Scene`] (i.e., its [`Scene::lights`], its
+    /// [`Scene::delta_lights`] and its sky), each with an estimate of the flux
+    /// it emits. Used for choosing an emitter proportionally to its power.
+    fn emitters<'a>(&self, scene: &'a Scene, rng: &mut RandGen) -> Vec<(&'a dyn Light, Float)> {
+        // The number of rays sampled from each light
+        const N: usize = 1000;
+        let sky: &'static dyn Light = &SkyLight;
+        let lights = scene
+            .lights
+            .iter()
+            .map(|l| l as &dyn Light)
+            .chain(scene.delta_lights.iter().map(|l| l.as_ref()))
+            .chain(scene.has_sky().then_some(sky));
+
+        let mut ret = Vec::new();
+        for light in lights {
+            let mut total = 0.0;
+            for _ in 0..N {
+                if let Some(flux) = light.sample_le(scene, rng).as_ref().and_then(flux) {
+                    total += flux.radiance();
+                }
+            }
+            ret.push((light, total / N as Float));
+        }
+        ret.retain(|(_, power)| *power > 0.);
+        ret
+    }
+
+    /// Emits a photon from one of the `emitters` (chosen proportionally to
+    /// their power), returning the ray it follows and its power
+    fn emit(
+        &self,
+        scene: &Scene,
+        emitters: &[(&dyn Light, Float)],
+        total_power: Float,
+        n_photons: usize,
+        rng: &mut RandGen,
+    ) -> Option<(Ray3D, Spectrum<{ crate::N_CHANNELS }>)> {
+        let mut r = rng.gen::<Float>() * total_power;
+        let (light, power) = emitters
+            .iter()
+            .find(|(_, p)| {
+                if r < *p {
+                    true
+                } else {
+                    r -= *p;
+                    false
+                }
+            })
+            .or_else(|| emitters.last())?;
+        let choice_pdf = power / total_power;
+
+        let sample = light.sample_le(scene, rng)?;
+        let flux = flux(&sample)?;
+        Some((sample.spawn_ray(), flux / (choice_pdf * n_photons as Float)))
+    }
+
+    /// Emits `n_photons` photons, and returns those that are stored. If `caustic`,
+    /// only photons that arrive at a non-specular surface after being scattered
+    /// specularly are stored.
+    fn shoot<F>(&self, scene: &Scene, n_photons: usize, caustic: bool, emit: F) -> Vec<Photon>
+    where
+        F: Fn(&mut RandGen) -> Option<(Ray3D, Spectrum<{ crate::N_CHANNELS }>, Option<usize>)>
+            + Sync,
+    {
+        let n_chunks = (n_photons + Self::CHUNK_LEN - 1) / Self::CHUNK_LEN;
+
+        #[cfg(not(feature = "parallel"))]
+        let aux_iter = 0..n_chunks;
+        #[cfg(feature = "parallel")]
+        let aux_iter = (0..n_chunks).into_par_iter();
+
+        let chunks: Vec<Vec<Photon>> = aux_iter
+            .map(|chunk| {
+                // The global and caustic passes use different streams
+                let mut rng = self.rng(2 * chunk + usize::from(caustic));
+                let mut node_aux = Vec::with_capacity(64);
+                let mut photons = Vec::new();
+                let n = Self::CHUNK_LEN.min(n_photons - chunk * Self::CHUNK_LEN);
+                for _ in 0..n {
+                    if let Some((ray, power, patch)) = emit(&mut rng) {
+                        self.trace_photon(
+                            scene,
+                            ray,
+                            power,
+                            patch,
+                            caustic,
+                            &mut rng,
+                            &mut node_aux,
+                            &mut photons,
+                        );
+                    }
+                }
+                photons
+            })
+            .collect();
+
+        chunks.into_iter().flatten().collect()
+    }
+
+    /// Follows a photon through the scene, storing it when it hits
+    /// non-specular surfaces
+    #[allow(clippy::too_many_arguments)]
+    fn trace_photon(
+        &self,
+        scene: &Scene,
+        ray: Ray3D,
+        mut power: Spectrum<{ crate::N_CHANNELS }>,
+        patch: Option<usize>,
+        caustic: bool,
+        rng: &mut RandGen,
+        node_aux: &mut Vec<usize>,
+        photons: &mut Vec<Photon>,
+    ) {
+        let mut ray = Ray {
+            geometry: ray,
+            ..Ray::default()
+        };
+        let mut specular_path = false;
+
+        for _ in 0..=self.max_depth {
+            let triangle_index = match scene.cast_ray(&mut ray, node_aux) {
+                Some(i) => i,
+                None => break,
+            };
+            let material = match ray.interaction.geometry_shading.side {
+                SurfaceSide::Front => &scene.materials[scene.front_material_index(triangle_index)],
+                SurfaceSide::Back => &scene.materials[scene.back_material_index(triangle_index)],
+                SurfaceSide::NonApplicable => break,
+            };
+            if material.emits_light() {
+                break;
+            }
+            ray.interaction
+                .interpolate_normal(scene.normals_at(triangle_index));
+            let (intersection_pt, normal, e1, e2) = ray.get_triad();
+
+            if material.specular_only() {
+                let u = rng.gen::<Float>();
+                let (new_ray, weight) =
+                    match material.choose_path(&normal, &intersection_pt, &ray, u) {
+                        Some(v) => v,
+                        None => break,
+                    };
+                power *= weight;
+                specular_path = true;
+                ray = Ray {
+                    geometry: new_ray.geometry,
+                    refraction_index: new_ray.refraction_index,
+                    ..Ray::default()
+                };
+                continue;
+            }
+
+            let photon = Photon {
+                position: intersection_pt,
+                direction: ray.geometry.direction,
+                power,
+                patch,
+            };
+            if caustic {
+                if specular_path {
+                    photons.push(photon);
+                }
+                break;
+            }
+            photons.push(photon);
+            if power.max() <= 0. {
+                break;
+            }
+
+            // Scatter... or be absorbed (i.e., Russian roulette)
+            let mut new_ray = ray;
+            material.sample_bsdf(normal, e1, e2, intersection_pt, &mut new_ray, rng);
+            let direction = new_ray.geometry.direction;
+            let vout = direction * -1.;
+            let pdf = material.bsdf_pdf(normal, e1, e2, &ray, vout);
+            if pdf < 1e-18 {
+                break;
+            }
+            let bsdf = material.eval_bsdf(normal, e1, e2, &ray, vout);
+            let scattered = power * bsdf * ((normal * direction).abs() / pdf);
+            let survival = (scattered.max() / power.max()).min(1.);
+            if survival <= 0. || rng.gen::<Float>() >= survival {
+                break;
+            }
+            power = scattered / survival;
+            ray = Ray {
+                geometry: new_ray.geometry,
+                refraction_index: ray.refraction_index,
+                ..Ray::default()
+            };
+        }
+    }
+
+    /// Emits photons from the lights of a [`Scene`] and
+    /// builds its global and caustic maps
+    pub fn build(&self, scene: &Scene) -> PhotonMaps {
+        let mut rng = self.rng(usize::MAX);
+        let emitters = self.emitters(scene, &mut rng);
+        let total_power: Float = emitters.iter().map(|(_, p)| p).sum();
+
+        let build_map = |n_photons: usize, caustic: bool| -> PhotonMap {
+            let photons = if emitters.is_empty() || n_photons == 0 {
+                Vec::new()
+            } else {
+                self.shoot(scene, n_photons, caustic, |rng| {
+                    self.emit(scene, &emitters, total_power, n_photons, rng)
+                        .map(|(ray, power)| (ray, power, None))
+                })
+            };
+            PhotonMap::new(photons, self.n_lookup, self.max_radius)
+        };
+
+        PhotonMaps {
+            global: build_map(self.n_global_photons, false),
+            caustic: build_map(self.n_caustic_photons, true),
+        }
+    }
+
+    /// Builds a global map for calculating Daylight Coefficients. Photons
+    /// are emitted from a uniform sky of unit radiance (ignoring the [`Scene`]'s
+    /// own sky and distant lights), and each of them remembers the patch of
+    /// the `reinhart` sky it comes from.
+    pub fn build_dc(&self, scene: &Scene, reinhart: &ReinhartSky) -> PhotonMap {
+        let (centre, radius) = scene.bounding_sphere();
+        let n_photons = self.n_global_photons;
+        let photons = if n_photons == 0 {
+            Vec::new()
+        } else {
+            self.shoot(scene, n_photons, false, |rng| {
+                let p = uniform_sample_sphere(rng);
+                let w = Vector3D::new(p.x, p.y, p.z).get_normalized();
+                let origin = uniform_sample_disc(rng, radius, centre + w * radius, w);
+                let area = PI * radius * radius;
+                let power =
+                    Spectrum::<{ crate::N_CHANNELS }>::gray(4. * PI * area / n_photons as Float);
+                let ray = Ray3D {
+                    origin,
+                    direction: w * -1.,
+                };
+                Some((ray, power, Some(reinhart.dir_to_bin(w))))
+            })
+        };
+        PhotonMap::new(photons, self.n_lookup, self.max_radius)
+    }
+}
+
+/// The flux carried by a ray leaving a light, or [`None`] if
+/// the ray could not have been sampled
+fn flux(sample: &EmissionSample) -> Option<Spectrum<{ crate::N_CHANNELS }>> {
+    let pdf = sample.pdf_pos * sample.pdf_dir;
+    if pdf <= 0. {
+        return None;
+    }
+    let cos = sample
+        .normal
+        .map_or(1., |n| (n * sample.ray.direction).abs());
+    Some(sample.radiance * (cos / pdf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lights::PointLight;
+    use crate::material::{Glass, Light, Material, Plastic};
+    use crate::primitive::Primitive;
+    use geometry3d::{DistantSource3D, Triangle3D};
+
+    fn push_rectangle(scene: &mut Scene, material: usize, l: Float, z: Float) {
+        let tri = Triangle3D::new(
+            Point3D::new(-l, -l, z),
+            Point3D::new(l, -l, z),
+            Point3D::new(l, l, z),
+        )
+        .unwrap();
+        scene.push_object(material, material, Primitive::Triangle(tri));
+        let tri = Triangle3D::new(
+            Point3D::new(-l, -l, z),
+            Point3D::new(l, l, z),
+            Point3D::new(-l, l, z),
+        )
+        .unwrap();
+        scene.push_object(material, material, Primitive::Triangle(tri));
+    }
+
+    #[test]
+    fn test_nearest() {
+        let mut rng = get_rng();
+        let photons: Vec<Photon> = (0..2000)
+            .map(|_| {
+                let (x, y, z): (Float, Float, Float) = rng.gen();
+                Photon {
+                    position: Point3D::new(x, y, z),
+                    direction: Vector3D::new(0., 0., -1.),
+                    power: Spectrum::<{ crate::N_CHANNELS }>::ONE,
+                    patch: None,
+                }
+            })
+            .collect();
+        let map = PhotonMap::new(photons.clone(), 20, 10.);
+        assert_eq!(map.len(), 2000);
+
+        let point = Point3D::new(0.5, 0.5, 0.5);
+        let normal = Vector3D::new(0., 0., 1.);
+        let (found, _area) = map.lookup(point, normal);
+        assert_eq!(found.len(), 20);
+
+        // Compare against brute force
+        let mut expected: Vec<Float> = photons
+            .iter()
+            .map(|p| (p.position - point).length_squared())
+            .collect();
+        expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let mut found: Vec<Float> = found.iter().map(|(d2, _)| *d2).collect();
+        found.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        for (a, b) in found.iter().zip(expected.iter()) {
+            assert!((a - b).abs() < 1e-9);
+        }
+
+        // Photons arriving from the other side are ignored
+        let (found, _area) = map.lookup(point, normal * -1.);
+        assert!(found.is_empty());
+    }
+
+    fn sunny_scene(with_glass: bool) -> (Scene, Float) {
+        let mut scene = Scene::new();
+        let reflectance = 0.5;
+        let gray = scene.push_material(Material::Plastic(Plastic {
+            colour: Spectrum::<{ crate::N_CHANNELS }>::gray(reflectance),
+            specularity: 0.0,
+            roughness: 0.0,
+        }));
+        push_rectangle(&mut scene, gray, 2., 0.);
+        if with_glass {
+            let glass = scene.push_material(Material::Glass(Glass {
+                colour: Spectrum::<{ crate::N_CHANNELS }>::gray(1.),
+                refraction_index: 1.52,
+            }));
+            push_rectangle(&mut scene, glass, 2., 1.);
+        }
+        let sun = scene.push_material(Material::Light(Light(
+            Spectrum::<{ crate::N_CHANNELS }>::gray(1000.),
+        )));
+        scene.push_object(
+            sun,
+            sun,
+            Primitive::Source(DistantSource3D::new(
+                Vector3D::new(0., 0., 1.),
+                (0.533 as Float).to_radians(),
+            )),
+        );
+        scene.build_accelerator();
+        let omega = 2. * PI * (1. - ((0.533 as Float).to_radians() / 2.).cos());
+        (scene, 1000. * omega)
+    }
+
+    #[test]
+    fn test_global_irradiance() {
+        let (scene, expected) = sunny_scene(false);
+        let mapper = PhotonMapper {
+            n_global_photons: 50_000,
+            n_caustic_photons: 1000,
+            n_lookup: 200,
+            ..PhotonMapper::default()
+        };
+        let maps = mapper.build(&scene);
+        // No specular materials
+        assert!(maps.caustic.is_empty());
+
+        let found = maps
+            .global
+            .irradiance(Point3D::new(0., 0., 0.), Vector3D::new(0., 0., 1.))
+            .radiance();
+        assert!(
+            (found - expected).abs() / expected < 0.1,
+            "expected {}, found {}",
+            expected,
+            found
+        );
+    }
+
+    #[test]
+    fn test_point_light() {
+        let mut scene = Scene::new();
+        let gray = scene.push_material(Material::Plastic(Plastic {
+            colour: Spectrum::<{ crate::N_CHANNELS }>::gray(0.5),
+            specularity: 0.0,
+            roughness: 0.0,
+        }));
+        push_rectangle(&mut scene, gray, 2., 0.);
+        let (height, intensity) = (1., 100.);
+        scene.push_light(Box::new(PointLight {
+            position: Point3D::new(0., 0., height),
+            intensity: Spectrum::<{ crate::N_CHANNELS }>::gray(intensity),
+        }));
+        scene.build_accelerator();
+
+        let mapper = PhotonMapper {
+            n_global_photons: 50_000,
+            n_caustic_photons: 0,
+            n_lookup: 200,
+            ..PhotonMapper::default()
+        };
+        let maps = mapper.build(&scene);
+        let found = maps
+            .global
+            .irradiance(Point3D::new(0., 0., 0.), Vector3D::new(0., 0., 1.))
+            .radiance();
+        let expected = intensity / (height * height);
+        assert!(
+            (found - expected).abs() / expected < 0.1,
+            "expected {}, found {}",
+            expected,
+            found
+        );
+    }
+
+    #[test]
+    fn test_caustic_through_glass() {
+        let (scene, sun_irradiance) = sunny_scene(true);
+        let mapper = PhotonMapper {
+            n_global_photons: 0,
+            n_caustic_photons: 50_000,
+            n_lookup: 200,
+            ..PhotonMapper::default()
+        };
+        let maps = mapper.build(&scene);
+        assert!(maps.global.is_empty());
+        assert!(!maps.caustic.is_empty());
+
+        // The glass lets some light through, but not all of it
+        let found = maps
+            .caustic
+            .irradiance(Point3D::new(0., 0., 0.), Vector3D::new(0., 0., 1.))
+            .radiance();
+        assert!(found > 0.5 * sun_irradiance, "found {}", found);
+        assert!(found < sun_irradiance, "found {}", found);
+    }
+}
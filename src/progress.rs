@@ -0,0 +1,246 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Reporting the progress of long calculations and cancelling them
+
+use crate::colour::Spectrum;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// Receives the progress of a calculation. It is called from
+/// the worker threads, every time a chunk of work is finished.
+pub trait ProgressReporter: Send + Sync {
+    /// Reports that `done` out of `total` units of work (e.g., pixels,
+    /// sensors or passes) have been finished
+    fn report(&self, done: usize, total: usize);
+}
+
+impl<F: Fn(usize, usize) + Send + Sync> ProgressReporter for F {
+    fn report(&self, done: usize, total: usize) {
+        self(done, total)
+    }
+}
+
+/// Prints the progress to the standard output, every time another
+/// percent is done
+#[derive(Default)]
+pub struct StdoutProgress {
+    last_percent: AtomicUsize,
+}
+
+impl ProgressReporter for StdoutProgress {
+    fn report(&self, done: usize, total: usize) {
+        if total == 0 {
+            return;
+        }
+        let percent = 100 * done / total;
+        if self.last_percent.fetch_max(percent, Ordering::Relaxed) < percent {
+            println!("... Done {}%", percent);
+        }
+    }
+}
+
+/// A flag that can be set—e.g., from another thread—to
+/// stop a calculation. Clones share the same flag.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Asks the calculations using this token to stop
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed)
+    }
+
+    /// Checks whether the calculation has been cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Controls a calculation from the outside: where its progress is reported
+/// and how it can be cancelled. The default reports nothing and is never
+/// cancelled.
+#[derive(Clone, Default)]
+pub struct RenderControl {
+    /// Receives the progress, if any
+    pub progress: Option<Arc<dyn ProgressReporter>>,
+
+    /// Is checked between chunks of work
+    pub cancel: CancelToken,
+}
+
+impl RenderControl {
+    /// Creates a control that prints the progress to the standard output
+    pub fn stdout() -> Self {
+        Self {
+            progress: Some(Arc::new(StdoutProgress::default())),
+            ..Self::default()
+        }
+    }
+
+    /// Passes the progress to the reporter, if any
+    pub fn report(&self, done: usize, total: usize) {
+        if let Some(progress) = &self.progress {
+            progress.report(done, total)
+        }
+    }
+
+    /// Checks whether the calculation has been cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+}
+
+/// Statistics of a finished—or cancelled—calculation
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RenderStats {
+    /// The time the calculation took
+    pub elapsed: Duration,
+
+    /// The number of passes done over the image (one, unless
+    /// rendering progressively)
+    pub passes: usize,
+
+    /// Was the calculation cancelled before finishing? If so,
+    /// the results are incomplete.
+    pub cancelled: bool,
+}
+
+/// The number of pixels calculated at a time by [`render_pixels`]
+const CHUNK_LEN: usize = 128;
+
+/// Calculates the `n_pixels` of an image in chunks, which are spread among
+/// threads if the `parallel` feature is enabled. Each chunk creates its own
+/// `state` (e.g., auxiliary buffers), passes it to `pixel` together with the
+/// index of each of its pixels, and then hands it to `finish`.
+///
+/// The number of pixels done is reported to the `control` after each chunk.
+/// Once it is cancelled, the remaining chunks are skipped and their
+/// pixels are left black.
+#[allow(clippy::needless_collect)]
+pub(crate) fn render_pixels<S>(
+    n_pixels: usize,
+    control: &RenderControl,
+    state: impl Fn() -> S + Sync,
+    pixel: impl Fn(&mut S, usize) -> Spectrum<{ crate::N_CHANNELS }> + Sync,
+    finish: impl Fn(S) + Sync,
+) -> (Vec<Spectrum<{ crate::N_CHANNELS }>>, RenderStats) {
+    let now = Instant::now();
+    let mut pixels = vec![Spectrum::<{ crate::N_CHANNELS }>::BLACK; n_pixels];
+    let counter = AtomicUsize::new(0);
+    let skipped = AtomicBool::new(false);
+
+    let chunks: Vec<&mut [Spectrum<{ crate::N_CHANNELS }>]> =
+        pixels.chunks_mut(CHUNK_LEN).collect();
+
+    #[cfg(not(feature = "parallel"))]
+    let chunks = chunks.into_iter();
+
+    #[cfg(feature = "parallel")]
+    let chunks = chunks.into_par_iter();
+
+    chunks.enumerate().for_each(|(i, chunk)| {
+        if control.is_cancelled() {
+            skipped.store(true, Ordering::Relaxed);
+            return;
+        }
+        let mut s = state();
+        let first = i * CHUNK_LEN;
+        for (j, value) in chunk.iter_mut().enumerate() {
+            *value = pixel(&mut s, first + j);
+        }
+        finish(s);
+
+        let n = chunk.len();
+        let done = counter.fetch_add(n, Ordering::Relaxed) + n;
+        control.report(done, n_pixels);
+    });
+
+    let stats = RenderStats {
+        elapsed: now.elapsed(),
+        passes: 1,
+        cancelled: skipped.load(Ordering::Relaxed),
+    };
+    (pixels, stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_closure_reporter() {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let r = reported.clone();
+        let control = RenderControl {
+            progress: Some(Arc::new(move |done: usize, total: usize| {
+                r.lock().unwrap().push((done, total))
+            })),
+            ..RenderControl::default()
+        };
+        control.report(1, 4);
+        control.report(4, 4);
+        assert_eq!(*reported.lock().unwrap(), vec![(1, 4), (4, 4)]);
+    }
+
+    #[test]
+    fn test_render_pixels() {
+        let n_pixels = 3 * CHUNK_LEN + 5;
+        let control = RenderControl::default();
+        let (pixels, stats) = render_pixels(
+            n_pixels,
+            &control,
+            || (),
+            |_, i| Spectrum::<{ crate::N_CHANNELS }>::gray(i as crate::Float),
+            |_| {},
+        );
+        assert_eq!(pixels.len(), n_pixels);
+        for (i, p) in pixels.iter().enumerate() {
+            assert_eq!(p.0[0], i as crate::Float);
+        }
+        // Cancelling once everything is done does not make it incomplete
+        control.cancel.cancel();
+        assert!(!stats.cancelled);
+
+        let (pixels, stats) = render_pixels(
+            n_pixels,
+            &control,
+            || (),
+            |_, _| Spectrum::<{ crate::N_CHANNELS }>::gray(1.),
+            |_| {},
+        );
+        assert!(stats.cancelled);
+        assert!(pixels.iter().all(|p| p.is_black()));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let control = RenderControl::default();
+        let token = control.cancel.clone();
+        assert!(!control.is_cancelled());
+        token.cancel();
+        assert!(control.is_cancelled());
+    }
+}
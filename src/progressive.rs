@@ -0,0 +1,205 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Progressive rendering: samples are accumulated into the image over
+//! several passes, until the image converges or runs out of time.
+
+use std::path::PathBuf;
+
+use crate::colour::Spectrum;
+use crate::image::ImageBuffer;
+use crate::Float;
+
+/// The options that control a progressive render
+pub struct ProgressiveOptions {
+    /// The maximum number of passes (i.e., samples per pixel)
+    pub max_passes: usize,
+
+    /// The number of passes done before checking for convergence, so that
+    /// the variance estimates are meaningful
+    pub min_passes: usize,
+
+    /// The render stops when the relative standard error of every pixel
+    /// is below this value. Zero disables this criterion.
+    pub noise_threshold: Float,
+
+    /// The maximum time, in seconds, to spend rendering. The pass running
+    /// when the budget is exceeded is always finished.
+    pub time_budget: Option<Float>,
+
+    /// A file where the image is saved every `save_interval` seconds, so
+    /// that long renders can be inspected before they finish
+    pub intermediate_file: Option<PathBuf>,
+
+    /// The number of seconds between saves of the `intermediate_file`
+    pub save_interval: Float,
+
+    /// Stop sampling the pixels that meet the `noise_threshold` after
+    /// `min_passes`, so that the following passes are spent on the noisy
+    /// ones (e.g., window edges and glossy highlights)
+    pub adaptive: bool,
+
+    /// A file where the relative error of each pixel is saved once
+    /// rendering finishes (see [`error_image`])
+    pub error_file: Option<PathBuf>,
+}
+
+impl std::default::Default for ProgressiveOptions {
+    fn default() -> Self {
+        Self {
+            max_passes: 64,
+            min_passes: 4,
+            noise_threshold: 0.02,
+            time_budget: None,
+            intermediate_file: None,
+            save_interval: 60.,
+            adaptive: true,
+            error_file: None,
+        }
+    }
+}
+
+/// The running mean and variance of the samples of a pixel, updated
+/// through Welford's algorithm. The variance is that of the radiance.
+#[derive(Clone, Copy, Default)]
+pub struct PixelStats {
+    /// The number of samples
+    pub n: usize,
+
+    /// The mean of the samples
+    pub mean: Spectrum<{ crate::N_CHANNELS }>,
+
+    /// The sum of squared differences from the mean
+    m2: Float,
+}
+
+impl PixelStats {
+    /// Adds a sample
+    pub fn push(&mut self, v: Spectrum<{ crate::N_CHANNELS }>) {
+        self.n += 1;
+        let old_radiance = self.mean.radiance();
+        self.mean += (v - self.mean) / self.n as Float;
+        self.m2 += (v.radiance() - old_radiance) * (v.radiance() - self.mean.radiance());
+    }
+
+    /// The sample variance of the radiance
+    pub fn variance(&self) -> Float {
+        if self.n < 2 {
+            return 0.0;
+        }
+        self.m2 / (self.n - 1) as Float
+    }
+
+    /// The standard error of the mean radiance, relative to the
+    /// mean radiance. It is infinite when fewer than two samples have
+    /// been taken, or when a noisy pixel has a zero mean.
+    pub fn relative_error(&self) -> Float {
+        if self.n < 2 {
+            return Float::INFINITY;
+        }
+        let std_error = (self.variance() / self.n as Float).sqrt();
+        if std_error <= 0.0 {
+            return 0.0;
+        }
+        let mean = self.mean.radiance();
+        if mean > 0.0 {
+            std_error / mean
+        } else {
+            Float::INFINITY
+        }
+    }
+}
+
+/// Gets the image made of the mean of each pixel
+pub fn mean_image(width: usize, height: usize, stats: &[PixelStats]) -> ImageBuffer {
+    let pixels = stats.iter().map(|s| s.mean).collect();
+    ImageBuffer::from_pixels(width, height, pixels)
+}
+
+/// Gets an image with the relative standard error of each pixel. Errors
+/// larger than 1 (including those of noisy pixels with zero mean) are
+/// reported as 1.
+pub fn error_image(width: usize, height: usize, stats: &[PixelStats]) -> ImageBuffer {
+    let pixels = stats
+        .iter()
+        .map(|s| Spectrum::<{ crate::N_CHANNELS }>::gray(s.relative_error().min(1.)))
+        .collect();
+    ImageBuffer::from_pixels(width, height, pixels)
+}
+
+/// Gets the largest relative error in the image
+pub fn max_relative_error(stats: &[PixelStats]) -> Float {
+    stats
+        .iter()
+        .map(|s| s.relative_error())
+        .fold(0.0, Float::max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_welford() {
+        let samples = [1.0, 2.0, 4.0, 7.0, 1.0];
+        let mut stats = PixelStats::default();
+        for v in samples {
+            stats.push(Spectrum::<{ crate::N_CHANNELS }>::gray(v));
+        }
+        let n = samples.len() as Float;
+        let mean = samples.iter().sum::<Float>() / n;
+        let var = samples.iter().map(|v| (v - mean).powi(2)).sum::<Float>() / (n - 1.);
+
+        assert_eq!(stats.n, samples.len());
+        assert!((stats.mean.radiance() - mean).abs() < 1e-5);
+        assert!((stats.variance() - var).abs() < 1e-4);
+        let expected = (var / n).sqrt() / mean;
+        assert!((stats.relative_error() - expected).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_converged_pixels() {
+        let mut black = PixelStats::default();
+        assert!(black.relative_error().is_infinite());
+        black.push(Spectrum::<{ crate::N_CHANNELS }>::BLACK);
+        black.push(Spectrum::<{ crate::N_CHANNELS }>::BLACK);
+        assert_eq!(black.relative_error(), 0.0);
+
+        let mut constant = PixelStats::default();
+        for _ in 0..3 {
+            constant.push(Spectrum::<{ crate::N_CHANNELS }>::gray(2.));
+        }
+        assert!(constant.relative_error() < 1e-6);
+        assert!(max_relative_error(&[black, constant]) < 1e-6);
+    }
+
+    #[test]
+    fn test_error_image() {
+        let mut noisy = PixelStats::default();
+        noisy.push(Spectrum::<{ crate::N_CHANNELS }>::gray(1.));
+        noisy.push(Spectrum::<{ crate::N_CHANNELS }>::gray(3.));
+        let expected = noisy.relative_error();
+        assert!(expected > 0. && expected < 1.);
+
+        let image = error_image(2, 1, &[noisy, PixelStats::default()]);
+        assert!((image[(0, 0)].radiance() - expected).abs() < 1e-5);
+        assert!((image[(1, 0)].radiance() - 1.).abs() < 1e-5);
+    }
+}
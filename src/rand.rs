@@ -20,10 +20,56 @@ SOFTWARE.
 
 pub use rand::prelude::*;
 
-pub type RandGen = SmallRng;
+pub use crate::sampler::{Sampler, SamplerKind};
+
+/// The source of the numbers of all Montecarlo estimations. Unless created
+/// through [`get_sampler`], it produces independent random numbers.
+pub type RandGen = Sampler;
 
 /// Gets a random number generator for Montecarlo estimations
 pub fn get_rng() -> RandGen {
     // rand::thread_rng()
-    SmallRng::from_entropy()
+    Sampler::new(SamplerKind::Independent, 0, 0, SmallRng::from_entropy())
+}
+
+/// Mixes the bits of `x` (i.e., the finalizer of SplitMix64)
+pub(crate) fn mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Gets the random number generator of the `stream`-th unit of work
+/// (e.g., a pixel or a sensor) of a calculation. It is fully determined
+/// by the `seed` and the `stream`, so results do not depend on how the
+/// work is split among threads.
+pub fn get_seeded_rng(seed: u64, stream: u64) -> RandGen {
+    get_sampler(SamplerKind::Independent, 0, seed, stream)
+}
+
+/// Gets the [`Sampler`] of the `stream`-th unit of work of a calculation (see
+/// [`get_seeded_rng`]), which takes `samples_per_pixel` from a `kind` of
+/// sequence. The sequences of all the samplers with the same `seed` are
+/// scrambled in the same way, so the samples of a pixel are well distributed
+/// even if they are taken by different samplers (e.g., in different passes).
+pub fn get_sampler(kind: SamplerKind, samples_per_pixel: usize, seed: u64, stream: u64) -> RandGen {
+    let rng = SmallRng::seed_from_u64(mix(seed ^ mix(stream)));
+    Sampler::new(kind, samples_per_pixel, mix(seed), rng)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_rng() {
+        let sample = |seed, stream| -> Vec<u64> {
+            let mut rng = get_seeded_rng(seed, stream);
+            (0..4).map(|_| rng.gen()).collect()
+        };
+        assert_eq!(sample(1, 2), sample(1, 2));
+        assert_ne!(sample(1, 2), sample(1, 3));
+        assert_ne!(sample(1, 2), sample(2, 2));
+    }
 }
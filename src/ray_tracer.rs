@@ -17,18 +17,28 @@ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
+use std::sync::Arc;
 use std::time::Instant;
 
 use crate::camera::{Camera, CameraSample};
 use crate::colour::Spectrum;
 use crate::image::ImageBuffer;
-use crate::material::Material;
+use crate::irradiance_cache::{stratified_directions, HemisphereSample, IrradianceCache};
+use crate::light_tree::{LightSelection, LightTree};
+use crate::lights::{Light, SkyLight, DELTA_POSITION_LIGHT};
+use crate::material::{Material, Plastic};
+use crate::path_tracer::{hit_light_pdf, power_heuristic};
+use crate::photon_map::PhotonMaps;
+use crate::progress::{render_pixels, RenderControl, RenderStats};
+use crate::progressive::{
+    error_image, max_relative_error, mean_image, PixelStats, ProgressiveOptions,
+};
 use crate::rand::*;
 use crate::ray::Ray;
 use crate::scene::{Object, Scene};
 use crate::Float;
 use geometry3d::intersection::SurfaceSide;
-use geometry3d::Ray3D;
+use geometry3d::{Point3D, Ray3D, Vector3D};
 
 #[cfg(feature = "parallel")]
 use rayon::prelude::*;
@@ -36,6 +46,14 @@ use rayon::prelude::*;
 pub struct RayTracerHelper {
     pub rays: Vec<Ray>,
     pub nodes: Vec<usize>,
+
+    /// Are we tracing final-gather rays, which end at the first
+    /// non-specular surface by looking up the global photon map?
+    pub gathering: bool,
+
+    /// The number of specular interactions found by the current
+    /// final-gather ray
+    pub gather_specular_bounces: usize,
 }
 
 impl std::default::Default for RayTracerHelper {
@@ -43,10 +61,20 @@ impl std::default::Default for RayTracerHelper {
         Self {
             rays: vec![Ray::default(); 15],
             nodes: Vec::with_capacity(64),
+            gathering: false,
+            gather_specular_bounces: 0,
         }
     }
 }
 
+/// The white Lambertian surface that replaces the materials of the surfaces
+/// seen by the camera when calculating irradiance
+const IRRADIANCE_PROXY: Material = Material::Plastic(Plastic {
+    colour: Spectrum::<{ crate::N_CHANNELS }>::ONE,
+    specularity: 0.0,
+    roughness: 0.0,
+});
+
 pub struct RayTracer {
     pub max_depth: usize,
     pub n_shadow_samples: usize,
@@ -54,6 +82,37 @@ pub struct RayTracer {
 
     pub limit_weight: Float,
     pub count_specular_bounce: Float,
+
+    /// Calculate the irradiance falling on the surfaces hit by primary
+    /// rays instead of the radiance leaving them (like Radiance's `rpict -i`).
+    /// The luminance of the resulting pixels is the illuminance, in lux.
+    pub irradiance: bool,
+
+    /// A cache used to interpolate the indirect irradiance over Lambertian
+    /// surfaces hit by primary rays, instead of sampling it every time. It
+    /// can be shared by several renders of the same scene.
+    pub irradiance_cache: Option<Arc<IrradianceCache>>,
+
+    /// Photon maps that add the caustics over Lambertian surfaces and
+    /// provide the radiance at the end of the secondary rays (i.e., a
+    /// final gather) instead of following them further.
+    pub photon_maps: Option<Arc<PhotonMaps>>,
+
+    /// The seed of the random numbers. If given, each pixel (or ray, when
+    /// calculating points) draws its random numbers from its own stream, so
+    /// results are reproducible regardless of the number of threads. Note that
+    /// the contents of an irradiance cache still depend on the order in which
+    /// pixels are calculated.
+    pub seed: Option<u64>,
+
+    /// The kind of sequence the samples of each pixel are drawn from
+    pub sampler: SamplerKind,
+
+    /// How the lights that shadow rays are sent towards are chosen. Unless
+    /// all of them are sampled, the `n_shadow_samples` are shared among the
+    /// lights chosen at each point, which is much faster in scenes with many
+    /// luminaires. Distant lights (e.g., the sun) are always sampled.
+    pub light_selection: LightSelection,
 }
 
 impl Default for RayTracer {
@@ -65,12 +124,30 @@ impl Default for RayTracer {
 
             limit_weight: 1e-3,
             count_specular_bounce: 0.3,
+            irradiance: false,
+            irradiance_cache: None,
+            photon_maps: None,
+            seed: None,
+            sampler: SamplerKind::default(),
+            light_selection: LightSelection::default(),
         }
     }
 }
 
 impl RayTracer {
-    /// Recursively traces a ray
+    /// Does the caustic photon map hold the light that
+    /// arrives through specular surfaces?
+    fn has_caustic_map(&self) -> bool {
+        match &self.photon_maps {
+            Some(maps) => !maps.caustic.is_empty(),
+            None => false,
+        }
+    }
+
+    /// Recursively traces a ray. Returns the radiance travelling against
+    /// the ray and—if it hit a light source that is also sampled through
+    /// shadow rays—the probability of such sampling having chosen its direction
+    /// (or zero otherwise).
     pub fn trace_ray(
         &self,
         rng: &mut RandGen,
@@ -78,31 +155,37 @@ impl RayTracer {
         ray: &mut Ray,
         aux: &mut RayTracerHelper,
     ) -> (Spectrum<{ crate::N_CHANNELS }>, Float) {
+        // In irradiance mode, the surfaces seen by the camera are
+        // replaced by a white Lambertian
+        let irradiance = self.irradiance && ray.depth == 0;
+
         if let Some(triangle_index) = scene.cast_ray(ray, &mut aux.nodes) {
             let material = match ray.interaction.geometry_shading.side {
-                SurfaceSide::Front => {
-                    &scene.materials[scene.front_material_indexes[triangle_index]]
-                }
-                SurfaceSide::Back => &scene.materials[scene.back_material_indexes[triangle_index]],
+                SurfaceSide::Front => &scene.materials[scene.front_material_index(triangle_index)],
+                SurfaceSide::Back => &scene.materials[scene.back_material_index(triangle_index)],
                 SurfaceSide::NonApplicable => {
                     // Hit parallel to the surface...
                     // ray.colour = Spectrum::BLACK; // We won't use this, I think
                     return (Spectrum::<{ crate::N_CHANNELS }>::BLACK, 0.0);
                 }
             };
+            let material = if irradiance {
+                &IRRADIANCE_PROXY
+            } else {
+                material
+            };
 
             let (intersection_pt, normal, ..) = ray.get_triad();
 
             // for now, emmiting materials don't reflect... but they
             // are visible when viewed directly from the camera
             if material.emits_light() {
-                let light_pdf = crate::triangle::triangle_solid_angle_pdf(
-                    &scene.triangles[triangle_index],
-                    intersection_pt,
-                    ray.interaction.geometry_shading.normal,
-                    &ray.geometry,
-                );
-
+                if aux.gather_specular_bounces > 0 && self.has_caustic_map() {
+                    // This light is in the caustic photon map
+                    return (Spectrum::<{ crate::N_CHANNELS }>::BLACK, 0.0);
+                }
+                let light_pdf =
+                    hit_light_pdf(scene, &ray.geometry, intersection_pt, self.light_selection);
                 return (material.colour(), light_pdf);
             }
 
@@ -112,7 +195,7 @@ impl RayTracer {
             }
 
             ray.interaction
-                .interpolate_normal(scene.normals[triangle_index]);
+                .interpolate_normal(scene.normals_at(triangle_index));
 
             // let mut wt = ray.value;
 
@@ -132,7 +215,9 @@ impl RayTracer {
                         new_ray.depth += 1;
                     }
 
+                    aux.gather_specular_bounces += usize::from(aux.gathering);
                     let (li, _light_pdf) = self.trace_ray(rng, scene, &mut new_ray, aux);
+                    aux.gather_specular_bounces -= usize::from(aux.gathering);
                     specular_li += li * *bsdf_value
                 }
 
@@ -140,6 +225,20 @@ impl RayTracer {
                 return (specular_li, 0.0);
             }
 
+            /* PHOTON MAPS */
+            let photon_maps = match (&self.photon_maps, material.lambertian_reflectance()) {
+                (Some(maps), Some(reflectance)) => Some((maps, reflectance)),
+                _ => None,
+            };
+            if let Some((maps, reflectance)) = photon_maps {
+                if aux.gathering {
+                    // The global map contains all the light arriving here
+                    let (point, normal, ..) = ray.get_triad();
+                    let irradiance = maps.global.irradiance(point, normal);
+                    return (reflectance * irradiance / crate::PI, 0.0);
+                }
+            }
+
             let n_ambient_samples = ray.get_n_ambient_samples(
                 self.n_ambient_samples,
                 self.max_depth,
@@ -156,6 +255,14 @@ impl RayTracer {
                 // self.n_shadow_samples
             };
 
+            // The irradiance cache does not store the light that comes
+            // from light sources, so these need to be fully accounted for by
+            // the shadow rays.
+            let cached = ray.depth == 0
+                && self.irradiance_cache.is_some()
+                && material.lambertian_reflectance().is_some();
+            let n_bsdf_samples = if cached { 0 } else { n_ambient_samples };
+
             /* DIRECT LIGHT */
             // let local = Spectrum::BLACK;
             let local = self.get_local_illumination(
@@ -164,93 +271,166 @@ impl RayTracer {
                 ray,
                 rng,
                 n_shadow_samples,
+                n_bsdf_samples,
+                !cached,
                 &mut aux.nodes,
             );
 
+            /* CAUSTICS */
+            let caustics = match photon_maps {
+                Some((maps, reflectance)) => {
+                    let (point, normal, ..) = ray.get_triad();
+                    reflectance * maps.caustic.irradiance(point, normal) / crate::PI
+                }
+                None => Spectrum::<{ crate::N_CHANNELS }>::BLACK,
+            };
+
             /* INDIRECT */
-            let global =
-                self.get_global_illumination(scene, n_ambient_samples, material, ray, rng, aux);
+            let was_gathering = aux.gathering;
+            aux.gathering =
+                was_gathering || matches!(photon_maps, Some((maps, _)) if !maps.global.is_empty());
+            let global = match (&self.irradiance_cache, material.lambertian_reflectance()) {
+                (Some(cache), Some(reflectance)) if ray.depth == 0 && n_ambient_samples > 0 => {
+                    let (point, normal, ..) = ray.get_triad();
+                    let irradiance = match cache.interpolate(point, normal) {
+                        Some(v) => v,
+                        None => {
+                            let record = self.irradiance_record(cache, scene, ray, rng, aux);
+                            let v = record.irradiance;
+                            cache.push(record);
+                            v
+                        }
+                    };
+                    reflectance * irradiance / crate::PI
+                }
+                _ => self.get_global_illumination(
+                    scene,
+                    n_ambient_samples,
+                    n_shadow_samples,
+                    material,
+                    ray,
+                    rng,
+                    aux,
+                ),
+            };
+
+            aux.gathering = was_gathering;
 
-            (local + global, 0.0)
+            if irradiance {
+                // The radiance reflected by a white Lambertian is E/PI
+                return ((local + global + caustics) * crate::PI, 0.0);
+            }
+            (local + global + caustics, 0.0)
+        } else if irradiance {
+            // There is no surface to receive light
+            (Spectrum::<{ crate::N_CHANNELS }>::BLACK, 0.0)
         } else {
             // Did not hit... so, let's check the sky
-            if let Some(sky) = &scene.sky {
-                let sky_brightness = sky(ray.geometry.direction);
-                let colour = scene
-                    .sky_colour
-                    .unwrap_or_else(|| Spectrum::<{ crate::N_CHANNELS }>::gray(1.0));
-                ray.colour *= colour * sky_brightness;
-                (colour * sky_brightness, 1. / 2. / crate::PI)
-            } else {
+            if aux.gather_specular_bounces > 0 && self.has_caustic_map() {
+                // This light is in the caustic photon map
                 (Spectrum::<{ crate::N_CHANNELS }>::BLACK, 0.0)
+            } else {
+                let direction = ray.geometry.direction;
+                let colour = SkyLight.le(scene, direction);
+                ray.colour *= colour;
+                // The sky is also sampled by shadow rays
+                let light_pdf = SkyLight.pdf_li(scene, ray.geometry.origin, direction);
+                (colour, light_pdf)
             }
         }
     }
 
+    /// Sends `n_shadow_samples` towards each of the `lights` or, if a `tree`
+    /// is given, towards lights chosen through it. The results are
+    /// weighted (through the power heuristic) against the `n_bsdf_samples` that
+    /// will be sent by [`Self::get_global_illumination`], which might also hit
+    /// these lights. Lights with a delta position are sampled once.
     #[allow(clippy::too_many_arguments)]
-    fn sample_light_array(
+    fn sample_light_array<L: Light>(
         &self,
         scene: &Scene,
         material: &Material,
         ray: &Ray,
         rng: &mut RandGen,
         n_shadow_samples: usize,
-        lights: &[Object],
+        n_bsdf_samples: usize,
+        lights: &[L],
+        tree: Option<&LightTree>,
         node_aux: &mut Vec<usize>,
     ) -> Spectrum<{ crate::N_CHANNELS }> {
-        let (mut intersection_pt, normal, e1, e2) = ray.get_triad();
-        intersection_pt += normal * 0.001; // prevent self-shading
+        let (intersection_pt, normal, e1, e2) = ray.get_triad();
         let mut local_illum = Spectrum::<{ crate::N_CHANNELS }>::BLACK;
 
-        let n = n_shadow_samples;
-        // let n_ambient_samples = n_ambient_samples as Float;
-        let n_shadow_samples = n_shadow_samples as Float;
+        let n_bsdf_samples = n_bsdf_samples as Float;
 
-        for light in lights.iter() {
-            // let this_origin = this_origin + normal * 0.001;
+        // With a tree, all the samples are shared among the lights
+        // it chooses, in a single round
+        let n_rounds = if tree.is_some() { 1 } else { lights.len() };
+        for fixed_light in lights.iter().take(n_rounds) {
+            // Sampling a point more than once gives the same result
+            let n = if tree.is_none() && fixed_light.flags() & DELTA_POSITION_LIGHT != 0 {
+                1
+            } else {
+                n_shadow_samples
+            };
+            let n_samples = n as Float;
             let mut i = 0;
-            // let mut missed = 0;
             while i < n {
-                let direction = light.primitive.sample_direction(rng, intersection_pt);
-                // let (_,direction) = light.primitive.direction( point);
-                let shadow_ray = Ray3D {
-                    origin: intersection_pt,
-                    direction,
+                // The light, and the probability of having chosen it
+                let (light, choice_pdf) = match tree {
+                    Some(tree) => {
+                        match tree.sample(self.light_selection, intersection_pt, rng.gen()) {
+                            Some((index, pmf)) => (&lights[index], pmf),
+                            // No light reaches this point
+                            None => break,
+                        }
+                    }
+                    None => (fixed_light, 1.),
                 };
-
-                if let Some((light_colour, light_pdf)) =
-                    intersect_light(scene, light, &shadow_ray, node_aux)
-                {
-                    i += 1;
-                    if light_pdf < 1e-18 {
-                        // The light is obstructed... don't add light, but count it.
+                let sample = match light.sample_li(scene, rng, intersection_pt) {
+                    Some(s) => s,
+                    None => {
+                        #[cfg(debug_assertions)]
+                        {
+                            eprintln!("Missed Light... (i = {})", i)
+                        }
+                        // ... missed light. Try again
                         continue;
                     }
+                };
+                i += 1;
+                // The materials that get here only reflect light
+                let direction = sample.direction;
+                if sample.pdf < 1e-18 || normal * direction <= 0. {
+                    continue;
+                }
 
-                    // Denominator of the Balance Heuristic... I am assuming that
-                    // when one light has a pdf>0, then all the rest are Zero... is this
-                    // correct?
-                    let cos_theta = (normal * direction).abs();
-                    let vout = shadow_ray.direction * -1.;
+                let shadow_ray = Ray3D {
+                    origin: ray.interaction.spawn_ray_origin(direction),
+                    direction,
+                };
+                let distance_squared = sample.distance * sample.distance;
+                if !scene.unobstructed_distance(&shadow_ray, distance_squared, node_aux) {
+                    // The light is obstructed... don't add light, but count it.
+                    continue;
+                }
+                let light_pdf = sample.pdf * choice_pdf;
 
-                    let mat_bsdf_value = material.eval_bsdf(normal, e1, e2, ray, vout);
-                    // let denominator = light_pdf * n_shadow_samples
-                    // + mat_bsdf_value.radiance() * n_ambient_samples;
-                    let fx = light_colour * cos_theta * mat_bsdf_value;
+                let cos_theta = normal * direction;
+                let vout = direction * -1.;
 
-                    // Return... light sources have a pdf equal to their 1/Omega (i.e. their size)
-                    local_illum += fx / (n_shadow_samples * light_pdf); //denominator;
+                let mat_bsdf_value = material.eval_bsdf(normal, e1, e2, ray, vout);
+                let fx = sample.radiance * cos_theta * mat_bsdf_value;
+
+                // Multiple Importance Sampling
+                let weight = if n_bsdf_samples > 0. && !light.is_delta_light() {
+                    let bsdf_pdf = material.bsdf_pdf(normal, e1, e2, ray, vout);
+                    power_heuristic(n_samples * light_pdf, n_bsdf_samples * bsdf_pdf)
                 } else {
-                    #[cfg(debug_assertions)]
-                    {
-                        eprintln!(
-                            "Missed Light... primitive '{}' (i = {})",
-                            light.primitive.id(),
-                            i
-                        )
-                    }
-                }
-                // ... missed light. Try again
+                    1.
+                };
+
+                local_illum += fx * weight / (n_samples * light_pdf);
             } // end of iterating samples
         } // end of iterating lights
 
@@ -258,7 +438,10 @@ impl RayTracer {
     }
 
     /// Calculates the luminance produced by the direct sources in the
-    /// scene
+    /// scene. `n_bsdf_samples` is the number of rays that will be sent
+    /// through [`Self::get_global_illumination`] from the same point. The
+    /// sky is only sampled if `sample_sky`.
+    #[allow(clippy::too_many_arguments)]
     fn get_local_illumination(
         &self,
         scene: &Scene,
@@ -266,6 +449,8 @@ impl RayTracer {
         ray: &Ray,
         rng: &mut RandGen,
         n_shadow_samples: usize,
+        n_bsdf_samples: usize,
+        sample_sky: bool,
         node_aux: &mut Vec<usize>,
     ) -> Spectrum<{ crate::N_CHANNELS }> {
         let close = self.sample_light_array(
@@ -274,27 +459,61 @@ impl RayTracer {
             ray,
             rng,
             n_shadow_samples,
+            n_bsdf_samples,
             &scene.lights,
+            scene
+                .light_tree
+                .as_ref()
+                .filter(|_| self.light_selection != LightSelection::All),
             node_aux,
         );
-        let distant = self.sample_light_array(
+        // Rays sampled from the BSDF never reach distant sources (they
+        // only see the sky), nor point and spot lights, so these are not weighted
+        let delta = self.sample_light_array(
             scene,
             material,
             ray,
             rng,
             n_shadow_samples,
-            &scene.distant_lights,
+            0,
+            &scene.delta_lights,
+            None,
             node_aux,
         );
+        let sky = if sample_sky && scene.has_sky() {
+            self.sample_light_array(
+                scene,
+                material,
+                ray,
+                rng,
+                n_shadow_samples,
+                n_bsdf_samples,
+                std::slice::from_ref(&SkyLight),
+                None,
+                node_aux,
+            )
+        } else {
+            Spectrum::<{ crate::N_CHANNELS }>::BLACK
+        };
 
         // return
-        close + distant
+        close + delta + sky
     }
 
+    /// Samples the BSDF `n_ambient_samples` times. Light sources hit by these
+    /// rays are weighted against the `n_shadow_samples` sent towards them
+    /// by [`Self::get_local_illumination`]
+    ///
+    /// The ambient rays sent from the surface seen by the camera are
+    /// different samples of the pixel: the `i`-th ray of its `s`-th sample is
+    /// sample `s * self.n_ambient_samples + i`, so the `rng` should take
+    /// [`Self::samples_per_pixel`] samples.
+    #[allow(clippy::too_many_arguments)]
     fn get_global_illumination(
         &self,
         scene: &Scene,
         n_ambient_samples: usize,
+        n_shadow_samples: usize,
         material: &Material,
         ray: &mut Ray,
         rng: &mut RandGen,
@@ -312,20 +531,33 @@ impl RayTracer {
         aux.rays[depth] = *ray; // store a copy.
         let n = n_ambient_samples;
         let n_ambient_samples = n_ambient_samples as Float;
-        // let n_shadow_samples = n_shadow_samples as Float;
-
-        // for _ in 0..n {
-        let mut count = 0;
-        while count < n {
-            // Choose a direction.
-            let (bsdf_value, ray_pdf) =
-                material.sample_bsdf(normal, e1, e2, intersection_pt, ray, rng);
+        let n_shadow_samples = n_shadow_samples as Float;
+        let (pixel, sample_index, dimension) = rng.position();
+
+        for i in 0..n {
+            if depth == 0 {
+                let index = sample_index * self.n_ambient_samples as u64 + i as u64;
+                rng.start_pixel_sample(pixel, index);
+                rng.skip_dimensions(dimension);
+            }
+            // Choose a direction. The value returned by the material is the
+            // weight of its own sampling scheme, so the BSDF and the pdf of
+            // the direction are evaluated here (as in the shadow rays)
+            material.sample_bsdf(normal, e1, e2, intersection_pt, ray, rng);
             let new_ray_dir = ray.geometry.direction;
             debug_assert!(
                 (1. - new_ray_dir.length()).abs() < 1e-2,
                 "Length is {}",
                 new_ray_dir.length()
             );
+            let vout = new_ray_dir * -1.;
+            let ray_pdf = material.bsdf_pdf(normal, e1, e2, &aux.rays[depth], vout);
+            if ray_pdf < 1e-18 {
+                // The sample counts, but carries no light
+                *ray = aux.rays[depth];
+                continue;
+            }
+            let bsdf_value = material.eval_bsdf(normal, e1, e2, &aux.rays[depth], vout);
 
             let cos_theta = (normal * new_ray_dir).abs();
             let bsdf_rad = bsdf_value.radiance();
@@ -334,16 +566,17 @@ impl RayTracer {
 
             let (li, light_pdf) = self.trace_ray(rng, scene, ray, aux);
 
-            if light_pdf > 0. {
-                // ray hit a light... reset and try again
-                *ray = aux.rays[depth];
-                continue;
-            }
-            count += 1;
+            // Multiple Importance Sampling... the light might have also been
+            // reached by shadow rays
+            let weight = if light_pdf > 0. {
+                power_heuristic(n_ambient_samples * ray_pdf, n_shadow_samples * light_pdf)
+            } else {
+                1.
+            };
 
             let fx = li * bsdf_value * cos_theta;
 
-            global += fx / ray_pdf;
+            global += fx * weight / ray_pdf;
 
             // restore ray, because it was modified by trace_ray executions
             *ray = aux.rays[depth];
@@ -352,60 +585,324 @@ impl RayTracer {
         global / n_ambient_samples
     }
 
-    #[allow(clippy::needless_collect)]
-    pub fn render(self, scene: &Scene, camera: &dyn Camera) -> ImageBuffer {
-        let (width, height) = camera.film_resolution();
-
-        let total_pixels = width * height;
-        let mut pixels = vec![Spectrum::<{ crate::N_CHANNELS }>::BLACK; total_pixels];
-
-        let chunk_len = 128;
-        let i: Vec<&mut [Spectrum<{ crate::N_CHANNELS }>]> = pixels.chunks_mut(chunk_len).collect();
+    /// Samples the hemisphere over the point hit by `ray` in order to
+    /// create a new record for an [`IrradianceCache`]
+    fn irradiance_record(
+        &self,
+        cache: &IrradianceCache,
+        scene: &Scene,
+        ray: &Ray,
+        rng: &mut RandGen,
+        aux: &mut RayTracerHelper,
+    ) -> crate::irradiance_cache::IrradianceRecord {
+        let (point, normal, e1, e2) = ray.get_triad();
+
+        let (n_theta, n_phi, directions) = stratified_directions(self.n_ambient_samples, rng);
+        let samples: Vec<HemisphereSample> = directions
+            .into_iter()
+            .map(|direction| {
+                let (x, y, z) = crate::samplers::local_to_world(
+                    e1,
+                    e2,
+                    normal,
+                    Point3D::new(0., 0., 0.),
+                    direction.x,
+                    direction.y,
+                    direction.z,
+                );
+                let direction_world = Vector3D::new(x, y, z).get_normalized();
+                let mut new_ray = Ray {
+                    geometry: Ray3D {
+                        origin: ray.interaction.spawn_ray_origin(direction_world),
+                        direction: direction_world,
+                    },
+                    depth: ray.depth + 1,
+                    value: ray.value,
+                    ..Ray::default()
+                };
+                // If the ray hits something, this will be updated
+                new_ray.interaction.point = point;
 
-        #[cfg(not(feature = "parallel"))]
-        let i = i.into_iter();
+                let (li, light_pdf) = self.trace_ray(rng, scene, &mut new_ray, aux);
+                let distance = (new_ray.interaction.point - point).length();
+                let distance = if distance > 1e-9 {
+                    distance
+                } else {
+                    Float::INFINITY
+                };
+                HemisphereSample {
+                    direction,
+                    // Light sources are accounted for by the direct illumination,
+                    // except for the sky, which is not sampled where the cache is used
+                    radiance: if light_pdf > 0. && distance.is_finite() {
+                        Spectrum::<{ crate::N_CHANNELS }>::BLACK
+                    } else {
+                        li
+                    },
+                    distance,
+                }
+            })
+            .collect();
 
-        #[cfg(feature = "parallel")]
-        let i = i.into_par_iter();
+        cache.record(point, normal, e1, e2, n_theta, n_phi, &samples)
+    }
 
-        let now = Instant::now();
-        // progress indicators
-        let last_progress = std::sync::Arc::new(std::sync::Mutex::new(0.0));
-        let counter = std::sync::Arc::new(std::sync::Mutex::new(0));
+    /// The number of samples per pixel (or sensor) taken from the
+    /// sampler when tracing `n_samples` rays through each of them
+    fn samples_per_pixel(&self, n_samples: usize) -> usize {
+        n_samples * self.n_ambient_samples.max(1)
+    }
 
-        let _ = &i.enumerate().for_each(|(first_p, chunk)| {
-            let mut pindex = first_p * chunk_len;
-            let mut aux = RayTracerHelper::default();
-            let mut rng = get_rng();
+    /// Renders the scene, without reporting progress
+    pub fn render(self, scene: &Scene, camera: &dyn Camera) -> ImageBuffer {
+        let (image, _stats) = self.render_with_control(scene, camera, &RenderControl::default());
+        image
+    }
 
-            for pixel in chunk {
+    /// Renders the scene, reporting the number of pixels done to the
+    /// `control` and checking whether it has been cancelled between chunks of
+    /// pixels. The pixels of a cancelled render are left black.
+    pub fn render_with_control(
+        &self,
+        scene: &Scene,
+        camera: &dyn Camera,
+        control: &RenderControl,
+    ) -> (ImageBuffer, RenderStats) {
+        let (width, height) = camera.film_resolution();
+        let seed = self.seed.unwrap_or_else(|| get_rng().gen());
+
+        let (pixels, stats) = render_pixels(
+            width * height,
+            control,
+            RayTracerHelper::default,
+            |aux, pindex| {
+                let spp = self.samples_per_pixel(1);
+                let mut rng = get_sampler(self.sampler, spp, seed, pindex as u64);
+                rng.start_pixel_sample(pindex as u64, 0);
                 let y = (pindex as Float / width as Float).floor() as usize;
                 let x = pindex - y * width;
-                let (mut ray, weight) = camera.gen_ray(&CameraSample { p_film: (x, y) });
+                let (mut ray, weight) = camera.gen_ray(&CameraSample::new((x, y)));
                 ray.value = weight;
 
-                let (v, _) = self.trace_ray(&mut rng, scene, &mut ray, &mut aux);
-                *pixel = v;
+                let (v, _) = self.trace_ray(&mut rng, scene, &mut ray, aux);
+                v
+            },
+            |_| {},
+        );
+        (ImageBuffer::from_pixels(width, height, pixels), stats)
+    }
 
-                // report
-                let mut c = counter.lock().unwrap();
-                *c += 1;
+    /// Renders the scene in passes of at most one sample per pixel,
+    /// accumulating the mean and variance of each pixel until one of the stop
+    /// conditions in `options` is met. If `options.adaptive`, the pixels that
+    /// meet the noise threshold stop being sampled.
+    ///
+    /// The number of passes done is reported to the `control`, which
+    /// is checked for cancellation between chunks of pixels. The samples
+    /// of a pass that is cancelled partway through are discarded.
+    pub fn render_progressive(
+        &self,
+        scene: &Scene,
+        camera: &dyn Camera,
+        options: &ProgressiveOptions,
+        control: &RenderControl,
+    ) -> (ImageBuffer, RenderStats) {
+        let (width, height) = camera.film_resolution();
+        let mut stats = vec![PixelStats::default(); width * height];
 
-                let mut lp = last_progress.lock().unwrap();
-                let progress = (100. * *c as Float / total_pixels as Float).round() as Float;
-                if (*lp - progress.floor()) < 0.1 && (progress - *lp).abs() > 1. {
-                    *lp = progress;
-                    println!("... Done {:.0}%", progress);
+        let now = Instant::now();
+        let mut last_save = 0.0;
+        let max_passes = options.max_passes.max(1);
+        // All the passes share the scrambling of the sequences
+        let seed = self.seed.unwrap_or_else(|| get_rng().gen());
+        // The passes report their own progress, not that of their pixels
+        let pass_control = RenderControl {
+            progress: None,
+            cancel: control.cancel.clone(),
+        };
+        let mut passes = 0;
+        let mut cancelled = false;
+        while passes < max_passes {
+            if control.is_cancelled() {
+                cancelled = true;
+                break;
+            }
+            // After the first passes, only the noisy pixels are sampled
+            let adaptive = options.adaptive && passes >= options.min_passes;
+            let converged: Vec<bool> = stats
+                .iter()
+                .map(|pixel| adaptive && pixel.relative_error() < options.noise_threshold)
+                .collect();
+
+            let (values, pass_stats) = render_pixels(
+                width * height,
+                &pass_control,
+                RayTracerHelper::default,
+                |aux, pindex| {
+                    if converged[pindex] {
+                        return Spectrum::<{ crate::N_CHANNELS }>::BLACK;
+                    }
+                    let y = (pindex as Float / width as Float).floor() as usize;
+                    let x = pindex - y * width;
+                    let stream = passes * width * height + pindex;
+                    let spp = self.samples_per_pixel(max_passes);
+                    let mut rng = get_sampler(self.sampler, spp, seed, stream as u64);
+                    rng.start_pixel_sample(pindex as u64, passes as u64);
+                    let (mut ray, weight) = camera.gen_ray(&CameraSample::new((x, y)));
+                    ray.value = weight;
+
+                    let (v, _) = self.trace_ray(&mut rng, scene, &mut ray, aux);
+                    v
+                },
+                |_| {},
+            );
+            // A pass cancelled partway through is left out, so that
+            // all the pixels keep the same number of samples
+            if pass_stats.cancelled {
+                cancelled = true;
+                break;
+            }
+            for ((pixel, v), converged) in stats.iter_mut().zip(values).zip(converged) {
+                if !converged {
+                    pixel.push(v);
                 }
+            }
+            passes += 1;
+            control.report(passes, max_passes);
+
+            let elapsed = now.elapsed().as_secs_f64() as Float;
+            if let Some(file) = &options.intermediate_file {
+                if elapsed - last_save >= options.save_interval {
+                    // A failed checkpoint should not lose the render
+                    if let Err(e) = mean_image(width, height, &stats).save(file) {
+                        eprintln!("{}", e);
+                    }
+                    last_save = elapsed;
+                }
+            }
 
-                pindex += 1;
+            if passes >= options.min_passes && max_relative_error(&stats) < options.noise_threshold
+            {
+                break;
             }
-        });
+            if matches!(options.time_budget, Some(budget) if elapsed >= budget) {
+                break;
+            }
+        }
 
-        println!("Scene took {} seconds to render", now.elapsed().as_secs());
+        if let Some(file) = &options.error_file {
+            if let Err(e) = error_image(width, height, &stats).save(file) {
+                eprintln!("{}", e);
+            }
+        }
 
-        // return
-        ImageBuffer::from_pixels(width, height, pixels)
+        let render_stats = RenderStats {
+            elapsed: now.elapsed(),
+            passes,
+            cancelled,
+        };
+        (mean_image(width, height, &stats), render_stats)
+    }
+
+    /// Calculates the irradiance over a white Lambertian surface located
+    /// at `origin` and facing towards `normal`
+    fn sensor_irradiance(
+        &self,
+        rng: &mut RandGen,
+        scene: &Scene,
+        origin: Point3D,
+        normal: Vector3D,
+        aux: &mut RayTracerHelper,
+    ) -> Spectrum<{ crate::N_CHANNELS }> {
+        let e2 = normal.get_perpendicular().unwrap();
+        let e1 = e2.cross(normal);
+
+        // Pretend that the ray has just hit a surface at the sensor
+        let mut ray = Ray {
+            geometry: Ray3D {
+                origin,
+                direction: normal * -1.,
+            },
+            ..Ray::default()
+        };
+        ray.interaction.point = origin;
+        ray.interaction.wo = normal;
+        ray.interaction.geometry_shading.p = origin;
+        ray.interaction.geometry_shading.normal = normal;
+        ray.interaction.geometry_shading.dpdu = e1;
+        ray.interaction.geometry_shading.dpdv = e2;
+        ray.interaction.geometry_shading.side = SurfaceSide::Front;
+
+        let n_ambient_samples = ray.get_n_ambient_samples(
+            self.n_ambient_samples,
+            self.max_depth,
+            self.limit_weight,
+            rng,
+        );
+        let local = self.get_local_illumination(
+            scene,
+            &IRRADIANCE_PROXY,
+            &ray,
+            rng,
+            self.n_shadow_samples,
+            n_ambient_samples,
+            true,
+            &mut aux.nodes,
+        );
+        let global = self.get_global_illumination(
+            scene,
+            n_ambient_samples,
+            self.n_shadow_samples,
+            &IRRADIANCE_PROXY,
+            &mut ray,
+            rng,
+            aux,
+        );
+
+        // The radiance reflected by a white Lambertian is E/PI
+        (local + global) * crate::PI
+    }
+
+    /// Calculates a value for each of the `rays` (like Radiance's `rtrace`).
+    ///
+    /// If `self.irradiance` is `false`, the radiance travelling against each
+    /// ray (i.e., seen from its origin) is returned. If it is `true`, each ray
+    /// is treated as a sensor located at its origin and facing towards
+    /// its direction, and the irradiance falling on it is returned (like `rtrace -I`).
+    pub fn calc_points(
+        &self,
+        scene: &Scene,
+        rays: &[Ray3D],
+    ) -> Vec<Spectrum<{ crate::N_CHANNELS }>> {
+        #[cfg(not(feature = "parallel"))]
+        let aux_iter = rays.iter();
+        #[cfg(feature = "parallel")]
+        let aux_iter = rays.par_iter();
+
+        let seed = self.seed.unwrap_or_else(|| get_rng().gen());
+        aux_iter
+            .enumerate()
+            .map(|(i, geometry)| {
+                let mut aux = RayTracerHelper::default();
+                let spp = self.samples_per_pixel(1);
+                let mut rng = get_sampler(self.sampler, spp, seed, i as u64);
+                rng.start_pixel_sample(i as u64, 0);
+                let direction = geometry.direction.get_normalized();
+                if self.irradiance {
+                    self.sensor_irradiance(&mut rng, scene, geometry.origin, direction, &mut aux)
+                } else {
+                    let mut ray = Ray {
+                        geometry: Ray3D {
+                            origin: geometry.origin,
+                            direction,
+                        },
+                        ..Ray::default()
+                    };
+                    let (v, _) = self.trace_ray(&mut rng, scene, &mut ray, &mut aux);
+                    v
+                }
+            })
+            .collect()
     }
 }
 
@@ -455,5 +952,762 @@ pub fn intersect_light(
 
 #[cfg(test)]
 mod tests {
-    // use super::*;
+    use super::*;
+    use crate::environment_map::{EnvironmentMap, EnvironmentProjection};
+    use crate::lights::{PointLight, SpotLight};
+    use crate::material::Light;
+    use crate::primitive::Primitive;
+    use geometry3d::{DistantSource3D, Sphere3D, Triangle3D};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Adds a large grey floor (at `z=0`) with the given `reflectance`
+    fn push_floor(scene: &mut Scene, reflectance: Float) {
+        let grey = scene.push_material(Material::Plastic(Plastic {
+            colour: Spectrum::<{ crate::N_CHANNELS }>::gray(reflectance),
+            specularity: 0.0,
+            roughness: 0.0,
+        }));
+        const L: Float = 10.;
+        let tri = Triangle3D::new(
+            Point3D::new(-L, -L, 0.),
+            Point3D::new(L, -L, 0.),
+            Point3D::new(L, L, 0.),
+        )
+        .unwrap();
+        scene.push_object(grey, grey, Primitive::Triangle(tri));
+        let tri = Triangle3D::new(
+            Point3D::new(-L, -L, 0.),
+            Point3D::new(L, L, 0.),
+            Point3D::new(-L, L, 0.),
+        )
+        .unwrap();
+        scene.push_object(grey, grey, Primitive::Triangle(tri));
+    }
+
+    /// Builds a black floor lit by a source right above it, returning the
+    /// scene and the expected irradiance over the floor
+    fn lit_floor() -> (Scene, Float) {
+        let mut scene = Scene::new();
+
+        // The floor is black, but that should not matter when calculating irradiance
+        push_floor(&mut scene, 0.0);
+
+        // A source right above
+        let angle = (5. as Float).to_radians();
+        let brightness = 1000.;
+        let sun = scene.push_material(Material::Light(Light(
+            Spectrum::<{ crate::N_CHANNELS }>::gray(brightness),
+        )));
+        scene.push_object(
+            sun,
+            sun,
+            Primitive::Source(DistantSource3D::new(Vector3D::new(0., 0., 1.), angle)),
+        );
+        scene.build_accelerator();
+
+        let omega = 2. * crate::PI * (1. - (angle / 2.).cos());
+        (scene, brightness * omega)
+    }
+
+    #[test]
+    fn test_photon_map_caustics() {
+        // A floor lit by the sun through a glass pane... shadow rays
+        // cannot go through the glass, so only the photons bring this light
+        let mut scene = Scene::new();
+        let reflectance = 0.5;
+        push_floor(&mut scene, reflectance);
+        let glass = scene.push_material(Material::Glass(crate::material::Glass {
+            colour: Spectrum::<{ crate::N_CHANNELS }>::gray(1.),
+            refraction_index: 1.52,
+        }));
+        const L: Float = 10.;
+        for (a, b, c) in [((-L, -L), (L, -L), (L, L)), ((-L, -L), (L, L), (-L, L))] {
+            let tri = Triangle3D::new(
+                Point3D::new(a.0, a.1, 2.),
+                Point3D::new(b.0, b.1, 2.),
+                Point3D::new(c.0, c.1, 2.),
+            )
+            .unwrap();
+            scene.push_object(glass, glass, Primitive::Triangle(tri));
+        }
+        let angle = (5. as Float).to_radians();
+        let brightness = 1000.;
+        let sun = scene.push_material(Material::Light(Light(
+            Spectrum::<{ crate::N_CHANNELS }>::gray(brightness),
+        )));
+        scene.push_object(
+            sun,
+            sun,
+            Primitive::Source(DistantSource3D::new(Vector3D::new(0., 0., 1.), angle)),
+        );
+        scene.build_accelerator();
+        let omega = 2. * crate::PI * (1. - (angle / 2.).cos());
+        let unobstructed = reflectance / crate::PI * brightness * omega;
+
+        let integrator = RayTracer {
+            n_ambient_samples: 16,
+            max_depth: 2,
+            ..RayTracer::default()
+        };
+        let found = average_floor_radiance(&integrator, &scene, 5);
+        assert!(found < 0.01 * unobstructed, "found {}", found);
+
+        let mapper = crate::photon_map::PhotonMapper {
+            n_global_photons: 0,
+            n_caustic_photons: 100_000,
+            max_radius: 1.,
+            ..crate::photon_map::PhotonMapper::default()
+        };
+        let integrator = RayTracer {
+            photon_maps: Some(Arc::new(mapper.build(&scene))),
+            ..integrator
+        };
+        let found = average_floor_radiance(&integrator, &scene, 5);
+        assert!(
+            found > 0.5 * unobstructed && found < unobstructed,
+            "found {}, unobstructed {}",
+            found,
+            unobstructed
+        );
+    }
+
+    #[test]
+    fn test_irradiance_mode() {
+        let (scene, expected) = lit_floor();
+
+        let integrator = RayTracer {
+            n_ambient_samples: 0,
+            n_shadow_samples: 100,
+            irradiance: true,
+            ..RayTracer::default()
+        };
+
+        let mut ray = Ray {
+            geometry: Ray3D {
+                origin: Point3D::new(0., 0., 1.),
+                direction: Vector3D::new(0., 0., -1.),
+            },
+            ..Ray::default()
+        };
+        let mut rng = get_rng();
+        let mut aux = RayTracerHelper::default();
+        let (irradiance, _) = integrator.trace_ray(&mut rng, &scene, &mut ray, &mut aux);
+
+        let found = irradiance.radiance();
+        assert!(
+            (found - expected).abs() / expected < 0.05,
+            "expected {}, found {}",
+            expected,
+            found
+        );
+    }
+
+    #[test]
+    fn test_render_progressive() {
+        use crate::camera::{Film, Pinhole, View};
+
+        let (scene, expected) = lit_floor();
+        let view = View {
+            view_point: Point3D::new(0., 0., 1.),
+            view_direction: Vector3D::new(0., 0., -1.),
+            view_up: Vector3D::new(0., 1., 0.),
+            ..View::default()
+        };
+        let camera = Pinhole::new(view, Film { resolution: (8, 6) });
+        let integrator = RayTracer {
+            n_ambient_samples: 0,
+            n_shadow_samples: 4,
+            irradiance: true,
+            ..RayTracer::default()
+        };
+
+        let file = std::env::temp_dir().join("rendering_test_render_progressive.pfm");
+        let error_file = std::env::temp_dir().join("rendering_test_render_progressive_error.pfm");
+        let _ = std::fs::remove_file(&file);
+        let noise_threshold = 0.01;
+        let options = ProgressiveOptions {
+            max_passes: 200,
+            noise_threshold,
+            intermediate_file: Some(file.clone()),
+            save_interval: 0.,
+            error_file: Some(error_file.clone()),
+            ..ProgressiveOptions::default()
+        };
+        let (image, stats) =
+            integrator.render_progressive(&scene, &camera, &options, &RenderControl::default());
+        assert!(!stats.cancelled);
+        assert!(stats.passes >= options.min_passes && stats.passes < options.max_passes);
+
+        assert!(file.exists());
+        let _ = std::fs::remove_file(&file);
+        let errors = ImageBuffer::from_file(&error_file).unwrap();
+        let _ = std::fs::remove_file(&error_file);
+        for e in errors.pixels {
+            assert!(e.radiance() < noise_threshold, "error is {}", e.radiance());
+        }
+        assert_eq!((image.width, image.height), (8, 6));
+        for pixel in image.pixels {
+            let found = pixel.radiance();
+            assert!(
+                (found - expected).abs() / expected < 0.05,
+                "expected {}, found {}",
+                expected,
+                found
+            );
+        }
+
+        // A render cancelled before starting skips every pass
+        let control = RenderControl::default();
+        control.cancel.cancel();
+        let options = ProgressiveOptions {
+            max_passes: 4,
+            ..ProgressiveOptions::default()
+        };
+        let (_, stats) = integrator.render_progressive(&scene, &camera, &options, &control);
+        assert!(stats.cancelled);
+        assert_eq!(stats.passes, 0);
+    }
+
+    #[test]
+    fn test_render_progressive_cancelled_pass() {
+        use crate::camera::{Film, Pinhole, View};
+
+        /// Cancels the render after generating some rays
+        struct CancellingCamera {
+            camera: Pinhole,
+            control: RenderControl,
+            rays: AtomicUsize,
+            limit: usize,
+        }
+        impl Camera for CancellingCamera {
+            fn pixel_from_ray(&self, ray: &Ray3D) -> ((usize, usize), Float) {
+                self.camera.pixel_from_ray(ray)
+            }
+            fn gen_ray(&self, sample: &CameraSample) -> (Ray, Float) {
+                if self.rays.fetch_add(1, Ordering::Relaxed) + 1 >= self.limit {
+                    self.control.cancel.cancel();
+                }
+                self.camera.gen_ray(sample)
+            }
+            fn gen_random_sample(&self, rng: &mut RandGen) -> CameraSample {
+                self.camera.gen_random_sample(rng)
+            }
+            fn film_resolution(&self) -> (usize, usize) {
+                self.camera.film_resolution()
+            }
+            fn view(&self) -> &View {
+                self.camera.view()
+            }
+            fn film_area(&self) -> Float {
+                self.camera.film_area()
+            }
+        }
+
+        let (scene, expected) = lit_floor();
+        let view = View {
+            view_point: Point3D::new(0., 0., 1.),
+            view_direction: Vector3D::new(0., 0., -1.),
+            view_up: Vector3D::new(0., 1., 0.),
+            ..View::default()
+        };
+        let (width, height) = (64, 64);
+        let control = RenderControl::default();
+        // Cancels as soon as the second pass starts
+        let camera = CancellingCamera {
+            camera: Pinhole::new(
+                view,
+                Film {
+                    resolution: (width, height),
+                },
+            ),
+            control: control.clone(),
+            rays: AtomicUsize::new(0),
+            limit: width * height + 1,
+        };
+        let integrator = RayTracer {
+            n_ambient_samples: 0,
+            n_shadow_samples: 4,
+            irradiance: true,
+            ..RayTracer::default()
+        };
+        let options = ProgressiveOptions {
+            max_passes: 4,
+            adaptive: false,
+            ..ProgressiveOptions::default()
+        };
+        let (image, stats) = integrator.render_progressive(&scene, &camera, &options, &control);
+        assert!(stats.cancelled);
+        assert_eq!(stats.passes, 1);
+
+        // The image is that of the first pass, with no black pixels left
+        // by the cancelled one
+        for pixel in image.pixels {
+            let found = pixel.radiance();
+            assert!(
+                (found - expected).abs() / expected < 0.2,
+                "expected {}, found {}",
+                expected,
+                found
+            );
+        }
+    }
+
+    #[test]
+    fn test_render_with_control() {
+        use crate::camera::{Film, Pinhole, View};
+
+        let (scene, expected) = lit_floor();
+        let view = View {
+            view_point: Point3D::new(0., 0., 1.),
+            view_direction: Vector3D::new(0., 0., -1.),
+            view_up: Vector3D::new(0., 1., 0.),
+            ..View::default()
+        };
+        let camera = Pinhole::new(
+            view,
+            Film {
+                resolution: (20, 10),
+            },
+        );
+        let integrator = RayTracer {
+            n_ambient_samples: 0,
+            n_shadow_samples: 4,
+            irradiance: true,
+            ..RayTracer::default()
+        };
+
+        // All pixels are reported
+        let last = Arc::new(AtomicUsize::new(0));
+        let l = last.clone();
+        let control = RenderControl {
+            progress: Some(Arc::new(move |done: usize, total: usize| {
+                assert_eq!(total, 200);
+                l.fetch_max(done, Ordering::Relaxed);
+            })),
+            ..RenderControl::default()
+        };
+        let (image, stats) = integrator.render_with_control(&scene, &camera, &control);
+        assert_eq!(last.load(Ordering::Relaxed), 200);
+        assert!(!stats.cancelled);
+        assert!((image.pixels[0].radiance() - expected).abs() / expected < 0.05);
+
+        // Nothing is rendered after cancelling
+        control.cancel.cancel();
+        let (image, stats) = integrator.render_with_control(&scene, &camera, &control);
+        assert!(stats.cancelled);
+        assert!(image.pixels.iter().all(|p| p.is_black()));
+    }
+
+    #[test]
+    fn test_seed() {
+        let mut scene = Scene::new();
+        push_floor(&mut scene, 0.5);
+        scene.sky = Some(Box::new(|_: Vector3D| -> Float { 1. }));
+        scene.build_accelerator();
+
+        let rays: Vec<Ray3D> = (0..20)
+            .map(|i| Ray3D {
+                origin: Point3D::new(i as Float / 10., 0., 1.),
+                direction: Vector3D::new(0., 0., -1.),
+            })
+            .collect();
+        let integrator = |seed| RayTracer {
+            n_ambient_samples: 4,
+            seed,
+            ..RayTracer::default()
+        };
+        let a = integrator(Some(1)).calc_points(&scene, &rays);
+        let b = integrator(Some(1)).calc_points(&scene, &rays);
+        let c = integrator(Some(2)).calc_points(&scene, &rays);
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn test_ambient_sampler() {
+        // A grey floor under a sky that is only bright towards +X
+        let mut scene = Scene::new();
+        push_floor(&mut scene, 0.5);
+        scene.sky = Some(Box::new(|d: Vector3D| -> Float { 2. * d.x.max(0.) }));
+        scene.build_accelerator();
+
+        // Each ray is a different sensor, so the values found are
+        // independent estimates of the same radiance
+        let rays: Vec<Ray3D> = (0..256)
+            .map(|i| Ray3D {
+                origin: Point3D::new(i as Float / 100. - 1., 0., 1.),
+                direction: Vector3D::new(0., 0., -1.),
+            })
+            .collect();
+        let variance = |sampler| {
+            let integrator = RayTracer {
+                n_ambient_samples: 16,
+                n_shadow_samples: 0,
+                max_depth: 1,
+                seed: Some(1),
+                sampler,
+                ..RayTracer::default()
+            };
+            let found: Vec<Float> = integrator
+                .calc_points(&scene, &rays)
+                .iter()
+                .map(|v| v.radiance())
+                .collect();
+            let n = found.len() as Float;
+            let mean = found.iter().sum::<Float>() / n;
+            found.iter().map(|v| (v - mean).powi(2)).sum::<Float>() / n
+        };
+
+        // The ambient rays of a sensor are well distributed samples
+        let independent = variance(SamplerKind::Independent);
+        let sobol = variance(SamplerKind::Sobol);
+        assert!(
+            sobol < 0.5 * independent,
+            "independent {}, sobol {}",
+            independent,
+            sobol
+        );
+    }
+
+    #[test]
+    fn test_calc_points() {
+        let (scene, expected) = lit_floor();
+
+        let mut integrator = RayTracer {
+            n_ambient_samples: 0,
+            n_shadow_samples: 100,
+            irradiance: true,
+            ..RayTracer::default()
+        };
+
+        // A sensor on the floor, facing up
+        let sensor = Ray3D {
+            origin: Point3D::new(0., 0., 0.01),
+            direction: Vector3D::new(0., 0., 1.),
+        };
+        let found = integrator.calc_points(&scene, &[sensor]);
+        assert_eq!(found.len(), 1);
+        let found = found[0].radiance();
+        assert!(
+            (found - expected).abs() / expected < 0.05,
+            "expected {}, found {}",
+            expected,
+            found
+        );
+
+        // Looking at the black floor
+        integrator.irradiance = false;
+        let ray = Ray3D {
+            origin: Point3D::new(0., 0., 1.),
+            direction: Vector3D::new(0., 0., -1.),
+        };
+        let found = integrator.calc_points(&scene, &[ray]);
+        assert!(found[0].radiance() < 1e-9);
+    }
+
+    /// Averages the radiance seen when looking down at the floor
+    fn average_floor_radiance(integrator: &RayTracer, scene: &Scene, n: usize) -> Float {
+        let mut rng = get_rng();
+        let mut aux = RayTracerHelper::default();
+        let mut total = 0.0;
+        for _ in 0..n {
+            let mut ray = Ray {
+                geometry: Ray3D {
+                    origin: Point3D::new(0., 0., 1.),
+                    direction: Vector3D::new(0., 0., -1.),
+                },
+                ..Ray::default()
+            };
+            let (v, _) = integrator.trace_ray(&mut rng, scene, &mut ray, &mut aux);
+            total += v.radiance();
+        }
+        total / n as Float
+    }
+
+    #[test]
+    fn test_mis_spherical_light() {
+        // A grey floor lit by a spherical source, which can be reached
+        // both by shadow rays and by rays sampled from the BSDF
+        let mut scene = Scene::new();
+        let rho = 0.5;
+        push_floor(&mut scene, rho);
+
+        let brightness = 1000.;
+        let (radius, height) = (0.5, 2.);
+        let light = scene.push_material(Material::Light(Light(
+            Spectrum::<{ crate::N_CHANNELS }>::gray(brightness),
+        )));
+        scene.push_object(
+            light,
+            light,
+            Primitive::Sphere(Sphere3D::new(radius, Point3D::new(0., 0., height))),
+        );
+        scene.build_accelerator();
+
+        let integrator = RayTracer {
+            n_ambient_samples: 128,
+            n_shadow_samples: 4,
+            max_depth: 1,
+            ..RayTracer::default()
+        };
+
+        // The irradiance below a sphere is brightness*PI*sin^2(alpha)
+        let sin_alpha = radius / height;
+        let expected = rho * brightness * sin_alpha * sin_alpha;
+        let found = average_floor_radiance(&integrator, &scene, 30);
+        assert!(
+            (found - expected).abs() / expected < 0.05,
+            "expected {}, found {}",
+            expected,
+            found
+        );
+    }
+
+    #[test]
+    fn test_mis_glossy() {
+        // The same as test_mis_spherical_light, but the floor is a glossy
+        // Ward plastic that reflects the light towards the viewer
+        let mut scene = Scene::new();
+        let (rho, specularity, roughness) = (0.5, 0.1, 0.2);
+        let floor = scene.push_material(Material::Plastic(Plastic {
+            colour: Spectrum::<{ crate::N_CHANNELS }>::gray(rho),
+            specularity,
+            roughness,
+        }));
+        const L: Float = 10.;
+        for (b, c) in [((L, -L), (L, L)), ((L, L), (-L, L))] {
+            let tri = Triangle3D::new(
+                Point3D::new(-L, -L, 0.),
+                Point3D::new(b.0, b.1, 0.),
+                Point3D::new(c.0, c.1, 0.),
+            )
+            .unwrap();
+            scene.push_object(floor, floor, Primitive::Triangle(tri));
+        }
+
+        let brightness = 1000.;
+        let (radius, height) = (0.5, 2.);
+        let light = scene.push_material(Material::Light(Light(
+            Spectrum::<{ crate::N_CHANNELS }>::gray(brightness),
+        )));
+        scene.push_object(
+            light,
+            light,
+            Primitive::Sphere(Sphere3D::new(radius, Point3D::new(0., 0., height))),
+        );
+        scene.build_accelerator();
+
+        // Integrate the BSDF over the cone subtended by the light
+        let plastic = &scene.materials[floor];
+        let view = Ray {
+            geometry: Ray3D {
+                origin: Point3D::new(0., 0., 1.),
+                direction: Vector3D::new(0., 0., -1.),
+            },
+            ..Ray::default()
+        };
+        let normal = Vector3D::new(0., 0., 1.);
+        let (e1, e2) = (Vector3D::new(1., 0., 0.), Vector3D::new(0., 1., 0.));
+        let sin_alpha = radius / height;
+        let cos_max = (1. - sin_alpha * sin_alpha).sqrt();
+        let cone_pdf = 1. / (2. * crate::PI * (1. - cos_max));
+        let mut rng = get_seeded_rng(0, 0);
+        let n = 200_000;
+        let mut expected = 0.0;
+        for _ in 0..n {
+            let (u, v): (Float, Float) = rng.gen();
+            let cos_theta = 1. - u * (1. - cos_max);
+            let sin_theta = (1. - cos_theta * cos_theta).sqrt();
+            let phi = 2. * crate::PI * v;
+            let vout = Vector3D::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta) * -1.;
+            let bsdf = plastic.eval_bsdf(normal, e1, e2, &view, vout);
+            expected += brightness * bsdf.radiance() * cos_theta / cone_pdf;
+        }
+        expected /= n as Float;
+
+        let integrator = RayTracer {
+            n_ambient_samples: 128,
+            n_shadow_samples: 4,
+            max_depth: 1,
+            ..RayTracer::default()
+        };
+        let found = average_floor_radiance(&integrator, &scene, 30);
+        assert!(
+            (found - expected).abs() / expected < 0.05,
+            "expected {}, found {}",
+            expected,
+            found
+        );
+    }
+
+    #[test]
+    fn test_light_selection() {
+        // A grey floor lit by a grid of spherical sources. Sampling a few
+        // of them at each point should give the same result as sampling all.
+        let mut scene = Scene::new();
+        let rho = 0.5;
+        push_floor(&mut scene, rho);
+
+        let brightness = 1000.;
+        let (radius, height) = (0.2, 2.);
+        let light = scene.push_material(Material::Light(Light(
+            Spectrum::<{ crate::N_CHANNELS }>::gray(brightness),
+        )));
+        let mut expected = 0.0;
+        for i in -1..=1 {
+            for j in -1..=1 {
+                let centre = Point3D::new(1.5 * i as Float, 1.5 * j as Float, height);
+                scene.push_object(
+                    light,
+                    light,
+                    Primitive::Sphere(Sphere3D::new(radius, centre)),
+                );
+
+                // E = brightness*PI*sin^2(alpha)*cos(theta)
+                let d2 = (centre - Point3D::new(0., 0., 0.)).length_squared();
+                expected += rho * brightness * radius * radius / d2 * height / d2.sqrt();
+            }
+        }
+        scene.build_accelerator();
+
+        for light_selection in [LightSelection::Power, LightSelection::Tree] {
+            let integrator = RayTracer {
+                n_ambient_samples: 64,
+                n_shadow_samples: 8,
+                max_depth: 1,
+                light_selection,
+                ..RayTracer::default()
+            };
+            let found = average_floor_radiance(&integrator, &scene, 30);
+            assert!(
+                (found - expected).abs() / expected < 0.05,
+                "{:?}: expected {}, found {}",
+                light_selection,
+                expected,
+                found
+            );
+        }
+    }
+
+    #[test]
+    fn test_sky_is_sampled() {
+        // Rays that escape used to be discarded, so an open scene lit
+        // only by the sky would never finish
+        let mut scene = Scene::new();
+        let rho = 0.5;
+        push_floor(&mut scene, rho);
+        scene.sky = Some(Box::new(|_: Vector3D| -> Float { 1. }));
+        scene.build_accelerator();
+
+        let integrator = RayTracer {
+            n_ambient_samples: 128,
+            n_shadow_samples: 1,
+            max_depth: 1,
+            ..RayTracer::default()
+        };
+
+        // A Lambertian under a uniform sky of radiance 1 reflects `rho`
+        let found = average_floor_radiance(&integrator, &scene, 10);
+        assert!(
+            (found - rho).abs() / rho < 0.05,
+            "expected {}, found {}",
+            rho,
+            found
+        );
+    }
+
+    #[test]
+    fn test_punctual_lights() {
+        // A grey floor lit by a point light right above it, and by a
+        // spot light that is pointing away
+        let mut scene = Scene::new();
+        let rho = 0.5;
+        push_floor(&mut scene, rho);
+
+        let (intensity, height) = (100., 2.);
+        let position = Point3D::new(0., 0., height);
+        scene.push_light(Box::new(PointLight {
+            position,
+            intensity: Spectrum::<{ crate::N_CHANNELS }>::gray(intensity),
+        }));
+        let spot = SpotLight::new(
+            position,
+            Vector3D::new(0., 0., 1.),
+            Spectrum::<{ crate::N_CHANNELS }>::gray(intensity),
+            (60. as Float).to_radians(),
+            (45. as Float).to_radians(),
+        );
+        scene.push_light(Box::new(spot));
+        scene.build_accelerator();
+
+        let integrator = RayTracer {
+            n_ambient_samples: 0,
+            max_depth: 0,
+            ..RayTracer::default()
+        };
+
+        // A Lambertian reflects rho/PI of the irradiance I/h^2
+        let expected = rho * intensity / (height * height) / crate::PI;
+        let found = average_floor_radiance(&integrator, &scene, 3);
+        assert!(
+            (found - expected).abs() / expected < 1e-3,
+            "expected {}, found {}",
+            expected,
+            found
+        );
+
+        // Now point the spot light at the floor
+        let spot = SpotLight::new(
+            position,
+            Vector3D::new(0., 0., -1.),
+            Spectrum::<{ crate::N_CHANNELS }>::gray(intensity),
+            (60. as Float).to_radians(),
+            (45. as Float).to_radians(),
+        );
+        scene.push_light(Box::new(spot));
+        let found = average_floor_radiance(&integrator, &scene, 3);
+        assert!(
+            (found - 2. * expected).abs() / expected < 1e-3,
+            "expected {}, found {}",
+            2. * expected,
+            found
+        );
+    }
+
+    #[test]
+    fn test_environment_map() {
+        // A grey floor under a lat-long map whose upper half has a
+        // radiance of 1, lit through shadow rays only and through MIS
+        let mut scene = Scene::new();
+        let rho = 0.5;
+        push_floor(&mut scene, rho);
+        let (width, height) = (64, 32);
+        let mut image = ImageBuffer::new(width, height);
+        for y in 0..height / 2 {
+            for x in 0..width {
+                image[(x, y)] = Spectrum::<{ crate::N_CHANNELS }>::gray(1.);
+            }
+        }
+        scene.environment =
+            Some(EnvironmentMap::new(image, EnvironmentProjection::LatLong).unwrap());
+        scene.build_accelerator();
+
+        for (n_ambient_samples, n_shadow_samples, max_depth) in [(0, 64, 0), (32, 16, 1)] {
+            let integrator = RayTracer {
+                n_ambient_samples,
+                n_shadow_samples,
+                max_depth,
+                ..RayTracer::default()
+            };
+
+            // A Lambertian under a uniform sky of radiance 1 reflects `rho`
+            let found = average_floor_radiance(&integrator, &scene, 30);
+            assert!(
+                (found - rho).abs() / rho < 0.05,
+                "expected {}, found {}",
+                rho,
+                found
+            );
+        }
+    }
 }
@@ -0,0 +1,382 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Samplers, which produce the numbers used by Monte Carlo estimations.
+//!
+//! A [`Sampler`] is a random number generator (it implements `RngCore`, so it
+//! is used through `rng.gen()`), but the numbers it produces can come from
+//! low-discrepancy sequences. These cover the space of samples more evenly than
+//! independent random numbers, which reduces noise for the same number of
+//! samples.
+//!
+//! Each of the samples of a pixel (or sensor) is a point in a space of many
+//! dimensions: every number drawn after [`Sampler::start_pixel_sample`] is
+//! the next dimension of the sample.
+
+use crate::Float;
+use rand::rngs::SmallRng;
+use rand::{Rng, RngCore};
+
+/// The kind of sequence a [`Sampler`] draws its numbers from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SamplerKind {
+    /// Independent random numbers
+    #[default]
+    Independent,
+
+    /// Each dimension is split into as many strata as samples per pixel, and each
+    /// sample falls (at a random position) within a different, randomly chosen, stratum
+    Stratified,
+
+    /// The Sobol sequence, padded in pairs of dimensions and scrambled as
+    /// proposed by Burley (2020), "Practical Hash-based Owen Scrambling"
+    Sobol,
+
+    /// The Halton sequence, with its digits scrambled differently for each pixel
+    Halton,
+}
+
+impl std::str::FromStr for SamplerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "independent" => Ok(Self::Independent),
+            "stratified" => Ok(Self::Stratified),
+            "sobol" => Ok(Self::Sobol),
+            "halton" => Ok(Self::Halton),
+            _ => Err(format!("Unknown sampler '{}'", s)),
+        }
+    }
+}
+
+/// The first primes, which are the bases of the dimensions of the Halton sequence.
+/// Dimensions beyond these get independent random numbers.
+const PRIMES: [u64; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+/// Produces the numbers used for sampling (see the module's documentation).
+pub struct Sampler {
+    kind: SamplerKind,
+
+    /// The number of samples taken per pixel, which is the number of
+    /// strata of the [`SamplerKind::Stratified`] sampler
+    samples_per_pixel: usize,
+
+    /// Produces the independent numbers (and the jitter of the stratified ones)
+    rng: SmallRng,
+
+    /// Makes the scrambling of the sequences different for each calculation
+    seed: u64,
+
+    pixel: u64,
+    sample_index: u64,
+    dimension: u64,
+}
+
+impl Sampler {
+    /// Creates a new sampler of a certain `kind`, taking `samples_per_pixel`. The
+    /// sequences are scrambled based on the `seed`, which should be the same for
+    /// all the samplers of a calculation, while the independent numbers
+    /// are drawn from `rng`.
+    pub fn new(kind: SamplerKind, samples_per_pixel: usize, seed: u64, rng: SmallRng) -> Self {
+        Self {
+            kind,
+            samples_per_pixel,
+            rng,
+            seed,
+            pixel: 0,
+            sample_index: 0,
+            dimension: 0,
+        }
+    }
+
+    /// The kind of sequence this sampler draws its numbers from
+    pub fn kind(&self) -> SamplerKind {
+        self.kind
+    }
+
+    /// Starts the `sample_index`-th sample of a `pixel` (or sensor). The
+    /// following numbers will be the dimensions of that sample, starting
+    /// from the first one.
+    pub fn start_pixel_sample(&mut self, pixel: u64, sample_index: u64) {
+        self.pixel = pixel;
+        self.sample_index = sample_index;
+        self.dimension = 0;
+    }
+
+    /// The pixel, the sample index and the dimension the sampler is at, so
+    /// that other samples can be started from the same point (see
+    /// [`Self::start_pixel_sample`] and [`Self::skip_dimensions`])
+    pub fn position(&self) -> (u64, u64, u64) {
+        (self.pixel, self.sample_index, self.dimension)
+    }
+
+    /// Skips `n` dimensions of the current sample (e.g., because another
+    /// sampler already used them)
+    pub fn skip_dimensions(&mut self, n: u64) {
+        self.dimension += n;
+    }
+
+    /// Gets the next dimension of the sample, as a number in `[0, 1)`
+    pub fn next_1d(&mut self) -> Float {
+        self.gen()
+    }
+
+    /// Gets the next two dimensions of the sample, as numbers in `[0, 1)`
+    pub fn next_2d(&mut self) -> (Float, Float) {
+        self.gen()
+    }
+
+    /// Gets the next dimension of the sample, as the bits of a fixed point
+    /// number in `[0, 1)` (i.e., the number is `bits / 2^64`)
+    fn next_bits(&mut self) -> u64 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        let n = self.samples_per_pixel as u64;
+        match self.kind {
+            SamplerKind::Independent => self.rng.next_u64(),
+            SamplerKind::Stratified if self.sample_index < n => {
+                let hash = self.hash(dimension);
+                let stratum = permutation_element(self.sample_index, n, hash);
+                let jitter: f64 = self.rng.gen();
+                to_bits((stratum as f64 + jitter) / n as f64)
+            }
+            SamplerKind::Sobol => {
+                let hash = self.hash(dimension / 2);
+                let index = nested_uniform_scramble(self.sample_index as u32, hash as u32);
+                let v = if dimension % 2 == 0 {
+                    index.reverse_bits()
+                } else {
+                    sobol_second_dimension(index)
+                };
+                let v = nested_uniform_scramble(v, (hash >> 32) as u32 ^ dimension as u32);
+                (v as u64) << 32
+            }
+            SamplerKind::Halton if (dimension as usize) < PRIMES.len() => {
+                let hash = self.hash(dimension);
+                let base = PRIMES[dimension as usize];
+                to_bits(scrambled_radical_inverse(base, self.sample_index, hash))
+            }
+            // Samples beyond the supported ones are independent
+            _ => self.rng.next_u64(),
+        }
+    }
+
+    /// A hash of the current pixel and `dimension`, for scrambling
+    fn hash(&self, dimension: u64) -> u64 {
+        crate::rand::mix(self.seed ^ crate::rand::mix(self.pixel ^ crate::rand::mix(dimension)))
+    }
+}
+
+impl RngCore for Sampler {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_bits() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.next_bits()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.rng.try_fill_bytes(dest)
+    }
+}
+
+/// Transforms a number in `[0, 1)` into the bits of a fixed point number
+fn to_bits(x: f64) -> u64 {
+    const TWO_TO_64: f64 = 18446744073709551616.0;
+    (x.clamp(0., 1.) * TWO_TO_64) as u64
+}
+
+/// Gets the second dimension of the Sobol sequence (the first one is
+/// simply the reversed bits of the index)
+fn sobol_second_dimension(index: u32) -> u32 {
+    let mut ret = 0;
+    let mut direction: u32 = 1 << 31;
+    for bit in 0..32 {
+        if (index >> bit) & 1 == 1 {
+            ret ^= direction;
+        }
+        direction ^= direction >> 1;
+    }
+    ret
+}
+
+/// A hash-based permutation of the bits of `x`, in which each bit only
+/// depends on the lower ones (by Laine and Karras)
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+/// Owen-scrambles the bits of a fixed point number in `[0, 1)`
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+/// Gets the radical inverse of `index` in a `base`, shifting each digit
+/// by a random amount that depends on the digits before it (i.e., a cheap form
+/// of Owen scrambling)
+fn scrambled_radical_inverse(base: u64, mut index: u64, hash: u64) -> f64 {
+    let inv_base = 1. / base as f64;
+    let mut inv_base_m = 1.;
+    let mut reversed_digits: u64 = 0;
+    while 1. - inv_base_m < 1. {
+        let next = index / base;
+        let digit = index - next * base;
+        let shift = crate::rand::mix(hash ^ reversed_digits) % base;
+        reversed_digits = reversed_digits * base + (digit + shift) % base;
+        inv_base_m *= inv_base;
+        index = next;
+    }
+    (inv_base_m * reversed_digits as f64).min(1. - f64::EPSILON / 2.)
+}
+
+/// Gets the position of `i` in a random permutation of `0..n`, chosen by
+/// `hash` (by Kensler, 2013, "Correlated Multi-Jittered Sampling")
+fn permutation_element(i: u64, n: u64, hash: u64) -> u64 {
+    let (mut i, l, p) = (i as u32, n as u32, hash as u32);
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    (i.wrapping_add(p) % l) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rand::get_sampler;
+
+    /// The first `n` two-dimensional samples of a pixel
+    fn samples(kind: SamplerKind, n: usize, pixel: u64) -> Vec<(Float, Float)> {
+        let mut sampler = get_sampler(kind, n, 1, pixel);
+        (0..n)
+            .map(|i| {
+                sampler.start_pixel_sample(pixel, i as u64);
+                sampler.next_2d()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_range_and_determinism() {
+        for kind in [
+            SamplerKind::Independent,
+            SamplerKind::Stratified,
+            SamplerKind::Sobol,
+            SamplerKind::Halton,
+        ] {
+            let a = samples(kind, 256, 3);
+            assert!(a
+                .iter()
+                .all(|(x, y)| (0. ..1.).contains(x) && (0. ..1.).contains(y)));
+            assert_eq!(a, samples(kind, 256, 3));
+            assert_ne!(a, samples(kind, 256, 4));
+        }
+    }
+
+    #[test]
+    fn test_stratification() {
+        // Every one of n strata of each dimension gets exactly one sample
+        let n = 64;
+        for kind in [SamplerKind::Stratified, SamplerKind::Sobol] {
+            let s = samples(kind, n, 7);
+            for dim in 0..2 {
+                let mut strata: Vec<usize> = s
+                    .iter()
+                    .map(|p| {
+                        let x = if dim == 0 { p.0 } else { p.1 };
+                        (x * n as Float).floor() as usize
+                    })
+                    .collect();
+                strata.sort_unstable();
+                assert_eq!(strata, (0..n).collect::<Vec<usize>>(), "{:?}", kind);
+            }
+        }
+
+        // The Halton sequence puts one of its first 2^k samples in each
+        // of the 2^k strata of the first dimension
+        let s = samples(SamplerKind::Halton, 32, 7);
+        let mut strata: Vec<usize> = s.iter().map(|p| (p.0 * 32.).floor() as usize).collect();
+        strata.sort_unstable();
+        assert_eq!(strata, (0..32).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn test_lower_error() {
+        // Estimate the integral of x*y over the unit square (i.e., 1/4)
+        let n = 256;
+        let error = |kind| -> Float {
+            (0..16)
+                .map(|pixel| {
+                    let mean = samples(kind, n, pixel)
+                        .iter()
+                        .map(|(x, y)| x * y)
+                        .sum::<Float>()
+                        / n as Float;
+                    (mean - 0.25).abs()
+                })
+                .sum::<Float>()
+        };
+        let independent = error(SamplerKind::Independent);
+        assert!(error(SamplerKind::Sobol) < independent);
+        assert!(error(SamplerKind::Halton) < independent);
+        assert!(error(SamplerKind::Stratified) < independent);
+    }
+}
@@ -183,6 +183,25 @@ pub fn uniform_sample_sphere(rng: &mut RandGen) -> Point3D {
     Point3D::new(x, y, z)
 }
 
+/// Samples a direction uniformly within a cone around `axis` (normalized),
+/// whose edge is at an angle of cosine `cos_max` from it
+pub fn sample_cone(rng: &mut RandGen, axis: Vector3D, cos_max: Float) -> Vector3D {
+    let (u, v): (Float, Float) = rng.gen();
+    let cos_theta = 1. - u * (1. - cos_max);
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+    let phi = 2. * crate::PI * v;
+    let e2 = axis.get_perpendicular().unwrap();
+    let e1 = e2.cross(axis);
+    (e1 * (sin_theta * phi.cos()) + e2 * (sin_theta * phi.sin()) + axis * cos_theta)
+        .get_normalized()
+}
+
+/// The probability (per unit solid angle) of [`sample_cone`]
+/// sampling any direction within the cone
+pub fn uniform_cone_pdf(cos_max: Float) -> Float {
+    1. / (2. * crate::PI * (1. - cos_max))
+}
+
 pub fn uniform_sample_disc(
     rng: &mut RandGen,
     radius: Float,
@@ -243,6 +262,18 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_sample_cone() {
+        let mut rng = get_rng();
+        let axis = Vector3D::new(1., 2., 3.).get_normalized();
+        let cos_max = (0.1 as Float).cos();
+        for _ in 0..100 {
+            let w = sample_cone(&mut rng, axis, cos_max);
+            assert!((w.length() - 1.).abs() < 1e-6);
+            assert!(w * axis >= cos_max - 1e-6);
+        }
+    }
+
     #[test]
     fn test_uniform_sample_hemisphere() {
         fn check(normal: Vector3D) -> Result<(), String> {
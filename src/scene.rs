@@ -20,16 +20,21 @@ SOFTWARE.
 
 // use std::rc::RefCount;
 use crate::bvh::BoundingVolumeTree;
+use crate::cache::{CacheReader, CacheWriter};
 use crate::colour::Spectrum;
+use crate::environment_map::EnvironmentMap;
 use crate::from_simple_model::SimpleModelReader;
+use crate::instance::{Instance, InstanceTree, Mesh};
+use crate::light_tree::LightTree;
 use crate::material::{Light, Material};
 use crate::primitive::Primitive;
 use crate::ray::Ray;
 use crate::triangle::Triangle;
 use crate::Float;
 use calendar::Date;
-use geometry3d::{Ray3D, Vector3D};
+use geometry3d::{BBox3D, Point3D, Ray3D, Transform, Vector3D};
 use simple_model::SimpleModel;
+use std::path::Path;
 
 #[derive(Clone)]
 pub struct Object {
@@ -53,6 +58,13 @@ pub struct Scene {
 
     pub back_material_indexes: Vec<usize>,
 
+    /// The id of the object (see [`Scene::push_object`]) that
+    /// each of the `triangles` belongs to
+    pub object_ids: Vec<usize>,
+
+    /// The number of objects pushed so far
+    object_count: usize,
+
     /// The materials in the scene
     pub materials: Vec<Material>,
 
@@ -61,15 +73,31 @@ pub struct Scene {
     /// The objects here are also in the objects part.
     pub lights: Vec<Object>,
 
-    /// A vector of distant [`Light`] objects that
-    /// are considered sources of direct light
-    pub distant_lights: Vec<Object>,
+    /// The sources of direct light that are not part of the geometry of
+    /// the scene, which have a delta position or direction: distant lights
+    /// (i.e., objects pushed with a [`Primitive::Source`]), as well as the
+    /// point and spot lights pushed through [`Scene::push_light`]
+    pub delta_lights: Vec<Box<dyn crate::lights::Light>>,
 
     /// The acceleration structure that helps trace rays.
     ///
     /// This needs to be build through the `build_accelerator` function.
     pub accelerator: Option<BoundingVolumeTree>,
 
+    /// The meshes that are placed in the scene through `instances`
+    pub meshes: Vec<Mesh>,
+
+    /// Copies of the `meshes`, each with its own transform
+    pub instances: Vec<Instance>,
+
+    /// The acceleration structure over the `instances`, built
+    /// with the `accelerator`
+    pub instance_accelerator: Option<InstanceTree>,
+
+    /// Chooses which of the `lights` are sampled from each point, when
+    /// not all of them are. It is built with the `accelerator`.
+    pub light_tree: Option<LightTree>,
+
     /// The colour of the sky, normalized
     pub sky_colour: Option<Spectrum<{ crate::N_CHANNELS }>>,
 
@@ -77,6 +105,14 @@ pub struct Scene {
     /// The sun should be added separately.
     /// Alternatively, you can use the `add_perez_sky` function
     pub sky: Option<Box<dyn Fn(Vector3D) -> Float + Sync>>,
+
+    /// An HDR image of the sky (e.g., a measured sky capture), which
+    /// replaces the `sky` and `sky_colour` if given
+    pub environment: Option<EnvironmentMap>,
+
+    /// The sphere returned by [`Scene::bounding_sphere`], computed
+    /// with the `accelerator`
+    bounds: Option<(Point3D, Float)>,
 }
 
 pub enum Wavelengths {
@@ -189,27 +225,308 @@ impl Scene {
         if self.accelerator.is_some() {
             panic!("Trying to re-build accelerator structure. If you really want this, use rebuild_accelerator")
         }
-        self.accelerator = Some(BoundingVolumeTree::new(self));
+        self.rebuild_accelerator();
     }
 
-    /// Builds the accelerator
+    /// Builds the accelerator, as well as those of the meshes that
+    /// do not have one and the one over the instances
     pub fn rebuild_accelerator(&mut self) {
         self.accelerator = Some(BoundingVolumeTree::new(self));
+        for mesh in self.meshes.iter_mut().filter(|m| m.accelerator.is_none()) {
+            mesh.build_accelerator();
+        }
+        self.instance_accelerator = Some(InstanceTree::new(&self.meshes, &self.instances));
+        self.light_tree = Some(LightTree::new(&self.lights, &self.materials));
+        self.bounds = Some(self.compute_bounding_sphere());
+    }
+
+    /// Saves the scene, including its acceleration structure, into a binary
+    /// file that can be loaded through [`Scene::load_cache`] much faster than
+    /// reading and processing the model it was created from (similar to
+    /// Radiance's octrees). The `source_hash` identifies that model (see
+    /// [`crate::cache::hash_files`]), so that caches of models that have
+    /// changed since can be rejected.
+    ///
+    /// The `sky` function and the `environment` are not saved. Scenes with meshes,
+    /// instances or point and spot lights cannot be saved.
+    pub fn save_cache(&self, filename: &Path, source_hash: u64) -> Result<(), String> {
+        if !self.meshes.is_empty() || !self.instances.is_empty() {
+            return Err("Scenes with meshes or instances cannot be cached".into());
+        }
+        // Distant lights are objects, but point and spot lights are not
+        let distant: Vec<&Object> = self
+            .delta_lights
+            .iter()
+            .filter_map(|l| l.object())
+            .collect();
+        if distant.len() != self.delta_lights.len() {
+            return Err("Scenes with point or spot lights cannot be cached".into());
+        }
+        let mut w = CacheWriter::default();
+        w.header(source_hash);
+
+        w.usize(self.triangles.len());
+        for (t, (n0, n1, n2)) in self.triangles.iter().zip(self.normals.iter()) {
+            w.floats(t);
+            w.vector(*n0);
+            w.vector(*n1);
+            w.vector(*n2);
+        }
+        w.usizes(&self.front_material_indexes);
+        w.usizes(&self.back_material_indexes);
+        w.usizes(&self.object_ids);
+        w.usize(self.object_count);
+
+        w.usize(self.materials.len());
+        for m in self.materials.iter() {
+            w.material(m);
+        }
+        let area: Vec<&Object> = self.lights.iter().collect();
+        for lights in [area, distant] {
+            w.usize(lights.len());
+            for light in lights {
+                w.primitive(&light.primitive)?;
+                w.usize(light.front_material_index);
+                w.usize(light.back_material_index);
+            }
+        }
+
+        match &self.sky_colour {
+            Some(colour) => {
+                w.u8(1);
+                w.spectrum(colour);
+            }
+            None => w.u8(0),
+        }
+        match &self.accelerator {
+            Some(accelerator) => {
+                w.u8(1);
+                accelerator.write_cache(&mut w);
+            }
+            None => w.u8(0),
+        }
+
+        std::fs::write(filename, w.data)
+            .map_err(|e| format!("Could not write cache '{}': {}", filename.display(), e))
+    }
+
+    /// Loads a scene saved through [`Scene::save_cache`], checking that it
+    /// was created from the model identified by `source_hash`
+    pub fn load_cache(filename: &Path, source_hash: u64) -> Result<Self, String> {
+        let data = std::fs::read(filename)
+            .map_err(|e| format!("Could not read cache '{}': {}", filename.display(), e))?;
+        let mut r = CacheReader::new(&data);
+        r.header(source_hash)?;
+        let mut scene = Scene::new();
+
+        let n_triangles = r.count(18 * std::mem::size_of::<Float>())?;
+        for _ in 0..n_triangles {
+            let mut t: Triangle = [0.; 9];
+            for v in t.iter_mut() {
+                *v = r.float()?;
+            }
+            scene.triangles.push(t);
+            scene.normals.push((r.vector()?, r.vector()?, r.vector()?));
+        }
+        scene.front_material_indexes = r.usizes()?;
+        scene.back_material_indexes = r.usizes()?;
+        scene.object_ids = r.usizes()?;
+        scene.object_count = r.usize()?;
+
+        let n_materials = r.count(1)?;
+        for _ in 0..n_materials {
+            scene.materials.push(r.material()?);
+        }
+        let mut distant = Vec::new();
+        for lights in [&mut scene.lights, &mut distant] {
+            let n_lights = r.count(1)?;
+            for _ in 0..n_lights {
+                lights.push(Object {
+                    primitive: r.primitive()?,
+                    front_material_index: r.usize()?,
+                    back_material_index: r.usize()?,
+                });
+            }
+        }
+
+        // Check the indexes, so that corrupt caches do not lead to panics
+        let n_triangles = scene.triangles.len();
+        if scene.front_material_indexes.len() != n_triangles
+            || scene.back_material_indexes.len() != n_triangles
+            || (!scene.object_ids.is_empty() && scene.object_ids.len() != n_triangles)
+        {
+            return Err("The number of materials of the triangles in the cache is wrong".into());
+        }
+        let n_materials = scene.materials.len();
+        let lights = scene.lights.iter().chain(distant.iter());
+        if scene
+            .front_material_indexes
+            .iter()
+            .chain(scene.back_material_indexes.iter())
+            .chain(lights.flat_map(|l| [&l.front_material_index, &l.back_material_index]))
+            .any(|i| *i >= n_materials)
+        {
+            return Err("The cache contains materials out of bounds".into());
+        }
+        if scene.object_ids.iter().any(|id| *id >= scene.object_count) {
+            return Err("The cache contains objects out of bounds".into());
+        }
+        for light in distant {
+            scene.push_light(Box::new(light));
+        }
+
+        if r.u8()? == 1 {
+            scene.sky_colour = Some(r.spectrum()?);
+        }
+        if r.u8()? == 1 {
+            scene.accelerator = Some(BoundingVolumeTree::read_cache(&mut r, &scene.triangles)?);
+            scene.instance_accelerator = Some(InstanceTree::default());
+            scene.light_tree = Some(LightTree::new(&scene.lights, &scene.materials));
+            scene.bounds = Some(scene.compute_bounding_sphere());
+        }
+        r.finish()?;
+        Ok(scene)
+    }
+
+    /// Reads a model from a Radiance (`.rad`) or SimpleModel (`.spl`) file,
+    /// for the visible wavelengths, and builds its acceleration structure.
+    ///
+    /// If a `cache` file is given, the scene is loaded from it when it was
+    /// created from the same `input`; otherwise, the model is read and the
+    /// cache is written for next time. Caches that cannot be used or saved
+    /// are reported to stderr, but are not errors.
+    pub fn from_file_with_cache(input: &Path, cache: Option<&Path>) -> Result<Self, String> {
+        let cache = match cache {
+            Some(file) => Some((file, crate::cache::hash_files(&[input])?)),
+            None => None,
+        };
+        if let Some((file, hash)) = cache {
+            match Self::load_cache(file, hash) {
+                Ok(scene) => return Ok(scene),
+                Err(e) if file.exists() => {
+                    eprintln!("Ignoring cache '{}': {}", file.display(), e)
+                }
+                // It does not exist yet
+                Err(_) => {}
+            }
+        }
+
+        let filename = input.to_string_lossy().to_string();
+        let mut scene = match input.extension().and_then(|e| e.to_str()) {
+            Some("rad") => Scene::from_radiance(filename),
+            Some("spl") => {
+                let (model, _header) = SimpleModel::from_file(filename.clone())
+                    .map_err(|e| format!("Could not read model '{}': {}", filename, e))?;
+                Scene::from_simple_model(&model, Wavelengths::Visible)
+            }
+            _ => return Err(format!("Unknown format in file {}", filename)),
+        };
+        scene.build_accelerator();
+
+        if let Some((file, hash)) = cache {
+            if let Err(e) = scene.save_cache(file, hash) {
+                eprintln!("Could not save cache: {}", e);
+            }
+        }
+        Ok(scene)
+    }
+
+    /// Checks whether the scene is surrounded by a sky (given
+    /// either by the `environment` or by the `sky` function)
+    pub fn has_sky(&self) -> bool {
+        self.environment.is_some() || self.sky.is_some()
+    }
+
+    /// The radiance of the sky in `direction`, which is black
+    /// if there is no sky
+    pub fn sky_radiance(&self, direction: Vector3D) -> Spectrum<{ crate::N_CHANNELS }> {
+        if let Some(environment) = &self.environment {
+            return environment.radiance(direction);
+        }
+        match &self.sky {
+            Some(sky) => {
+                let colour = self
+                    .sky_colour
+                    .unwrap_or_else(|| Spectrum::<{ crate::N_CHANNELS }>::gray(1.0));
+                colour * sky(direction)
+            }
+            None => Spectrum::<{ crate::N_CHANNELS }>::BLACK,
+        }
     }
 
     /// Returns the number of total lights; that is,
-    /// those in the `lighs` field and those in the `distant_lights`
-    /// one
+    /// those in the `lighs` and `delta_lights` fields
     pub fn count_all_lights(&self) -> usize {
-        self.lights.len() + self.distant_lights.len()
+        self.lights.len() + self.delta_lights.len()
+    }
+
+    /// Returns the centre and the radius of a sphere that contains
+    /// all the triangles in the scene. It is computed when the
+    /// accelerator is built or updated.
+    pub fn bounding_sphere(&self) -> (Point3D, Float) {
+        match self.bounds {
+            Some(bounds) => bounds,
+            None => self.compute_bounding_sphere(),
+        }
+    }
+
+    fn compute_bounding_sphere(&self) -> (Point3D, Float) {
+        let instance_bounds: Vec<BBox3D> = self
+            .instances
+            .iter()
+            .filter_map(|i| i.bounds(&self.meshes[i.mesh]))
+            .collect();
+        if self.triangles.is_empty() && instance_bounds.is_empty() {
+            return (Point3D::new(0., 0., 0.), 1.);
+        }
+        let mut min = Point3D::new(Float::MAX, Float::MAX, Float::MAX);
+        let mut max = Point3D::new(Float::MIN, Float::MIN, Float::MIN);
+        for t in self.triangles.iter() {
+            for v in t.chunks_exact(3) {
+                min = Point3D::new(min.x.min(v[0]), min.y.min(v[1]), min.z.min(v[2]));
+                max = Point3D::new(max.x.max(v[0]), max.y.max(v[1]), max.z.max(v[2]));
+            }
+        }
+        for b in instance_bounds.iter() {
+            min = Point3D::new(min.x.min(b.min.x), min.y.min(b.min.y), min.z.min(b.min.z));
+            max = Point3D::new(max.x.max(b.max.x), max.y.max(b.max.y), max.z.max(b.max.z));
+        }
+        let centre = Point3D::new(
+            (max.x + min.x) / 2.,
+            (max.y + min.y) / 2.,
+            (max.z + min.z) / 2.,
+        );
+        (centre, ((max - min).length() / 2.).max(1e-3))
     }
 
     /// Casts a [`Ray3D`] and returns an `Option<usize>` indicating the index
     /// of the first primitive hit by the ray, if any. The `ray` passed will now contain
     /// the Interaction
+    ///
+    /// Triangles within instances have indexes beyond those of the `triangles`
+    /// of the scene, so their materials and normals need to be obtained through
+    /// [`Scene::front_material_index`], [`Scene::back_material_index`] and
+    /// [`Scene::normals_at`].
     pub fn cast_ray(&self, ray: &mut Ray, node_aux: &mut Vec<usize>) -> Option<usize> {
         if let Some(accelerator) = &self.accelerator {
-            accelerator.intersect(&self.triangles, ray, node_aux)
+            let index = accelerator.intersect(&self.triangles, ray, node_aux);
+            if self.instances.is_empty() {
+                return index;
+            }
+            let max_distance_squared = match index {
+                Some(_) => (ray.interaction.point - ray.geometry.origin).length_squared(),
+                None => Float::INFINITY,
+            };
+            self.instance_tree()
+                .intersect(
+                    &self.meshes,
+                    &self.instances,
+                    ray,
+                    max_distance_squared,
+                    node_aux,
+                )
+                .map(|i| self.triangles.len() + i)
+                .or(index)
         } else {
             panic!("Trying to cast_ray() in a scene without an acceleration structure")
         }
@@ -224,11 +541,102 @@ impl Scene {
     ) -> bool {
         if let Some(a) = &self.accelerator {
             a.unobstructed_distance(&self.triangles, ray, distance_squared, node_aux)
+                && (self.instances.is_empty()
+                    || self.instance_tree().unobstructed_distance(
+                        &self.meshes,
+                        &self.instances,
+                        ray,
+                        distance_squared,
+                        node_aux,
+                    ))
         } else {
             panic!("Trying to check if unobstructed_distance() in a scene without an acceleration structure")
         }
     }
 
+    /// The acceleration structure over the instances
+    fn instance_tree(&self) -> &InstanceTree {
+        self.instance_accelerator
+            .as_ref()
+            .expect("Trying to intersect instances in a scene without an acceleration structure")
+    }
+
+    /// Gets the [`Instance`] and the index of the triangle within its [`Mesh`]
+    /// that were hit, if the `triangle_index` returned by [`Scene::cast_ray`]
+    /// does not belong to the `triangles` of the scene.
+    fn instance_triangle(&self, triangle_index: usize) -> Option<(&Instance, usize)> {
+        let index = triangle_index.checked_sub(self.triangles.len())?;
+        let (instance, triangle) = self.instance_tree().locate(index);
+        Some((&self.instances[instance], triangle))
+    }
+
+    /// Gets the index of the front material of a triangle hit by [`Scene::cast_ray`]
+    pub fn front_material_index(&self, triangle_index: usize) -> usize {
+        match self.instance_triangle(triangle_index) {
+            Some((instance, i)) => self.meshes[instance.mesh].front_material_indexes[i],
+            None => self.front_material_indexes[triangle_index],
+        }
+    }
+
+    /// Gets the index of the back material of a triangle hit by [`Scene::cast_ray`]
+    pub fn back_material_index(&self, triangle_index: usize) -> usize {
+        match self.instance_triangle(triangle_index) {
+            Some((instance, i)) => self.meshes[instance.mesh].back_material_indexes[i],
+            None => self.back_material_indexes[triangle_index],
+        }
+    }
+
+    /// Gets the normals at the vertices of a triangle hit by [`Scene::cast_ray`],
+    /// in world space
+    pub fn normals_at(&self, triangle_index: usize) -> (Vector3D, Vector3D, Vector3D) {
+        match self.instance_triangle(triangle_index) {
+            Some((instance, i)) => {
+                let (n0, n1, n2) = self.meshes[instance.mesh].normals[i];
+                (
+                    instance.normal_to_world(n0),
+                    instance.normal_to_world(n1),
+                    instance.normal_to_world(n2),
+                )
+            }
+            None => self.normals[triangle_index],
+        }
+    }
+
+    /// Pushes a [`Mesh`] to the [`Scene`] and returns its position
+    /// in the `meshes` Vector, so that it can be instanced.
+    ///
+    /// Meshes cannot emit direct light, as their triangles are not
+    /// sampled as light sources; those need to be pushed as objects.
+    pub fn push_mesh(&mut self, mesh: Mesh) -> usize {
+        let materials = mesh
+            .front_material_indexes
+            .iter()
+            .chain(mesh.back_material_indexes.iter());
+        for &i in materials {
+            if i >= self.materials.len() {
+                panic!("Pushing mesh with material out of bounds")
+            }
+            if self.materials[i].emits_direct_light() {
+                panic!("Pushing mesh with a light-emitting material. Push light sources as objects")
+            }
+        }
+        self.meshes.push(mesh);
+        self.meshes.len() - 1
+    }
+
+    /// Places the [`Mesh`] at position `mesh` of the `meshes` in the
+    /// scene, transformed from object space to world space
+    /// through `transform`. Returns the position of the new [`Instance`].
+    ///
+    /// The accelerator needs to be (re)built after pushing instances.
+    pub fn push_instance(&mut self, mesh: usize, transform: Transform) -> usize {
+        if mesh >= self.meshes.len() {
+            panic!("Pushing instance of a mesh out of bounds")
+        }
+        self.instances.push(Instance::new(mesh, transform));
+        self.instances.len() - 1
+    }
+
     /// Pushes a [`Material`] to the [`Scene`] and return its
     /// position in the `materials` Vector.
     pub fn push_material(&mut self, material: Material) -> usize {
@@ -237,17 +645,18 @@ impl Scene {
         self.materials.len() - 1
     }
 
-    /// Pushes a [`Primitive`] object into the [`Scene`]   
+    /// Pushes a [`Primitive`] object into the [`Scene`], returning its id. Ids
+    /// are given in the order in which objects are pushed, starting from zero.
     ///
     /// If the [`Primitive`] is made of a light-emmiting [`Material`], then
     /// it will be added twice: One to the normal scene, and then another to
-    /// the list of light sources.
+    /// the list of light sources (the `delta_lights`, for distant sources).
     pub fn push_object(
         &mut self,
         front_material_index: usize,
         back_material_index: usize,
         primitive: Primitive,
-    ) {
+    ) -> usize {
         if front_material_index >= self.materials.len() {
             panic!("Pushing object with front material out of bounds")
         }
@@ -260,18 +669,14 @@ impl Scene {
         let is_light = if self.materials[front_material_index].emits_direct_light()
             || self.materials[back_material_index].emits_direct_light()
         {
-            let ob_id = primitive.id();
             let object = Object {
                 front_material_index,
                 back_material_index,
                 primitive: primitive.clone(),
                 // texture: None,
             };
-            // I know this is not very fast... but we will
-            // only do this while creating the scene, not while
-            // rendering
-            if ob_id == "source" {
-                self.distant_lights.push(object);
+            if matches!(primitive, Primitive::Source(_)) {
+                self.push_light(Box::new(object));
             } else {
                 // register object as light
                 self.lights.push(object);
@@ -299,11 +704,202 @@ impl Scene {
         self.normals.extend_from_slice(&normals);
         self.front_material_indexes.extend_from_slice(&front);
         self.back_material_indexes.extend_from_slice(&back);
+
+        let id = self.object_count;
+        self.object_count += 1;
+        self.object_ids.resize(self.triangles.len(), id);
+        id
+    }
+
+    /// Pushes a source of direct light that has no surface, such as a
+    /// [`PointLight`](crate::lights::PointLight) or a
+    /// [`SpotLight`](crate::lights::SpotLight), into the `delta_lights`. These
+    /// are not seen by the camera nor by rays sampled from BSDFs.
+    pub fn push_light(&mut self, light: Box<dyn crate::lights::Light>) {
+        self.delta_lights.push(light);
+    }
+
+    /// Transforms the objects with the given `ids` (see [`Scene::push_object`]),
+    /// e.g., for moving a shading device or changing the angle of the louvres
+    /// across the iterations of a parametric study. The accelerator
+    /// needs to be updated afterwards, through [`Scene::update_accelerator`].
+    ///
+    /// Light sources cannot be transformed.
+    pub fn transform_objects(
+        &mut self,
+        ids: &[usize],
+        transform: &Transform,
+    ) -> Result<(), String> {
+        let mut selected = vec![false; self.object_count];
+        for &id in ids {
+            if id >= self.object_count {
+                return Err(format!("There is no object with id {}", id));
+            }
+            selected[id] = true;
+        }
+        let positions: Vec<usize> = self
+            .object_ids
+            .iter()
+            .enumerate()
+            .filter(|(_, id)| selected[**id])
+            .map(|(i, _)| i)
+            .collect();
+
+        for &i in positions.iter() {
+            let front = &self.materials[self.front_material_indexes[i]];
+            let back = &self.materials[self.back_material_indexes[i]];
+            if front.emits_direct_light() || back.emits_direct_light() {
+                return Err(format!(
+                    "Object {} is a light source, which cannot be transformed",
+                    self.object_ids[i]
+                ));
+            }
+        }
+
+        for i in positions {
+            for v in self.triangles[i].chunks_exact_mut(3) {
+                let p = transform.transform_pt(Point3D::new(v[0], v[1], v[2]));
+                v.copy_from_slice(&[p.x, p.y, p.z]);
+            }
+            let (n0, n1, n2) = self.normals[i];
+            self.normals[i] = (
+                transform.transform_normal(n0).get_normalized(),
+                transform.transform_normal(n1).get_normalized(),
+                transform.transform_normal(n2).get_normalized(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Updates the accelerator after some objects have been transformed
+    /// (see [`Scene::transform_objects`]). The bounds of the existing tree
+    /// are refitted, unless that makes it much slower than a new one, in
+    /// which case it is rebuilt. Returns whether it was rebuilt.
+    pub fn update_accelerator(&mut self) -> bool {
+        // The cost of the refitted tree, relative to the
+        // original one, above which it is rebuilt
+        const MAX_RELATIVE_COST: Float = 1.5;
+
+        let relative_cost = match &mut self.accelerator {
+            Some(accelerator) => accelerator.refit(&self.triangles),
+            None => Float::INFINITY,
+        };
+        if relative_cost > MAX_RELATIVE_COST {
+            self.rebuild_accelerator();
+            true
+        } else {
+            self.bounds = Some(self.compute_bounding_sphere());
+            false
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::material::Plastic;
+    use geometry3d::Sphere3D;
+
+    fn get_scene() -> Scene {
+        let mut scene = Scene::new();
+        let plastic = scene.push_material(Material::Plastic(Plastic {
+            colour: Spectrum::<{ crate::N_CHANNELS }>::from(0.5),
+            specularity: 0.,
+            roughness: 0.,
+        }));
+        for x in [-1., 1.] {
+            let sphere = Sphere3D::new(0.5, Point3D::new(x, 0., 0.));
+            scene.push_object(plastic, plastic, Primitive::Sphere(sphere));
+        }
+        scene.build_accelerator();
+        scene
+    }
+
+    fn hits(scene: &Scene, origin: Point3D) -> bool {
+        let mut ray = Ray {
+            geometry: Ray3D {
+                origin,
+                direction: Vector3D::new(0., 1., 0.),
+            },
+            ..Ray::default()
+        };
+        scene.cast_ray(&mut ray, &mut Vec::new()).is_some()
+    }
+
+    #[test]
+    fn test_transform_objects() {
+        let mut scene = get_scene();
+        assert_eq!(scene.object_count, 2);
+        assert_eq!(scene.object_ids.len(), scene.triangles.len());
+        assert!(scene
+            .transform_objects(&[2], &Transform::translate(Vector3D::new(0., 0., 1.)))
+            .is_err());
+
+        // Move the second sphere a bit... this is refitted
+        let t = Transform::translate(Vector3D::new(0., 0., 0.2));
+        scene.transform_objects(&[1], &t).unwrap();
+        assert!(!scene.update_accelerator());
+        assert!(hits(&scene, Point3D::new(-1., -10., 0.)));
+        assert!(hits(&scene, Point3D::new(1., -10., 0.5)));
+        assert!(!hits(&scene, Point3D::new(1., -10., -0.4)));
+
+        // Move it far away... this is rebuilt
+        let t = Transform::translate(Vector3D::new(0., 0., 1000.));
+        scene.transform_objects(&[1], &t).unwrap();
+        assert!(scene.update_accelerator());
+        assert!(hits(&scene, Point3D::new(1., -10., 1000.2)));
+        assert!(!hits(&scene, Point3D::new(1., -10., 0.2)));
+    }
+
+    #[test]
+    fn test_cache() {
+        let mut scene = get_scene();
+        let light = scene.push_material(Material::Light(Light(
+            Spectrum::<{ crate::N_CHANNELS }>::gray(100.),
+        )));
+        scene.push_object(
+            light,
+            light,
+            Primitive::Source(geometry3d::DistantSource3D::new(
+                Vector3D::new(0., 0., 1.),
+                0.1,
+            )),
+        );
+
+        let file = std::env::temp_dir().join("rendering_test_scene_cache.scn");
+        scene.save_cache(&file, 7).unwrap();
+        assert!(Scene::load_cache(&file, 8).is_err());
+        let loaded = Scene::load_cache(&file, 7).unwrap();
+        assert_eq!(loaded.triangles, scene.triangles);
+        assert_eq!(loaded.object_ids, scene.object_ids);
+        assert_eq!(loaded.materials.len(), 2);
+        assert_eq!(loaded.delta_lights.len(), 1);
+        assert!(loaded.delta_lights[0].object().is_some());
+        assert!(hits(&loaded, Point3D::new(-1., -10., 0.)));
+        assert!(!hits(&loaded, Point3D::new(0., -10., 0.)));
+
+        // Corrupt caches are rejected
+        let data = std::fs::read(&file).unwrap();
+        std::fs::write(&file, &data[..data.len() - 5]).unwrap();
+        assert!(Scene::load_cache(&file, 7).is_err());
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn test_from_file_with_cache() {
+        let input = Path::new("./tests/scenes/cornell.rad");
+        let file = std::env::temp_dir().join("rendering_test_from_file_with_cache.scn");
+        let _ = std::fs::remove_file(&file);
+
+        // The first time, the cache is written
+        let scene = Scene::from_file_with_cache(input, Some(&file)).unwrap();
+        assert!(file.exists());
+        let cached = Scene::from_file_with_cache(input, Some(&file)).unwrap();
+        assert_eq!(cached.triangles, scene.triangles);
+        std::fs::remove_file(&file).unwrap();
+
+        assert!(Scene::from_file_with_cache(Path::new("./tests/scenes/room.obj"), None).is_err());
+    }
 
     // #[test]
     // fn test_push_material() {
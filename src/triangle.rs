@@ -66,109 +66,261 @@ pub fn world_bounds(t: &Triangle) -> BBox3D {
     BBox3D::from_union_point(&bbox, c)
 }
 
-// /// Tests the intersection between a `Ray3D` and a pack (i.e., `&[]`)
-// /// of [`Triangle`]. Returns the index of the intersected triangle within the
-// /// pack, the point of intersection, and the `u` and `v` baricentric coordinates
-// /// of the intersection point.
-// pub fn triangle_pack_baricentric_coorinates(
-//     ts: &[Triangle],
-//     ray: &geometry3d::Ray3D,
-// ) -> Option<(usize, geometry3d::Point3D, Float, Float)> {
-//     let ax = std::simd::Simd::from([ts[0][0], ts[1][0], ts[2][0], ts[3][0]]);
-//     let ay = std::simd::Simd::from([ts[0][1], ts[1][1], ts[2][1], ts[3][1]]);
-//     let az = std::simd::Simd::from([ts[0][2], ts[1][2], ts[2][2], ts[3][2]]);
-
-//     let bx = std::simd::Simd::from([ts[0][3], ts[1][3], ts[2][3], ts[3][3]]);
-//     let by = std::simd::Simd::from([ts[0][4], ts[1][4], ts[2][4], ts[3][4]]);
-//     let bz = std::simd::Simd::from([ts[0][5], ts[1][5], ts[2][5], ts[3][5]]);
-
-//     let cx = std::simd::Simd::from([ts[0][6], ts[1][6], ts[2][6], ts[3][6]]);
-//     let cy = std::simd::Simd::from([ts[0][7], ts[1][7], ts[2][7], ts[3][7]]);
-//     let cz = std::simd::Simd::from([ts[0][8], ts[1][8], ts[2][8], ts[3][8]]);
-
-//     // Calculate baricentric coordinates
-//     let ox: std::simd::Simd<Float, 4> = std::simd::Simd::splat(ray.origin.x);
-//     let oy: std::simd::Simd<Float, 4> = std::simd::Simd::splat(ray.origin.y);
-//     let oz: std::simd::Simd<Float, 4> = std::simd::Simd::splat(ray.origin.z);
-
-//     let dx: std::simd::Simd<Float, 4> = std::simd::Simd::splat(ray.direction.x);
-//     let dy: std::simd::Simd<Float, 4> = std::simd::Simd::splat(ray.direction.y);
-//     let dz: std::simd::Simd<Float, 4> = std::simd::Simd::splat(ray.direction.z);
-
-//     // let a_rox = ax - ox;
-//     // let a_roy = ay - oy;
-//     // let a_roz = az - oz;
-
-//     let edge1_x = bx - ax;
-//     let edge1_y = by - ay;
-//     let edge1_z = bz - az;
-
-//     let edge2_x = cx - ax;
-//     let edge2_y = cy - ay;
-//     let edge2_z = cz - az;
-
-//     let edge1 = &[edge1_x, edge1_y, edge1_z];
-//     let edge2 = &[edge2_x, edge2_y, edge2_z];
-//     let ray_direction = &[dx, dy, dz];
-//     const TINY: Float = 1e-5;
-//     let h = cross(ray_direction, edge2);
-//     let a = dot(edge1, &h);
-//     if a.reduce_min().abs() < TINY {
-//         return None;
-//     }
-//     let f = std::simd::Simd::splat(1.) / a;
-//     let s = [ox - ax, oy - ay, oz - az];
-//     let u = f * dot(&s, &h);
-//     if u.reduce_min() > 1. + Float::EPSILON || u.reduce_max() < -Float::EPSILON {
-//         return None;
-//     }
-//     let q = cross(&s, edge1);
-//     let v = f * dot(ray_direction, &q);
-//     let uv = u + v;
-//     if uv.reduce_min() > 1.0 + Float::EPSILON || uv.reduce_max() < -Float::EPSILON {
-//         return None;
-//     }
-//     let t = f * dot(edge2, &q);
-//     if t.reduce_max() < TINY {
-//         return None;
-//     }
-
-//     // t must be positive, and alpha, beta and gamma must add to 1 and
-//     // be positive
-//     let us = u.as_array();
-//     let vs = v.as_array();
-//     let ts = t.as_array();
-
-//     let mut any_intersect = false;
-//     let mut t = Float::MAX;
-//     let mut v = Float::MAX;
-//     let mut u = Float::MAX;
-//     let mut which_tri = usize::MAX;
-
-//     for (i, found_t) in ts.iter().enumerate() {
-//         let found_u = us[i];
-//         let found_v = vs[i];
-
-//         // If it is valid AND is closer than the other
-//         let is_valid = *found_t > TINY
-//             && found_u + found_v <= 1.
-//             && found_u > -Float::EPSILON
-//             && found_v > -Float::EPSILON;
-//         if is_valid && *found_t < t {
-//             any_intersect = true; // mark as found
-//             t = *found_t;
-//             u = found_u;
-//             v = found_v;
-//             which_tri = i;
-//         }
-//     }
-
-//     if any_intersect {
-//         Some((which_tri, ray.project(t), u, v))
-//     } else {
-//         None
-//     }
-// }
+/// The number of [`Triangle`]s that are tested against a ray at once
+pub const PACK_SIZE: usize = 4;
+
+/// A bound on the relative error accumulated by `n` floating point
+/// operations (see Section 3.9 of Pharr et al., Physically Based Rendering)
+pub fn gamma(n: Float) -> Float {
+    let machine_epsilon = Float::EPSILON * 0.5;
+    n * machine_epsilon / (1. - n * machine_epsilon)
+}
+
+/// Gets the point at the baricentric coordinates `u` and `v` of a
+/// [`Triangle`] (i.e., the weights of its second and third vertices),
+/// together with a conservative bound on its absolute error.
+///
+/// Interpolating the vertices, instead of projecting the ray, leads to
+/// errors that do not grow with the distance travelled by the ray.
+pub fn triangle_point(t: &Triangle, u: Float, v: Float) -> (Point3D, Vector3D) {
+    let w = 1. - u - v;
+    let mut p = [0.; 3];
+    let mut error = [0.; 3];
+    for axis in 0..3 {
+        let (a, b, c) = (w * t[axis], u * t[3 + axis], v * t[6 + axis]);
+        p[axis] = a + b + c;
+        error[axis] = gamma(7.) * (a.abs() + b.abs() + c.abs());
+    }
+    (
+        Point3D::new(p[0], p[1], p[2]),
+        Vector3D::new(error[0], error[1], error[2]),
+    )
+}
+
+/// A `Ray3D` prepared for the watertight intersection test: the axes are
+/// permuted so that the direction's largest component is `z`, and
+/// the vertices are then sheared so that the ray points towards `+Z`.
+///
+/// Building it takes a few divisions, so it is meant to be built once
+/// per ray and then tested against many triangles.
+#[derive(Clone, Copy)]
+pub struct ShearedRay {
+    origin: [Float; 3],
+    /// The axes that become `x`, `y` and `z`
+    axes: [usize; 3],
+    /// The shear of `x` and `y`, and the scale of `z`
+    shear: [Float; 3],
+}
+
+impl ShearedRay {
+    /// Prepares a `Ray3D` for being intersected with triangles
+    pub fn new(ray: &Ray3D) -> Self {
+        let d = [ray.direction.x, ray.direction.y, ray.direction.z];
+        let kz = if d[0].abs() > d[1].abs() {
+            if d[0].abs() > d[2].abs() {
+                0
+            } else {
+                2
+            }
+        } else if d[1].abs() > d[2].abs() {
+            1
+        } else {
+            2
+        };
+        let mut kx = (kz + 1) % 3;
+        let mut ky = (kx + 1) % 3;
+        // Keep the winding of the triangles
+        if d[kz] < 0. {
+            std::mem::swap(&mut kx, &mut ky);
+        }
+        Self {
+            origin: [ray.origin.x, ray.origin.y, ray.origin.z],
+            axes: [kx, ky, kz],
+            shear: [d[kx] / d[kz], d[ky] / d[kz], 1. / d[kz]],
+        }
+    }
+
+    /// Puts a vertex in the coordinate system of the ray
+    fn transform(&self, p: [Float; 3]) -> [Float; 3] {
+        let [kx, ky, kz] = self.axes;
+        let p = [
+            p[0] - self.origin[0],
+            p[1] - self.origin[1],
+            p[2] - self.origin[2],
+        ];
+        [
+            p[kx] - self.shear[0] * p[kz],
+            p[ky] - self.shear[1] * p[kz],
+            p[kz] * self.shear[2],
+        ]
+    }
+
+    /// Puts the vertices in each lane of a [`TrianglePack`] in the
+    /// coordinate system of the ray
+    #[allow(clippy::needless_range_loop)]
+    fn transform_pack(&self, p: &[[Float; PACK_SIZE]; 3]) -> [[Float; PACK_SIZE]; 3] {
+        let [kx, ky, kz] = self.axes;
+        let mut ret = [[0.; PACK_SIZE]; 3];
+        for i in 0..PACK_SIZE {
+            let z = p[kz][i] - self.origin[kz];
+            ret[0][i] = (p[kx][i] - self.origin[kx]) - self.shear[0] * z;
+            ret[1][i] = (p[ky][i] - self.origin[ky]) - self.shear[1] * z;
+            ret[2][i] = z * self.shear[2];
+        }
+        ret
+    }
+}
+
+/// Tests the intersection between a [`ShearedRay`] and a triangle, using the
+/// watertight algorithm by Woop, Benthin and Wald (2013): rays hitting
+/// an edge or a vertex shared by several triangles hit at least one
+/// of them, so light does not leak through the joints of a mesh. Hits
+/// closer than the error bound of `t` are discarded.
+///
+/// Returns the distance `t`—in units of the length of the ray's
+/// direction, and infinite if the triangle is missed—and the
+/// `u` and `v` baricentric coordinates of the intersection point.
+#[inline(always)]
+fn watertight_baricentric_coordinates(
+    ray: &ShearedRay,
+    a: [Float; 3],
+    b: [Float; 3],
+    c: [Float; 3],
+) -> (Float, Float, Float) {
+    const MISS: (Float, Float, Float) = (Float::INFINITY, 0., 0.);
+    let a = ray.transform(a);
+    let b = ray.transform(b);
+    let c = ray.transform(c);
+
+    // Edge functions... i.e., the weights of a, b and c
+    let e0 = b[0] * c[1] - b[1] * c[0];
+    let e1 = c[0] * a[1] - c[1] * a[0];
+    let e2 = a[0] * b[1] - a[1] * b[0];
+    if (e0 < 0. || e1 < 0. || e2 < 0.) && (e0 > 0. || e1 > 0. || e2 > 0.) {
+        return MISS;
+    }
+    let det = e0 + e1 + e2;
+    if det == 0. {
+        return MISS;
+    }
+    let inv_det = 1. / det;
+    let t = (e0 * a[2] + e1 * b[2] + e2 * c[2]) * inv_det;
+
+    // Make sure that t is positive, even considering its error
+    let max_z = a[2].abs().max(b[2].abs()).max(c[2].abs());
+    let max_x = a[0].abs().max(b[0].abs()).max(c[0].abs());
+    let max_y = a[1].abs().max(b[1].abs()).max(c[1].abs());
+    let delta_z = gamma(3.) * max_z;
+    let delta_x = gamma(5.) * (max_x + max_z);
+    let delta_y = gamma(5.) * (max_y + max_z);
+    let delta_e = 2. * (gamma(2.) * max_x * max_y + delta_y * max_x + delta_x * max_y);
+    let max_e = e0.abs().max(e1.abs()).max(e2.abs());
+    let delta_t =
+        3. * (gamma(3.) * max_e * max_z + delta_e * max_z + delta_z * max_e) * inv_det.abs();
+    if t <= delta_t {
+        return MISS;
+    }
+    (t, e1 * inv_det, e2 * inv_det)
+}
+
+/// Up to [`PACK_SIZE`] [`Triangle`]s stored as a structure of arrays (i.e.,
+/// each coordinate of all the triangles is contiguous), so that they can
+/// be intersected at once, running the same operations over every lane.
+#[derive(Clone, Copy, Debug)]
+pub struct TrianglePack {
+    /// The X, Y and Z coordinates of the first vertex of each triangle
+    a: [[Float; PACK_SIZE]; 3],
+    /// The X, Y and Z coordinates of the second vertex of each triangle
+    b: [[Float; PACK_SIZE]; 3],
+    /// The X, Y and Z coordinates of the third vertex of each triangle
+    c: [[Float; PACK_SIZE]; 3],
+}
+
+impl TrianglePack {
+    /// Packs up to [`PACK_SIZE`] triangles. The lanes left empty hold
+    /// degenerate triangles, which are never hit.
+    #[allow(clippy::needless_range_loop)]
+    pub fn new(triangles: &[Triangle]) -> Self {
+        debug_assert!(triangles.len() <= PACK_SIZE);
+        let mut pack = Self {
+            a: [[0.; PACK_SIZE]; 3],
+            b: [[0.; PACK_SIZE]; 3],
+            c: [[0.; PACK_SIZE]; 3],
+        };
+        for (lane, t) in triangles.iter().take(PACK_SIZE).enumerate() {
+            for axis in 0..3 {
+                pack.a[axis][lane] = t[axis];
+                pack.b[axis][lane] = t[3 + axis];
+                pack.c[axis][lane] = t[6 + axis];
+            }
+        }
+        pack
+    }
+
+    /// Packs a slice of triangles of any length into as many packs as needed
+    pub fn pack_all(triangles: &[Triangle]) -> Vec<Self> {
+        triangles.chunks(PACK_SIZE).map(Self::new).collect()
+    }
+}
+
+/// Tests the intersection between a [`ShearedRay`] and each of the triangles
+/// in a [`TrianglePack`] (i.e., a watertight test in each lane). Returns,
+/// for each lane, the distance `t` to the intersection—in units of
+/// the length of the ray's direction, and infinite if the triangle is missed—and
+/// the `u` and `v` baricentric coordinates of the intersection point.
+///
+/// All lanes are calculated, and the misses are masked out at the end
+/// rather than by returning early.
+#[allow(clippy::type_complexity, clippy::needless_range_loop)]
+pub fn triangle_pack_baricentric_coorinates(
+    pack: &TrianglePack,
+    ray: &ShearedRay,
+) -> ([Float; PACK_SIZE], [Float; PACK_SIZE], [Float; PACK_SIZE]) {
+    let a = ray.transform_pack(&pack.a);
+    let b = ray.transform_pack(&pack.b);
+    let c = ray.transform_pack(&pack.c);
+
+    // Edge functions... i.e., the weights of a, b and c
+    let mut e0 = [0.; PACK_SIZE];
+    let mut e1 = [0.; PACK_SIZE];
+    let mut e2 = [0.; PACK_SIZE];
+    for i in 0..PACK_SIZE {
+        e0[i] = b[0][i] * c[1][i] - b[1][i] * c[0][i];
+        e1[i] = c[0][i] * a[1][i] - c[1][i] * a[0][i];
+        e2[i] = a[0][i] * b[1][i] - a[1][i] * b[0][i];
+    }
+
+    let mut ts = [Float::INFINITY; PACK_SIZE];
+    let mut us = [0.; PACK_SIZE];
+    let mut vs = [0.; PACK_SIZE];
+    for i in 0..PACK_SIZE {
+        let det = e0[i] + e1[i] + e2[i];
+        let inv_det = 1. / det;
+        let t = (e0[i] * a[2][i] + e1[i] * b[2][i] + e2[i] * c[2][i]) * inv_det;
+
+        // The error bound of t, as in the single-triangle test
+        let max_z = a[2][i].abs().max(b[2][i].abs()).max(c[2][i].abs());
+        let max_x = a[0][i].abs().max(b[0][i].abs()).max(c[0][i].abs());
+        let max_y = a[1][i].abs().max(b[1][i].abs()).max(c[1][i].abs());
+        let delta_z = gamma(3.) * max_z;
+        let delta_x = gamma(5.) * (max_x + max_z);
+        let delta_y = gamma(5.) * (max_y + max_z);
+        let delta_e = 2. * (gamma(2.) * max_x * max_y + delta_y * max_x + delta_x * max_y);
+        let max_e = e0[i].abs().max(e1[i].abs()).max(e2[i].abs());
+        let delta_t =
+            3. * (gamma(3.) * max_e * max_z + delta_e * max_z + delta_z * max_e) * inv_det.abs();
+
+        // Non-short-circuiting operators, so the mask has no branches
+        let any_negative = (e0[i] < 0.) | (e1[i] < 0.) | (e2[i] < 0.);
+        let any_positive = (e0[i] > 0.) | (e1[i] > 0.) | (e2[i] > 0.);
+        let hit = !(any_negative & any_positive) & (det != 0.) & (t > delta_t);
+
+        ts[i] = if hit { t } else { Float::INFINITY };
+        us[i] = if hit { e1[i] * inv_det } else { 0. };
+        vs[i] = if hit { e2[i] * inv_det } else { 0. };
+    }
+    (ts, us, vs)
+}
 
 // /// Calculates the determinant of a 3x3 matrix
 // fn det_3x3<T>(col0: &[T; 3], col1: &[T; 3], col2: &[T; 3]) -> T
@@ -183,90 +335,19 @@ pub fn world_bounds(t: &Triangle) -> BBox3D {
 //         + col2[0] * (col0[1] * col1[2] - col1[1] * col0[2])
 // }
 
-fn dot<T>(a: &[T; 3], b: &[T; 3]) -> T
-where
-    T: std::ops::Mul<T, Output = T>
-        + std::ops::Sub<T, Output = T>
-        + std::ops::Add<T, Output = T>
-        + Copy,
-{
-    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
-}
-
-fn cross<T>(a: &[T; 3], b: &[T; 3]) -> [T; 3]
-where
-    T: std::ops::Mul<T, Output = T>
-        + std::ops::Sub<T, Output = T>
-        + std::ops::Add<T, Output = T>
-        + Copy,
-{
-    let dx = a[1] * b[2] - a[2] * b[1];
-    let dy = a[2] * b[0] - a[0] * b[2];
-    let dz = a[0] * b[1] - a[1] * b[0];
-    [dx, dy, dz]
-}
-
 /// Tests the intersection between a `Ray3D` and a
 /// [`Triangle`]. Returns the the point of intersection, and the `u`
 /// and `v` baricentric coordinates of the intersection point.
-#[allow(clippy::too_many_arguments)]
-fn baricentric_coorinates(
-    ray: &Ray3D,
-    ax: Float,
-    ay: Float,
-    az: Float,
-    bx: Float,
-    by: Float,
-    bz: Float,
-    cx: Float,
-    cy: Float,
-    cz: Float,
-) -> Option<(Point3D, Float, Float)> {
-    // let ox = ray.origin.x;
-    // let oy = ray.origin.y;
-    // let oz = ray.origin.z;
-
-    // let dx = ray.direction.x;
-    // let dy = ray.direction.y;
-    // let dz = ray.direction.z;
-
-    // let a_rox = ax - ox;
-    // let a_roy = ay - oy;
-    // let a_roz = az - oz;
-
-    let edge1_x = bx - ax;
-    let edge1_y = by - ay;
-    let edge1_z = bz - az;
-
-    let edge2_x = cx - ax;
-    let edge2_y = cy - ay;
-    let edge2_z = cz - az;
-
-    let edge1 = &[edge1_x, edge1_y, edge1_z];
-    let edge2 = &[edge2_x, edge2_y, edge2_z];
-    let ray_direction = [ray.direction.x, ray.direction.y, ray.direction.z];
-    const TINY: Float = 1e-5;
-    let h = cross(&ray_direction, edge2);
-    let a = dot(edge1, &h);
-
-    if a.abs() < TINY {
-        return None;
-    }
-    let f = 1. / a;
-    let s = [ray.origin.x - ax, ray.origin.y - ay, ray.origin.z - az];
-    let u = f * dot(&s, &h);
-    // if u > 1. + Float::EPSILON || u < -Float::EPSILON {
-    if !(-Float::EPSILON..=1. + Float::EPSILON).contains(&u) {
-        return None;
-    }
-    let q = cross(&s, edge1);
-    let v = f * dot(&ray_direction, &q);
-    if u + v > 1.0 + Float::EPSILON || v < -Float::EPSILON {
-        return None;
-    }
-    let t = f * dot(edge2, &q);
-    if t > TINY {
-        return Some((ray.project(t), u, v));
+fn baricentric_coorinates(ray: &Ray3D, t: &Triangle) -> Option<(Point3D, Float, Float)> {
+    let (t_hit, u, v) = watertight_baricentric_coordinates(
+        &ShearedRay::new(ray),
+        [t[0], t[1], t[2]],
+        [t[3], t[4], t[5]],
+        [t[6], t[7], t[8]],
+    );
+    if t_hit.is_finite() {
+        let (p, _) = triangle_point(t, u, v);
+        return Some((p, u, v));
     }
     None
 }
@@ -274,19 +355,7 @@ fn baricentric_coorinates(
 /// Intersects a `Ray3D` and a [`Triangle`], returning the [`IntersectionInfo`]
 /// (or `None` if they don't intersect)
 pub fn triangle_intersect(t: &Triangle, ray: &geometry3d::Ray3D) -> Option<IntersectionInfo> {
-    let ax = t[0];
-    let ay = t[1];
-    let az = t[2];
-
-    let bx = t[3];
-    let by = t[4];
-    let bz = t[5];
-
-    let cx = t[6];
-    let cy = t[7];
-    let cz = t[8];
-
-    let (p, u, v) = baricentric_coorinates(ray, ax, ay, az, bx, by, bz, cx, cy, cz)?;
+    let (p, u, v) = baricentric_coorinates(ray, t)?;
     Some(new_info(t, p, u, v, ray.direction))
 }
 
@@ -296,43 +365,53 @@ pub fn simple_triangle_intersect(
     t: &Triangle,
     ray: &geometry3d::Ray3D,
 ) -> Option<geometry3d::Point3D> {
-    let ax = t[0];
-    let ay = t[1];
-    let az = t[2];
-
-    let bx = t[3];
-    let by = t[4];
-    let bz = t[5];
-
-    let cx = t[6];
-    let cy = t[7];
-    let cz = t[8];
-    let (pt, ..) = baricentric_coorinates(ray, ax, ay, az, bx, by, bz, cx, cy, cz)?;
+    let (pt, ..) = baricentric_coorinates(ray, t)?;
     Some(pt)
 }
 
-// /// Intersects a `Ray3D` and a pack (i.e., `&[]`) of [`Triangle`], returning the
-// /// index of the intersected [`Triangle`] within the pack, and its [`IntersectionInfo`]
-// /// (or `None` if they don't intersect)
-// pub fn triangle_intersect_pack(
-//     t: &[Triangle],
-//     ray: &geometry3d::Ray3D,
-// ) -> Option<(usize, IntersectionInfo)> {
-//     let (tri_index, p, u, v) = triangle_pack_baricentric_coorinates(t, ray)?;
-//     let triangle = &t[tri_index];
-//     Some((tri_index, new_info(triangle, p, u, v, ray.direction)))
-// }
+/// Finds the closest hit in a pack of lanes calculated by
+/// [`triangle_pack_baricentric_coorinates`]
+fn closest_in_pack(
+    ts: &[Float; PACK_SIZE],
+    us: &[Float; PACK_SIZE],
+    vs: &[Float; PACK_SIZE],
+) -> Option<(usize, Float, Float, Float)> {
+    let mut closest: Option<(usize, Float, Float, Float)> = None;
+    for (i, t) in ts.iter().enumerate() {
+        if t.is_finite() && closest.map_or(true, |(_, best, ..)| *t < best) {
+            closest = Some((i, *t, us[i], vs[i]));
+        }
+    }
+    closest
+}
 
-// /// Intersects a `Ray3D` and a pack (i.e., `&[]`) of [`Triangle`], returning the
-// /// index of the intersected [`Triangle`] within the pack, and the `Point3D` of
-// /// intersection
-// pub fn simple_triangle_intersect_pack(
-//     t: &[Triangle],
-//     ray: &geometry3d::Ray3D,
-// ) -> Option<(usize, geometry3d::Point3D)> {
-//     let (tri_index, pt, ..) = triangle_pack_baricentric_coorinates(t, ray)?;
-//     Some((tri_index, pt))
-// }
+/// Intersects a `Ray3D` and a pack (i.e., `&[]`, of up to [`PACK_SIZE`] elements)
+/// of [`Triangle`], returning the index of the closest intersected [`Triangle`]
+/// within the pack, and its [`IntersectionInfo`] (or `None` if none of them is hit)
+pub fn triangle_intersect_pack(
+    t: &[Triangle],
+    ray: &geometry3d::Ray3D,
+) -> Option<(usize, IntersectionInfo)> {
+    let (ts, us, vs) =
+        triangle_pack_baricentric_coorinates(&TrianglePack::new(t), &ShearedRay::new(ray));
+    let (tri_index, _, u, v) = closest_in_pack(&ts, &us, &vs)?;
+    let (p, _) = triangle_point(&t[tri_index], u, v);
+    Some((tri_index, new_info(&t[tri_index], p, u, v, ray.direction)))
+}
+
+/// Intersects a `Ray3D` and a pack (i.e., `&[]`, of up to [`PACK_SIZE`] elements)
+/// of [`Triangle`], returning the index of the closest intersected [`Triangle`]
+/// within the pack, and the `Point3D` of intersection
+pub fn simple_triangle_intersect_pack(
+    t: &[Triangle],
+    ray: &geometry3d::Ray3D,
+) -> Option<(usize, geometry3d::Point3D)> {
+    let (ts, us, vs) =
+        triangle_pack_baricentric_coorinates(&TrianglePack::new(t), &ShearedRay::new(ray));
+    let (tri_index, _, u, v) = closest_in_pack(&ts, &us, &vs)?;
+    let (p, _) = triangle_point(&t[tri_index], u, v);
+    Some((tri_index, p))
+}
 
 pub struct Intersection {
     pub e1: Vector3D,
@@ -748,201 +827,267 @@ mod testing {
         test_hit(p + UP, DOWN, Some(p), SurfaceSide::Front).unwrap();
     }
 
-    // #[test]
-    // fn test_triangle_intersect_pack() {
-    //     let a = Point3D::new(0., 0., 0.);
-    //     let b = Point3D::new(1., 0., 0.);
-    //     let c = Point3D::new(0., 1., 0.);
-
-    //     let triangle: [Triangle; 4] = [
-    //         [a.x, a.y, 0.0, b.x, b.y, 0.0, c.x, c.y, 0.0],
-    //         [a.x, a.y, 0.1, b.x, b.y, 0.1, c.x, c.y, 0.1],
-    //         [a.x, a.y, 0.2, b.x, b.y, 0.2, c.x, c.y, 0.2],
-    //         [a.x, a.y, 0.3, b.x, b.y, 0.3, c.x, c.y, 0.3],
-    //     ];
-
-    //     let test_hit = |pt: Point3D,
-    //                     dir: Vector3D,
-    //                     exp_index: usize,
-    //                     expect_pt: Option<Point3D>,
-    //                     exp_side: SurfaceSide|
-    //      -> Result<(), String> {
-    //         let ray = Ray3D {
-    //             origin: pt,
-    //             direction: dir,
-    //         };
-
-    //         if let Some((index, info)) = triangle_intersect_pack(&triangle, &ray) {
-    //             let phit = info.p;
-
-    //             if let Some(exp_p) = expect_pt {
-    //                 if !phit.compare(exp_p) {
-    //                     return Err(format!(
-    //                         "Hit in incorrect point...: pt = {}, dir = {}, phit = {}",
-    //                         pt, dir, phit
-    //                     ));
-    //                 }
-    //             } else {
-    //                 return Err(format!("Was NOT expecting hit: pt = {}, dir = {}", pt, dir));
-    //             }
-    //             if exp_side != info.side {
-    //                 return Err(format!(
-    //                     "Expecing a hit at the {:?} (dir = {}, pt = {})",
-    //                     exp_side, dir, pt
-    //                 ));
-    //             }
-    //             if exp_index != index {
-    //                 return Err(format!(
-    //                     "Expecing a hit at triangle {} (dir = {}, pt = {})",
-    //                     index, dir, pt
-    //                 ));
-    //             }
-    //         } else {
-    //             if expect_pt.is_some() {
-    //                 return Err(format!("WAS expecting hit: pt = {}, dir = {}", pt, dir));
-    //             }
-    //         }
-
-    //         Ok(())
-    //     }; // end of closure
-
-    //     /* FROM THE BOTTOM, GOING UP */
-    //     // Vertex A
-    //     test_hit(a + DOWN, UP, 0, Some(a), SurfaceSide::Back).unwrap();
-
-    //     // Vertex B.
-    //     test_hit(b + DOWN, UP, 0, Some(b), SurfaceSide::Back).unwrap();
-
-    //     // Vertex C.
-    //     test_hit(c + DOWN, UP, 0, Some(c), SurfaceSide::Back).unwrap();
-
-    //     // Segment AB.
-    //     let p = Point3D::new(0.5, 0., 0.);
-    //     test_hit(p + DOWN, UP, 0, Some(p), SurfaceSide::Back).unwrap();
-
-    //     // Segment AC.
-    //     let p = Point3D::new(0., 0.5, 0.);
-    //     test_hit(p + DOWN, UP, 0, Some(p), SurfaceSide::Back).unwrap();
-
-    //     // Segment BC.
-    //     let p = Point3D::new(0.5, 0.5, 0.);
-    //     test_hit(p + DOWN, UP, 0, Some(p), SurfaceSide::Back).unwrap();
-
-    //     // Point outside
-    //     let p = Point3D::new(0., -1., 0.);
-    //     test_hit(p + DOWN, UP, 0, None, SurfaceSide::Back).unwrap();
-
-    //     // Point inside
-    //     let p = Point3D::new(0.1, 0.1, 0.);
-    //     test_hit(p + DOWN, UP, 0, Some(p), SurfaceSide::Back).unwrap();
-
-    //     /* FROM THE TOP, GOING DOWN */
-    //     // Vertex A
-    //     test_hit(a + UP, DOWN, 3, Some(a + UP * 0.3), SurfaceSide::Front).unwrap();
-
-    //     // Vertex B.
-    //     test_hit(b + UP, DOWN, 3, Some(b + UP * 0.3), SurfaceSide::Front).unwrap();
-
-    //     // Vertex C.
-    //     test_hit(c + UP, DOWN, 3, Some(c + UP * 0.3), SurfaceSide::Front).unwrap();
-
-    //     // Segment AB.
-    //     let p = Point3D::new(0.5, 0., 0.);
-    //     test_hit(p + UP, DOWN, 3, Some(p + UP * 0.3), SurfaceSide::Front).unwrap();
-
-    //     // Segment AC.
-    //     let p = Point3D::new(0., 0.5, 0.);
-    //     test_hit(p + UP, DOWN, 3, Some(p + UP * 0.3), SurfaceSide::Front).unwrap();
-
-    //     // Segment BC.
-    //     let p = Point3D::new(0.5, 0.5, 0.);
-    //     test_hit(p + UP, DOWN, 3, Some(p + UP * 0.3), SurfaceSide::Front).unwrap();
-
-    //     // Point outside
-    //     let p = Point3D::new(0., -1., 0.);
-    //     test_hit(p + UP, DOWN, 3, None, SurfaceSide::Front).unwrap();
-
-    //     // Point inside
-    //     let p = Point3D::new(0.1, 0.1, 0.);
-    //     test_hit(p + UP, DOWN, 3, Some(p + UP * 0.3), SurfaceSide::Front).unwrap();
-
-    //     /* FROM THE TOP, GOING DOWN... BUT STARTING BETWEEN TRIANGLES */
-    //     // Vertex A
-    //     test_hit(
-    //         a + UP * 0.15,
-    //         DOWN,
-    //         1,
-    //         Some(a + UP * 0.1),
-    //         SurfaceSide::Front,
-    //     )
-    //     .unwrap();
-
-    //     // Vertex B.
-    //     test_hit(
-    //         b + UP * 0.15,
-    //         DOWN,
-    //         1,
-    //         Some(b + UP * 0.1),
-    //         SurfaceSide::Front,
-    //     )
-    //     .unwrap();
-
-    //     // Vertex C.
-    //     test_hit(
-    //         c + UP * 0.15,
-    //         DOWN,
-    //         1,
-    //         Some(c + UP * 0.1),
-    //         SurfaceSide::Front,
-    //     )
-    //     .unwrap();
-
-    //     // Segment AB.
-    //     let p = Point3D::new(0.5, 0., 0.);
-    //     test_hit(
-    //         p + UP * 0.15,
-    //         DOWN,
-    //         1,
-    //         Some(p + UP * 0.1),
-    //         SurfaceSide::Front,
-    //     )
-    //     .unwrap();
-
-    //     // Segment AC.
-    //     let p = Point3D::new(0., 0.5, 0.);
-    //     test_hit(
-    //         p + UP * 0.15,
-    //         DOWN,
-    //         1,
-    //         Some(p + UP * 0.1),
-    //         SurfaceSide::Front,
-    //     )
-    //     .unwrap();
-
-    //     // Segment BC.
-    //     let p = Point3D::new(0.5, 0.5, 0.);
-    //     test_hit(
-    //         p + UP * 0.15,
-    //         DOWN,
-    //         1,
-    //         Some(p + UP * 0.1),
-    //         SurfaceSide::Front,
-    //     )
-    //     .unwrap();
-
-    //     // Point outside
-    //     let p = Point3D::new(0., -1., 0.);
-    //     test_hit(p + UP * 0.15, DOWN, 1, None, SurfaceSide::Front).unwrap();
-
-    //     // Point inside
-    //     let p = Point3D::new(0.1, 0.1, 0.);
-    //     test_hit(
-    //         p + UP * 0.15,
-    //         DOWN,
-    //         1,
-    //         Some(p + UP * 0.1),
-    //         SurfaceSide::Front,
-    //     )
-    //     .unwrap();
-    // }
+    #[test]
+    fn test_triangle_intersect_pack() {
+        let a = Point3D::new(0., 0., 0.);
+        let b = Point3D::new(1., 0., 0.);
+        let c = Point3D::new(0., 1., 0.);
+
+        let triangle: [Triangle; 4] = [
+            [a.x, a.y, 0.0, b.x, b.y, 0.0, c.x, c.y, 0.0],
+            [a.x, a.y, 0.1, b.x, b.y, 0.1, c.x, c.y, 0.1],
+            [a.x, a.y, 0.2, b.x, b.y, 0.2, c.x, c.y, 0.2],
+            [a.x, a.y, 0.3, b.x, b.y, 0.3, c.x, c.y, 0.3],
+        ];
+
+        let test_hit = |pt: Point3D,
+                        dir: Vector3D,
+                        exp_index: usize,
+                        expect_pt: Option<Point3D>,
+                        exp_side: SurfaceSide|
+         -> Result<(), String> {
+            let ray = Ray3D {
+                origin: pt,
+                direction: dir,
+            };
+
+            if let Some((index, info)) = triangle_intersect_pack(&triangle, &ray) {
+                let phit = info.p;
+
+                if let Some(exp_p) = expect_pt {
+                    if !phit.compare(exp_p) {
+                        return Err(format!(
+                            "Hit in incorrect point...: pt = {}, dir = {}, phit = {}",
+                            pt, dir, phit
+                        ));
+                    }
+                } else {
+                    return Err(format!("Was NOT expecting hit: pt = {}, dir = {}", pt, dir));
+                }
+                if exp_side != info.side {
+                    return Err(format!(
+                        "Expecing a hit at the {:?} (dir = {}, pt = {})",
+                        exp_side, dir, pt
+                    ));
+                }
+                if exp_index != index {
+                    return Err(format!(
+                        "Expecing a hit at triangle {} (dir = {}, pt = {})",
+                        index, dir, pt
+                    ));
+                }
+            } else {
+                if expect_pt.is_some() {
+                    return Err(format!("WAS expecting hit: pt = {}, dir = {}", pt, dir));
+                }
+            }
+
+            Ok(())
+        }; // end of closure
+
+        /* FROM THE BOTTOM, GOING UP */
+        // Vertex A
+        test_hit(a + DOWN, UP, 0, Some(a), SurfaceSide::Back).unwrap();
+
+        // Vertex B.
+        test_hit(b + DOWN, UP, 0, Some(b), SurfaceSide::Back).unwrap();
+
+        // Vertex C.
+        test_hit(c + DOWN, UP, 0, Some(c), SurfaceSide::Back).unwrap();
+
+        // Segment AB.
+        let p = Point3D::new(0.5, 0., 0.);
+        test_hit(p + DOWN, UP, 0, Some(p), SurfaceSide::Back).unwrap();
+
+        // Segment AC.
+        let p = Point3D::new(0., 0.5, 0.);
+        test_hit(p + DOWN, UP, 0, Some(p), SurfaceSide::Back).unwrap();
+
+        // Segment BC.
+        let p = Point3D::new(0.5, 0.5, 0.);
+        test_hit(p + DOWN, UP, 0, Some(p), SurfaceSide::Back).unwrap();
+
+        // Point outside
+        let p = Point3D::new(0., -1., 0.);
+        test_hit(p + DOWN, UP, 0, None, SurfaceSide::Back).unwrap();
+
+        // Point inside
+        let p = Point3D::new(0.1, 0.1, 0.);
+        test_hit(p + DOWN, UP, 0, Some(p), SurfaceSide::Back).unwrap();
+
+        /* FROM THE TOP, GOING DOWN */
+        // Vertex A
+        test_hit(a + UP, DOWN, 3, Some(a + UP * 0.3), SurfaceSide::Front).unwrap();
+
+        // Vertex B.
+        test_hit(b + UP, DOWN, 3, Some(b + UP * 0.3), SurfaceSide::Front).unwrap();
+
+        // Vertex C.
+        test_hit(c + UP, DOWN, 3, Some(c + UP * 0.3), SurfaceSide::Front).unwrap();
+
+        // Segment AB.
+        let p = Point3D::new(0.5, 0., 0.);
+        test_hit(p + UP, DOWN, 3, Some(p + UP * 0.3), SurfaceSide::Front).unwrap();
+
+        // Segment AC.
+        let p = Point3D::new(0., 0.5, 0.);
+        test_hit(p + UP, DOWN, 3, Some(p + UP * 0.3), SurfaceSide::Front).unwrap();
+
+        // Segment BC.
+        let p = Point3D::new(0.5, 0.5, 0.);
+        test_hit(p + UP, DOWN, 3, Some(p + UP * 0.3), SurfaceSide::Front).unwrap();
+
+        // Point outside
+        let p = Point3D::new(0., -1., 0.);
+        test_hit(p + UP, DOWN, 3, None, SurfaceSide::Front).unwrap();
+
+        // Point inside
+        let p = Point3D::new(0.1, 0.1, 0.);
+        test_hit(p + UP, DOWN, 3, Some(p + UP * 0.3), SurfaceSide::Front).unwrap();
+
+        /* FROM THE TOP, GOING DOWN... BUT STARTING BETWEEN TRIANGLES */
+        // Vertex A
+        test_hit(
+            a + UP * 0.15,
+            DOWN,
+            1,
+            Some(a + UP * 0.1),
+            SurfaceSide::Front,
+        )
+        .unwrap();
+
+        // Vertex B.
+        test_hit(
+            b + UP * 0.15,
+            DOWN,
+            1,
+            Some(b + UP * 0.1),
+            SurfaceSide::Front,
+        )
+        .unwrap();
+
+        // Vertex C.
+        test_hit(
+            c + UP * 0.15,
+            DOWN,
+            1,
+            Some(c + UP * 0.1),
+            SurfaceSide::Front,
+        )
+        .unwrap();
+
+        // Segment AB.
+        let p = Point3D::new(0.5, 0., 0.);
+        test_hit(
+            p + UP * 0.15,
+            DOWN,
+            1,
+            Some(p + UP * 0.1),
+            SurfaceSide::Front,
+        )
+        .unwrap();
+
+        // Segment AC.
+        let p = Point3D::new(0., 0.5, 0.);
+        test_hit(
+            p + UP * 0.15,
+            DOWN,
+            1,
+            Some(p + UP * 0.1),
+            SurfaceSide::Front,
+        )
+        .unwrap();
+
+        // Segment BC.
+        let p = Point3D::new(0.5, 0.5, 0.);
+        test_hit(
+            p + UP * 0.15,
+            DOWN,
+            1,
+            Some(p + UP * 0.1),
+            SurfaceSide::Front,
+        )
+        .unwrap();
+
+        // Point outside
+        let p = Point3D::new(0., -1., 0.);
+        test_hit(p + UP * 0.15, DOWN, 1, None, SurfaceSide::Front).unwrap();
+
+        // Point inside
+        let p = Point3D::new(0.1, 0.1, 0.);
+        test_hit(
+            p + UP * 0.15,
+            DOWN,
+            1,
+            Some(p + UP * 0.1),
+            SurfaceSide::Front,
+        )
+        .unwrap();
+    }
+
+    /// Rays crossing the edge shared by two triangles—and the vertex
+    /// shared by them—far away from the origin must hit one of them
+    #[test]
+    fn test_watertight() {
+        let offset = 12345.678;
+        let a = Point3D::new(offset, offset, 0.);
+        let b = Point3D::new(offset + 1., offset, 0.);
+        let c = Point3D::new(offset, offset + 1., 0.);
+        let d = Point3D::new(offset + 1., offset + 1., 0.);
+        let triangles: [Triangle; 2] = [
+            [a.x, a.y, a.z, b.x, b.y, b.z, c.x, c.y, c.z],
+            [b.x, b.y, b.z, d.x, d.y, d.z, c.x, c.y, c.z],
+        ];
+        let n = 1000;
+        for i in 0..=n {
+            let x = i as Float / n as Float;
+            let on_edge = Point3D::new(offset + x, offset + 1. - x, 0.);
+            let ray = Ray3D {
+                origin: on_edge + Vector3D::new(0.1, 0.3, 1.),
+                direction: Vector3D::new(-0.1, -0.3, -1.).get_normalized(),
+            };
+            assert!(
+                triangle_intersect_pack(&triangles, &ray).is_some(),
+                "leaked through {}",
+                on_edge
+            );
+        }
+    }
+
+    /// Rays leaving a surface far away from the origin, from the offset
+    /// origin, should not hit that surface again
+    #[test]
+    fn test_offset_ray_origin() {
+        use crate::interaction::offset_ray_origin;
+        let offset = 5000.;
+        let tilted: Triangle = [
+            offset,
+            offset,
+            offset,
+            offset + 3.,
+            offset + 0.1,
+            offset + 0.2,
+            offset + 0.3,
+            offset + 2.,
+            offset + 1.,
+        ];
+        for i in 1..100 {
+            let x = i as Float / 100.;
+            let ray = Ray3D {
+                origin: Point3D::new(offset + x, offset + 0.2, offset + 10.),
+                direction: Vector3D::new(0., 0., -1.),
+            };
+            let info = match triangle_intersect(&tilted, &ray) {
+                Some(info) => info,
+                None => continue,
+            };
+            let (point, error) = triangle_point(&tilted, info.u, info.v);
+            for direction in [ray.direction * -1., Vector3D::new(x, 1. - x, 0.1)] {
+                let direction = direction.get_normalized();
+                let origin = offset_ray_origin(point, error, info.normal, direction);
+                let ray = Ray3D { origin, direction };
+                assert!(triangle_intersect(&tilted, &ray).is_none());
+            }
+        }
+    }
 }
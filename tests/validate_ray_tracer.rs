@@ -1,5 +1,5 @@
 use geometry3d::{Point3D, Ray3D, Vector3D};
-use rendering::{Float, Ray, RayTracer, RayTracerHelper, Scene};
+use rendering::{Float, RayTracer, Scene};
 use validate::{valid, SeriesValidator, Validate, Validator};
 
 const MAX_DEPTH: usize = 13;
@@ -32,7 +32,7 @@ fn load_expected_results(filename: String) -> Vec<Float> {
         .collect()
 }
 
-fn load_rays(filename: &str) -> Vec<Ray> {
+fn load_rays(filename: &str) -> Vec<Ray3D> {
     let s = std::fs::read_to_string(filename).unwrap();
     s.lines()
         .map(|line| {
@@ -42,12 +42,9 @@ fn load_rays(filename: &str) -> Vec<Ray> {
                 .map(|x| x.parse::<Float>().unwrap())
                 .collect();
 
-            Ray {
-                geometry: Ray3D {
-                    origin: Point3D::new(a[0], a[1], a[2]),
-                    direction: Vector3D::new(a[3], a[4], a[5]).get_normalized(),
-                },
-                ..Ray::default()
+            Ray3D {
+                origin: Point3D::new(a[0], a[1], a[2]),
+                direction: Vector3D::new(a[3], a[4], a[5]).get_normalized(),
             }
         })
         .collect()
@@ -64,17 +61,12 @@ fn get_simple_results(dir: &str, max_depth: usize) -> (Vec<Float>, Vec<Float>) {
         limit_weight: 1e-9,
         ..RayTracer::default()
     };
-    let mut aux = RayTracerHelper::default();
-    let mut rng = rendering::rand::get_rng();
+    let rays = load_rays("./tests/points.pts");
 
-    let mut rays = load_rays("./tests/points.pts");
-
-    let found = rays
-        .iter_mut()
-        .map(|ray| {
-            let (c, _) = integrator.trace_ray(&mut rng, &scene, ray, &mut aux);
-            c.radiance()
-        })
+    let found = integrator
+        .calc_points(&scene, &rays)
+        .iter()
+        .map(|c| c.radiance())
         .collect();
 
     let expected = if max_depth == 0 {